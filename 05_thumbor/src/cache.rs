@@ -0,0 +1,570 @@
+use anyhow::Result;
+use axum::http::StatusCode;
+use bytes::Bytes;
+use lru::LruCache;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, sync::OnceCell};
+use tracing::{info, warn};
+
+use crate::{format::ImageFormat, pb::ImageSpec};
+
+// 缓存相关的配置，容量都是字节数
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    // 源图片在内存里最多占用的空间
+    pub source_capacity: usize,
+    // 处理好的图片在内存里最多占用的空间
+    pub rendered_capacity: usize,
+    // 处理好的图片在磁盘上的缓存目录，不设置时不使用磁盘缓存
+    pub disk_dir: Option<String>,
+    // 磁盘缓存最多占用的空间
+    pub disk_capacity: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            source_capacity: 256 * 1024 * 1024,
+            rendered_capacity: 128 * 1024 * 1024,
+            disk_dir: None,
+            disk_capacity: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+// 从源站获取的图片
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub data: Bytes,
+    pub hash: String,
+    pub last_modified: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+// 处理好的图片，和源图片同时过期
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    pub data: Bytes,
+    pub etag: String,
+    pub last_modified: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+// 缓存项占用的字节数
+pub trait Weight {
+    fn weight(&self) -> usize;
+}
+
+trait Expiring {
+    fn expires_at(&self) -> SystemTime;
+
+    fn is_fresh(&self) -> bool {
+        self.expires_at() > SystemTime::now()
+    }
+}
+
+// 按照字节数而不是个数限制容量的 LRU 缓存
+pub struct ByteLru<K: Hash + Eq, V> {
+    inner: LruCache<K, V>,
+    used: usize,
+    capacity: usize,
+}
+
+// 两级缓存：源图片和处理好的图片。相同的请求同时到达时，只获取和处理一次
+pub struct ImageCache {
+    sources: Mutex<ByteLru<String, Source>>,
+    rendered: Mutex<ByteLru<String, Rendered>>,
+    disk: Option<DiskCache>,
+    fetching: Coalescer<Source, StatusCode>,
+    rendering: Coalescer<Rendered, StatusCode>,
+}
+
+// 合并相同 key 的并发请求：第一个请求执行，其它请求等待它的结果。
+// 只在查找和删除 key 时短暂持有锁，不同 key 的请求互不影响
+pub struct Coalescer<V, E> {
+    inflight: Mutex<HashMap<String, Inflight<V, E>>>,
+}
+
+type Inflight<V, E> = Arc<OnceCell<Result<V, E>>>;
+
+struct DiskCache {
+    dir: PathBuf,
+    index: Mutex<ByteLru<String, usize>>,
+}
+
+// 磁盘缓存里和图片放在一起的元数据，时间都是 unix 秒
+#[derive(Serialize, Deserialize)]
+struct DiskMeta {
+    etag: String,
+    last_modified: u64,
+    expires_at: u64,
+}
+
+// 处理好的图片的 key，由 spec，输出格式和参数，以及源图片的 url 决定
+pub fn render_key(spec: &ImageSpec, format: &ImageFormat, url: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(spec.encode_to_vec());
+    hasher.update(format!("{:?}", format));
+    hasher.update(url);
+    format!("{:x}", hasher.finalize())
+}
+
+impl ImageCache {
+    pub fn new(config: &CacheConfig) -> Result<Self> {
+        let disk = match &config.disk_dir {
+            Some(dir) => Some(DiskCache::open(dir, config.disk_capacity)?),
+            None => None,
+        };
+        Ok(Self {
+            sources: Mutex::new(ByteLru::new(config.source_capacity)),
+            rendered: Mutex::new(ByteLru::new(config.rendered_capacity)),
+            disk,
+            fetching: Coalescer::default(),
+            rendering: Coalescer::default(),
+        })
+    }
+
+    // 获取源图片，缓存里没有或者已经过期时调用 fetch
+    pub async fn source<F, Fut>(&self, url: &str, fetch: F) -> Result<Source, StatusCode>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Source, StatusCode>>,
+    {
+        if let Some(v) = get_fresh(&self.sources, url) {
+            info!("Match source cache {}", url);
+            return Ok(v);
+        }
+
+        self.fetching
+            .run(url, || async move {
+                if let Some(v) = get_fresh(&self.sources, url) {
+                    return Ok(v);
+                }
+                let source = fetch().await?;
+                // 源站不允许缓存时只给这一批并发的请求使用
+                if source.is_fresh() {
+                    put(&self.sources, url, source.clone());
+                }
+                Ok(source)
+            })
+            .await
+    }
+
+    // 获取处理好的图片，依次查找内存和磁盘，都没有时调用 render
+    pub async fn rendered<F, Fut>(&self, key: &str, render: F) -> Result<Rendered, StatusCode>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Rendered, StatusCode>>,
+    {
+        if let Some(v) = get_fresh(&self.rendered, key) {
+            info!("Match rendered cache {}", key);
+            return Ok(v);
+        }
+
+        self.rendering
+            .run(key, || async move {
+                if let Some(v) = get_fresh(&self.rendered, key) {
+                    return Ok(v);
+                }
+                if let Some(v) = self.disk_get(key).await {
+                    info!("Match disk cache {}", key);
+                    put(&self.rendered, key, v.clone());
+                    return Ok(v);
+                }
+
+                let rendered = render().await?;
+                if rendered.is_fresh() {
+                    put(&self.rendered, key, rendered.clone());
+                    if let Some(disk) = &self.disk {
+                        disk.put(key, &rendered).await;
+                    }
+                }
+                Ok(rendered)
+            })
+            .await
+    }
+
+    async fn disk_get(&self, key: &str) -> Option<Rendered> {
+        self.disk.as_ref()?.get(key).await
+    }
+}
+
+fn get_fresh<V>(lru: &Mutex<ByteLru<String, V>>, key: &str) -> Option<V>
+where
+    V: Weight + Expiring + Clone,
+{
+    // lru 只能用 &String 查找
+    let key = key.to_owned();
+    let mut lru = lru.lock().unwrap();
+    match lru.get(&key) {
+        Some(v) if v.is_fresh() => Some(v),
+        Some(_) => {
+            lru.remove(&key);
+            None
+        }
+        None => None,
+    }
+}
+
+fn put<V: Weight + Clone>(lru: &Mutex<ByteLru<String, V>>, key: &str, value: V) {
+    lru.lock().unwrap().put(key.to_owned(), value);
+}
+
+impl<K: Hash + Eq, V: Weight + Clone> ByteLru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: LruCache::unbounded(),
+            used: 0,
+            capacity,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.inner.get(key).cloned()
+    }
+
+    // 放入一项，返回因为空间不够被淘汰的项。比容量还大的项不会被放入，也在返回值中
+    pub fn put(&mut self, key: K, value: V) -> Vec<(K, V)> {
+        self.remove(&key);
+        if value.weight() > self.capacity {
+            return vec![(key, value)];
+        }
+
+        self.used += value.weight();
+        self.inner.put(key, value);
+        let mut evicted = Vec::new();
+        while self.used > self.capacity {
+            match self.inner.pop_lru() {
+                Some((k, v)) => {
+                    self.used -= v.weight();
+                    evicted.push((k, v));
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let v = self.inner.pop(key)?;
+        self.used -= v.weight();
+        Some(v)
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+impl<V: Clone, E: Clone> Coalescer<V, E> {
+    pub async fn run<F, Fut>(&self, key: &str, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+        // 执行的请求被取消时，等待的请求中会有一个接着执行
+        let result = cell.get_or_init(f).await.clone();
+
+        // 第一个拿到结果的请求把 key 删掉，之后的请求重新查找缓存
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            inflight.remove(key);
+        }
+        result
+    }
+}
+
+impl<V, E> Default for Coalescer<V, E> {
+    fn default() -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl DiskCache {
+    // 打开缓存目录，已有的文件加入索引，超出容量的部分直接删掉
+    fn open(dir: &str, capacity: usize) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let cache = Self {
+            dir: PathBuf::from(dir),
+            index: Mutex::new(ByteLru::new(capacity)),
+        };
+
+        let mut evicted = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "img") {
+                if let Some(key) = path.file_stem().and_then(|s| s.to_str()) {
+                    let size = entry.metadata()?.len() as usize;
+                    evicted.extend(cache.index.lock().unwrap().put(key.to_owned(), size));
+                }
+            }
+        }
+        for (key, _) in evicted {
+            let (data, meta) = cache.paths(&key);
+            let _ = std::fs::remove_file(data);
+            let _ = std::fs::remove_file(meta);
+        }
+        Ok(cache)
+    }
+
+    fn paths(&self, key: &str) -> (PathBuf, PathBuf) {
+        (
+            self.dir.join(format!("{}.img", key)),
+            self.dir.join(format!("{}.meta", key)),
+        )
+    }
+
+    async fn get(&self, key: &str) -> Option<Rendered> {
+        self.index.lock().unwrap().get(&key.to_owned())?;
+
+        let (data, meta) = self.paths(key);
+        let result = async {
+            let meta: DiskMeta = toml::from_str(&fs::read_to_string(&meta).await?)?;
+            let data = fs::read(&data).await?;
+            Ok::<_, anyhow::Error>(Rendered {
+                data: data.into(),
+                etag: meta.etag,
+                last_modified: from_secs(meta.last_modified),
+                expires_at: from_secs(meta.expires_at),
+            })
+        }
+        .await;
+
+        match result {
+            Ok(v) if v.is_fresh() => Some(v),
+            _ => {
+                self.index.lock().unwrap().remove(&key.to_owned());
+                self.remove_files(key).await;
+                None
+            }
+        }
+    }
+
+    async fn put(&self, key: &str, value: &Rendered) {
+        let evicted = self
+            .index
+            .lock()
+            .unwrap()
+            .put(key.to_owned(), value.data.len());
+        let mut kept = true;
+        for (k, _) in evicted {
+            kept &= k != key;
+            self.remove_files(&k).await;
+        }
+        if !kept {
+            return;
+        }
+
+        if let Err(e) = self.write(key, value).await {
+            warn!("Failed to write disk cache {}: {:?}", key, e);
+            self.index.lock().unwrap().remove(&key.to_owned());
+            self.remove_files(key).await;
+        }
+    }
+
+    // 先写图片再写元数据，读取时元数据存在就说明图片已经写完
+    async fn write(&self, key: &str, value: &Rendered) -> Result<()> {
+        let (data, meta) = self.paths(key);
+        fs::write(&data, &value.data).await?;
+        let content = toml::to_string(&DiskMeta {
+            etag: value.etag.clone(),
+            last_modified: to_secs(value.last_modified),
+            expires_at: to_secs(value.expires_at),
+        })?;
+        fs::write(&meta, content).await?;
+        Ok(())
+    }
+
+    async fn remove_files(&self, key: &str) {
+        let (data, meta) = self.paths(key);
+        let _ = fs::remove_file(meta).await;
+        let _ = fs::remove_file(data).await;
+    }
+}
+
+fn to_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn from_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+impl Weight for Source {
+    fn weight(&self) -> usize {
+        self.data.len() + self.hash.len()
+    }
+}
+
+impl Weight for Rendered {
+    fn weight(&self) -> usize {
+        self.data.len() + self.etag.len()
+    }
+}
+
+impl Weight for usize {
+    fn weight(&self) -> usize {
+        *self
+    }
+}
+
+impl Expiring for Source {
+    fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
+}
+
+impl Expiring for Rendered {
+    fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{sync::Notify, time::timeout};
+
+    fn rendered(data: &'static [u8], ttl: u64) -> Rendered {
+        let now = SystemTime::now();
+        Rendered {
+            data: Bytes::from_static(data),
+            etag: "\"tag\"".into(),
+            last_modified: from_secs(to_secs(now)),
+            expires_at: now + Duration::from_secs(ttl),
+        }
+    }
+
+    #[test]
+    fn byte_lru_should_evict_by_size() {
+        let mut lru = ByteLru::new(10);
+        assert!(lru.put("a", 4).is_empty());
+        assert!(lru.put("b", 4).is_empty());
+        lru.get(&"a");
+        // b 最久没有使用，被淘汰
+        assert_eq!(lru.put("c", 4), vec![("b", 4)]);
+        assert_eq!(lru.used(), 8);
+        // 比容量还大的项不会放入
+        assert_eq!(lru.put("d", 11), vec![("d", 11)]);
+        assert_eq!(lru.get(&"d"), None);
+        // 覆盖已有的项时重新计算大小
+        assert!(lru.put("a", 6).is_empty());
+        assert_eq!(lru.used(), 10);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_should_be_coalesced() {
+        let coalescer = Coalescer::<usize, ()>::default();
+        let calls = AtomicUsize::new(0);
+        let run = || {
+            coalescer.run("k", || async {
+                tokio::task::yield_now().await;
+                Ok(calls.fetch_add(1, Ordering::SeqCst))
+            })
+        };
+
+        let (a, b, c) = tokio::join!(run(), run(), run());
+        assert_eq!((a, b, c), (Ok(0), Ok(0), Ok(0)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 之前的请求都完成后，新的请求会重新执行
+        assert_eq!(run().await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn different_keys_should_not_block_each_other() {
+        let coalescer = Coalescer::<usize, ()>::default();
+        let notify = Notify::new();
+        let slow = coalescer.run("slow", || async {
+            notify.notified().await;
+            Ok(1)
+        });
+        let fast = async {
+            let v = coalescer.run("fast", || async { Ok(2) }).await;
+            notify.notify_one();
+            v
+        };
+
+        let result = timeout(Duration::from_secs(1), async { tokio::join!(slow, fast) }).await;
+        assert_eq!(result.unwrap(), (Ok(1), Ok(2)));
+    }
+
+    #[tokio::test]
+    async fn rendered_should_be_cached_until_expired() {
+        let cache = ImageCache::new(&CacheConfig::default()).unwrap();
+        let calls = AtomicUsize::new(0);
+        let render = |ttl| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async move { Ok(rendered(b"image", ttl)) }
+        };
+
+        cache.rendered("k1", || render(60)).await.unwrap();
+        cache.rendered("k1", || render(60)).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 已经过期的结果不会缓存
+        cache.rendered("k2", || render(0)).await.unwrap();
+        cache.rendered("k2", || render(0)).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn disk_cache_should_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig {
+            disk_dir: Some(dir.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let expected = rendered(b"image", 60);
+
+        let cache = ImageCache::new(&config).unwrap();
+        let value = expected.clone();
+        cache.rendered("k", || async { Ok(value) }).await.unwrap();
+
+        // 新的实例从磁盘读取，不会再调用 render
+        let cache = ImageCache::new(&config).unwrap();
+        let v = cache
+            .rendered("k", || async { Err(StatusCode::INTERNAL_SERVER_ERROR) })
+            .await
+            .unwrap();
+        assert_eq!(v.data, expected.data);
+        assert_eq!(v.etag, expected.etag);
+        assert_eq!(v.last_modified, expected.last_modified);
+    }
+
+    #[test]
+    fn disk_cache_should_respect_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        for key in ["a", "b", "c"] {
+            std::fs::write(dir.path().join(format!("{}.img", key)), [0u8; 4]).unwrap();
+        }
+
+        let dir_name = dir.path().to_string_lossy().into_owned();
+        let cache = DiskCache::open(&dir_name, 10).unwrap();
+        assert_eq!(cache.index.lock().unwrap().used(), 8);
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 2);
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::{env, fs};
+
+use crate::{
+    cache::CacheConfig, fetch::FetchConfig, format::FormatConfig, http_cache::HttpCacheConfig,
+    signing::SigningConfig,
+};
+
+// thumbor 的配置，所有的字段都有缺省值，配置文件里只需要写要修改的部分
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub format: FormatConfig,
+    pub http: HttpCacheConfig,
+    pub cache: CacheConfig,
+    pub fetch: FetchConfig,
+    pub signing: SigningConfig,
+}
+
+impl Config {
+    // 从环境变量 THUMBOR_CONFIG 指定的 toml 文件加载配置，没有设置时使用缺省配置。
+    // 缺省配置没有签名密钥，不能直接使用
+    pub fn load() -> Result<Self> {
+        let config: Self = match env::var("THUMBOR_CONFIG") {
+            Ok(path) => toml::from_str(&fs::read_to_string(path)?)?,
+            Err(_) => Self::default(),
+        };
+        config.signing.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config_should_use_defaults() {
+        let config: Config = toml::from_str("[format]\nwebp_quality = 60").unwrap();
+        assert_eq!(config.format.webp_quality, 60);
+        assert_eq!(
+            config.format.jpeg_quality,
+            FormatConfig::default().jpeg_quality
+        );
+        assert!(config.signing.validate().is_err());
+
+        let config: Config = toml::from_str("[signing]\nsecrets = [\"new\", \"old\"]").unwrap();
+        assert_eq!(config.signing.secrets, vec!["new", "old"]);
+        assert!(config.signing.validate().is_ok());
+
+        let config: Config = toml::from_str("[signing]\ndisabled = true").unwrap();
+        assert!(!config.signing.enabled());
+        assert!(config.signing.validate().is_ok());
+    }
+}
@@ -1,5 +1,5 @@
-use crate::pb::Spec;
-use image::ImageOutputFormat;
+use crate::{format::ImageFormat, pb::Spec};
+use anyhow::Result;
 
 mod photon;
 pub use photon::Photon;
@@ -9,7 +9,7 @@ pub trait Engine {
     // 对 engine 按照 specs 进行一系列有序的处理
     fn apply(&mut self, specs: &[Spec]);
     // 从 engine 中生成目标图片，注意这里用的是 self，而非 self 的引用
-    fn generate(self, format: ImageOutputFormat) -> Vec<u8>;
+    fn generate(self, format: ImageFormat) -> Result<Vec<u8>>;
 }
 
 // SpecTransform：未来如果添加更多的 spec，只需要实现它即可
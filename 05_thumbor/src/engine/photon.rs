@@ -1,8 +1,8 @@
 use super::{Engine, SpecTransform};
-use crate::pb::*;
+use crate::{format::ImageFormat, pb::*};
 use anyhow::Result;
 use bytes::Bytes;
-use image::{DynamicImage, ImageBuffer, ImageOutputFormat};
+use image::{codecs::avif::AvifEncoder, ColorType, DynamicImage, ImageBuffer, ImageOutputFormat};
 use lazy_static::lazy_static;
 use photon_rs::{
     effects, filters, multiple, native::open_image_from_bytes, transform, PhotonImage,
@@ -46,7 +46,7 @@ impl Engine for Photon {
         }
     }
 
-    fn generate(self, format: ImageOutputFormat) -> Vec<u8> {
+    fn generate(self, format: ImageFormat) -> Result<Vec<u8>> {
         image_to_buf(self.0, format)
     }
 }
@@ -108,15 +108,90 @@ impl SpecTransform<&Watermark> for Photon {
 }
 
 // photon 库竟然没有提供在内存中对图片转换格式的方法，只好手工实现
-fn image_to_buf(img: PhotonImage, format: ImageOutputFormat) -> Vec<u8> {
+fn image_to_buf(img: PhotonImage, format: ImageFormat) -> Result<Vec<u8>> {
     let raw_pixels = img.get_raw_pixels();
     let width = img.get_width();
     let height = img.get_height();
 
-    let img_buffer = ImageBuffer::from_vec(width, height, raw_pixels).unwrap();
+    let mut buffer = Vec::with_capacity(32768);
+    let format = match format {
+        ImageFormat::Jpeg(quality) => ImageOutputFormat::Jpeg(quality),
+        ImageFormat::Png => ImageOutputFormat::Png,
+        ImageFormat::Gif => ImageOutputFormat::Gif,
+        // image 0.23 不能编码 WebP，使用 libwebp
+        ImageFormat::Webp(quality) => {
+            let encoder = webp::Encoder::from_rgba(&raw_pixels, width, height);
+            return Ok(encoder.encode(quality as f32).to_vec());
+        }
+        // ImageOutputFormat::Avif 不能设置质量和速度，直接使用 AvifEncoder
+        ImageFormat::Avif { quality, speed } => {
+            AvifEncoder::new_with_speed_quality(&mut buffer, speed, quality).write_image(
+                &raw_pixels,
+                width,
+                height,
+                ColorType::Rgba8,
+            )?;
+            return Ok(buffer);
+        }
+    };
+
+    let img_buffer = ImageBuffer::from_vec(width, height, raw_pixels)
+        .ok_or_else(|| anyhow::anyhow!("Invalid image buffer"))?;
     let dynimage = DynamicImage::ImageRgba8(img_buffer);
+    dynimage.write_to(&mut buffer, format)?;
+    Ok(buffer)
+}
 
-    let mut buffer = Vec::with_capacity(32768);
-    dynimage.write_to(&mut buffer, format).unwrap();
-    buffer
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine() -> Photon {
+        let data = Bytes::from_static(include_bytes!("../../rust-logo.png"));
+        data.try_into().unwrap()
+    }
+
+    fn generate(format: ImageFormat) -> Vec<u8> {
+        let mut engine = engine();
+        engine.apply(&[Spec::new_resize(32, 32, resize::SampleFilter::Nearest)]);
+        engine.generate(format).unwrap()
+    }
+
+    #[test]
+    fn jpeg_should_be_encoded() {
+        assert!(generate(ImageFormat::Jpeg(85)).starts_with(&[0xff, 0xd8, 0xff]));
+    }
+
+    #[test]
+    fn png_should_be_encoded() {
+        assert!(generate(ImageFormat::Png).starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn gif_should_be_encoded() {
+        assert!(generate(ImageFormat::Gif).starts_with(b"GIF8"));
+    }
+
+    #[test]
+    fn webp_should_be_encoded() {
+        let data = generate(ImageFormat::Webp(80));
+        assert!(data.starts_with(b"RIFF"));
+        assert_eq!(&data[8..12], b"WEBP");
+    }
+
+    #[test]
+    fn avif_should_be_encoded() {
+        let data = generate(ImageFormat::Avif {
+            quality: 70,
+            speed: 10,
+        });
+        assert_eq!(&data[4..12], b"ftypavif");
+    }
+
+    #[test]
+    fn quality_should_affect_size() {
+        let low = generate(ImageFormat::Jpeg(10));
+        let high = generate(ImageFormat::Jpeg(100));
+        assert!(low.len() < high.len());
+    }
 }
@@ -0,0 +1,580 @@
+use anyhow::Result;
+use axum::http::{header::CONTENT_TYPE, HeaderMap, StatusCode};
+use bytes::{Bytes, BytesMut};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect::Policy,
+    Client, Url,
+};
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::warn;
+
+// 获取源图片的限制，防止 thumbor 被用来访问内网或者下载超大的文件
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FetchConfig {
+    // 允许的 scheme
+    pub schemes: Vec<String>,
+    // 允许的 host，"*.example.com" 匹配 example.com 的所有子域名。为空时允许所有 host
+    pub allowed_hosts: Vec<String>,
+    // 是否允许访问内网、本机和链路本地地址，只应该在开发时打开
+    pub allow_private: bool,
+    // 最多跟随的重定向次数
+    pub max_redirects: usize,
+    // 源图片最大的字节数
+    pub max_size: usize,
+    // 允许的源图片 Content-Type
+    pub content_types: Vec<String>,
+    // 建立连接的超时时间，单位毫秒
+    pub connect_timeout_ms: u64,
+    // 整个请求（包括下载内容）的超时时间，单位毫秒。
+    // 要比服务器处理请求的超时时间短，这样源站太慢时返回的是 504 而不是服务器的超时错误
+    pub timeout_ms: u64,
+}
+
+// 获取源图片时的错误，每一种都对应一个明确的 HTTP 状态码
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    InvalidUrl(String),
+    SchemeNotAllowed(String),
+    HostNotAllowed(String),
+    PrivateAddress(IpAddr),
+    TooManyRedirects,
+    TooLarge(usize),
+    UnsupportedType(String),
+    // 源站返回的错误状态码
+    Upstream(u16),
+    Timeout,
+    Unreachable(String),
+}
+
+// 源站返回的 header 和内容
+#[derive(Debug)]
+pub struct Fetched {
+    pub headers: HeaderMap,
+    pub data: Bytes,
+}
+
+// 按照 FetchConfig 的限制获取源图片
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    client: Client,
+    config: Arc<FetchConfig>,
+}
+
+// 解析域名后检查所有的地址，有一个不是公网地址就拒绝连接。
+// reqwest 直接连接这里返回的地址，所以 DNS rebinding 也绕不过去
+struct PublicResolver;
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            schemes: vec!["http".into(), "https".into()],
+            allowed_hosts: vec![],
+            allow_private: false,
+            max_redirects: 5,
+            max_size: 20 * 1024 * 1024,
+            content_types: [
+                "image/jpeg",
+                "image/png",
+                "image/gif",
+                "image/webp",
+                "image/avif",
+                "image/bmp",
+                "image/tiff",
+            ]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+            connect_timeout_ms: 3000,
+            timeout_ms: 8000,
+        }
+    }
+}
+
+impl FetchConfig {
+    // 检查 url 的 scheme 和 host，host 是 IP 时同时检查 IP。
+    // 域名解析出的 IP 由 PublicResolver 检查
+    pub fn check_url(&self, url: &Url) -> Result<(), FetchError> {
+        if !self
+            .schemes
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(url.scheme()))
+        {
+            return Err(FetchError::SchemeNotAllowed(url.scheme().into()));
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| FetchError::InvalidUrl(url.to_string()))?;
+        if !self.host_allowed(host) {
+            return Err(FetchError::HostNotAllowed(host.into()));
+        }
+
+        // IPv6 的 host 是 "[::1]" 这样的格式
+        let ip = host.trim_start_matches('[').trim_end_matches(']').parse();
+        match ip {
+            Ok(ip) if !self.allow_private && !is_public(ip) => Err(FetchError::PrivateAddress(ip)),
+            _ => Ok(()),
+        }
+    }
+
+    fn host_allowed(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.allowed_hosts.is_empty()
+            || self.allowed_hosts.iter().any(|pattern| {
+                let pattern = pattern.to_ascii_lowercase();
+                match pattern.strip_prefix("*.") {
+                    Some(domain) => host
+                        .strip_suffix(domain)
+                        .is_some_and(|sub| sub.ends_with('.')),
+                    None => host == pattern,
+                }
+            })
+    }
+
+    fn content_type_allowed(&self, content_type: &str) -> bool {
+        // 去掉 "image/jpeg; charset=binary" 这样的参数
+        let media = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(media))
+    }
+}
+
+impl Fetcher {
+    pub fn new(config: &FetchConfig) -> Result<Self> {
+        let config = Arc::new(config.clone());
+        let policy_config = config.clone();
+        let policy = Policy::custom(move |attempt| {
+            if attempt.previous().len() > policy_config.max_redirects {
+                return attempt.error(FetchError::TooManyRedirects);
+            }
+            // 重定向的目标同样需要检查，否则一个公网地址就能把请求转到内网
+            match policy_config.check_url(attempt.url()) {
+                Ok(_) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        });
+
+        let mut builder = Client::builder()
+            .redirect(policy)
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .timeout(Duration::from_millis(config.timeout_ms));
+        if !config.allow_private {
+            // 通过代理访问时由代理解析域名，PublicResolver 就检查不到了，所以不使用环境变量里的代理
+            builder = builder.dns_resolver(Arc::new(PublicResolver)).no_proxy();
+        }
+        Ok(Self {
+            client: builder.build()?,
+            config,
+        })
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<Fetched, FetchError> {
+        let url = Url::parse(url).map_err(|_| FetchError::InvalidUrl(url.into()))?;
+        self.config.check_url(&url)?;
+
+        let mut resp = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(FetchError::from)?;
+        if !resp.status().is_success() {
+            return Err(FetchError::Upstream(resp.status().as_u16()));
+        }
+
+        // 在下载和解码之前检查 Content-Type 和大小
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !self.config.content_type_allowed(content_type) {
+            return Err(FetchError::UnsupportedType(content_type.into()));
+        }
+        let max_size = self.config.max_size;
+        if resp
+            .content_length()
+            .is_some_and(|len| len > max_size as u64)
+        {
+            return Err(FetchError::TooLarge(max_size));
+        }
+
+        // Content-Length 可能不存在或者不准确，下载时也要检查
+        let headers = resp.headers().clone();
+        let mut data = BytesMut::new();
+        while let Some(chunk) = resp.chunk().await.map_err(FetchError::from)? {
+            if data.len() + chunk.len() > max_size {
+                return Err(FetchError::TooLarge(max_size));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(Fetched {
+            headers,
+            data: data.freeze(),
+        })
+    }
+}
+
+impl FetchError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FetchError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+            FetchError::SchemeNotAllowed(_)
+            | FetchError::HostNotAllowed(_)
+            | FetchError::PrivateAddress(_) => StatusCode::FORBIDDEN,
+            FetchError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            FetchError::UnsupportedType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            FetchError::Upstream(404) | FetchError::Upstream(410) => StatusCode::NOT_FOUND,
+            FetchError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            FetchError::TooManyRedirects | FetchError::Upstream(_) | FetchError::Unreachable(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidUrl(url) => write!(f, "invalid url: {}", url),
+            FetchError::SchemeNotAllowed(scheme) => write!(f, "scheme not allowed: {}", scheme),
+            FetchError::HostNotAllowed(host) => write!(f, "host not allowed: {}", host),
+            FetchError::PrivateAddress(ip) => write!(f, "private address not allowed: {}", ip),
+            FetchError::TooManyRedirects => write!(f, "too many redirects"),
+            FetchError::TooLarge(max) => write!(f, "source image larger than {} bytes", max),
+            FetchError::UnsupportedType(t) => write!(f, "unsupported content type: {:?}", t),
+            FetchError::Upstream(status) => write!(f, "upstream returned {}", status),
+            FetchError::Timeout => write!(f, "upstream timed out"),
+            FetchError::Unreachable(e) => write!(f, "upstream unreachable: {}", e),
+        }
+    }
+}
+
+impl Error for FetchError {}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        // 重定向策略和 PublicResolver 返回的 FetchError 被 reqwest 包在了里面
+        let mut source = e.source();
+        while let Some(err) = source {
+            if let Some(err) = err.downcast_ref::<FetchError>() {
+                return err.clone();
+            }
+            source = err.source();
+        }
+        if e.is_timeout() {
+            FetchError::Timeout
+        } else {
+            FetchError::Unreachable(e.to_string())
+        }
+    }
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                warn!("{} resolved to {}", name, addr.ip());
+                return Err(FetchError::PrivateAddress(addr.ip()).into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// std 的 is_global 还没有稳定，这里排除所有不应该从外部访问的地址段
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // 0.0.0.0/8
+        || a == 0
+        // 100.64.0.0/10 运营商级 NAT
+        || (a == 100 && (b & 0xc0) == 64)
+        // 192.0.0.0/24 IETF 协议分配
+        || (a == 192 && b == 0 && c == 0)
+        // 198.18.0.0/15 性能测试
+        || (a == 198 && (b & 0xfe) == 18)
+        // 240.0.0.0/4 保留
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // IPv4 映射地址和 NAT64 地址按照里面的 IPv4 地址判断
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_v4(v4);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let o = ip.octets();
+        return is_public_v4(Ipv4Addr::new(o[12], o[13], o[14], o[15]));
+    }
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 唯一本地地址
+        || (segments[0] & 0xfe00) == 0xfc00
+        // fe80::/10 链路本地地址，fec0::/10 站点本地地址
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // 2001:db8::/32 文档
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn check(config: &FetchConfig, url: &str) -> Result<(), FetchError> {
+        config.check_url(&Url::parse(url).unwrap())
+    }
+
+    // 启动一个只会返回同样响应的 HTTP 服务器，返回它的地址
+    async fn serve(response: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let response = response.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn response(content_type: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn scheme_and_host_should_be_checked() {
+        let config = FetchConfig {
+            allowed_hosts: vec!["*.pexels.com".into(), "example.com".into()],
+            ..Default::default()
+        };
+        assert!(check(&config, "https://images.pexels.com/a.jpg").is_ok());
+        assert!(check(&config, "https://a.b.PEXELS.com/a.jpg").is_ok());
+        assert!(check(&config, "http://example.com/a.jpg").is_ok());
+
+        let cases = [
+            (
+                "ftp://example.com/a.jpg",
+                FetchError::SchemeNotAllowed("ftp".into()),
+            ),
+            (
+                "file:///etc/passwd",
+                FetchError::SchemeNotAllowed("file".into()),
+            ),
+            (
+                "https://pexels.com/a.jpg",
+                FetchError::HostNotAllowed("pexels.com".into()),
+            ),
+            (
+                "https://evilpexels.com/a.jpg",
+                FetchError::HostNotAllowed("evilpexels.com".into()),
+            ),
+            (
+                "https://a.example.com/a.jpg",
+                FetchError::HostNotAllowed("a.example.com".into()),
+            ),
+        ];
+        for (url, err) in cases {
+            assert_eq!(check(&config, url), Err(err), "url: {}", url);
+        }
+    }
+
+    #[test]
+    fn private_ip_should_be_rejected() {
+        let config = FetchConfig::default();
+        for url in [
+            "http://127.0.0.1/a.jpg",
+            "http://10.1.2.3/a.jpg",
+            "http://169.254.169.254/latest/meta-data",
+            "http://0.0.0.0/a.jpg",
+            "http://[::1]/a.jpg",
+            "http://[::ffff:192.168.1.1]/a.jpg",
+            "http://[fd00::1]/a.jpg",
+            "http://[fe80::1]/a.jpg",
+        ] {
+            let err = check(&config, url).unwrap_err();
+            assert!(matches!(err, FetchError::PrivateAddress(_)), "url: {}", url);
+            assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(check(&config, "http://8.8.8.8/a.jpg").is_ok());
+        assert!(check(&config, "http://[2606:4700::1111]/a.jpg").is_ok());
+
+        let config = FetchConfig {
+            allow_private: true,
+            ..Default::default()
+        };
+        assert!(check(&config, "http://127.0.0.1/a.jpg").is_ok());
+    }
+
+    #[test]
+    fn special_ranges_should_not_be_public() {
+        for ip in [
+            "100.64.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "255.255.255.255",
+            "240.0.0.1",
+            "64:ff9b::a00:1",
+            "2001:db8::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "ip: {}", ip);
+        }
+        assert!(is_public("64:ff9b::808:808".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn resolved_private_address_should_be_rejected() {
+        let err = PublicResolver
+            .resolve("localhost".parse().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<FetchError>(),
+            Some(FetchError::PrivateAddress(_))
+        ));
+
+        // 默认配置下 reqwest 通过 PublicResolver 解析域名
+        let fetcher = Fetcher::new(&FetchConfig::default()).unwrap();
+        let err = fetcher.fetch("http://localhost:1/a.jpg").await.unwrap_err();
+        assert!(matches!(err, FetchError::PrivateAddress(_)));
+    }
+
+    #[tokio::test]
+    async fn content_type_and_size_should_be_checked() {
+        let config = FetchConfig {
+            allow_private: true,
+            max_size: 8,
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(&config).unwrap();
+
+        let url = serve(response("image/png", "small")).await;
+        let fetched = fetcher.fetch(&url).await.unwrap();
+        assert_eq!(&fetched.data[..], b"small");
+
+        let url = serve(response("text/html; charset=utf-8", "<html>")).await;
+        let err = fetcher.fetch(&url).await.unwrap_err();
+        assert_eq!(
+            err,
+            FetchError::UnsupportedType("text/html; charset=utf-8".into())
+        );
+        assert_eq!(err.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let url = serve(response("image/png", "too large image")).await;
+        let err = fetcher.fetch(&url).await.unwrap_err();
+        assert_eq!(err, FetchError::TooLarge(8));
+
+        // 没有 Content-Length 时在下载的过程中检查大小
+        let chunked = "HTTP/1.1 200 OK\r\ncontent-type: image/png\r\nconnection: close\r\n\r\ntoo large image";
+        let url = serve(chunked.into()).await;
+        assert_eq!(
+            fetcher.fetch(&url).await.unwrap_err(),
+            FetchError::TooLarge(8)
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_should_be_checked() {
+        let config = FetchConfig {
+            allow_private: true,
+            allowed_hosts: vec!["127.0.0.1".into()],
+            max_redirects: 3,
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(&config).unwrap();
+
+        // 重定向到自己，直到超过次数限制
+        let url = serve(
+            "HTTP/1.1 302 Found\r\nlocation: /again\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                .into(),
+        )
+        .await;
+        let err = fetcher.fetch(&url).await.unwrap_err();
+        assert_eq!(err, FetchError::TooManyRedirects);
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+
+        // 重定向的目标也要在 allowed_hosts 里
+        let url = serve(
+            "HTTP/1.1 302 Found\r\nlocation: http://localhost/\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                .into(),
+        )
+        .await;
+        let err = fetcher.fetch(&url).await.unwrap_err();
+        assert_eq!(err, FetchError::HostNotAllowed("localhost".into()));
+
+        let url = serve(
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".into(),
+        )
+        .await;
+        let err = fetcher.fetch(&url).await.unwrap_err();
+        assert_eq!(err, FetchError::Upstream(404));
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn slow_upstream_should_time_out() {
+        let config = FetchConfig {
+            allow_private: true,
+            timeout_ms: 100,
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(&config).unwrap();
+
+        // 接受连接之后一直不返回
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let err = fetcher.fetch(&url).await.unwrap_err();
+        assert_eq!(err, FetchError::Timeout);
+        assert_eq!(err.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+}
@@ -0,0 +1,194 @@
+use serde::Deserialize;
+
+use crate::pb::OutputFormat;
+
+// 客户端没有明确要求时，按照这个顺序选择格式。
+// AVIF 编码很慢，所以只有客户端对 AVIF 给出更高的 q 值时才会选它
+const NEGOTIABLE: [OutputFormat; 5] = [
+    OutputFormat::Webp,
+    OutputFormat::Avif,
+    OutputFormat::Jpeg,
+    OutputFormat::Png,
+    OutputFormat::Gif,
+];
+
+// Accept 里只有 image/* 或者 */* 时使用的格式
+const FALLBACK: OutputFormat = OutputFormat::Jpeg;
+
+// 各种格式的编码参数
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FormatConfig {
+    pub jpeg_quality: u8,
+    pub webp_quality: u8,
+    pub avif_quality: u8,
+    // AVIF 编码速度 1-10，越大越快，但压缩率越低
+    pub avif_speed: u8,
+}
+
+// 最终编码使用的格式和参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg(u8),
+    Png,
+    Gif,
+    Webp(u8),
+    Avif { quality: u8, speed: u8 },
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        Self {
+            jpeg_quality: 85,
+            webp_quality: 80,
+            avif_quality: 70,
+            avif_speed: 8,
+        }
+    }
+}
+
+impl FormatConfig {
+    // spec 里指定了格式时直接使用，否则根据 Accept header 选择。
+    // 返回的 bool 表示结果是否取决于 Accept，这时响应需要带上 Vary: Accept
+    pub fn negotiate(&self, format: OutputFormat, accept: Option<&str>) -> (ImageFormat, bool) {
+        match format {
+            OutputFormat::Auto => {
+                let format = accept.map(negotiate).unwrap_or(FALLBACK);
+                (self.encoding(format), true)
+            }
+            format => (self.encoding(format), false),
+        }
+    }
+
+    pub fn encoding(&self, format: OutputFormat) -> ImageFormat {
+        match format {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Gif => ImageFormat::Gif,
+            OutputFormat::Webp => ImageFormat::Webp(self.webp_quality),
+            OutputFormat::Avif => ImageFormat::Avif {
+                quality: self.avif_quality,
+                speed: self.avif_speed,
+            },
+            OutputFormat::Jpeg | OutputFormat::Auto => ImageFormat::Jpeg(self.jpeg_quality),
+        }
+    }
+}
+
+impl ImageFormat {
+    pub fn content_type(&self) -> &'static str {
+        media_type(self.into())
+    }
+}
+
+impl From<&ImageFormat> for OutputFormat {
+    fn from(format: &ImageFormat) -> Self {
+        match format {
+            ImageFormat::Jpeg(_) => OutputFormat::Jpeg,
+            ImageFormat::Png => OutputFormat::Png,
+            ImageFormat::Gif => OutputFormat::Gif,
+            ImageFormat::Webp(_) => OutputFormat::Webp,
+            ImageFormat::Avif { .. } => OutputFormat::Avif,
+        }
+    }
+}
+
+fn media_type(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Png => "image/png",
+        OutputFormat::Gif => "image/gif",
+        OutputFormat::Webp => "image/webp",
+        OutputFormat::Avif => "image/avif",
+        OutputFormat::Jpeg | OutputFormat::Auto => "image/jpeg",
+    }
+}
+
+// 从 Accept header 里选出 q 值最高的格式，q 值相同时按照 NEGOTIABLE 的顺序。
+// 通配符只用于 FALLBACK，否则 curl 这样发送 */* 的客户端会收到它未必认识的格式
+fn negotiate(accept: &str) -> OutputFormat {
+    let ranges: Vec<(&str, f32)> = accept.split(',').filter_map(parse_range).collect();
+    let quality = |format: OutputFormat| {
+        let media = media_type(format);
+        let exact = ranges.iter().find(|(m, _)| m.eq_ignore_ascii_case(media));
+        let wildcard = || {
+            ranges
+                .iter()
+                .filter(|(m, _)| format == FALLBACK && (*m == "image/*" || *m == "*/*"))
+                .map(|(_, q)| *q)
+                .reduce(f32::max)
+        };
+        exact.map(|(_, q)| *q).or_else(wildcard).unwrap_or(0.0)
+    };
+
+    let mut best = (FALLBACK, 0.0);
+    for format in NEGOTIABLE {
+        let q = quality(format);
+        if q > best.1 {
+            best = (format, q);
+        }
+    }
+    best.0
+}
+
+// 解析 "image/webp;q=0.8" 这样的一项，q 值缺省为 1
+fn parse_range(range: &str) -> Option<(&str, f32)> {
+    let mut parts = range.split(';').map(str::trim);
+    let media = parts.next().filter(|m| !m.is_empty())?;
+    let q = parts
+        .find_map(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()))
+        .unwrap_or(1.0);
+    Some((media, q))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME: &str = "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+
+    #[test]
+    fn explicit_format_should_not_vary() {
+        let config = FormatConfig::default();
+        let (format, vary) = config.negotiate(OutputFormat::Png, Some(CHROME));
+        assert_eq!(format, ImageFormat::Png);
+        assert!(!vary);
+    }
+
+    #[test]
+    fn accept_should_be_negotiated() {
+        let config = FormatConfig::default();
+        let cases = [
+            (Some(CHROME), ImageFormat::Webp(80)),
+            (
+                Some("image/avif;q=1,image/webp;q=0.9"),
+                ImageFormat::Avif {
+                    quality: 70,
+                    speed: 8,
+                },
+            ),
+            (Some("image/webp;q=0,image/png"), ImageFormat::Png),
+            (Some("image/gif, image/jpeg;q=0.5"), ImageFormat::Gif),
+            (Some("*/*"), ImageFormat::Jpeg(85)),
+            (Some("text/html"), ImageFormat::Jpeg(85)),
+            (None, ImageFormat::Jpeg(85)),
+        ];
+        for (accept, expected) in cases {
+            let (format, vary) = config.negotiate(OutputFormat::Auto, accept);
+            assert_eq!(format, expected, "accept: {:?}", accept);
+            assert!(vary);
+        }
+    }
+
+    #[test]
+    fn content_type_should_match_format() {
+        assert_eq!(ImageFormat::Jpeg(85).content_type(), "image/jpeg");
+        assert_eq!(ImageFormat::Webp(80).content_type(), "image/webp");
+        assert_eq!(
+            ImageFormat::Avif {
+                quality: 70,
+                speed: 8
+            }
+            .content_type(),
+            "image/avif"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use axum::http::{
+    header::{CACHE_CONTROL, EXPIRES},
+    HeaderMap,
+};
+use prost::Message;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime};
+
+use crate::{format::ImageFormat, pb::ImageSpec};
+
+// HTTP 缓存相关的配置
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpCacheConfig {
+    // 响应里的 Cache-Control
+    pub cache_control: String,
+    // 是否在响应里带上源图片的 Last-Modified
+    pub last_modified: bool,
+    // 源站没有返回缓存相关的 header 时，源图片在缓存里保存的秒数
+    pub source_ttl: u64,
+    // 源图片在缓存里最多保存的秒数，源站的 max-age 更长也以它为准
+    pub source_max_ttl: u64,
+}
+
+impl Default for HttpCacheConfig {
+    fn default() -> Self {
+        Self {
+            cache_control: "public, max-age=86400".into(),
+            last_modified: true,
+            source_ttl: 3600,
+            source_max_ttl: 86400,
+        }
+    }
+}
+
+impl HttpCacheConfig {
+    // 根据源站的 Cache-Control / Expires 决定源图片可以在缓存里保存多久，为 0 时不缓存
+    pub fn source_ttl(&self, headers: &HeaderMap) -> Duration {
+        let ttl = upstream_ttl(headers).unwrap_or(self.source_ttl);
+        Duration::from_secs(ttl.min(self.source_max_ttl))
+    }
+}
+
+// 源图片内容的 hash，用来计算 ETag
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// 强 ETag：同样的 spec，同样的输出格式和参数，同样的源图片，生成的图片一定相同
+pub fn etag(spec: &ImageSpec, format: &ImageFormat, source_hash: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(spec.encode_to_vec());
+    hasher.update(format!("{:?}", format));
+    hasher.update(source_hash);
+    let digest = format!("{:x}", hasher.finalize());
+    format!("\"{}\"", &digest[..32])
+}
+
+// If-None-Match 中有任何一个 ETag 匹配（弱比较）时返回 true，这时应该返回 304
+pub fn not_modified(if_none_match: Option<&str>, etag: &str) -> bool {
+    if_none_match.is_some_and(|v| {
+        v.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    })
+}
+
+// 源站允许缓存的秒数。源站没有说明时返回 None
+fn upstream_ttl(headers: &HeaderMap) -> Option<u64> {
+    if let Some(cc) = headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()) {
+        let mut max_age = None;
+        for directive in cc.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+            match directive.split_once('=') {
+                // 我们是共享的缓存，s-maxage 优先于 max-age
+                Some(("s-maxage", v)) => return v.trim_matches('"').parse().ok().or(Some(0)),
+                Some(("max-age", v)) => max_age = v.trim_matches('"').parse().ok().or(Some(0)),
+                None if matches!(directive.as_str(), "no-store" | "no-cache" | "private") => {
+                    return Some(0)
+                }
+                _ => {}
+            }
+        }
+        if max_age.is_some() {
+            return max_age;
+        }
+    }
+
+    // 没有 max-age 时使用 Expires，无法解析的 Expires 表示已经过期
+    let expires = headers.get(EXPIRES)?.to_str().ok();
+    let ttl = expires
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .and_then(|t| t.duration_since(SystemTime::now()).ok())
+        .map_or(0, |d| d.as_secs());
+    Some(ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::{filter, Spec};
+    use axum::http::HeaderValue;
+
+    fn headers(name: axum::http::header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn etag_should_depend_on_spec_format_and_source() {
+        let spec = ImageSpec::new(vec![Spec::new_filter(filter::Filter::Marine)]);
+        let hash = content_hash(b"image");
+        let tag = etag(&spec, &ImageFormat::Jpeg(85), &hash);
+
+        assert_eq!(tag, etag(&spec, &ImageFormat::Jpeg(85), &hash));
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert_ne!(tag, etag(&spec, &ImageFormat::Jpeg(80), &hash));
+        assert_ne!(tag, etag(&spec, &ImageFormat::Png, &hash));
+        assert_ne!(
+            tag,
+            etag(&ImageSpec::new(vec![]), &ImageFormat::Jpeg(85), &hash)
+        );
+        assert_ne!(
+            tag,
+            etag(&spec, &ImageFormat::Jpeg(85), &content_hash(b"other"))
+        );
+    }
+
+    #[test]
+    fn if_none_match_should_be_compared() {
+        let tag = "\"abc\"";
+        assert!(not_modified(Some("\"abc\""), tag));
+        assert!(not_modified(Some("\"x\", W/\"abc\""), tag));
+        assert!(not_modified(Some("*"), tag));
+        assert!(!not_modified(Some("\"abcd\""), tag));
+        assert!(!not_modified(None, tag));
+    }
+
+    #[test]
+    fn source_ttl_should_follow_upstream() {
+        let config = HttpCacheConfig::default();
+        let ttl = |h: HeaderMap| config.source_ttl(&h).as_secs();
+
+        assert_eq!(ttl(HeaderMap::new()), 3600);
+        assert_eq!(ttl(headers(CACHE_CONTROL, "public, max-age=600")), 600);
+        assert_eq!(ttl(headers(CACHE_CONTROL, "max-age=600, s-maxage=60")), 60);
+        assert_eq!(ttl(headers(CACHE_CONTROL, "max-age=31536000")), 86400);
+        assert_eq!(ttl(headers(CACHE_CONTROL, "no-store")), 0);
+        assert_eq!(ttl(headers(CACHE_CONTROL, "private, max-age=600")), 0);
+        assert_eq!(ttl(headers(EXPIRES, "0")), 0);
+
+        let later = SystemTime::now() + Duration::from_secs(120);
+        let expires = httpdate::fmt_http_date(later);
+        assert!((118..=120).contains(&ttl(headers(EXPIRES, &expires))));
+    }
+}
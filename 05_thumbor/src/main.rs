@@ -1,33 +1,40 @@
 use anyhow::Result;
 use axum::{
-    extract::{Extension, Path},
+    extract::{Extension, Path, Query},
     handler::get,
     http::{HeaderMap, HeaderValue, StatusCode},
     Router,
 };
 use bytes::Bytes;
-use image::ImageOutputFormat;
-use lru::LruCache;
-use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
+use percent_encoding::percent_decode_str;
 use serde::Deserialize;
 use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::Mutex;
 use tower::ServiceBuilder;
 use tower_http::{
     add_extension::AddExtensionLayer, compression::CompressionLayer, trace::TraceLayer,
 };
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 
+mod cache;
+mod config;
 mod engine;
+mod fetch;
+mod format;
+mod http_cache;
 mod pb;
+mod signing;
 
+use cache::{ImageCache, Rendered, Source};
+use config::Config;
 use engine::Photon;
+use fetch::Fetcher;
+use format::ImageFormat;
+use http_cache::HttpCacheConfig;
 use pb::*;
+use signing::{Signature, SigningConfig};
 
 use crate::engine::Engine;
 
@@ -38,13 +45,18 @@ struct Params {
     url: String,
 }
 
-type Cache = Arc<Mutex<LruCache<u64, Bytes>>>;
+type Cache = Arc<ImageCache>;
 
 #[tokio::main]
 async fn main() {
     // 初始化 tracing
     tracing_subscriber::fmt::init();
-    let cache: Cache = Arc::new(Mutex::new(LruCache::new(1024)));
+    let config = Arc::new(Config::load().unwrap());
+    let cache: Cache = Arc::new(ImageCache::new(&config.cache).unwrap());
+    let fetcher = Arc::new(Fetcher::new(&config.fetch).unwrap());
+    if !config.signing.enabled() {
+        warn!("Signing is disabled, unsigned urls are accepted");
+    }
     // 构建路由
     let app = Router::new()
         // `GET /` 会执行
@@ -56,13 +68,18 @@ async fn main() {
                 .timeout(Duration::from_secs(10))
                 .layer(TraceLayer::new_for_http())
                 .layer(AddExtensionLayer::new(cache))
+                .layer(AddExtensionLayer::new(fetcher))
+                .layer(AddExtensionLayer::new(config))
                 .layer(CompressionLayer::new())
                 .into_inner(),
         );
 
     // 运行 web 服务器
     let addr = "127.0.0.1:3000".parse().unwrap();
-    print_test_url("https://images.pexels.com/photos/1562477/pexels-photo-1562477.jpeg?auto=compress&cs=tinysrgb&dpr=3&h=750&w=1260");
+    print_test_url(
+        &config.signing,
+        "https://images.pexels.com/photos/1562477/pexels-photo-1562477.jpeg?auto=compress&cs=tinysrgb&dpr=3&h=750&w=1260",
+    );
     info!("Listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
@@ -73,65 +90,155 @@ async fn main() {
 // basic handler that responds with a static string
 async fn generate(
     Path(Params { spec, url }): Path<Params>,
+    Query(signature): Query<Signature>,
     Extension(cache): Extension<Cache>,
-) -> Result<(HeaderMap, Vec<u8>), StatusCode> {
+    Extension(fetcher): Extension<Arc<Fetcher>>,
+    Extension(config): Extension<Arc<Config>>,
+    req_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Bytes), StatusCode> {
+    // 先检查签名，没有签名或者签名不对的请求不会去获取源图片
+    let url = percent_decode_str(&url).decode_utf8_lossy().into_owned();
+    if !config.signing.verify(&spec, &url, &signature, unix_now()) {
+        warn!("Invalid signature for {}", url);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
     let spec: ImageSpec = spec
         .as_str()
         .try_into()
         .map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    let url: &str = &percent_decode_str(&url).decode_utf8_lossy();
-    let data = retrieve_image(url, cache)
-        .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    // 使用 image engine 处理
-    let mut engine: Photon = data
-        .try_into()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    engine.apply(&spec.specs);
-    // TODO: 这里目前类型写死了，应该使用 content negotiation
-    let image = engine.generate(ImageOutputFormat::Jpeg(85));
+    // spec 里没有指定格式时，根据 Accept header 选择
+    let accept = req_headers.get("accept").and_then(|v| v.to_str().ok());
+    let (format, vary) = config.format.negotiate(spec.output_format(), accept);
+
+    let key = cache::render_key(&spec, &format, &url);
+    let rendered = cache
+        .rendered(&key, || {
+            render(
+                spec,
+                format,
+                url,
+                cache.clone(),
+                fetcher.clone(),
+                config.clone(),
+            )
+        })
+        .await?;
 
-    info!("Finished processing: image size {}", image.len());
     let mut headers = HeaderMap::new();
+    headers.insert("etag", HeaderValue::from_str(&rendered.etag).unwrap());
+    if let Ok(v) = HeaderValue::from_str(&config.http.cache_control) {
+        headers.insert("cache-control", v);
+    }
+    if config.http.last_modified {
+        let v = httpdate::fmt_http_date(rendered.last_modified);
+        headers.insert("last-modified", HeaderValue::from_str(&v).unwrap());
+    }
+    if vary {
+        headers.insert("vary", HeaderValue::from_static("accept"));
+    }
+
+    // 客户端已经有同样的图片，不需要再返回
+    let if_none_match = req_headers
+        .get("if-none-match")
+        .and_then(|v| v.to_str().ok());
+    if http_cache::not_modified(if_none_match, &rendered.etag) {
+        info!("Not modified: {}", rendered.etag);
+        return Ok((StatusCode::NOT_MODIFIED, headers, Bytes::new()));
+    }
+
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static(format.content_type()),
+    );
+    Ok((StatusCode::OK, headers, rendered.data))
+}
+
+// 获取源图片并按照 spec 处理，生成的图片和源图片同时过期
+async fn render(
+    spec: ImageSpec,
+    format: ImageFormat,
+    url: String,
+    cache: Cache,
+    fetcher: Arc<Fetcher>,
+    config: Arc<Config>,
+) -> Result<Rendered, StatusCode> {
+    let source = cache
+        .source(&url, || retrieve_image(&url, &fetcher, &config.http))
+        .await?;
+    let etag = http_cache::etag(&spec, &format, &source.hash);
+
+    // 图片处理很耗 CPU，放到 blocking 线程池里执行，不阻塞其它请求
+    let data = source.data.clone();
+    let image = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, StatusCode> {
+        // Content-Type 是图片但是无法解码，说明源图片有问题，不是服务器的错误
+        let mut engine: Photon = data
+            .try_into()
+            .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+        engine.apply(&spec.specs);
+        engine
+            .generate(format)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+
+    info!(
+        "Finished processing: {:?}, image size {}",
+        format,
+        image.len()
+    );
+    Ok(Rendered {
+        data: image.into(),
+        etag,
+        last_modified: source.last_modified,
+        expires_at: source.expires_at,
+    })
+}
 
-    headers.insert("content-type", HeaderValue::from_static("image/jpeg"));
-    Ok((headers, image))
+#[instrument(level = "info", skip(fetcher, config))]
+async fn retrieve_image(
+    url: &str,
+    fetcher: &Fetcher,
+    config: &HttpCacheConfig,
+) -> Result<Source, StatusCode> {
+    info!("Retrieve url");
+    let fetched = fetcher.fetch(url).await.map_err(|e| {
+        warn!("Failed to retrieve: {}", e);
+        e.status()
+    })?;
+    let ttl = config.source_ttl(&fetched.headers);
+    let last_modified = fetched
+        .headers
+        .get("last-modified")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .unwrap_or_else(SystemTime::now);
+
+    Ok(Source {
+        hash: http_cache::content_hash(&fetched.data),
+        data: fetched.data,
+        last_modified,
+        expires_at: SystemTime::now() + ttl,
+    })
 }
 
-#[instrument(level = "info", skip(cache))]
-async fn retrieve_image(url: &str, cache: Cache) -> Result<Bytes> {
-    let mut hasher = DefaultHasher::new();
-    url.hash(&mut hasher);
-    let key = hasher.finish();
-
-    let g = &mut cache.lock().await;
-    let data = match g.get(&key) {
-        Some(v) => {
-            info!("Match cache {}", key);
-            v.to_owned()
-        }
-        None => {
-            info!("Retrieve url");
-            let resp = reqwest::get(url).await?;
-            let data = resp.bytes().await?;
-            g.put(key, data.clone());
-            data
-        }
-    };
-
-    Ok(data)
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
-// 调试辅助函数
-fn print_test_url(url: &str) {
+// 调试辅助函数，打印一个签好名的测试 url，一天后过期
+fn print_test_url(signing: &SigningConfig, url: &str) {
     use std::borrow::Borrow;
     let spec1 = Spec::new_resize(500, 800, resize::SampleFilter::CatmullRom);
     let spec2 = Spec::new_watermark(20, 20);
     let spec3 = Spec::new_filter(filter::Filter::Marine);
     let image_spec = ImageSpec::new(vec![spec1, spec2, spec3]);
     let s: String = image_spec.borrow().into();
-    let test_image = percent_encode(url.as_bytes(), NON_ALPHANUMERIC).to_string();
-    println!("test url: http://localhost:3000/image/{}/{}", s, test_image);
+    let path = signing.signed_path(&s, url, Some(unix_now() + 86400));
+    println!("test url: http://localhost:3000{}", path);
 }
@@ -3,6 +3,9 @@
 pub struct ImageSpec {
     #[prost(message, repeated, tag = "1")]
     pub specs: ::prost::alloc::vec::Vec<Spec>,
+    /// 输出的图片格式
+    #[prost(enumeration = "OutputFormat", tag = "2")]
+    pub format: i32,
 }
 /// 处理图片改变大小
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -111,3 +114,14 @@ pub mod spec {
         Watermark(super::Watermark),
     }
 }
+/// 输出的图片格式，AUTO 时根据请求的 Accept header 选择
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum OutputFormat {
+    Auto = 0,
+    Jpeg = 1,
+    Png = 2,
+    Webp = 3,
+    Avif = 4,
+    Gif = 5,
+}
@@ -7,7 +7,21 @@ pub use abi::*;
 
 impl ImageSpec {
     pub fn new(specs: Vec<Spec>) -> Self {
-        Self { specs }
+        Self {
+            specs,
+            format: OutputFormat::Auto as i32,
+        }
+    }
+
+    // 指定输出的图片格式，不再根据 Accept header 选择
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.format = format as i32;
+        self
+    }
+
+    // 不认识的格式当作 Auto 处理
+    pub fn output_format(&self) -> OutputFormat {
+        OutputFormat::from_i32(self.format).unwrap_or(OutputFormat::Auto)
     }
 }
 
@@ -107,4 +121,19 @@ mod tests {
         let s: String = image_spec.borrow().into();
         assert_eq!(image_spec, s.as_str().try_into().unwrap());
     }
+
+    #[test]
+    fn output_format_could_be_decoded() {
+        let spec = Spec::new_filter(filter::Filter::Marine);
+        let image_spec = ImageSpec::new(vec![spec]).with_format(OutputFormat::Webp);
+        let s: String = image_spec.borrow().into();
+        let decoded: ImageSpec = s.as_str().try_into().unwrap();
+        assert_eq!(decoded.output_format(), OutputFormat::Webp);
+
+        let image_spec = ImageSpec {
+            format: 100,
+            ..Default::default()
+        };
+        assert_eq!(image_spec.output_format(), OutputFormat::Auto);
+    }
 }
@@ -0,0 +1,187 @@
+use anyhow::{anyhow, Result};
+use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac, NewMac};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// URL 签名的配置
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SigningConfig {
+    // 第一个密钥用来生成签名，所有的密钥都可以用来验证，这样轮换密钥时旧的 URL 仍然可用。
+    // 没有配置密钥时拒绝所有请求
+    pub secrets: Vec<String>,
+    // 显式关闭签名检查，接受没有签名的请求。只应该在本地调试时使用
+    pub disabled: bool,
+}
+
+// 请求 query 里的签名和过期时间（unix 秒）
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Signature {
+    pub sig: Option<String>,
+    pub exp: Option<u64>,
+}
+
+impl SigningConfig {
+    pub fn enabled(&self) -> bool {
+        !self.disabled
+    }
+
+    // 启动时检查配置：没有密钥又没有显式关闭签名时，服务器不应该启动
+    pub fn validate(&self) -> Result<()> {
+        if self.enabled() && self.secrets.is_empty() {
+            return Err(anyhow!(
+                "no signing secrets configured, set signing.secrets or signing.disabled = true"
+            ));
+        }
+        Ok(())
+    }
+
+    // 用第一个密钥对 spec 和 url 签名。没有配置密钥时返回 None
+    pub fn sign(&self, spec: &str, url: &str, exp: Option<u64>) -> Option<Signature> {
+        let secret = self.secrets.first()?;
+        let tag = mac(secret, spec, url, exp).finalize().into_bytes();
+        Some(Signature {
+            sig: Some(encode_config(tag, URL_SAFE_NO_PAD)),
+            exp,
+        })
+    }
+
+    // 生成签好名的路径，url 是源图片未编码的 url
+    pub fn signed_path(&self, spec: &str, url: &str, exp: Option<u64>) -> String {
+        let encoded = percent_encode(url.as_bytes(), NON_ALPHANUMERIC);
+        let path = format!("/image/{}/{}", spec, encoded);
+        let query = match self.sign(spec, url, exp) {
+            Some(Signature {
+                sig: Some(sig),
+                exp: Some(exp),
+            }) => format!("?sig={}&exp={}", sig, exp),
+            Some(Signature { sig: Some(sig), .. }) => format!("?sig={}", sig),
+            _ => String::new(),
+        };
+        path + &query
+    }
+
+    // 签名和任意一个密钥匹配，并且没有过期时返回 true。没有密钥时任何签名都不匹配
+    pub fn verify(&self, spec: &str, url: &str, signature: &Signature, now: u64) -> bool {
+        if !self.enabled() {
+            return true;
+        }
+        if signature.exp.is_some_and(|exp| exp < now) {
+            return false;
+        }
+        let tag = match signature
+            .sig
+            .as_deref()
+            .and_then(|sig| decode_config(sig, URL_SAFE_NO_PAD).ok())
+        {
+            Some(tag) => tag,
+            None => return false,
+        };
+
+        // verify 使用常数时间的比较
+        self.secrets
+            .iter()
+            .any(|secret| mac(secret, spec, url, signature.exp).verify(&tag).is_ok())
+    }
+}
+
+// spec 是 base64，不包含换行，所以这样拼接不会有歧义
+fn mac(secret: &str, spec: &str, url: &str, exp: Option<u64>) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key");
+    let exp = exp.map(|v| v.to_string()).unwrap_or_default();
+    mac.update(format!("{}\n{}\n{}", spec, exp, url).as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = "CgoKCAj0AxCgBhgB";
+    const URL: &str = "https://example.com/a.jpg?w=100";
+
+    fn config(secrets: &[&str]) -> SigningConfig {
+        SigningConfig {
+            secrets: secrets.iter().map(|s| s.to_string()).collect(),
+            disabled: false,
+        }
+    }
+
+    #[test]
+    fn signed_url_should_be_verified() {
+        let config = config(&["secret1"]);
+        let sig = config.sign(SPEC, URL, None).unwrap();
+        assert!(config.verify(SPEC, URL, &sig, 0));
+
+        // 修改 spec，url 或者过期时间都会让签名失效
+        assert!(!config.verify("CgoKCAj0AxCgBhgC", URL, &sig, 0));
+        assert!(!config.verify(SPEC, "https://example.com/b.jpg", &sig, 0));
+        let forged = Signature {
+            exp: Some(u64::MAX),
+            ..sig
+        };
+        assert!(!config.verify(SPEC, URL, &forged, 0));
+    }
+
+    #[test]
+    fn unsigned_or_expired_url_should_be_rejected() {
+        let config = config(&["secret1"]);
+        assert!(!config.verify(SPEC, URL, &Signature::default(), 0));
+        let bad = Signature {
+            sig: Some("not base64!".into()),
+            exp: None,
+        };
+        assert!(!config.verify(SPEC, URL, &bad, 0));
+
+        let sig = config.sign(SPEC, URL, Some(1000)).unwrap();
+        assert!(config.verify(SPEC, URL, &sig, 1000));
+        assert!(!config.verify(SPEC, URL, &sig, 1001));
+    }
+
+    #[test]
+    fn rotated_secrets_should_be_accepted() {
+        let old = config(&["secret1"]);
+        let sig = old.sign(SPEC, URL, None).unwrap();
+
+        let rotated = config(&["secret2", "secret1"]);
+        assert!(rotated.verify(SPEC, URL, &sig, 0));
+        assert_ne!(rotated.sign(SPEC, URL, None).unwrap(), sig);
+
+        let retired = config(&["secret2"]);
+        assert!(!retired.verify(SPEC, URL, &sig, 0));
+    }
+
+    #[test]
+    fn signing_could_be_disabled() {
+        let config = SigningConfig {
+            disabled: true,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        assert!(config.verify(SPEC, URL, &Signature::default(), 0));
+        assert_eq!(config.sign(SPEC, URL, None), None);
+        assert!(!config.signed_path(SPEC, URL, None).contains('?'));
+    }
+
+    #[test]
+    fn missing_secrets_should_fail_closed() {
+        let config = SigningConfig::default();
+        assert!(config.validate().is_err());
+        assert!(!config.verify(SPEC, URL, &Signature::default(), 0));
+        let sig = self::config(&["secret1"]).sign(SPEC, URL, None).unwrap();
+        assert!(!config.verify(SPEC, URL, &sig, 0));
+    }
+
+    #[test]
+    fn signed_path_should_contain_signature() {
+        let config = config(&["secret1"]);
+        let path = config.signed_path(SPEC, URL, Some(1000));
+        let sig = config.sign(SPEC, URL, Some(1000)).unwrap().sig.unwrap();
+        assert!(path.starts_with(&format!("/image/{}/https%3A%2F%2F", SPEC)));
+        assert!(path.ends_with(&format!("?sig={}&exp=1000", sig)));
+    }
+}
@@ -0,0 +1,59 @@
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kv6::{CommandResponse, Compression, FrameCoder, FrameOptions, Value};
+use prost::Message;
+use rand::RngCore;
+
+/// 准备测试数据：可压缩的文本和不可压缩的随机数据
+fn payloads() -> Vec<(&'static str, CommandResponse)> {
+    let text = include_str!("../fixtures/server.conf").repeat(64);
+    let mut random = vec![0u8; text.len()];
+    rand::thread_rng().fill_bytes(&mut random);
+
+    vec![
+        ("text", Value::from(text).into()),
+        ("random", Value::from(Bytes::from(random)).into()),
+    ]
+}
+
+fn compression(c: &mut Criterion) {
+    for (name, res) in payloads() {
+        let mut group = c.benchmark_group(format!("compression/{}", name));
+        let size = res.encoded_len();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        for algorithm in Compression::ALL {
+            let options = FrameOptions {
+                compression: algorithm,
+                threshold: 0,
+                checksum: false,
+            };
+            let mut frame = BytesMut::new();
+            res.encode_frame_with(&mut frame, &options).unwrap();
+
+            group.bench_function(BenchmarkId::new("encode", algorithm), |b| {
+                b.iter(|| {
+                    let mut buf = BytesMut::new();
+                    res.encode_frame_with(&mut buf, &options).unwrap();
+                    buf
+                })
+            });
+
+            group.bench_function(BenchmarkId::new("decode", algorithm), |b| {
+                b.iter(|| {
+                    let mut buf = frame.clone();
+                    CommandResponse::decode_frame(&mut buf).unwrap()
+                })
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = compression
+}
+criterion_main!(benches);
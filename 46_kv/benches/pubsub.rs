@@ -2,15 +2,13 @@ use anyhow::Result;
 use criterion::{criterion_group, criterion_main, Criterion};
 use futures::StreamExt;
 use kv6::{
-    start_client_with_config, start_server_with_config, ClientConfig, CommandRequest, ServerConfig,
-    StorageConfig, YamuxCtrl,
+    start_client_with_config, start_server_with_config, BoxedStream, ClientConfig, CommandRequest,
+    ServerConfig, StorageConfig, YamuxCtrl,
 };
 use rand::prelude::SliceRandom;
 use std::time::Duration;
-use tokio::net::TcpStream;
 use tokio::runtime::Builder;
 use tokio::time;
-use tokio_rustls::client::TlsStream;
 use tracing::{info, span};
 use tracing_subscriber::{layer::SubscriberExt, prelude::*, EnvFilter};
 
@@ -27,7 +25,7 @@ async fn start_server() -> Result<()> {
     Ok(())
 }
 
-async fn connect() -> Result<YamuxCtrl<TlsStream<TcpStream>>> {
+async fn connect() -> Result<YamuxCtrl<BoxedStream>> {
     let addr = "127.0.0.1:9999";
     let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
     config.general.addr = addr.into();
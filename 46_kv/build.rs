@@ -2,6 +2,14 @@ fn main() {
     let mut config = prost_build::Config::new();
     config.bytes(&["."]);
     config.type_attribute(".", "#[derive(PartialOrd)]");
+    // 这几个 enum 的成员名沿用 proto 里的命名（比如 Vote、VoteResponse），会触发
+    // clippy 的 enum_variant_names，在生成的代码上关掉这个检查
+    for name in ["RaftMessageType", "RaftEntryType", "ConfChangeType"] {
+        config.type_attribute(
+            format!(".abi.{}", name),
+            "#[allow(clippy::enum_variant_names)]",
+        );
+    }
     config
         .out_dir("src/pb")
         .compile_protos(&["abi.proto"], &["."])
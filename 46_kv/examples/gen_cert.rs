@@ -1,62 +1,24 @@
 use anyhow::Result;
-use certify::{generate_ca, generate_cert, load_ca, CertType, CA};
+use kv6::{generate_ca_cert, issue_client_cert, issue_server_cert, CertPem, CertSubject};
 use tokio::fs;
 
-struct CertPem {
-    cert_type: CertType,
-    cert: String,
-    key: String,
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
-    let pem = create_ca()?;
-    gen_files(&pem).await?;
-    let ca = load_ca(&pem.cert, &pem.key)?;
-    let pem = create_cert(&ca, &["kvserver.acme.inc"], "Acme KV server", false)?;
-    gen_files(&pem).await?;
-    let pem = create_cert(&ca, &[], "awesome-device-id", true)?;
-    gen_files(&pem).await?;
+    let ca = generate_ca_cert(&subject("Acme CA"), 10 * 365)?;
+    gen_files("ca", &ca).await?;
+    let sans = ["kvserver.acme.inc".to_string()];
+    let pem = issue_server_cert(&ca, &subject("Acme KV server"), &sans, 5 * 365)?;
+    gen_files("server", &pem).await?;
+    let pem = issue_client_cert(&ca, &subject("awesome-device-id"), 365)?;
+    gen_files("client", &pem).await?;
     Ok(())
 }
 
-fn create_ca() -> Result<CertPem> {
-    let (cert, key) = generate_ca(
-        &["acme.inc"],
-        "CN",
-        "Acme Inc.",
-        "Acme CA",
-        None,
-        Some(10 * 365),
-    )?;
-    Ok(CertPem {
-        cert_type: CertType::CA,
-        cert,
-        key,
-    })
-}
-
-fn create_cert(ca: &CA, domains: &[&str], cn: &str, is_client: bool) -> Result<CertPem> {
-    let (days, cert_type) = if is_client {
-        (Some(365), CertType::Client)
-    } else {
-        (Some(5 * 365), CertType::Server)
-    };
-    let (cert, key) = generate_cert(ca, domains, "CN", "Acme Inc.", cn, None, is_client, days)?;
-
-    Ok(CertPem {
-        cert_type,
-        cert,
-        key,
-    })
+fn subject(cn: &str) -> CertSubject {
+    CertSubject::new("CN", "Acme Inc.", cn)
 }
 
-async fn gen_files(pem: &CertPem) -> Result<()> {
-    let name = match pem.cert_type {
-        CertType::Client => "client",
-        CertType::Server => "server",
-        CertType::CA => "ca",
-    };
+async fn gen_files(name: &str, pem: &CertPem) -> Result<()> {
     fs::write(format!("fixtures/{}.cert", name), pem.cert.as_bytes()).await?;
     fs::write(format!("fixtures/{}.key", name), pem.key.as_bytes()).await?;
     Ok(())
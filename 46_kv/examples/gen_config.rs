@@ -1,47 +1,26 @@
 use anyhow::Result;
-use kv6::{
-    ClientConfig, ClientTlsConfig, GeneralConfig, LogConfig, RotationConfig, ServerConfig,
-    ServerTlsConfig, StorageConfig,
-};
+use kv6::{client_config, server_config, CertPem, StorageConfig};
 use std::fs;
 
 fn main() -> Result<()> {
-    const CA_CERT: &str = include_str!("../fixtures/ca.cert");
-    const SERVER_CERT: &str = include_str!("../fixtures/server.cert");
-    const SERVER_KEY: &str = include_str!("../fixtures/server.key");
-
-    let general_config = GeneralConfig {
-        addr: "127.0.0.1:9527".into(),
+    let ca = CertPem {
+        cert: include_str!("../fixtures/ca.cert").into(),
+        key: include_str!("../fixtures/ca.key").into(),
     };
-    let server_config = ServerConfig {
-        storage: StorageConfig::SledDb("/tmp/kv_server".into()),
-        general: general_config.clone(),
-        tls: ServerTlsConfig {
-            cert: SERVER_CERT.into(),
-            key: SERVER_KEY.into(),
-            ca: None,
-        },
-        log: LogConfig {
-            path: "/tmp/kv-log".into(),
-            rotation: RotationConfig::Daily,
-        },
+    let server = CertPem {
+        cert: include_str!("../fixtures/server.cert").into(),
+        key: include_str!("../fixtures/server.key").into(),
     };
 
+    let addr = "127.0.0.1:9527";
+    let storage = StorageConfig::SledDb("/tmp/kv_server".into());
+    let server_config = server_config(addr, storage, &server, None);
     fs::write(
         "fixtures/server.conf",
         toml::to_string_pretty(&server_config)?,
     )?;
 
-    let client_config = ClientConfig {
-        general: general_config,
-
-        tls: ClientTlsConfig {
-            identity: None,
-            ca: Some(CA_CERT.into()),
-            domain: "kvserver.acme.inc".into(),
-        },
-    };
-
+    let client_config = client_config(addr, "kvserver.acme.inc", &ca, None);
     fs::write(
         "fixtures/client.conf",
         toml::to_string_pretty(&client_config)?,
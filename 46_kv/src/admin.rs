@@ -0,0 +1,568 @@
+use std::{
+    convert::TryInto,
+    ffi::OsStr,
+    fs::{self, OpenOptions},
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, ValueEnum};
+use comfy_table::{presets::UTF8_FULL, Table};
+use futures::StreamExt;
+use kv6::{
+    client_config, export_csv, export_jsonl, format_response, generate_ca_cert, import_csv,
+    import_jsonl, inspect_cert, issue_client_cert, issue_server_cert, restore_storage,
+    server_config, start_client_with_config, CertPem, CertSubject, CertSummary, ClientConfig,
+    CommandRequest, DumpReader, DumpWriter, KvClient, SledDb, StorageConfig,
+};
+use tokio::fs::File;
+use tracing::info;
+
+/// kv6 管理工具：在线备份，恢复，和 JSON Lines / CSV 之间的转换，管理集群成员，以及签发证书
+#[derive(Parser, Debug)]
+#[clap(version = "0.1")]
+struct Opts {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+
+#[derive(Parser, Debug)]
+enum SubCommand {
+    Backup(Backup),
+    Restore(Restore),
+    Export(Export),
+    Import(Import),
+    Cluster(Cluster),
+    Cert(Cert),
+}
+
+/// 从运行中的服务器获取一致的 snapshot，写入备份文件
+#[derive(Parser, Debug)]
+struct Backup {
+    /// 客户端配置文件，缺省时使用环境变量 KV_CLIENT_CONFIG
+    #[clap(short, long)]
+    config: Option<String>,
+    /// 备份文件
+    #[clap(short, long)]
+    output: String,
+    /// 需要备份的 table，缺省时备份所有 table
+    #[clap(short, long)]
+    table: Vec<String>,
+}
+
+/// 把备份文件恢复到运行中的服务器，或者直接恢复到 sled 目录
+#[derive(Parser, Debug)]
+struct Restore {
+    /// 备份文件
+    #[clap(short, long)]
+    input: String,
+    /// 客户端配置文件，缺省时使用环境变量 KV_CLIENT_CONFIG
+    #[clap(short, long)]
+    config: Option<String>,
+    /// 直接写入 sled 目录（使用这个目录的服务器不能在运行）
+    #[clap(long)]
+    sled: Option<String>,
+}
+
+/// 把备份文件导出成 JSON Lines 或 CSV
+#[derive(Parser, Debug)]
+struct Export {
+    /// 备份文件
+    #[clap(short, long)]
+    input: String,
+    /// 导出的文件
+    #[clap(short, long)]
+    output: String,
+    #[clap(short, long, value_enum, default_value = "jsonl")]
+    format: Format,
+}
+
+/// 把 JSON Lines 或 CSV 导入成备份文件
+#[derive(Parser, Debug)]
+struct Import {
+    /// 需要导入的文件
+    #[clap(short, long)]
+    input: String,
+    /// 备份文件
+    #[clap(short, long)]
+    output: String,
+    #[clap(short, long, value_enum, default_value = "jsonl")]
+    format: Format,
+}
+
+/// 查看集群状态，或者增加、删除集群的成员（需要连接到 leader）
+#[derive(Parser, Debug)]
+struct Cluster {
+    /// 客户端配置文件，缺省时使用环境变量 KV_CLIENT_CONFIG
+    #[clap(short, long)]
+    config: Option<String>,
+    #[clap(subcommand)]
+    action: ClusterAction,
+}
+
+#[derive(Parser, Debug)]
+enum ClusterAction {
+    /// 查看连接的节点看到的集群状态
+    Status,
+    /// 加入一个用 join = true 启动的节点
+    Add {
+        #[clap(long)]
+        id: u64,
+        /// 其它节点连接这个节点使用的地址
+        #[clap(long)]
+        addr: String,
+    },
+    /// 从集群中删除一个节点
+    Remove {
+        #[clap(long)]
+        id: u64,
+    },
+}
+
+/// 创建 CA，签发服务器和客户端证书并生成对应的配置，查看证书的有效期。
+/// 证书和私钥保存成 <dir>/<name>.cert 和 <dir>/<name>.key
+#[derive(Parser, Debug)]
+struct Cert {
+    #[clap(subcommand)]
+    action: CertAction,
+}
+
+#[derive(Parser, Debug)]
+enum CertAction {
+    /// 创建自签名的 CA
+    Ca {
+        #[clap(flatten)]
+        out: CertOutput,
+        /// CA 的 CN
+        #[clap(long)]
+        cn: String,
+        /// 有效天数
+        #[clap(long, default_value = "3650")]
+        days: i64,
+    },
+    /// 用 CA 签发服务器证书
+    Server {
+        #[clap(flatten)]
+        out: CertOutput,
+        /// 签发用的 CA 的名字
+        #[clap(long, default_value = "ca")]
+        ca: String,
+        /// 服务器的域名或者 IP 地址，可以指定多个
+        #[clap(long, required = true)]
+        san: Vec<String>,
+        /// 服务器证书的 CN，缺省时使用第一个 SAN
+        #[clap(long)]
+        cn: Option<String>,
+        /// 有效天数
+        #[clap(long, default_value = "365")]
+        days: i64,
+        /// 同时生成服务器配置文件
+        #[clap(long)]
+        config: Option<String>,
+        /// 配置文件中服务器监听的地址
+        #[clap(long, default_value = "0.0.0.0:9527")]
+        addr: String,
+        /// 配置文件中使用的 sled 目录，缺省时使用 MemTable
+        #[clap(long)]
+        sled: Option<String>,
+        /// 配置文件中要求客户端提供这个 CA 签发的证书
+        #[clap(long)]
+        client_auth: bool,
+    },
+    /// 用 CA 签发客户端证书
+    Client {
+        #[clap(flatten)]
+        out: CertOutput,
+        /// 签发用的 CA 的名字
+        #[clap(long, default_value = "ca")]
+        ca: String,
+        /// 客户端的身份，写入证书的 CN
+        #[clap(long)]
+        identity: String,
+        /// 有效天数
+        #[clap(long, default_value = "365")]
+        days: i64,
+        /// 同时生成客户端配置文件
+        #[clap(long, requires = "domain")]
+        config: Option<String>,
+        /// 配置文件中服务器的地址
+        #[clap(long, default_value = "127.0.0.1:9527")]
+        addr: String,
+        /// 配置文件中服务器证书的域名
+        #[clap(long)]
+        domain: Option<String>,
+    },
+    /// 列出目录下所有的证书和它们的过期时间
+    List {
+        /// 证书目录
+        #[clap(long, default_value = "certs")]
+        dir: String,
+        /// 剩余天数少于这个值时提示快要过期
+        #[clap(long, default_value = "30")]
+        warn_days: i64,
+        /// 有证书过期或者快要过期时返回错误
+        #[clap(long)]
+        check: bool,
+    },
+    /// 查看一个证书的详细信息
+    Inspect {
+        /// 证书文件
+        path: String,
+        #[clap(short, long, value_enum, default_value = "text")]
+        format: CertFormat,
+    },
+}
+
+#[derive(Args, Debug)]
+struct CertOutput {
+    /// 证书目录
+    #[clap(long, default_value = "certs")]
+    dir: String,
+    /// 证书的名字，缺省时 CA 为 ca，服务器为 server，客户端为 identity
+    #[clap(long)]
+    name: Option<String>,
+    /// 证书 subject 中的组织
+    #[clap(long, default_value = "Acme Inc.")]
+    org: String,
+    /// 证书 subject 中的国家
+    #[clap(long, default_value = "CN")]
+    country: String,
+    /// 覆盖已经存在的证书
+    #[clap(long)]
+    force: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Jsonl,
+    Csv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CertFormat {
+    Text,
+    Json,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let opts: Opts = Opts::parse();
+
+    match opts.subcmd {
+        SubCommand::Backup(args) => backup(args).await?,
+        SubCommand::Restore(args) => restore(args).await?,
+        SubCommand::Export(args) => export(args).await?,
+        SubCommand::Import(args) => import(args).await?,
+        SubCommand::Cluster(args) => cluster(args).await?,
+        SubCommand::Cert(args) => cert(args)?,
+    }
+
+    Ok(())
+}
+
+async fn backup(args: Backup) -> Result<()> {
+    let config = load_client_config(args.config.as_deref())?;
+    let mut ctrl = start_client_with_config(&config).await?;
+    let stream = ctrl.open_stream().await?;
+
+    let cmd = CommandRequest::new_dump(args.table);
+    let mut stream = stream.execute_dump(&cmd).await?;
+
+    let mut writer = DumpWriter::new(File::create(&args.output).await?);
+    let mut count = 0;
+    while let Some(res) = stream.next().await {
+        let res = res?;
+        if res.status != 200 {
+            return Err(anyhow!("Backup failed: {}", res.message));
+        }
+        let table: String = match res.values.first() {
+            Some(v) => v.clone().try_into()?,
+            None => return Err(anyhow!("Invalid dump response: {:?}", res)),
+        };
+        writer.write_section(&table, &res.pairs).await?;
+        count += res.pairs.len();
+    }
+    writer.finish().await?;
+
+    info!("{} pairs are written to {}", count, args.output);
+    Ok(())
+}
+
+async fn restore(args: Restore) -> Result<()> {
+    let input = File::open(&args.input).await?;
+
+    // 离线恢复到 sled 目录
+    if let Some(path) = args.sled {
+        let count = restore_storage(&SledDb::new(&path), input).await?;
+        info!("{} pairs are restored to {}", count, path);
+        return Ok(());
+    }
+
+    // 在线恢复到服务器，每一段数据用一个 HMSET 写入
+    let config = load_client_config(args.config.as_deref())?;
+    let mut ctrl = start_client_with_config(&config).await?;
+    let mut stream = ctrl.open_stream().await?;
+
+    let mut reader = DumpReader::new(input);
+    let mut count = 0;
+    while let Some((table, pairs)) = reader.read_section().await? {
+        count += pairs.len();
+        let res = stream
+            .execute_unary(&CommandRequest::new_hmset(table, pairs))
+            .await?;
+        if res.status != 200 {
+            return Err(anyhow!("Restore failed: {}", res.message));
+        }
+    }
+
+    info!("{} pairs are restored to {}", count, config.general.addr);
+    Ok(())
+}
+
+async fn export(args: Export) -> Result<()> {
+    let input = File::open(&args.input).await?;
+    let output = fs::File::create(&args.output)?;
+    let count = match args.format {
+        Format::Jsonl => export_jsonl(input, output).await?,
+        Format::Csv => export_csv(input, output).await?,
+    };
+
+    info!("{} records are exported to {}", count, args.output);
+    Ok(())
+}
+
+async fn import(args: Import) -> Result<()> {
+    let input = BufReader::new(fs::File::open(&args.input)?);
+    let output = File::create(&args.output).await?;
+    let count = match args.format {
+        Format::Jsonl => import_jsonl(input, output).await?,
+        Format::Csv => import_csv(input, output).await?,
+    };
+
+    info!("{} records are imported to {}", count, args.output);
+    Ok(())
+}
+
+async fn cluster(args: Cluster) -> Result<()> {
+    let config = load_client_config(args.config.as_deref())?;
+    let client = KvClient::connect(&config).await?;
+    let cmd = match args.action {
+        ClusterAction::Status => CommandRequest::new_cluster_status(),
+        ClusterAction::Add { id, addr } => CommandRequest::new_cluster_add(id, addr),
+        ClusterAction::Remove { id } => CommandRequest::new_cluster_remove(id),
+    };
+
+    let res = client.request(cmd).await?;
+    println!("{}", format_response(&res));
+    Ok(())
+}
+
+fn cert(args: Cert) -> Result<()> {
+    match args.action {
+        CertAction::Ca { out, cn, days } => {
+            let pem = generate_ca_cert(&out.subject(cn), days)?;
+            out.save("ca", &pem)?;
+        }
+        CertAction::Server {
+            out,
+            ca,
+            san,
+            cn,
+            days,
+            config,
+            addr,
+            sled,
+            client_auth,
+        } => {
+            let ca = load_pem(&out.dir, &ca)?;
+            let cn = cn.unwrap_or_else(|| san[0].clone());
+            let pem = issue_server_cert(&ca, &out.subject(cn), &san, days)?;
+            out.save("server", &pem)?;
+            if let Some(path) = config {
+                let storage = match sled {
+                    Some(path) => StorageConfig::SledDb(path),
+                    None => StorageConfig::MemTable,
+                };
+                let client_ca = if client_auth { Some(&ca) } else { None };
+                let config = server_config(addr, storage, &pem, client_ca);
+                write_config(&path, &toml::to_string_pretty(&config)?)?;
+            }
+        }
+        CertAction::Client {
+            out,
+            ca,
+            identity,
+            days,
+            config,
+            addr,
+            domain,
+        } => {
+            let ca = load_pem(&out.dir, &ca)?;
+            let pem = issue_client_cert(&ca, &out.subject(identity.clone()), days)?;
+            out.save(&identity, &pem)?;
+            if let (Some(path), Some(domain)) = (config, domain) {
+                let config = client_config(addr, domain, &ca, Some(&pem));
+                write_config(&path, &toml::to_string_pretty(&config)?)?;
+            }
+        }
+        CertAction::List {
+            dir,
+            warn_days,
+            check,
+        } => list_certs(&dir, warn_days, check)?,
+        CertAction::Inspect { path, format } => {
+            let summary = inspect_cert(&fs::read_to_string(&path)?)?;
+            match format {
+                CertFormat::Text => print_cert(&summary),
+                CertFormat::Json => println!("{}", serde_json::to_string_pretty(&summary)?),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl CertOutput {
+    fn subject(&self, cn: String) -> CertSubject {
+        CertSubject::new(&self.country, &self.org, cn)
+    }
+
+    /// 保存证书和私钥，私钥只有自己可以读写
+    fn save(&self, default_name: &str, pem: &CertPem) -> Result<()> {
+        let name = self.name.as_deref().unwrap_or(default_name);
+        let (cert, key) = pem_paths(&self.dir, name);
+        if !self.force {
+            if let Some(path) = [&cert, &key].iter().find(|p| p.exists()) {
+                return Err(anyhow!(
+                    "{} already exists, use --force to overwrite",
+                    path.display()
+                ));
+            }
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        fs::write(&cert, &pem.cert)?;
+        write_private(&key, &pem.key)?;
+
+        info!("Certificate is written to {}", cert.display());
+        Ok(())
+    }
+}
+
+fn pem_paths(dir: &str, name: &str) -> (PathBuf, PathBuf) {
+    let dir = Path::new(dir);
+    (
+        dir.join(format!("{}.cert", name)),
+        dir.join(format!("{}.key", name)),
+    )
+}
+
+fn load_pem(dir: &str, name: &str) -> Result<CertPem> {
+    let (cert, key) = pem_paths(dir, name);
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))
+    };
+    Ok(CertPem {
+        cert: read(&cert)?,
+        key: read(&key)?,
+    })
+}
+
+/// 生成的配置里内嵌了私钥，和私钥文件一样只有自己可以读写
+fn write_config(path: &str, content: &str) -> Result<()> {
+    write_private(Path::new(path), content)?;
+    info!("Config is written to {}", path);
+    Ok(())
+}
+
+/// 写入只有自己可以读写的文件。新文件创建时就是 0600，已有的文件先改权限再写入内容，
+/// 这样私钥在任何时候都不会被其他人读到
+fn write_private(path: &Path, content: &str) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+fn list_certs(dir: &str, warn_days: i64, check: bool) -> Result<()> {
+    let mut certs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("cert")) {
+            let summary = inspect_cert(&fs::read_to_string(&path)?)?;
+            certs.push((path, summary));
+        }
+    }
+    certs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL).set_header(vec![
+        "file",
+        "kind",
+        "subject",
+        "sans",
+        "expires",
+        "days left",
+        "status",
+    ]);
+    let mut expiring = 0;
+    for (path, summary) in &certs {
+        let status = match summary.days_left {
+            d if d < 0 => "expired",
+            d if d < warn_days => "expiring",
+            _ => "ok",
+        };
+        if status != "ok" {
+            expiring += 1;
+        }
+        table.add_row(vec![
+            path.display().to_string(),
+            summary.kind.as_str().to_string(),
+            summary.subject.clone(),
+            summary.sans.join(", "),
+            summary.expires.clone(),
+            summary.days_left.to_string(),
+            status.to_string(),
+        ]);
+    }
+    println!("{}", table);
+
+    if check && expiring > 0 {
+        return Err(anyhow!(
+            "{} certificate(s) expire within {} days",
+            expiring,
+            warn_days
+        ));
+    }
+    Ok(())
+}
+
+fn print_cert(summary: &CertSummary) {
+    println!("subject:    {}", summary.subject);
+    println!("issuer:     {}", summary.issuer);
+    println!("kind:       {}", summary.kind.as_str());
+    println!("sans:       {}", summary.sans.join(", "));
+    println!("expires:    {}", summary.expires);
+    println!("days left:  {}", summary.days_left);
+}
+
+fn load_client_config(path: Option<&str>) -> Result<ClientConfig> {
+    match ClientConfig::load_from(path)? {
+        Some(config) => Ok(config),
+        None => Ok(toml::from_str(include_str!("../fixtures/client.conf"))?),
+    }
+}
@@ -0,0 +1,525 @@
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    io::{BufRead, Write},
+    str,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{
+    format_key, read_frame, tokenize, value, FrameCoder, KvError, Kvpair, Storage, TableHeader,
+    Token, Value, DEFAULT_MAX_FRAME,
+};
+
+/// 导入时，每一段最多放多少个 kv pair
+const SECTION_SIZE: usize = 1024;
+
+/// 写入备份文件：每一段是一个 TableHeader frame 加上 count 个 Kvpair frame
+pub struct DumpWriter<W> {
+    writer: W,
+    buf: BytesMut,
+}
+
+/// 读取备份文件
+pub struct DumpReader<R> {
+    reader: BufReader<R>,
+    buf: BytesMut,
+}
+
+impl<W> DumpWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// 写入 table 的一段数据，同一个 table 可以分成多段写入
+    pub async fn write_section(&mut self, table: &str, pairs: &[Kvpair]) -> Result<(), KvError> {
+        TableHeader::new(table, pairs.len()).encode_frame(&mut self.buf)?;
+        for pair in pairs {
+            pair.encode_frame(&mut self.buf)?;
+        }
+        self.writer.write_all(&self.buf).await?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// 写完后 flush，并把内部的 writer 还回去
+    pub async fn finish(mut self) -> Result<W, KvError> {
+        self.writer.flush().await?;
+        Ok(self.writer)
+    }
+}
+
+impl<R> DumpReader<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// 读取下一段数据，文件结束时返回 None
+    pub async fn read_section(&mut self) -> Result<Option<(String, Vec<Kvpair>)>, KvError> {
+        if self.reader.fill_buf().await?.is_empty() {
+            return Ok(None);
+        }
+
+        read_frame(&mut self.reader, &mut self.buf, DEFAULT_MAX_FRAME).await?;
+        let header = TableHeader::decode_frame(&mut self.buf)?;
+
+        // count 来自文件，不能直接拿来分配内存
+        let mut pairs = Vec::with_capacity((header.count as usize).min(SECTION_SIZE));
+        for _ in 0..header.count {
+            read_frame(&mut self.reader, &mut self.buf, DEFAULT_MAX_FRAME).await?;
+            pairs.push(Kvpair::decode_frame(&mut self.buf)?);
+        }
+
+        Ok(Some((header.table, pairs)))
+    }
+}
+
+/// 把 store 里的所有数据写入 writer，返回写入的 kv pair 数量
+pub async fn dump_storage<W>(store: &impl Storage, writer: W) -> Result<usize, KvError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = DumpWriter::new(writer);
+    let mut count = 0;
+    for table in store.get_tables()? {
+        let pairs = store.get_all(&table)?;
+        if !pairs.is_empty() {
+            writer.write_section(&table, &pairs).await?;
+            count += pairs.len();
+        }
+    }
+    writer.finish().await?;
+    Ok(count)
+}
+
+/// 把备份文件里的数据恢复到 store 里，返回恢复的 kv pair 数量
+pub async fn restore_storage<R>(store: &impl Storage, reader: R) -> Result<usize, KvError>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut reader = DumpReader::new(reader);
+    let mut count = 0;
+    while let Some((table, pairs)) = reader.read_section().await? {
+        count += pairs.len();
+        for pair in pairs {
+            store.set(&table, pair.key, pair.value.unwrap_or_default())?;
+        }
+    }
+    Ok(count)
+}
+
+/// 导出导入时使用的一条记录，用 JSON Lines 表示时，value 使用 JSON 原生的类型，
+/// binary 使用 base64 编码。不是合法 UTF-8 的 key 使用和 CLI 一样的 b"..." 表示
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Record {
+    pub table: String,
+    pub key: String,
+    #[serde(rename = "type")]
+    pub kind: ValueKind,
+    pub value: serde_json::Value,
+}
+
+/// CSV 里的一行：table,key,type,value
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct CsvRecord {
+    table: String,
+    key: String,
+    #[serde(rename = "type")]
+    kind: ValueKind,
+    value: String,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueKind {
+    Null,
+    String,
+    Binary,
+    Integer,
+    Float,
+    Bool,
+}
+
+impl Record {
+    pub fn new(table: impl Into<String>, pair: Kvpair) -> Self {
+        use serde_json::Value as Json;
+
+        let (kind, value) = match pair.value.and_then(|v| v.value) {
+            None => (ValueKind::Null, Json::Null),
+            Some(value::Value::String(s)) => (ValueKind::String, Json::String(s)),
+            Some(value::Value::Binary(b)) => (ValueKind::Binary, Json::String(base64::encode(b))),
+            Some(value::Value::Integer(i)) => (ValueKind::Integer, i.into()),
+            Some(value::Value::Float(f)) => (ValueKind::Float, f.into()),
+            Some(value::Value::Bool(b)) => (ValueKind::Bool, b.into()),
+        };
+
+        Self {
+            table: table.into(),
+            key: export_key(&pair.key),
+            kind,
+            value,
+        }
+    }
+}
+
+impl TryFrom<Record> for Kvpair {
+    type Error = KvError;
+
+    fn try_from(record: Record) -> Result<Self, Self::Error> {
+        use serde_json::Value as Json;
+
+        let err = |kind| KvError::ConvertError(record.value.to_string(), kind);
+        let value: Value = match (record.kind, &record.value) {
+            (ValueKind::Null, _) => Value::default(),
+            (ValueKind::String, Json::String(s)) => s.as_str().into(),
+            (ValueKind::Binary, Json::String(s)) => {
+                let data = base64::decode(s).map_err(|_| err("Binary"))?;
+                Bytes::from(data).into()
+            }
+            (ValueKind::Integer, v) => v.as_i64().ok_or_else(|| err("Integer"))?.into(),
+            (ValueKind::Float, v) => v.as_f64().ok_or_else(|| err("Float"))?.into(),
+            (ValueKind::Bool, v) => v.as_bool().ok_or_else(|| err("Boolean"))?.into(),
+            (_, _) => return Err(err("String")),
+        };
+
+        Ok(Kvpair::new(parse_key(record.key), value))
+    }
+}
+
+impl From<Record> for CsvRecord {
+    fn from(record: Record) -> Self {
+        use serde_json::Value as Json;
+
+        let value = match record.value {
+            Json::Null => String::new(),
+            Json::String(s) => s,
+            v => v.to_string(),
+        };
+
+        Self {
+            table: record.table,
+            key: record.key,
+            kind: record.kind,
+            value,
+        }
+    }
+}
+
+impl TryFrom<CsvRecord> for Record {
+    type Error = KvError;
+
+    fn try_from(record: CsvRecord) -> Result<Self, Self::Error> {
+        use serde_json::Value as Json;
+
+        let err = |kind| KvError::ConvertError(record.value.clone(), kind);
+        let value = match record.kind {
+            ValueKind::Null => Json::Null,
+            ValueKind::String | ValueKind::Binary => Json::String(record.value.clone()),
+            ValueKind::Integer => record
+                .value
+                .parse::<i64>()
+                .map_err(|_| err("Integer"))?
+                .into(),
+            ValueKind::Float => record
+                .value
+                .parse::<f64>()
+                .map_err(|_| err("Float"))?
+                .into(),
+            ValueKind::Bool => record
+                .value
+                .parse::<bool>()
+                .map_err(|_| err("Boolean"))?
+                .into(),
+        };
+
+        Ok(Self {
+            table: record.table,
+            key: record.key,
+            kind: record.kind,
+            value,
+        })
+    }
+}
+
+/// 把备份文件转换成 JSON Lines，返回导出的记录数
+pub async fn export_jsonl<R, W>(reader: R, mut writer: W) -> Result<usize, KvError>
+where
+    R: AsyncRead + Unpin + Send,
+    W: Write,
+{
+    let mut reader = DumpReader::new(reader);
+    let mut count = 0;
+    while let Some((table, pairs)) = reader.read_section().await? {
+        for pair in pairs {
+            serde_json::to_writer(&mut writer, &Record::new(table.as_str(), pair))?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// 把 JSON Lines 转换成备份文件，返回导入的记录数
+pub async fn import_jsonl<R, W>(reader: R, writer: W) -> Result<usize, KvError>
+where
+    R: BufRead,
+    W: AsyncWrite + Unpin,
+{
+    let records = reader.lines().filter_map(|line| match line {
+        Ok(line) if line.trim().is_empty() => None,
+        Ok(line) => Some(serde_json::from_str::<Record>(&line).map_err(KvError::from)),
+        Err(e) => Some(Err(e.into())),
+    });
+    write_records(records, writer).await
+}
+
+/// 把备份文件转换成 CSV，返回导出的记录数
+pub async fn export_csv<R, W>(reader: R, writer: W) -> Result<usize, KvError>
+where
+    R: AsyncRead + Unpin + Send,
+    W: Write,
+{
+    let mut reader = DumpReader::new(reader);
+    let mut writer = csv::Writer::from_writer(writer);
+    let mut count = 0;
+    while let Some((table, pairs)) = reader.read_section().await? {
+        for pair in pairs {
+            writer.serialize(CsvRecord::from(Record::new(table.as_str(), pair)))?;
+            count += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// 把 CSV 转换成备份文件，返回导入的记录数
+pub async fn import_csv<R, W>(reader: R, writer: W) -> Result<usize, KvError>
+where
+    R: BufRead,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = csv::Reader::from_reader(reader);
+    let records = reader
+        .deserialize::<CsvRecord>()
+        .map(|record| match record {
+            Ok(record) => Record::try_from(record),
+            Err(e) => Err(e.into()),
+        });
+    write_records(records, writer).await
+}
+
+/// 把连续的、属于同一个 table 的记录合并成一段写入
+async fn write_records<W>(
+    records: impl Iterator<Item = Result<Record, KvError>>,
+    writer: W,
+) -> Result<usize, KvError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = DumpWriter::new(writer);
+    let mut table = String::new();
+    let mut pairs = Vec::new();
+    let mut count = 0;
+
+    for record in records {
+        let record = record?;
+        if (record.table != table || pairs.len() == SECTION_SIZE) && !pairs.is_empty() {
+            writer.write_section(&table, &pairs).await?;
+            pairs.clear();
+        }
+        table = record.table.clone();
+        pairs.push(Kvpair::try_from(record)?);
+        count += 1;
+    }
+
+    if !pairs.is_empty() {
+        writer.write_section(&table, &pairs).await?;
+    }
+    writer.finish().await?;
+
+    Ok(count)
+}
+
+/// 合法 UTF-8 的 key 原样导出，只有其它的 key 才使用 CLI 的 b"..." 表示
+fn export_key(key: &[u8]) -> String {
+    match str::from_utf8(key) {
+        Ok(s) => s.into(),
+        Err(_) => format_key(key),
+    }
+}
+
+/// 导出时只有不是合法 UTF-8 的 key 才会写成 b"..."，其它形式的 key 原样使用
+fn parse_key(key: String) -> Bytes {
+    match tokenize(&key).as_deref() {
+        Ok([Token::Bytes(b)]) if str::from_utf8(b).is_err() => Bytes::copy_from_slice(b),
+        _ => key.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb};
+    use bytes::Bytes;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn dump_and_restore_should_work() {
+        let store = MemTable::new();
+        prepare_store(&store);
+
+        let mut data = Vec::new();
+        let count = dump_storage(&store, &mut data).await.unwrap();
+        assert_eq!(count, 8);
+
+        // MemTable 的备份可以恢复到 SledDb 里
+        let dir = tempdir().unwrap();
+        let sled = SledDb::new(dir);
+        let count = restore_storage(&sled, &data[..]).await.unwrap();
+        assert_eq!(count, 8);
+        assert_store_eq(&store, &sled);
+
+        // 反之亦然
+        let mut data = Vec::new();
+        dump_storage(&sled, &mut data).await.unwrap();
+        let store1 = MemTable::new();
+        restore_storage(&store1, &data[..]).await.unwrap();
+        assert_store_eq(&sled, &store1);
+    }
+
+    #[tokio::test]
+    async fn dump_reader_should_read_multiple_sections_of_same_table() {
+        let mut writer = DumpWriter::new(Vec::new());
+        writer
+            .write_section("t1", &[Kvpair::new("k1", 1.into())])
+            .await
+            .unwrap();
+        writer
+            .write_section("t1", &[Kvpair::new("k2", 2.into())])
+            .await
+            .unwrap();
+        let data = writer.finish().await.unwrap();
+
+        let store = MemTable::new();
+        restore_storage(&store, &data[..]).await.unwrap();
+        assert_eq!(store.get("t1", b"k1").unwrap(), Some(1.into()));
+        assert_eq!(store.get("t1", b"k2").unwrap(), Some(2.into()));
+    }
+
+    #[tokio::test]
+    async fn dump_reader_with_truncated_data_should_error() {
+        let store = MemTable::new();
+        prepare_store(&store);
+        let mut data = Vec::new();
+        dump_storage(&store, &mut data).await.unwrap();
+        data.truncate(data.len() - 1);
+
+        let result = restore_storage(&MemTable::new(), &data[..]).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn jsonl_export_import_should_work() {
+        let store = MemTable::new();
+        prepare_store(&store);
+        let mut data = Vec::new();
+        dump_storage(&store, &mut data).await.unwrap();
+
+        let mut jsonl = Vec::new();
+        let count = export_jsonl(&data[..], &mut jsonl).await.unwrap();
+        assert_eq!(count, 8);
+        assert!(String::from_utf8_lossy(&jsonl)
+            .contains(r#"{"table":"t2","key":"k2","type":"integer","value":42}"#));
+        assert!(String::from_utf8_lossy(&jsonl).contains(r#""key":"b\"\\xff\\x00:k\"""#));
+
+        let mut data = Vec::new();
+        let count = import_jsonl(Cursor::new(jsonl), &mut data).await.unwrap();
+        assert_eq!(count, 8);
+
+        let store1 = MemTable::new();
+        restore_storage(&store1, &data[..]).await.unwrap();
+        assert_store_eq(&store, &store1);
+    }
+
+    #[tokio::test]
+    async fn csv_export_import_should_work() {
+        let store = MemTable::new();
+        prepare_store(&store);
+        let mut data = Vec::new();
+        dump_storage(&store, &mut data).await.unwrap();
+
+        let mut csv = Vec::new();
+        let count = export_csv(&data[..], &mut csv).await.unwrap();
+        assert_eq!(count, 8);
+        let text = String::from_utf8_lossy(&csv);
+        assert!(text.starts_with("table,key,type,value\n"));
+        assert!(text.contains("t1,k3,binary,aGVsbG8=\n"));
+
+        let mut data = Vec::new();
+        let count = import_csv(Cursor::new(csv), &mut data).await.unwrap();
+        assert_eq!(count, 8);
+
+        let store1 = MemTable::new();
+        restore_storage(&store1, &data[..]).await.unwrap();
+        assert_store_eq(&store, &store1);
+    }
+
+    #[tokio::test]
+    async fn import_with_bad_value_should_error() {
+        let jsonl = r#"{"table":"t1","key":"k1","type":"integer","value":"abc"}"#;
+        let result = import_jsonl(Cursor::new(jsonl), Vec::new()).await;
+        assert!(matches!(result, Err(KvError::ConvertError(_, "Integer"))));
+
+        let csv = "table,key,type,value\nt1,k1,bool,maybe\n";
+        let result = import_csv(Cursor::new(csv), Vec::new()).await;
+        assert!(matches!(result, Err(KvError::ConvertError(_, "Boolean"))));
+    }
+
+    fn prepare_store(store: &impl Storage) {
+        let data: Vec<(&str, &'static [u8], Value)> = vec![
+            ("t1", b"k1", "v1".into()),
+            ("t1", b"k2", Value::default()),
+            ("t1", b"k3", Bytes::from_static(b"hello").into()),
+            ("t2", b"k1", 1.5.into()),
+            ("t2", b"k2", 42.into()),
+            ("t3", b"k1", true.into()),
+            // 二进制 key，以及看起来像二进制字面量的 string key
+            ("t3", b"\xff\x00:k", 1.into()),
+            ("t3", br#"b"k""#, 2.into()),
+        ];
+        for (table, key, value) in data {
+            store.set(table, key.into(), value).unwrap();
+        }
+    }
+
+    fn assert_store_eq(store1: &impl Storage, store2: &impl Storage) {
+        let mut tables1 = store1.get_tables().unwrap();
+        let mut tables2 = store2.get_tables().unwrap();
+        tables1.sort();
+        tables2.sort();
+        assert_eq!(tables1, tables2);
+
+        for table in tables1 {
+            let mut pairs1 = store1.get_all(&table).unwrap();
+            let mut pairs2 = store2.get_all(&table).unwrap();
+            pairs1.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            pairs2.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(pairs1, pairs2);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{net::TcpListener, time::Duration};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use kv6::{
+    run_workload, start_client_with_config, start_server_with_config, ClientConfig,
+    KeyDistribution, KvError, ListenerConfig, ServerConfig, StorageConfig, Transport, Workload,
+};
+use tokio::time;
+use tracing::{error, info};
+
+/// kv6 压测工具：向服务器（或者在本进程中启动的服务器）发送配置好的负载，
+/// 输出吞吐量和延迟分布
+#[derive(Parser, Debug)]
+#[clap(version = "0.1")]
+struct Opts {
+    /// 客户端配置文件，缺省时使用环境变量 KV_CLIENT_CONFIG，都没有时使用内置的配置
+    #[clap(short, long)]
+    config: Option<String>,
+    /// 服务器地址，覆盖配置文件中的地址
+    #[clap(long)]
+    addr: Option<String>,
+    /// 在本进程中启动一个使用 MemTable 和明文 TCP 的服务器，压测这个服务器
+    #[clap(long, conflicts_with_all = &["config", "addr"])]
+    embedded: bool,
+    /// 读写的 table
+    #[clap(long, default_value = "bench")]
+    table: String,
+    /// key 的数量
+    #[clap(short, long, default_value = "10000")]
+    keys: u64,
+    /// 选择 key 时使用的分布
+    #[clap(long, value_enum, default_value = "uniform")]
+    distribution: Distribution,
+    /// zipf 分布的指数，越大访问越集中
+    #[clap(long, default_value = "0.99")]
+    zipf_exponent: f64,
+    /// HGET 的比例
+    #[clap(short, long, default_value = "0.9")]
+    read_ratio: f64,
+    /// PUBLISH 的比例，剩下的请求是 HSET
+    #[clap(long, default_value = "0")]
+    publish_ratio: f64,
+    /// value 的大小，单位字节
+    #[clap(long, default_value = "64")]
+    value_size: usize,
+    /// value 的最大大小，设置后 value 的大小在 value_size 和它之间均匀分布
+    #[clap(long)]
+    max_value_size: Option<usize>,
+    /// 连接数
+    #[clap(short = 'C', long, default_value = "4")]
+    connections: usize,
+    /// 每个连接上同时等待 response 的请求数
+    #[clap(short, long, default_value = "16")]
+    pipeline: usize,
+    /// 订阅者的数量，每个订阅者使用单独的连接
+    #[clap(long, default_value = "0")]
+    subscribers: usize,
+    /// 请求总数，0 表示不限制
+    #[clap(short = 'n', long, default_value = "100000")]
+    requests: u64,
+    /// 最长运行时间，单位秒
+    #[clap(short, long)]
+    duration: Option<u64>,
+    /// 不预先写入 key
+    #[clap(long)]
+    no_prefill: bool,
+    /// 随机数种子
+    #[clap(long, default_value = "0")]
+    seed: u64,
+    /// 结果的输出格式
+    #[clap(short, long, value_enum, default_value = "text")]
+    format: Format,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Distribution {
+    Uniform,
+    Zipf,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let opts: Opts = Opts::parse();
+
+    let config = match opts.embedded {
+        true => start_embedded_server().await?,
+        false => {
+            let mut config = match ClientConfig::load_from(opts.config.as_deref())? {
+                Some(config) => config,
+                None => toml::from_str(include_str!("../fixtures/client.conf"))?,
+            };
+            if let Some(addr) = &opts.addr {
+                config.general.addr = addr.clone();
+            }
+            config
+        }
+    };
+
+    let workload = Workload {
+        table: opts.table,
+        keys: opts.keys,
+        distribution: match opts.distribution {
+            Distribution::Uniform => KeyDistribution::Uniform,
+            Distribution::Zipf => KeyDistribution::Zipf(opts.zipf_exponent),
+        },
+        read_ratio: opts.read_ratio,
+        publish_ratio: opts.publish_ratio,
+        value_size: opts.value_size..=opts.max_value_size.unwrap_or(opts.value_size),
+        connections: opts.connections,
+        pipeline: opts.pipeline,
+        subscribers: opts.subscribers,
+        requests: opts.requests,
+        duration: opts.duration.map(Duration::from_secs),
+        prefill: !opts.no_prefill,
+        seed: opts.seed,
+        ..Default::default()
+    };
+
+    info!("Run {:?} against {}", workload, config.general.addr);
+    let report = run_workload(&workload, || async {
+        let mut ctrl = start_client_with_config(&config)
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))?;
+        ctrl.open_stream().await
+    })
+    .await?;
+
+    match opts.format {
+        Format::Text => println!("{}", report),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+/// 在本进程中启动服务器，返回连接它使用的客户端配置
+async fn start_embedded_server() -> Result<ClientConfig> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.storage = StorageConfig::MemTable;
+    config.cluster = None;
+    config.listeners = vec![ListenerConfig {
+        transport: Transport::Tcp,
+        addr: addr.clone(),
+        allow_plaintext: false,
+    }];
+    tokio::spawn(async move {
+        if let Err(e) = start_server_with_config(&config).await {
+            error!("Embedded server failed: {:?}", e);
+        }
+    });
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr;
+    config.transport = Transport::Tcp;
+
+    // 等待服务器开始监听
+    for _ in 0..50 {
+        if start_client_with_config(&config).await.is_ok() {
+            return Ok(config);
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(anyhow!("embedded server is not ready"))
+}
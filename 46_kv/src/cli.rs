@@ -0,0 +1,643 @@
+use bytes::Bytes;
+use comfy_table::{presets::UTF8_FULL, Table};
+use std::{str, time::Duration};
+
+use crate::{value, CommandRequest, CommandResponse, KvError, Kvpair, Value};
+
+/// 支持的命令及其用法，用于命令补全和帮助
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("hget", "hget <table> <key>"),
+    ("hgetall", "hgetall <table>"),
+    ("hmget", "hmget <table> <key>..."),
+    ("hset", "hset <table> <key> <value>"),
+    ("hmset", "hmset <table> <key> <value> [<key> <value>]..."),
+    ("hdel", "hdel <table> <key>"),
+    ("hmdel", "hmdel <table> <key>..."),
+    ("hexist", "hexist <table> <key>"),
+    ("hmexist", "hmexist <table> <key>..."),
+    ("hexpire", "hexpire <table> <key> <ttl_ms>"),
+    ("subscribe", "subscribe <topic>"),
+    ("unsubscribe", "unsubscribe <topic> <id>"),
+    ("publish", "publish <topic> <value>..."),
+    ("dump", "dump [<table>]..."),
+    ("eval", "eval <script> [<arg>]..."),
+    ("evalsha", "evalsha <sha> [<arg>]..."),
+    ("script", "script flush"),
+    ("select", "select [<namespace>]"),
+    ("snapshot", "snapshot | snapshot release <version>"),
+    (
+        "asof",
+        "asof <version> <hget|hgetall|hmget|hexist|hmexist> ...",
+    ),
+    ("info", "info [<section>]"),
+    ("slowlog", "slowlog get [<count>] | slowlog reset"),
+    (
+        "cluster",
+        "cluster status | cluster add <id> <addr> | cluster remove <id>",
+    ),
+];
+
+/// 命令行中的一个参数
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// 没有引号的参数，作为 value 时会根据字面量推断类型
+    Word(String),
+    /// "..." 引起来的参数，永远是 string
+    Str(String),
+    /// b"..." 引起来的参数，永远是 binary；作为 key 时就是 key 的字节
+    Bytes(Vec<u8>),
+}
+
+/// 把一行命令拆分成 Token，支持 "..." 和 b"..."，引号内支持 \" \\ \n \t \xNN 转义
+pub fn tokenize(line: &str) -> Result<Vec<Token>, KvError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == '"' {
+                break;
+            }
+            word.push(c);
+            chars.next();
+        }
+
+        let token =
+            match chars.peek() {
+                Some('"') if word.is_empty() || word == "b" => {
+                    chars.next();
+                    let data = read_quoted(&mut chars)?;
+                    match word.is_empty() {
+                        true => Token::Str(String::from_utf8(data).map_err(|_| {
+                            KvError::InvalidCommand(format!("bad string: {}", line))
+                        })?),
+                        false => Token::Bytes(data),
+                    }
+                }
+                Some('"') => {
+                    return Err(KvError::InvalidCommand(format!(
+                        "unexpected quote after {}",
+                        word
+                    )))
+                }
+                _ => Token::Word(word),
+            };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// 读取引号内的内容，直到遇到结尾的引号
+fn read_quoted(chars: &mut impl Iterator<Item = char>) -> Result<Vec<u8>, KvError> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4];
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(data),
+            Some('\\') => match chars.next() {
+                Some('n') => data.push(b'\n'),
+                Some('t') => data.push(b'\t'),
+                Some('r') => data.push(b'\r'),
+                Some('x') => {
+                    let hex: String = chars.take(2).collect();
+                    let byte = u8::from_str_radix(&hex, 16)
+                        .map_err(|_| KvError::InvalidCommand(format!("bad escape: \\x{}", hex)))?;
+                    data.push(byte);
+                }
+                Some(c) => data.extend_from_slice(c.encode_utf8(&mut buf).as_bytes()),
+                None => break,
+            },
+            Some(c) => data.extend_from_slice(c.encode_utf8(&mut buf).as_bytes()),
+            None => break,
+        }
+    }
+    Err(KvError::InvalidCommand("unterminated quote".into()))
+}
+
+/// 把一个字面量转换成 Value：
+/// 42 是 integer，4.2、inf、-inf、NaN 是 float，true/false 是 bool，nil 是空值，
+/// 0x 开头的是 binary，其它的是 string；如果想要字符串 "42"，请使用引号
+pub fn parse_value(token: &Token) -> Result<Value, KvError> {
+    let word = match token {
+        Token::Str(s) => return Ok(s.as_str().into()),
+        Token::Bytes(b) => return Ok(Bytes::from(b.clone()).into()),
+        Token::Word(w) => w.as_str(),
+    };
+
+    if let Ok(i) = word.parse::<i64>() {
+        return Ok(i.into());
+    }
+    if let Ok(f) = word.parse::<f64>() {
+        if word.contains(|c: char| c.is_ascii_digit()) {
+            return Ok(f.into());
+        }
+    }
+
+    match word {
+        "inf" => Ok(f64::INFINITY.into()),
+        "-inf" => Ok(f64::NEG_INFINITY.into()),
+        "NaN" => Ok(f64::NAN.into()),
+        "true" => Ok(true.into()),
+        "false" => Ok(false.into()),
+        "nil" => Ok(Value::default()),
+        _ => match word.strip_prefix("0x") {
+            Some(hex) => parse_hex(hex).map(|v| Bytes::from(v).into()),
+            None => Ok(word.into()),
+        },
+    }
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, KvError> {
+    let err = || KvError::InvalidCommand(format!("bad hex literal: 0x{}", hex));
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|s| s.len() == 2)
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                .ok_or_else(err)
+        })
+        .collect()
+}
+
+/// 把一行命令解析成 CommandRequest
+pub fn parse_command(line: &str) -> Result<CommandRequest, KvError> {
+    parse_tokens(tokenize(line)?)
+}
+
+/// 把已经拆分好的参数解析成 CommandRequest
+pub fn parse_tokens(tokens: Vec<Token>) -> Result<CommandRequest, KvError> {
+    let mut iter = tokens.into_iter();
+    let name = match iter.next() {
+        Some(token) => to_string(token)?.to_lowercase(),
+        None => return Err(KvError::InvalidCommand("empty command".into())),
+    };
+    let args: Vec<Token> = iter.collect();
+
+    let usage = match COMMANDS.iter().find(|(n, _)| *n == name) {
+        Some((_, usage)) => *usage,
+        None => return Err(KvError::InvalidCommand(format!("unknown command {}", name))),
+    };
+    let err = || KvError::InvalidCommand(format!("usage: {}", usage));
+
+    let strings = |args: Vec<Token>| {
+        args.into_iter()
+            .map(to_string)
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let keys = |args: &[Token]| args.iter().map(key_of).collect::<Vec<_>>();
+    let values = |args: &[Token]| args.iter().map(parse_value).collect::<Result<Vec<_>, _>>();
+
+    let cmd = match (name.as_str(), args.as_slice()) {
+        ("hget", [t, k]) => CommandRequest::new_hget(str_of(t)?, key_of(k)),
+        ("hgetall", [t]) => CommandRequest::new_hgetall(str_of(t)?),
+        ("hmget", [t, _, ..]) => CommandRequest::new_hmget(str_of(t)?, keys(&args[1..])),
+        ("hset", [t, k, v]) => CommandRequest::new_hset(str_of(t)?, key_of(k), parse_value(v)?),
+        ("hmset", [t, rest @ ..]) if !rest.is_empty() && rest.len() % 2 == 0 => {
+            let pairs = rest
+                .chunks(2)
+                .map(|kv| Ok(Kvpair::new(key_of(&kv[0]), parse_value(&kv[1])?)))
+                .collect::<Result<Vec<_>, KvError>>()?;
+            CommandRequest::new_hmset(str_of(t)?, pairs)
+        }
+        ("hdel", [t, k]) => CommandRequest::new_hdel(str_of(t)?, key_of(k)),
+        ("hmdel", [t, _, ..]) => CommandRequest::new_hmdel(str_of(t)?, keys(&args[1..])),
+        ("hexist", [t, k]) => CommandRequest::new_hexist(str_of(t)?, key_of(k)),
+        ("hmexist", [t, _, ..]) => CommandRequest::new_hmexist(str_of(t)?, keys(&args[1..])),
+        ("hexpire", [t, k, ttl]) => {
+            let ttl = str_of(ttl)?.parse::<u64>().map_err(|_| err())?;
+            CommandRequest::new_hexpire(str_of(t)?, key_of(k), Duration::from_millis(ttl))
+        }
+        ("subscribe", [topic]) => CommandRequest::new_subscribe(str_of(topic)?),
+        ("unsubscribe", [topic, id]) => {
+            let id = str_of(id)?.parse::<u32>().map_err(|_| err())?;
+            CommandRequest::new_unsubscribe(str_of(topic)?, id)
+        }
+        ("publish", [topic, _, ..]) => {
+            CommandRequest::new_publish(str_of(topic)?, values(&args[1..])?)
+        }
+        ("dump", _) => CommandRequest::new_dump(strings(args)?),
+        ("eval", [script, ..]) => CommandRequest::new_eval(str_of(script)?, values(&args[1..])?),
+        ("evalsha", [sha, ..]) => CommandRequest::new_evalsha(str_of(sha)?, values(&args[1..])?),
+        ("script", [sub]) if str_of(sub)?.eq_ignore_ascii_case("flush") => {
+            CommandRequest::new_script_flush()
+        }
+        ("select", []) => CommandRequest::new_select(""),
+        ("select", [namespace]) => CommandRequest::new_select(str_of(namespace)?),
+        ("snapshot", []) => CommandRequest::new_snapshot(),
+        ("snapshot", [sub, version]) if str_of(sub)?.eq_ignore_ascii_case("release") => {
+            let version = str_of(version)?.parse::<u64>().map_err(|_| err())?;
+            CommandRequest::new_snapshot_release(version)
+        }
+        ("asof", [version, _, ..]) => {
+            let version = str_of(version)?.parse::<u64>().map_err(|_| err())?;
+            parse_tokens(args[1..].to_vec())?.with_as_of(version)
+        }
+        ("info", []) => CommandRequest::new_info(""),
+        ("info", [section]) => CommandRequest::new_info(str_of(section)?),
+        ("slowlog", [sub, rest @ ..]) => match (str_of(sub)?.to_lowercase().as_str(), rest) {
+            ("get", []) => CommandRequest::new_slowlog_get(0),
+            ("get", [count]) => {
+                let count = str_of(count)?.parse::<u32>().map_err(|_| err())?;
+                CommandRequest::new_slowlog_get(count)
+            }
+            ("reset", []) => CommandRequest::new_slowlog_reset(),
+            _ => return Err(err()),
+        },
+        ("cluster", [sub, rest @ ..]) => {
+            let id = |t: &Token| str_of(t)?.parse::<u64>().map_err(|_| err());
+            match (str_of(sub)?.to_lowercase().as_str(), rest) {
+                ("status", []) => CommandRequest::new_cluster_status(),
+                ("add", [node, addr]) => CommandRequest::new_cluster_add(id(node)?, str_of(addr)?),
+                ("remove", [node]) => CommandRequest::new_cluster_remove(id(node)?),
+                _ => return Err(err()),
+            }
+        }
+        _ => return Err(err()),
+    };
+
+    Ok(cmd)
+}
+
+fn str_of(token: &Token) -> Result<String, KvError> {
+    to_string(token.clone())
+}
+
+fn to_string(token: Token) -> Result<String, KvError> {
+    match token {
+        Token::Word(s) | Token::Str(s) => Ok(s),
+        Token::Bytes(_) => Err(KvError::InvalidCommand(
+            "binary literal can only be used as key or value".into(),
+        )),
+    }
+}
+
+/// key 可以是任意字节，b"..." 给出的就是 key 的字节，其它的使用 string 的 UTF-8 编码
+fn key_of(token: &Token) -> Bytes {
+    match token {
+        Token::Word(s) | Token::Str(s) => Bytes::copy_from_slice(s.as_bytes()),
+        Token::Bytes(b) => Bytes::copy_from_slice(b),
+    }
+}
+
+/// 把 key 格式化成便于阅读的字面量：合法的 UTF-8 输出 "..."，否则输出 b"..."，
+/// 这样输出的 key 可以直接拷贝到命令中使用
+pub fn format_key(key: &[u8]) -> String {
+    match str::from_utf8(key) {
+        Ok(s) => format_str(s),
+        Err(_) => format_bytes(key),
+    }
+}
+
+/// 把 Value 格式化成命令行中的字面量，这样输出的结果可以直接拷贝到命令中使用
+pub fn format_value(v: &Value) -> String {
+    match &v.value {
+        None => "nil".into(),
+        Some(value::Value::String(s)) => format_str(s),
+        Some(value::Value::Binary(b)) => format_bytes(b),
+        Some(value::Value::Integer(i)) => i.to_string(),
+        Some(value::Value::Float(f)) => format_float(*f),
+        Some(value::Value::Bool(b)) => b.to_string(),
+    }
+}
+
+/// inf、-inf、NaN 使用和 parse_value 约定的写法，其它的 float 总是带小数点
+fn format_float(f: f64) -> String {
+    match f {
+        f if f.is_nan() => "NaN".into(),
+        f if f.is_infinite() && f > 0.0 => "inf".into(),
+        f if f.is_infinite() => "-inf".into(),
+        f => format!("{:?}", f),
+    }
+}
+
+/// 字符串总是加上引号，这样 "42"、"true" 这样的字符串不会被当成其它类型
+fn format_str(s: &str) -> String {
+    let s: String = s
+        .chars()
+        .map(|c| match c {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            '\r' => "\\r".to_string(),
+            c if c.is_ascii_control() => format!("\\x{:02x}", c as u8),
+            c => c.to_string(),
+        })
+        .collect();
+    format!("\"{}\"", s)
+}
+
+fn format_bytes(data: &[u8]) -> String {
+    let s: String = data
+        .iter()
+        .map(|&c| match c {
+            b'"' => "\\\"".to_string(),
+            b'\\' => "\\\\".to_string(),
+            0x20..=0x7e => (c as char).to_string(),
+            _ => format!("\\x{:02x}", c),
+        })
+        .collect();
+    format!("b\"{}\"", s)
+}
+
+/// Value 的类型名
+pub fn value_type(v: &Value) -> &'static str {
+    match &v.value {
+        None => "nil",
+        Some(value::Value::String(_)) => "string",
+        Some(value::Value::Binary(_)) => "binary",
+        Some(value::Value::Integer(_)) => "integer",
+        Some(value::Value::Float(_)) => "float",
+        Some(value::Value::Bool(_)) => "bool",
+    }
+}
+
+/// 把 CommandResponse 格式化成表格
+pub fn format_response(res: &CommandResponse) -> String {
+    if !(200..300).contains(&res.status) {
+        return format!("(error {}) {}", res.status, res.message);
+    }
+
+    let mut output = Vec::new();
+    if !res.values.is_empty() {
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .set_header(vec!["#", "value", "type"]);
+        for (i, v) in res.values.iter().enumerate() {
+            table.add_row(vec![
+                (i + 1).to_string(),
+                format_value(v),
+                value_type(v).into(),
+            ]);
+        }
+        output.push(table.to_string());
+    }
+
+    if !res.pairs.is_empty() {
+        let mut pairs = res.pairs.clone();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .set_header(vec!["key", "value", "type"]);
+        for pair in pairs {
+            let v = pair.value.unwrap_or_default();
+            table.add_row(vec![
+                format_key(&pair.key),
+                format_value(&v),
+                value_type(&v).into(),
+            ]);
+        }
+        output.push(table.to_string());
+    }
+
+    if !res.slowlog.is_empty() {
+        let mut table = Table::new();
+        table.load_preset(UTF8_FULL).set_header(vec![
+            "id",
+            "timestamp",
+            "duration (us)",
+            "client",
+            "command",
+        ]);
+        for entry in &res.slowlog {
+            table.add_row(vec![
+                entry.id.to_string(),
+                entry.timestamp.to_string(),
+                entry.duration_us.to_string(),
+                entry.client.clone(),
+                entry.command.clone(),
+            ]);
+        }
+        output.push(table.to_string());
+    }
+
+    if output.is_empty() {
+        return "OK".into();
+    }
+
+    output.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SlowlogEntry;
+
+    #[test]
+    fn tokenize_should_work() {
+        let tokens = tokenize(r#"hset t1  "hello world" b"a\x00\"" 42"#).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("hset".into()),
+                Token::Word("t1".into()),
+                Token::Str("hello world".into()),
+                Token::Bytes(vec![b'a', 0, b'"']),
+                Token::Word("42".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_with_bad_quote_should_fail() {
+        assert!(tokenize(r#"hset t1 k1 "hello"#).is_err());
+        assert!(tokenize(r#"hset t1 k1 abc"hello""#).is_err());
+    }
+
+    #[test]
+    fn parse_value_should_infer_type() {
+        let v = |s: &str| parse_value(&Token::Word(s.into())).unwrap();
+        assert_eq!(v("42"), 42.into());
+        assert_eq!(v("-42"), (-42).into());
+        assert_eq!(v("4.2"), 4.2.into());
+        assert_eq!(v("true"), true.into());
+        assert_eq!(v("nil"), Value::default());
+        assert_eq!(v("0x6869"), Bytes::from_static(b"hi").into());
+        assert_eq!(v("hello"), "hello".into());
+        assert_eq!(v("inf"), f64::INFINITY.into());
+        assert_eq!(v("-inf"), f64::NEG_INFINITY.into());
+        assert_eq!(v("infinity"), "infinity".into());
+        assert_eq!(v("nan"), "nan".into());
+
+        let v = parse_value(&Token::Str("42".into())).unwrap();
+        assert_eq!(v, "42".into());
+        assert!(parse_value(&Token::Word("0x123".into())).is_err());
+    }
+
+    #[test]
+    fn parse_command_should_work() {
+        let cmd = parse_command("HSET t1 k1 42").unwrap();
+        assert_eq!(cmd, CommandRequest::new_hset("t1", "k1", 42.into()));
+
+        let cmd = parse_command(r#"hmset t1 k1 "v1" k2 b"v2""#).unwrap();
+        let pairs = vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", Bytes::from_static(b"v2").into()),
+        ];
+        assert_eq!(cmd, CommandRequest::new_hmset("t1", pairs));
+
+        let cmd = parse_command(r#"hset t1 b"\x00\xffk1" 42"#).unwrap();
+        let key = Bytes::from_static(b"\x00\xffk1");
+        assert_eq!(cmd, CommandRequest::new_hset("t1", key, 42.into()));
+
+        let cmd = parse_command("hmget t1 k1 k2").unwrap();
+        assert_eq!(
+            cmd,
+            CommandRequest::new_hmget("t1", vec!["k1".into(), "k2".into()])
+        );
+
+        let cmd = parse_command("hexpire t1 k1 1500").unwrap();
+        let ttl = Duration::from_millis(1500);
+        assert_eq!(cmd, CommandRequest::new_hexpire("t1", "k1", ttl));
+
+        let cmd = parse_command("unsubscribe lobby 3").unwrap();
+        assert_eq!(cmd, CommandRequest::new_unsubscribe("lobby", 3));
+
+        let cmd = parse_command("publish lobby 1 hello").unwrap();
+        let values = vec![1.into(), "hello".into()];
+        assert_eq!(cmd, CommandRequest::new_publish("lobby", values));
+
+        let cmd = parse_command("dump").unwrap();
+        assert_eq!(cmd, CommandRequest::new_dump(vec![]));
+
+        let cmd = parse_command(r#"eval "ARGS[0] + 1" 41"#).unwrap();
+        assert_eq!(
+            cmd,
+            CommandRequest::new_eval("ARGS[0] + 1", vec![41.into()])
+        );
+
+        let cmd = parse_command("select tenant-a").unwrap();
+        assert_eq!(cmd, CommandRequest::new_select("tenant-a"));
+
+        let cmd = parse_command("snapshot release 42").unwrap();
+        assert_eq!(cmd, CommandRequest::new_snapshot_release(42));
+
+        let cmd = parse_command("asof 42 hget t1 k1").unwrap();
+        assert_eq!(cmd, CommandRequest::new_hget("t1", "k1").with_as_of(42));
+
+        let cmd = parse_command("info clients").unwrap();
+        assert_eq!(cmd, CommandRequest::new_info("clients"));
+
+        let cmd = parse_command("slowlog get 10").unwrap();
+        assert_eq!(cmd, CommandRequest::new_slowlog_get(10));
+
+        let cmd = parse_command("SLOWLOG reset").unwrap();
+        assert_eq!(cmd, CommandRequest::new_slowlog_reset());
+
+        let cmd = parse_command("script flush").unwrap();
+        assert_eq!(cmd, CommandRequest::new_script_flush());
+
+        let cmd = parse_command("cluster add 4 127.0.0.1:9547").unwrap();
+        assert_eq!(cmd, CommandRequest::new_cluster_add(4, "127.0.0.1:9547"));
+
+        let cmd = parse_command("cluster remove 4").unwrap();
+        assert_eq!(cmd, CommandRequest::new_cluster_remove(4));
+    }
+
+    #[test]
+    fn parse_command_with_bad_args_should_fail() {
+        assert!(parse_command("").is_err());
+        assert!(parse_command("hello t1").is_err());
+        assert!(parse_command("hget t1").is_err());
+        assert!(parse_command("hmset t1 k1").is_err());
+        assert!(parse_command("unsubscribe lobby abc").is_err());
+        assert!(parse_command("hexpire t1 k1 -1").is_err());
+        assert!(parse_command(r#"hget b"t1" k1"#).is_err());
+        assert!(parse_command("slowlog").is_err());
+        assert!(parse_command("script load 1").is_err());
+        assert!(parse_command("asof abc hget t1 k1").is_err());
+        assert!(parse_command("slowlog get abc").is_err());
+        assert!(parse_command("cluster add abc 127.0.0.1:9547").is_err());
+    }
+
+    #[test]
+    fn format_value_should_roundtrip() {
+        let values: Vec<Value> = vec![
+            42.into(),
+            4.0.into(),
+            f64::INFINITY.into(),
+            f64::NEG_INFINITY.into(),
+            true.into(),
+            Value::default(),
+            Bytes::from_static(b"a\x00\"b").into(),
+            "hello world".into(),
+            "42".into(),
+            "4.2".into(),
+            "true".into(),
+            "nil".into(),
+            "0x12".into(),
+            "".into(),
+            "say \"hi\"\\".into(),
+            "line\nbreak\ttab\x01".into(),
+            "中文".into(),
+            "inf".into(),
+        ];
+        for v in values {
+            let s = format_value(&v);
+            let token = tokenize(&s).unwrap().remove(0);
+            assert_eq!(parse_value(&token).unwrap(), v);
+        }
+
+        // NaN 不等于自身，只能检查类型
+        let token = tokenize(&format_value(&f64::NAN.into())).unwrap().remove(0);
+        match parse_value(&token).unwrap().value {
+            Some(value::Value::Float(f)) => assert!(f.is_nan()),
+            v => panic!("expect NaN, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn format_key_should_roundtrip() {
+        assert_eq!(format_key(b"hello"), r#""hello""#);
+        let key = b"\x01\xfe:\"k\\";
+        let s = format_key(key);
+        assert_eq!(s, r#"b"\x01\xfe:\"k\\""#);
+
+        let keys: Vec<&[u8]> = vec![
+            key,
+            b"hello",
+            b"hello world",
+            b"say \"hi\"",
+            br#"b"x""#,
+            b"",
+            b"42",
+        ];
+        for key in keys {
+            let tokens = tokenize(&format_key(key)).unwrap();
+            assert_eq!(tokens.len(), 1);
+            assert_eq!(key_of(&tokens[0]), key);
+        }
+    }
+
+    #[test]
+    fn format_response_should_work() {
+        let res: CommandResponse = vec![Kvpair::new("k1", 42.into())].into();
+        let output = format_response(&res);
+        assert!(output.contains("k1"));
+        assert!(output.contains("integer"));
+
+        let res: CommandResponse = KvError::NotFound("t1 k1".into()).into();
+        assert_eq!(format_response(&res), "(error 404) Not found: t1 k1");
+
+        assert_eq!(format_response(&CommandResponse::ok()), "OK");
+
+        let entry = SlowlogEntry {
+            client: "127.0.0.1:1234".into(),
+            ..Default::default()
+        };
+        let output = format_response(&vec![entry].into());
+        assert!(output.contains("127.0.0.1:1234"));
+    }
+}
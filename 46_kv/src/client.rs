@@ -1,72 +1,296 @@
-use std::time::Duration;
+use std::{borrow::Cow, convert::TryFrom, env, process};
 
 use anyhow::Result;
+use clap::Parser;
 use futures::StreamExt;
-use kv6::{start_client_with_config, ClientConfig, CommandRequest, KvError, ProstClientStream};
-use tokio::time;
+use kv6::{
+    format_response, parse_tokens, start_client_with_config, tokenize, BoxedStream, ClientConfig,
+    CommandRequest, ProstClientStream, Token, YamuxCtrl, COMMANDS,
+};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+use tokio::task;
 use tokio_util::compat::Compat;
-use tracing::info;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// kv6 命令行客户端，不带命令时进入交互模式
+#[derive(Parser, Debug)]
+#[clap(version = "0.1")]
+struct Opts {
+    /// 客户端配置文件，缺省时使用环境变量 KV_CLIENT_CONFIG
+    #[clap(short, long)]
+    config: Option<String>,
+    /// 直接执行的命令，例如：kvc hset t1 k1 42
+    #[clap(allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+type Ctrl = YamuxCtrl<BoxedStream>;
+type ClientStream = ProstClientStream<Compat<yamux::Stream>>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    let opts: Opts = Opts::parse();
+
+    let config = match ClientConfig::load_from(opts.config.as_deref())? {
+        Some(config) => config,
+        None => toml::from_str(include_str!("../fixtures/client.conf"))?,
+    };
+
+    // 配置了 trace 时，请求会带上 trace context，服务器的 span 会挂在客户端的 span 下面
+    let opentelemetry = match &config.trace {
+        Some(trace) => trace.tracer("kv-client")?,
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(fmt::layer())
+        .with(opentelemetry.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .init();
 
     // 打开一个 yamux ctrl
     let mut ctrl = start_client_with_config(&config).await?;
 
-    let channel = "lobby";
-    start_publishing(ctrl.open_stream().await?, channel)?;
+    if opts.command.is_empty() {
+        repl(&mut ctrl, &config.general.addr, config.namespace.as_deref()).await
+    } else {
+        // 命令行参数已经被 shell 拆分好了，这里只需要处理每个参数中的引号
+        let tokens = opts
+            .command
+            .into_iter()
+            .map(|arg| match tokenize(&arg) {
+                Ok(mut tokens) if tokens.len() == 1 => tokens.remove(0),
+                _ => Token::Word(arg),
+            })
+            .collect();
+        let cmd = parse_tokens(tokens)?;
+        let stream = ctrl.open_stream().await?;
+        if !execute(stream, cmd, false).await? {
+            process::exit(1);
+        }
+        Ok(())
+    }
+}
+
+/// 交互模式
+async fn repl(ctrl: &mut Ctrl, addr: &str, namespace: Option<&str>) -> Result<()> {
+    use kv6::command_request::RequestData;
+
+    let history = env::var("HOME")
+        .map(|home| format!("{}/.kvc_history", home))
+        .ok();
+
+    let mut rl = Editor::<KvHelper>::new();
+    rl.set_helper(Some(KvHelper));
+    if let Some(path) = &history {
+        let _ = rl.load_history(path);
+    }
 
+    println!(
+        "Connected to {}. Type `help` for commands, `quit` to exit.",
+        addr
+    );
+    let mut prompt = prompt_of(addr, namespace);
     let mut stream = ctrl.open_stream().await?;
 
-    // 生成一个 HSET 命令
-    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+    loop {
+        // rustyline 的 readline 是阻塞的，不能直接在 tokio 的 worker 上运行
+        let line = match task::block_in_place(|| rl.readline(&prompt)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        rl.add_history_entry(line);
+
+        match line {
+            "quit" | "exit" => break,
+            "help" => {
+                for (_, usage) in COMMANDS {
+                    println!("  {}", usage);
+                }
+                continue;
+            }
+            _ => {}
+        }
 
-    // 发送 HSET 命令
-    let data = stream.execute_unary(&cmd).await?;
-    info!("Got response {:?}", data);
+        let cmd = match tokenize(line).and_then(parse_tokens) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                println!("(error) {}", e);
+                continue;
+            }
+        };
 
-    // 生成一个 Subscribe 命令
-    let cmd = CommandRequest::new_subscribe(channel);
-    let mut stream = stream.execute_streaming(&cmd).await?;
-    let id = stream.id;
-    start_unsubscribe(ctrl.open_stream().await?, channel, id)?;
+        // namespace 对之后打开的 stream 同样生效
+        if let Some(RequestData::Select(select)) = &cmd.request_data {
+            match stream.select(&select.namespace).await {
+                Ok(_) => {
+                    let namespace = Some(select.namespace.clone()).filter(|ns| !ns.is_empty());
+                    prompt = prompt_of(addr, namespace.as_deref());
+                    ctrl.set_namespace(namespace);
+                    println!("OK");
+                }
+                Err(e) => println!("(error) {}", e),
+            }
+            continue;
+        }
 
-    while let Some(Ok(data)) = stream.next().await {
-        println!("Got published data: {:?}", data);
+        // 订阅和 dump 会占用整个 stream，所以给它们单独打开一个
+        let result = match is_streaming(&cmd) {
+            true => match ctrl.open_stream().await {
+                Ok(s) => execute(s, cmd, true).await,
+                Err(e) => Err(e.into()),
+            },
+            false => execute_unary(&mut stream, &cmd).await,
+        };
+
+        if let Err(e) = result {
+            println!("(error) {}", e);
+            // 连接可能已经出问题了，重新打开一个 stream
+            stream = ctrl.open_stream().await?;
+        }
     }
 
-    println!("Done!");
+    if let Some(path) = &history {
+        let _ = rl.save_history(path);
+    }
 
     Ok(())
 }
 
-fn start_publishing(
-    mut stream: ProstClientStream<Compat<yamux::Stream>>,
-    name: &str,
-) -> Result<(), KvError> {
-    let cmd = CommandRequest::new_publish(name, vec![1.into(), 2.into(), "hello".into()]);
-    tokio::spawn(async move {
-        time::sleep(Duration::from_millis(1000)).await;
-        let res = stream.execute_unary(&cmd).await.unwrap();
-        println!("Finished publishing: {:?}", res);
-    });
+/// 提示符中显示当前的 namespace
+fn prompt_of(addr: &str, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(ns) => format!("{}[{}]> ", addr, ns),
+        None => format!("{}> ", addr),
+    }
+}
 
-    Ok(())
+fn is_streaming(cmd: &CommandRequest) -> bool {
+    use kv6::command_request::RequestData;
+    matches!(
+        cmd.request_data,
+        Some(RequestData::Subscribe(_)) | Some(RequestData::Dump(_))
+    )
 }
 
-fn start_unsubscribe(
-    mut stream: ProstClientStream<Compat<yamux::Stream>>,
-    name: &str,
-    id: u32,
-) -> Result<(), KvError> {
-    let cmd = CommandRequest::new_unsubscribe(name, id as _);
-    tokio::spawn(async move {
-        time::sleep(Duration::from_millis(2000)).await;
-        let res = stream.execute_unary(&cmd).await.unwrap();
-        println!("Finished unsubscribing: {:?}", res);
-    });
+/// 执行一个命令并打印结果，返回命令是否成功。
+/// background 为 true 时，订阅的数据会在后台打印，不阻塞交互
+async fn execute(mut stream: ClientStream, cmd: CommandRequest, background: bool) -> Result<bool> {
+    use kv6::command_request::RequestData;
 
-    Ok(())
+    match &cmd.request_data {
+        Some(RequestData::Subscribe(sub)) => {
+            let topic = sub.topic.clone();
+            let mut stream = stream.execute_streaming(&cmd).await?;
+            println!("Subscribed to {} with id {}", topic, stream.id);
+
+            let fut = async move {
+                while let Some(Ok(data)) = stream.next().await {
+                    println!("[{}]\n{}", topic, format_response(&data));
+                }
+            };
+            match background {
+                true => drop(tokio::spawn(fut)),
+                false => fut.await,
+            }
+            Ok(true)
+        }
+        Some(RequestData::Dump(_)) => {
+            let mut stream = stream.execute_dump(&cmd).await?;
+            let mut ok = true;
+            while let Some(data) = stream.next().await {
+                let data = data?;
+                ok &= (200..300).contains(&data.status);
+                if let Some(Ok(table)) = data.values.first().cloned().map(String::try_from) {
+                    println!("[{}]", table);
+                }
+                let pairs = data.pairs.into();
+                println!("{}", format_response(&pairs));
+            }
+            Ok(ok)
+        }
+        _ => execute_unary(&mut stream, &cmd).await,
+    }
+}
+
+async fn execute_unary(stream: &mut ClientStream, cmd: &CommandRequest) -> Result<bool> {
+    let res = stream.execute_unary(cmd).await?;
+    println!("{}", format_response(&res));
+    Ok((200..300).contains(&res.status))
+}
+
+/// 为 rustyline 提供命令补全和参数提示
+struct KvHelper;
+
+impl Helper for KvHelper {}
+impl Validator for KvHelper {}
+
+impl Highlighter for KvHelper {
+    /// 提示信息使用灰色显示
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[2m{}\x1b[m", hint))
+    }
+}
+
+impl Completer for KvHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // 只补全第一个单词，也就是命令名
+        let prefix = &line[..pos];
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, vec![]));
+        }
+
+        let prefix = prefix.to_lowercase();
+        let candidates = COMMANDS
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(["help", "quit"])
+            .filter(|name| name.starts_with(&prefix))
+            .map(|name| Pair {
+                display: name.into(),
+                replacement: format!("{} ", name),
+            })
+            .collect();
+
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for KvHelper {
+    type Hint = String;
+
+    /// 输入命令后，提示还需要输入的参数
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() || !line.ends_with(' ') {
+            return None;
+        }
+
+        let words: Vec<_> = line.split_whitespace().collect();
+        let name = words.first()?.to_lowercase();
+        let (_, usage) = COMMANDS.iter().find(|(n, _)| *n == name)?;
+        let rest: Vec<_> = usage.split_whitespace().skip(words.len()).collect();
+        match rest.is_empty() {
+            true => None,
+            false => Some(rest.join(" ")),
+        }
+    }
 }
@@ -0,0 +1,9 @@
+mod node;
+mod raft;
+mod storage;
+mod transport;
+
+pub use node::ClusterNode;
+pub use raft::{Raft, Ready, Role};
+pub use storage::RAFT_TABLE;
+pub use transport::{NetworkTransport, RaftTransport};
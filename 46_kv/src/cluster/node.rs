@@ -0,0 +1,642 @@
+use futures::{future, StreamExt};
+use prost::Message;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    time,
+};
+use tracing::{error, warn};
+
+use super::storage::{RaftStorage, RAFT_TABLE};
+use crate::{
+    command_request::RequestData, dispatch, once_response, AsyncStorage, ClusterConfig,
+    CommandRequest, CommandResponse, ConfChange, ConfChangeType, KvError, Kvpair, Next, Raft,
+    RaftEntry, RaftEntryType, RaftMessage, RaftTransport, Role, StreamingResponse, Value,
+};
+
+/// 等待处理的请求最多有多少个
+const MAX_QUEUED: usize = 1024;
+
+type Reply = oneshot::Sender<CommandResponse>;
+
+/// 集群中的一个节点。后台 task 驱动 Raft，把提交的命令应用到 Storage；
+/// 这个结构只是发给后台 task 的句柄，可以 clone
+#[derive(Clone)]
+pub struct ClusterNode {
+    tx: mpsc::Sender<Request>,
+    applied: watch::Receiver<u64>,
+}
+
+enum Request {
+    Step(RaftMessage),
+    Propose(CommandRequest, Reply),
+    ConfChange(ConfChange, Reply),
+    ReadIndex(oneshot::Sender<Result<u64, KvError>>),
+    Status(oneshot::Sender<Vec<Kvpair>>),
+}
+
+struct Driver<Store, T> {
+    raft: Raft,
+    store: Arc<Store>,
+    storage: RaftStorage<Store>,
+    transport: T,
+    /// 节点的地址，包括配置中的 peers，加入集群时用它联系 leader
+    addrs: BTreeMap<u64, String>,
+    rx: mpsc::Receiver<Request>,
+    applied: watch::Sender<u64>,
+    /// 等待提交的写入：index 到 (term, reply)
+    proposals: BTreeMap<u64, (u64, Reply)>,
+    reads: BTreeMap<u64, oneshot::Sender<Result<u64, KvError>>>,
+    next_context: u64,
+    tick: Duration,
+    snapshot_threshold: u64,
+}
+
+impl ClusterNode {
+    /// 从 Storage 中恢复 Raft 的状态，没有状态时按配置创建新集群或者等待加入集群，
+    /// 然后在后台运行节点
+    pub async fn start<Store, T>(
+        config: &ClusterConfig,
+        store: Arc<Store>,
+        transport: T,
+    ) -> Result<Self, KvError>
+    where
+        Store: AsyncStorage,
+        T: RaftTransport,
+    {
+        if config.id == 0 {
+            return Err(KvError::InvalidCommand("cluster id must not be 0".into()));
+        }
+        let (election, heartbeat) = (config.election_ticks, config.heartbeat_ticks);
+        let mut storage = RaftStorage::new(Arc::clone(&store));
+        let raft = match storage.load().await? {
+            Some(state) => Raft::restore(
+                config.id,
+                election,
+                heartbeat,
+                state.hard_state,
+                &state.snapshot,
+                state.entries,
+            ),
+            None if config.join => Raft::new(config.id, election, heartbeat),
+            None => {
+                let peers: Vec<_> = config
+                    .peers
+                    .iter()
+                    .map(|p| (p.id, p.addr.clone()))
+                    .collect();
+                if !peers.iter().any(|(id, _)| *id == config.id) {
+                    let msg = "peers must contain the node itself";
+                    return Err(KvError::InvalidCommand(msg.into()));
+                }
+                Raft::bootstrap(config.id, election, heartbeat, &peers)
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(MAX_QUEUED);
+        let (applied_tx, applied_rx) = watch::channel(0);
+        let driver = Driver {
+            raft,
+            store,
+            storage,
+            transport,
+            addrs: config
+                .peers
+                .iter()
+                .map(|p| (p.id, p.addr.clone()))
+                .collect(),
+            rx,
+            applied: applied_tx,
+            proposals: BTreeMap::new(),
+            reads: BTreeMap::new(),
+            next_context: 1,
+            tick: Duration::from_millis(config.tick_ms.max(1)),
+            snapshot_threshold: config.snapshot_threshold.max(1),
+        };
+        tokio::spawn(driver.run());
+
+        Ok(Self {
+            tx,
+            applied: applied_rx,
+        })
+    }
+
+    /// 作为 Service 的中间件：写入通过 Raft 提交，读取之前确认自己仍然是 leader，
+    /// 并处理集群内部和管理集群的命令
+    pub async fn handle(self, cmd: CommandRequest, next: Next) -> StreamingResponse {
+        if uses_raft_table(&cmd) {
+            let msg = format!("table {} is reserved", RAFT_TABLE);
+            return once_response(KvError::InvalidCommand(msg).into());
+        }
+
+        let res = match cmd.request_data {
+            Some(RequestData::Raft(msg)) => self.step(msg).await,
+            Some(RequestData::ClusterAdd(param)) => {
+                let cc = ConfChange::new(ConfChangeType::AddNode, param.node_id, param.addr);
+                self.call(|tx| Request::ConfChange(cc, tx)).await
+            }
+            Some(RequestData::ClusterRemove(param)) => {
+                let cc = ConfChange::new(ConfChangeType::RemoveNode, param.node_id, "");
+                self.call(|tx| Request::ConfChange(cc, tx)).await
+            }
+            Some(RequestData::ClusterStatus(_)) => match self.status().await {
+                Ok(pairs) => pairs.into(),
+                Err(e) => e.into(),
+            },
+            Some(
+                RequestData::Hset(_)
+                | RequestData::Hmset(_)
+                | RequestData::Hdel(_)
+                | RequestData::Hmdel(_)
+                | RequestData::Hexpire(_),
+            ) => {
+                // id 只对当前的 stream 有意义，不需要写进日志
+                let cmd = cmd.with_id(0);
+                self.call(|tx| Request::Propose(cmd, tx)).await
+            }
+            Some(
+                RequestData::Hget(_)
+                | RequestData::Hgetall(_)
+                | RequestData::Hmget(_)
+                | RequestData::Hexist(_)
+                | RequestData::Hmexist(_)
+                | RequestData::Dump(_),
+            ) => match self.read_barrier().await {
+                Ok(()) => return hide_raft_table(next.run(cmd).await),
+                Err(e) => e.into(),
+            },
+            // 脚本在 leader 上执行时会直接修改 Storage，不能复制到其它节点
+            Some(RequestData::Eval(_)) => {
+                KvError::InvalidCommand("EVAL is not supported in cluster mode".into()).into()
+            }
+            _ => return next.run(cmd).await,
+        };
+        once_response(res)
+    }
+
+    /// 处理其它节点发来的 Raft 消息
+    async fn step(&self, msg: RaftMessage) -> CommandResponse {
+        match self.tx.send(Request::Step(msg)).await {
+            Ok(()) => CommandResponse::ok(),
+            Err(_) => stopped().into(),
+        }
+    }
+
+    async fn call(&self, f: impl FnOnce(Reply) -> Request) -> CommandResponse {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(f(tx)).await.is_err() {
+            return stopped().into();
+        }
+        rx.await.unwrap_or_else(|_| stopped().into())
+    }
+
+    async fn status(&self) -> Result<Vec<Kvpair>, KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Request::Status(tx))
+            .await
+            .map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())
+    }
+
+    /// ReadIndex：确认自己仍然是 leader，并等待状态机应用到确认时的 commit index
+    async fn read_barrier(&self) -> Result<(), KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Request::ReadIndex(tx))
+            .await
+            .map_err(|_| stopped())?;
+        let index = rx.await.map_err(|_| stopped())??;
+
+        let mut applied = self.applied.clone();
+        while *applied.borrow() < index {
+            applied.changed().await.map_err(|_| stopped())?;
+        }
+        Ok(())
+    }
+}
+
+impl<Store: AsyncStorage, T: RaftTransport> Driver<Store, T> {
+    async fn run(mut self) {
+        // 先应用重启前已经提交的日志
+        self.on_ready().await;
+
+        let mut interval = time::interval(self.tick);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.raft.tick(),
+                req = self.rx.recv() => match req {
+                    Some(req) => self.handle(req),
+                    None => break,
+                },
+            }
+            self.on_ready().await;
+        }
+    }
+
+    fn handle(&mut self, req: Request) {
+        match req {
+            Request::Step(msg) => self.raft.step(msg),
+            Request::Propose(cmd, tx) => match self.raft.propose(cmd.encode_to_vec().into()) {
+                Ok((index, term)) => {
+                    self.proposals.insert(index, (term, tx));
+                }
+                Err(e) => {
+                    let _ = tx.send(e.into());
+                }
+            },
+            Request::ConfChange(cc, tx) => match self.raft.propose_conf_change(cc) {
+                Ok((index, term)) => {
+                    self.proposals.insert(index, (term, tx));
+                }
+                Err(e) => {
+                    let _ = tx.send(e.into());
+                }
+            },
+            Request::ReadIndex(tx) => {
+                let context = self.next_context;
+                self.next_context += 1;
+                match self.raft.read_index(context) {
+                    Ok(()) => {
+                        self.reads.insert(context, tx);
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                }
+            }
+            Request::Status(tx) => {
+                let _ = tx.send(self.status());
+            }
+        }
+    }
+
+    async fn on_ready(&mut self) {
+        if let Err(e) = self.process_ready().await {
+            error!("Failed to process raft ready: {:?}", e);
+        }
+
+        // 不再是 leader 时，没有完成的请求无法知道结果，让客户端去找新的 leader
+        if self.raft.role() != Role::Leader {
+            let leader = self.raft.leader_addr().to_string();
+            for (_, (_, tx)) in std::mem::take(&mut self.proposals) {
+                let _ = tx.send(KvError::NotLeader(leader.clone()).into());
+            }
+            for (_, tx) in std::mem::take(&mut self.reads) {
+                let _ = tx.send(Err(KvError::NotLeader(leader.clone())));
+            }
+        }
+    }
+
+    async fn process_ready(&mut self) -> Result<(), KvError> {
+        while self.raft.has_ready() {
+            let ready = self.raft.ready();
+            if let Some(snapshot) = &ready.snapshot {
+                self.storage.install_snapshot(snapshot).await?;
+            }
+            self.storage.append(&ready.entries).await?;
+            if let Some(hard_state) = &ready.hard_state {
+                self.storage.save_hard_state(hard_state).await?;
+            }
+
+            for (id, addr) in self.raft.members() {
+                self.addrs.insert(*id, addr.clone());
+            }
+            for msg in ready.messages {
+                match self.addrs.get(&msg.to) {
+                    Some(addr) => self.transport.send(msg, addr),
+                    None => warn!("Unknown address of node {}", msg.to),
+                }
+            }
+
+            for entry in ready.committed {
+                self.apply(entry).await;
+            }
+            for (context, index) in ready.reads {
+                if let Some(tx) = self.reads.remove(&context) {
+                    let _ = tx.send(Ok(index));
+                }
+            }
+            for to in ready.snapshot_requests {
+                let snapshot = self
+                    .storage
+                    .build_snapshot(self.raft.snapshot_meta())
+                    .await?;
+                self.raft.send_snapshot(to, snapshot);
+            }
+            let _ = self.applied.send(self.raft.applied());
+        }
+
+        if self.raft.applied() + 1 - self.raft.first_index() > self.snapshot_threshold {
+            let snapshot = self.raft.snapshot_meta();
+            self.storage.compact(&snapshot).await?;
+            self.raft.compact(snapshot.index);
+        }
+        Ok(())
+    }
+
+    /// 把提交的日志应用到 Storage，如果是这个节点发起的写入，把结果返回给客户端
+    async fn apply(&mut self, entry: RaftEntry) {
+        let res = match entry.entry_type() {
+            RaftEntryType::Normal if entry.data.is_empty() => return,
+            RaftEntryType::Normal => match CommandRequest::decode(entry.data) {
+                Ok(cmd) => dispatch(cmd, &self.store).await,
+                Err(e) => {
+                    error!("Failed to decode raft entry {}: {:?}", entry.index, e);
+                    KvError::from(e).into()
+                }
+            },
+            // 成员变更已经在 Raft 内部生效了
+            RaftEntryType::ConfChange => CommandResponse::ok(),
+        };
+
+        if let Some((term, tx)) = self.proposals.remove(&entry.index) {
+            let res = match term == entry.term {
+                true => res,
+                // 同一个 index 上提交的是新 leader 的日志，之前的写入被丢弃了
+                false => KvError::NotLeader(self.raft.leader_addr().into()).into(),
+            };
+            let _ = tx.send(res);
+        }
+    }
+
+    fn status(&self) -> Vec<Kvpair> {
+        let raft = &self.raft;
+        let count = |n: u64| (n as i64).into();
+        let members: Vec<_> = raft
+            .members()
+            .iter()
+            .map(|(id, addr)| format!("{}={}", id, addr))
+            .collect();
+        vec![
+            Kvpair::new("id", count(raft.id())),
+            Kvpair::new("role", raft.role().as_str().into()),
+            Kvpair::new("term", count(raft.term())),
+            Kvpair::new("leader", count(raft.leader())),
+            Kvpair::new("leader_addr", raft.leader_addr().into()),
+            Kvpair::new("commit", count(raft.committed())),
+            Kvpair::new("applied", count(raft.applied())),
+            Kvpair::new("first_index", count(raft.first_index())),
+            Kvpair::new("last_index", count(raft.last_index())),
+            Kvpair::new("members", members.join(",").into()),
+        ]
+    }
+}
+
+/// 命令是否访问了保存 Raft 状态的 table
+fn uses_raft_table(cmd: &CommandRequest) -> bool {
+    let table = match &cmd.request_data {
+        Some(RequestData::Hget(v)) => &v.table,
+        Some(RequestData::Hgetall(v)) => &v.table,
+        Some(RequestData::Hmget(v)) => &v.table,
+        Some(RequestData::Hset(v)) => &v.table,
+        Some(RequestData::Hmset(v)) => &v.table,
+        Some(RequestData::Hdel(v)) => &v.table,
+        Some(RequestData::Hmdel(v)) => &v.table,
+        Some(RequestData::Hexist(v)) => &v.table,
+        Some(RequestData::Hmexist(v)) => &v.table,
+        Some(RequestData::Hexpire(v)) => &v.table,
+        Some(RequestData::Dump(v)) => return v.tables.iter().any(|t| t == RAFT_TABLE),
+        _ => return false,
+    };
+    table == RAFT_TABLE
+}
+
+/// DUMP 所有 table 时，去掉保存 Raft 状态的 table
+fn hide_raft_table(res: StreamingResponse) -> StreamingResponse {
+    let table: Value = RAFT_TABLE.into();
+    Box::pin(res.filter(move |res| {
+        let hidden = res.values.first() == Some(&table);
+        future::ready(!hidden)
+    }))
+}
+
+fn stopped() -> KvError {
+    KvError::Internal("Cluster node is stopped".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok, MemTable, PeerConfig, Service, ServiceInner, Storage,
+    };
+    use dashmap::{DashMap, DashSet};
+    use std::convert::TryInto;
+
+    /// 在进程内把消息直接交给其它节点，可以隔离某个节点
+    #[derive(Clone, Default)]
+    struct Router {
+        nodes: Arc<DashMap<u64, ClusterNode>>,
+        isolated: Arc<DashSet<u64>>,
+    }
+
+    impl RaftTransport for Router {
+        fn send(&self, msg: RaftMessage, _addr: &str) {
+            if self.isolated.contains(&msg.from) || self.isolated.contains(&msg.to) {
+                return;
+            }
+            if let Some(node) = self.nodes.get(&msg.to).map(|n| n.clone()) {
+                tokio::spawn(async move { node.step(msg).await });
+            }
+        }
+    }
+
+    struct TestNode {
+        service: Service,
+        store: Arc<MemTable>,
+    }
+
+    async fn start_cluster(router: &Router, n: u64) -> Vec<TestNode> {
+        let peers: Vec<_> = (1..=n)
+            .map(|id| PeerConfig {
+                id,
+                addr: format!("node{}", id),
+            })
+            .collect();
+        let mut nodes = Vec::new();
+        for id in 1..=n {
+            let config = ClusterConfig {
+                id,
+                tick_ms: 5,
+                election_ticks: 10,
+                heartbeat_ticks: 2,
+                snapshot_threshold: 20,
+                peers: peers.clone(),
+                ..Default::default()
+            };
+            nodes.push(start_node(router, &config).await);
+        }
+        nodes
+    }
+
+    async fn start_node(router: &Router, config: &ClusterConfig) -> TestNode {
+        let inner = ServiceInner::new(MemTable::new());
+        let store = Arc::clone(inner.store());
+        let node = ClusterNode::start(config, Arc::clone(&store), router.clone())
+            .await
+            .unwrap();
+        router.nodes.insert(config.id, node.clone());
+        let service = inner
+            .layer(move |cmd, next| node.clone().handle(cmd, next))
+            .into();
+        TestNode { service, store }
+    }
+
+    async fn execute(service: &Service, cmd: CommandRequest) -> CommandResponse {
+        let res = service.execute(cmd).next().await.unwrap();
+        (*res).clone()
+    }
+
+    /// 等待选出 leader，返回 leader 的 id
+    async fn wait_leader(nodes: &[TestNode], router: &Router) -> u64 {
+        for _ in 0..200 {
+            for node in nodes {
+                let res = execute(&node.service, CommandRequest::new_cluster_status()).await;
+                let get = |key: &str| {
+                    res.pairs
+                        .iter()
+                        .find(|p| p.key == key)
+                        .and_then(|p| p.value.clone())
+                };
+                let id: i64 = get("id").unwrap().try_into().unwrap();
+                if get("role") == Some("leader".into()) && !router.isolated.contains(&(id as u64)) {
+                    return id as u64;
+                }
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no leader is elected");
+    }
+
+    async fn wait_value(store: &MemTable, key: &str, value: Value) {
+        for _ in 0..200 {
+            if store.get("t1", key.as_bytes()).unwrap() == Some(value.clone()) {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} is not replicated", key);
+    }
+
+    #[tokio::test]
+    async fn cluster_should_replicate_writes() {
+        let router = Router::default();
+        let nodes = start_cluster(&router, 3).await;
+        let leader = wait_leader(&nodes, &router).await;
+        let service = &nodes[leader as usize - 1].service;
+
+        let res = execute(service, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = execute(service, CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+        for node in &nodes {
+            wait_value(&node.store, "k1", "v1".into()).await;
+        }
+
+        // follower 不处理读写，返回 leader 的地址
+        let follower = &nodes[leader as usize % 3].service;
+        let res = execute(follower, CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_error(&res, 421, &format!("node{}", leader));
+
+        // 客户端不能访问 Raft 的状态，也不能执行脚本
+        let res = execute(service, CommandRequest::new_hgetall(RAFT_TABLE)).await;
+        assert_res_error(&res, 400, "reserved");
+        let res = execute(service, CommandRequest::new_eval("1", vec![])).await;
+        assert_res_error(&res, 400, "cluster mode");
+        let mut res = service.execute(CommandRequest::new_dump(vec![]));
+        while let Some(res) = res.next().await {
+            assert_ne!(res.values.first(), Some(&RAFT_TABLE.into()));
+        }
+    }
+
+    #[tokio::test]
+    async fn cluster_should_elect_new_leader_after_partition() {
+        let router = Router::default();
+        let nodes = start_cluster(&router, 3).await;
+        let old = wait_leader(&nodes, &router).await;
+
+        router.isolated.insert(old);
+        let leader = wait_leader(&nodes, &router).await;
+        assert_ne!(leader, old);
+        let service = &nodes[leader as usize - 1].service;
+        // 写入足够多的数据，触发快照和日志压缩
+        for i in 0..50 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            let res = execute(service, cmd).await;
+            assert_res_ok(&res, &[Value::default()], &[]);
+        }
+
+        // 被隔离的旧 leader 无法处理读写
+        let res = execute(
+            &nodes[old as usize - 1].service,
+            CommandRequest::new_hget("t1", "k1"),
+        )
+        .await;
+        assert_eq!(res.status, 421);
+
+        // 恢复之后，旧 leader 通过快照赶上进度
+        router.isolated.clear();
+        wait_value(&nodes[old as usize - 1].store, "k49", 49.into()).await;
+        assert_eq!(
+            nodes[old as usize - 1]
+                .store
+                .as_ref()
+                .get_all("t1")
+                .unwrap()
+                .len(),
+            50
+        );
+    }
+
+    #[tokio::test]
+    async fn cluster_should_add_and_remove_members() {
+        let router = Router::default();
+        let nodes = start_cluster(&router, 3).await;
+        let leader = wait_leader(&nodes, &router).await;
+        let service = &nodes[leader as usize - 1].service;
+        execute(service, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+
+        let config = ClusterConfig {
+            id: 4,
+            join: true,
+            tick_ms: 5,
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            peers: vec![PeerConfig {
+                id: leader,
+                addr: format!("node{}", leader),
+            }],
+            ..Default::default()
+        };
+        let new = start_node(&router, &config).await;
+        let res = execute(service, CommandRequest::new_cluster_add(4, "node4")).await;
+        assert_res_ok(&res, &[], &[]);
+        wait_value(&new.store, "k1", "v1".into()).await;
+
+        let res = execute(service, CommandRequest::new_cluster_add(4, "node4")).await;
+        assert_res_error(&res, 400, "already a member");
+
+        let res = execute(service, CommandRequest::new_cluster_remove(4)).await;
+        assert_res_ok(&res, &[], &[]);
+        let res = execute(service, CommandRequest::new_cluster_status()).await;
+        let members = res.pairs.iter().find(|p| p.key == "members").unwrap();
+        assert_eq!(
+            members
+                .value
+                .as_ref()
+                .unwrap()
+                .format()
+                .matches("node")
+                .count(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn cluster_commands_should_fail_without_cluster() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = execute(&service, CommandRequest::new_cluster_status()).await;
+        assert_res_error(&res, 400, "cluster mode is not enabled");
+    }
+}
@@ -0,0 +1,1441 @@
+use bytes::Bytes;
+use prost::Message;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{
+    ConfChange, ConfChangeType, KvError, RaftEntry, RaftEntryType, RaftHardState, RaftMember,
+    RaftMessage, RaftMessageType, RaftSnapshot,
+};
+
+/// 一条 Append 消息里最多带多少条日志
+const MAX_ENTRIES_PER_MSG: usize = 64;
+
+/// 节点当前的角色
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// 调用者需要处理的状态变化，按字段的顺序处理：
+/// 先安装快照、持久化日志和 hard state，再发送消息、应用已提交的日志
+#[derive(Debug, Default)]
+pub struct Ready {
+    /// 发生变化的 hard state
+    pub hard_state: Option<RaftHardState>,
+    /// 从 leader 收到的快照，需要用它替换状态机
+    pub snapshot: Option<RaftSnapshot>,
+    /// 需要持久化的日志，持久化时覆盖 index 相同的旧日志，并删除更后面的日志
+    pub entries: Vec<RaftEntry>,
+    /// 需要发送给其它节点的消息
+    pub messages: Vec<RaftMessage>,
+    /// 已经提交、需要应用到状态机的日志
+    pub committed: Vec<RaftEntry>,
+    /// 已经确认的 ReadIndex 请求：(context, index)，状态机应用到 index 之后就可以读取
+    pub reads: Vec<(u64, u64)>,
+    /// 需要发送快照的节点，调用者生成快照后调用 `send_snapshot`
+    pub snapshot_requests: Vec<u64>,
+}
+
+/// 一个不做任何 IO 的 Raft 状态机。调用者负责驱动时钟（`tick`）、传递消息（`step`）
+/// 以及处理 `ready` 返回的结果，所以同样的输入总是得到同样的输出，方便测试
+pub struct Raft {
+    id: u64,
+    election_ticks: u64,
+    heartbeat_ticks: u64,
+
+    term: u64,
+    vote: u64,
+    role: Role,
+    leader: u64,
+    /// 已经应用的成员配置：节点 id 到地址
+    members: BTreeMap<u64, String>,
+    log: RaftLog,
+
+    /// leader 记录的每个节点的复制进度
+    progress: BTreeMap<u64, Progress>,
+    votes: BTreeMap<u64, bool>,
+    /// 没有应用的成员变更日志的 index，同一时间只允许一个成员变更
+    pending_conf_index: u64,
+    read_requests: VecDeque<ReadRequest>,
+
+    elapsed: u64,
+    heartbeat_elapsed: u64,
+    randomized_timeout: u64,
+    rng: u64,
+
+    prev_hard_state: RaftHardState,
+    snapshot: Option<RaftSnapshot>,
+    msgs: Vec<RaftMessage>,
+    reads: Vec<(u64, u64)>,
+    snapshot_requests: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    next: u64,
+    matched: u64,
+    /// 最近一个选举周期内是否收到过这个节点的回复
+    recent_active: bool,
+    /// 已经请求发送快照，在收到回复之前不再发送日志
+    pending_snapshot: bool,
+}
+
+#[derive(Debug)]
+struct ReadRequest {
+    context: u64,
+    /// leader 在当前 term 提交过日志之后才能确定 read index
+    index: Option<u64>,
+    acks: BTreeSet<u64>,
+}
+
+#[derive(Debug, Default)]
+struct RaftLog {
+    /// 快照包含的最后一条日志
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// snapshot_index 之后的日志
+    entries: Vec<RaftEntry>,
+    committed: u64,
+    applied: u64,
+    /// 已经交给调用者持久化的最后一条日志
+    stabled: u64,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        }
+    }
+}
+
+impl Raft {
+    /// 创建一个没有任何状态的节点，用于加入已有的集群，等待 leader 发来日志或者快照
+    pub fn new(id: u64, election_ticks: u64, heartbeat_ticks: u64) -> Self {
+        assert!(id != 0, "raft node id must not be 0");
+        let mut raft = Self {
+            id,
+            election_ticks,
+            heartbeat_ticks,
+            term: 0,
+            vote: 0,
+            role: Role::Follower,
+            leader: 0,
+            members: BTreeMap::new(),
+            log: RaftLog::default(),
+            progress: BTreeMap::new(),
+            votes: BTreeMap::new(),
+            pending_conf_index: 0,
+            read_requests: VecDeque::new(),
+            elapsed: 0,
+            heartbeat_elapsed: 0,
+            randomized_timeout: 0,
+            rng: id,
+            prev_hard_state: RaftHardState::default(),
+            snapshot: None,
+            msgs: Vec::new(),
+            reads: Vec::new(),
+            snapshot_requests: Vec::new(),
+        };
+        raft.reset_timeout();
+        raft
+    }
+
+    /// 创建一个新集群的初始节点。所有初始节点使用同样的 peers，
+    /// 它们会在 term 1 写入同样的成员变更日志，并且认为这些日志已经提交
+    pub fn bootstrap(
+        id: u64,
+        election_ticks: u64,
+        heartbeat_ticks: u64,
+        peers: &[(u64, String)],
+    ) -> Self {
+        let mut raft = Self::new(id, election_ticks, heartbeat_ticks);
+        for (i, (node_id, addr)) in peers.iter().enumerate() {
+            let cc = ConfChange::new(ConfChangeType::AddNode, *node_id, addr.clone());
+            raft.log.entries.push(RaftEntry {
+                term: 1,
+                index: i as u64 + 1,
+                entry_type: RaftEntryType::ConfChange as _,
+                data: cc.encode_to_vec().into(),
+            });
+        }
+        raft.term = 1;
+        raft.log.committed = peers.len() as _;
+        raft
+    }
+
+    /// 从持久化的状态恢复节点。snapshot 中不需要 data，状态机在重启前已经包含了快照的数据，
+    /// snapshot 之后已提交的日志会重新交给调用者应用，所以应用日志必须是幂等的
+    pub fn restore(
+        id: u64,
+        election_ticks: u64,
+        heartbeat_ticks: u64,
+        hard_state: RaftHardState,
+        snapshot: &RaftSnapshot,
+        entries: Vec<RaftEntry>,
+    ) -> Self {
+        let mut raft = Self::new(id, election_ticks, heartbeat_ticks);
+        raft.log.restore(snapshot.index, snapshot.term);
+        raft.log.entries = entries;
+        raft.log.stabled = raft.log.last_index();
+        raft.log.committed = hard_state
+            .commit
+            .clamp(snapshot.index, raft.log.last_index());
+        raft.members = snapshot
+            .members
+            .iter()
+            .map(|m| (m.id, m.addr.clone()))
+            .collect();
+        raft.term = hard_state.term;
+        raft.vote = hard_state.vote;
+        raft.prev_hard_state = raft.hard_state();
+        raft
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// 当前的 leader，不知道时返回 0
+    pub fn leader(&self) -> u64 {
+        self.leader
+    }
+
+    /// leader 的地址，不知道 leader 时返回空字符串
+    pub fn leader_addr(&self) -> &str {
+        self.members
+            .get(&self.leader)
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+    }
+
+    pub fn members(&self) -> &BTreeMap<u64, String> {
+        &self.members
+    }
+
+    pub fn committed(&self) -> u64 {
+        self.log.committed
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.log.applied
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.log.last_index()
+    }
+
+    /// 最近一次压缩后，日志中第一条的 index
+    pub fn first_index(&self) -> u64 {
+        self.log.snapshot_index + 1
+    }
+
+    /// 逻辑时钟前进一步
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader => {
+                self.heartbeat_elapsed += 1;
+                if self.heartbeat_elapsed >= self.heartbeat_ticks {
+                    self.heartbeat_elapsed = 0;
+                    self.broadcast_heartbeat(0);
+                }
+                if self.elapsed >= self.election_ticks {
+                    self.elapsed = 0;
+                    self.check_quorum();
+                }
+            }
+            _ => {
+                if self.elapsed >= self.randomized_timeout && self.promotable() {
+                    self.campaign();
+                }
+            }
+        }
+    }
+
+    /// 处理其它节点发来的消息
+    pub fn step(&mut self, m: RaftMessage) {
+        let msg_type = m.msg_type();
+        if m.term > self.term {
+            // 最近收到过 leader 的消息时忽略投票请求，避免被隔离过的节点打断正常工作的集群
+            if msg_type == RaftMessageType::Vote
+                && self.leader != 0
+                && self.elapsed < self.election_ticks
+            {
+                return;
+            }
+            let leader = match msg_type {
+                RaftMessageType::Append
+                | RaftMessageType::Heartbeat
+                | RaftMessageType::Snapshot => m.from,
+                _ => 0,
+            };
+            self.become_follower(m.term, leader);
+        } else if m.term < self.term {
+            // 让过期的 leader 或者候选人知道新的 term
+            match msg_type {
+                RaftMessageType::Append
+                | RaftMessageType::Heartbeat
+                | RaftMessageType::Snapshot => {
+                    self.send(m.from, RaftMessageType::AppendResponse, |_| {})
+                }
+                RaftMessageType::Vote => {
+                    self.send(m.from, RaftMessageType::VoteResponse, |r| r.reject = true)
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match msg_type {
+            RaftMessageType::Vote => self.handle_vote(m),
+            RaftMessageType::VoteResponse => self.handle_vote_response(m),
+            RaftMessageType::Append => {
+                self.follow(m.from);
+                self.handle_append(m)
+            }
+            RaftMessageType::Heartbeat => {
+                self.follow(m.from);
+                self.handle_heartbeat(m)
+            }
+            RaftMessageType::Snapshot => {
+                self.follow(m.from);
+                self.handle_snapshot(m)
+            }
+            RaftMessageType::AppendResponse => self.handle_append_response(m),
+            RaftMessageType::HeartbeatResponse => self.handle_heartbeat_response(m),
+        }
+    }
+
+    /// 追加一条普通日志，返回它的 (index, term)。日志被提交后才会出现在 Ready::committed 中
+    pub fn propose(&mut self, data: Bytes) -> Result<(u64, u64), KvError> {
+        self.check_leader()?;
+        Ok(self.append(RaftEntryType::Normal, data))
+    }
+
+    /// 追加一条成员变更日志，同一时间只允许有一个没有应用的成员变更
+    pub fn propose_conf_change(&mut self, cc: ConfChange) -> Result<(u64, u64), KvError> {
+        self.check_leader()?;
+        if self.pending_conf_index > self.log.applied {
+            let msg = "another membership change is in progress";
+            return Err(KvError::InvalidCommand(msg.into()));
+        }
+        match cc.change_type() {
+            ConfChangeType::AddNode if self.members.contains_key(&cc.node_id) => {
+                let msg = format!("node {} is already a member", cc.node_id);
+                return Err(KvError::InvalidCommand(msg));
+            }
+            ConfChangeType::AddNode if cc.node_id == 0 || cc.addr.is_empty() => {
+                let msg = "node id and address must not be empty";
+                return Err(KvError::InvalidCommand(msg.into()));
+            }
+            ConfChangeType::RemoveNode if !self.members.contains_key(&cc.node_id) => {
+                return Err(KvError::NotFound(format!("node {}", cc.node_id)));
+            }
+            ConfChangeType::RemoveNode if self.members.len() == 1 => {
+                let msg = "cannot remove the last member";
+                return Err(KvError::InvalidCommand(msg.into()));
+            }
+            _ => {}
+        }
+
+        let (index, term) = self.append(RaftEntryType::ConfChange, cc.encode_to_vec().into());
+        self.pending_conf_index = index;
+        Ok((index, term))
+    }
+
+    /// 发起一个线性一致读。leader 确认自己仍然是 leader 之后，
+    /// (context, index) 会出现在 Ready::reads 中
+    pub fn read_index(&mut self, context: u64) -> Result<(), KvError> {
+        self.check_leader()?;
+        let mut req = ReadRequest {
+            context,
+            index: None,
+            acks: BTreeSet::from([self.id]),
+        };
+        // 新的 leader 在当前 term 提交过日志之后，才知道最新的 commit index
+        if self.committed_in_term() {
+            req.index = Some(self.log.committed);
+        }
+        let stamped = req.index.is_some();
+        self.read_requests.push_back(req);
+        if stamped {
+            self.broadcast_heartbeat(context);
+            self.release_reads();
+        }
+        Ok(())
+    }
+
+    /// 当前应用到的位置的快照元数据，data 由调用者从状态机中生成
+    pub fn snapshot_meta(&self) -> RaftSnapshot {
+        let index = self.log.applied;
+        RaftSnapshot {
+            index,
+            term: self.log.term(index).unwrap_or_default(),
+            members: self
+                .members
+                .iter()
+                .map(|(id, addr)| RaftMember {
+                    id: *id,
+                    addr: addr.clone(),
+                })
+                .collect(),
+            data: Bytes::new(),
+        }
+    }
+
+    /// 把快照发给 Ready::snapshot_requests 中的节点
+    pub fn send_snapshot(&mut self, to: u64, snapshot: RaftSnapshot) {
+        match self.progress.get(&to) {
+            Some(pr) if self.role == Role::Leader && pr.pending_snapshot => {
+                self.send(to, RaftMessageType::Snapshot, |m| {
+                    m.snapshot = Some(snapshot)
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// 状态机已经保存了 index 之前的数据，丢弃这部分日志
+    pub fn compact(&mut self, index: u64) {
+        assert!(index <= self.log.applied, "cannot compact unapplied logs");
+        self.log.compact(index);
+    }
+
+    /// 需要持久化的状态
+    pub fn hard_state(&self) -> RaftHardState {
+        RaftHardState {
+            term: self.term,
+            vote: self.vote,
+            commit: self.log.committed,
+        }
+    }
+
+    pub fn has_ready(&self) -> bool {
+        self.hard_state() != self.prev_hard_state
+            || self.snapshot.is_some()
+            || self.log.stabled < self.log.last_index()
+            || !self.msgs.is_empty()
+            || self.log.applied < self.log.committed
+            || !self.reads.is_empty()
+            || !self.snapshot_requests.is_empty()
+    }
+
+    /// 取出需要处理的状态变化，调用者处理完之后再继续调用 tick/step
+    pub fn ready(&mut self) -> Ready {
+        let hard_state = self.hard_state();
+        let hard_state = (hard_state != self.prev_hard_state).then(|| {
+            self.prev_hard_state = hard_state.clone();
+            hard_state
+        });
+
+        let entries = self.log.unstable().to_vec();
+        self.log.stabled = self.log.last_index();
+
+        let committed = self.log.slice(self.log.applied + 1, self.log.committed + 1);
+        self.log.applied = self.log.committed;
+        for entry in &committed {
+            if entry.entry_type() == RaftEntryType::ConfChange {
+                if let Ok(cc) = ConfChange::decode(entry.data.clone()) {
+                    self.apply_conf_change(cc);
+                }
+            }
+        }
+
+        Ready {
+            hard_state,
+            snapshot: self.snapshot.take(),
+            entries,
+            messages: std::mem::take(&mut self.msgs),
+            committed,
+            reads: std::mem::take(&mut self.reads),
+            snapshot_requests: std::mem::take(&mut self.snapshot_requests),
+        }
+    }
+
+    fn check_leader(&self) -> Result<(), KvError> {
+        match self.role {
+            Role::Leader => Ok(()),
+            _ => Err(KvError::NotLeader(self.leader_addr().into())),
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    /// 只有集群的成员才能发起选举
+    fn promotable(&self) -> bool {
+        self.members.contains_key(&self.id)
+    }
+
+    fn committed_in_term(&self) -> bool {
+        self.log.term(self.log.committed) == Some(self.term)
+    }
+
+    fn reset_timeout(&mut self) {
+        // xorshift，用节点 id 做种子，测试时每次运行的结果都一样
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.randomized_timeout = self.election_ticks + self.rng % self.election_ticks.max(1);
+    }
+
+    fn become_follower(&mut self, term: u64, leader: u64) {
+        if term != self.term {
+            self.term = term;
+            self.vote = 0;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.elapsed = 0;
+        self.votes.clear();
+        self.progress.clear();
+        self.read_requests.clear();
+        self.reset_timeout();
+    }
+
+    /// 收到当前 term 的 leader 发来的消息
+    fn follow(&mut self, leader: u64) {
+        if self.role != Role::Follower {
+            self.become_follower(self.term, leader);
+        }
+        self.leader = leader;
+        self.elapsed = 0;
+    }
+
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.vote = self.id;
+        self.role = Role::Candidate;
+        self.leader = 0;
+        self.elapsed = 0;
+        self.reset_timeout();
+        self.votes = BTreeMap::from([(self.id, true)]);
+        if self.quorum() == 1 {
+            self.become_leader();
+            return;
+        }
+
+        let (log_term, index) = (self.log.last_term(), self.log.last_index());
+        let peers: Vec<u64> = self.peers().collect();
+        for to in peers {
+            self.send(to, RaftMessageType::Vote, |m| {
+                m.log_term = log_term;
+                m.index = index;
+            });
+        }
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = self.id;
+        self.elapsed = 0;
+        self.heartbeat_elapsed = 0;
+        let last = self.log.last_index();
+        self.progress = self
+            .members
+            .keys()
+            .map(|id| {
+                let pr = Progress {
+                    next: last + 1,
+                    recent_active: true,
+                    ..Default::default()
+                };
+                (*id, pr)
+            })
+            .collect();
+        // 之前的 leader 可能留下了没有应用的成员变更
+        self.pending_conf_index = last;
+        // 写入一条空日志，提交之后就知道之前 term 的日志都已经提交了
+        self.append(RaftEntryType::Normal, Bytes::new());
+    }
+
+    fn peers(&self) -> impl Iterator<Item = u64> + '_ {
+        let id = self.id;
+        self.members.keys().copied().filter(move |v| *v != id)
+    }
+
+    fn send(&mut self, to: u64, msg_type: RaftMessageType, f: impl FnOnce(&mut RaftMessage)) {
+        let mut m = RaftMessage {
+            from: self.id,
+            to,
+            term: self.term,
+            ..Default::default()
+        };
+        m.set_msg_type(msg_type);
+        f(&mut m);
+        self.msgs.push(m);
+    }
+
+    fn append(&mut self, entry_type: RaftEntryType, data: Bytes) -> (u64, u64) {
+        let index = self.log.last_index() + 1;
+        let mut entry = RaftEntry {
+            term: self.term,
+            index,
+            data,
+            ..Default::default()
+        };
+        entry.set_entry_type(entry_type);
+        self.log.entries.push(entry);
+        if let Some(pr) = self.progress.get_mut(&self.id) {
+            pr.matched = index;
+            pr.next = index + 1;
+        }
+
+        self.broadcast_append();
+        // 单节点集群不需要等待其它节点
+        self.maybe_commit();
+        (index, self.term)
+    }
+
+    fn broadcast_append(&mut self) {
+        let peers: Vec<u64> = self.peers().collect();
+        for to in peers {
+            self.send_append(to);
+        }
+    }
+
+    fn send_append(&mut self, to: u64) {
+        let last = self.log.last_index();
+        let (prev, log_term) = match self.progress.get_mut(&to) {
+            Some(pr) if !pr.pending_snapshot => {
+                pr.next = pr.next.clamp(1, last + 1);
+                (pr.next - 1, self.log.term(pr.next - 1))
+            }
+            _ => return,
+        };
+
+        let log_term = match log_term {
+            Some(term) => term,
+            // 需要的日志已经被压缩了，只能发送快照
+            None => {
+                if let Some(pr) = self.progress.get_mut(&to) {
+                    pr.pending_snapshot = true;
+                }
+                self.snapshot_requests.push(to);
+                return;
+            }
+        };
+
+        let end = (prev + 1 + MAX_ENTRIES_PER_MSG as u64).min(last + 1);
+        let entries = self.log.slice(prev + 1, end);
+        if let Some(pr) = self.progress.get_mut(&to) {
+            // 不等回复，乐观地认为这些日志会被接受，回复 reject 时再回退
+            pr.next = end;
+        }
+        let commit = self.log.committed;
+        self.send(to, RaftMessageType::Append, |m| {
+            m.index = prev;
+            m.log_term = log_term;
+            m.entries = entries;
+            m.commit = commit;
+        });
+    }
+
+    fn broadcast_heartbeat(&mut self, context: u64) {
+        let peers: Vec<u64> = self.peers().collect();
+        for to in peers {
+            let matched = self.progress.get(&to).map(|pr| pr.matched).unwrap_or(0);
+            // follower 只能提交已经确认和 leader 一致的日志
+            let commit = matched.min(self.log.committed);
+            self.send(to, RaftMessageType::Heartbeat, |m| {
+                m.commit = commit;
+                m.context = context;
+            });
+        }
+    }
+
+    /// leader 在一个选举周期内没有收到多数节点的回复时，认为自己被隔离了，主动退位
+    fn check_quorum(&mut self) {
+        let id = self.id;
+        let mut active = 0;
+        for (node, pr) in self.progress.iter_mut() {
+            if *node == id || pr.recent_active {
+                active += 1;
+            }
+            pr.recent_active = false;
+            // 快照可能丢失了，下一次重新发送
+            pr.pending_snapshot = false;
+        }
+        if active < self.quorum() {
+            self.become_follower(self.term, 0);
+        }
+    }
+
+    fn maybe_commit(&mut self) -> bool {
+        if self.role != Role::Leader || self.members.is_empty() {
+            return false;
+        }
+        let mut matched: Vec<u64> = self
+            .members
+            .keys()
+            .map(|id| self.progress.get(id).map(|pr| pr.matched).unwrap_or(0))
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+
+        // 只能通过计数提交当前 term 的日志
+        if index <= self.log.committed || self.log.term(index) != Some(self.term) {
+            return false;
+        }
+        self.log.committed = index;
+
+        // 当前 term 第一次提交日志，可以处理之前挂起的读请求了
+        let mut stamped = Vec::new();
+        for req in self.read_requests.iter_mut().filter(|r| r.index.is_none()) {
+            req.index = Some(index);
+            stamped.push(req.context);
+        }
+        for context in stamped {
+            self.broadcast_heartbeat(context);
+        }
+        self.release_reads();
+        true
+    }
+
+    /// 按顺序放行已经得到多数节点确认的读请求
+    fn release_reads(&mut self) {
+        let quorum = self.quorum();
+        let confirmed = self
+            .read_requests
+            .iter()
+            .rposition(|r| r.index.is_some() && r.acks.len() >= quorum);
+        if let Some(pos) = confirmed {
+            for req in self.read_requests.drain(..=pos) {
+                // 后面的请求被确认时，前面的请求 leader 身份一定也是有效的
+                let index = req.index.unwrap_or(self.log.committed);
+                self.reads.push((req.context, index));
+            }
+        }
+    }
+
+    fn handle_vote(&mut self, m: RaftMessage) {
+        let can_vote = self.vote == m.from || (self.vote == 0 && self.leader == 0);
+        let granted = can_vote && self.log.is_up_to_date(m.log_term, m.index);
+        if granted {
+            self.vote = m.from;
+            self.elapsed = 0;
+        }
+        self.send(m.from, RaftMessageType::VoteResponse, |r| {
+            r.reject = !granted
+        });
+    }
+
+    fn handle_vote_response(&mut self, m: RaftMessage) {
+        if self.role != Role::Candidate {
+            return;
+        }
+        self.votes.insert(m.from, !m.reject);
+        let count = |granted: bool| {
+            self.members
+                .keys()
+                .filter(|id| self.votes.get(id) == Some(&granted))
+                .count()
+        };
+        if count(true) >= self.quorum() {
+            self.become_leader();
+        } else if count(false) >= self.quorum() {
+            self.become_follower(self.term, 0);
+        }
+    }
+
+    fn handle_append(&mut self, m: RaftMessage) {
+        if m.index < self.log.committed {
+            let committed = self.log.committed;
+            self.send(m.from, RaftMessageType::AppendResponse, |r| {
+                r.index = committed
+            });
+            return;
+        }
+
+        if self.log.match_term(m.index, m.log_term) {
+            let last_new = m.index + m.entries.len() as u64;
+            self.log.append(m.entries);
+            self.log.commit_to(m.commit.min(last_new));
+            self.send(m.from, RaftMessageType::AppendResponse, |r| {
+                r.index = last_new
+            });
+        } else {
+            let hint = self.log.last_index();
+            self.send(m.from, RaftMessageType::AppendResponse, |r| {
+                r.index = m.index;
+                r.reject = true;
+                r.reject_hint = hint;
+            });
+        }
+    }
+
+    fn handle_heartbeat(&mut self, m: RaftMessage) {
+        self.log.commit_to(m.commit);
+        self.send(m.from, RaftMessageType::HeartbeatResponse, |r| {
+            r.context = m.context
+        });
+    }
+
+    fn handle_snapshot(&mut self, m: RaftMessage) {
+        let snapshot = m.snapshot.unwrap_or_default();
+        let index = snapshot.index;
+        if index <= self.log.committed {
+            let committed = self.log.committed;
+            self.send(m.from, RaftMessageType::AppendResponse, |r| {
+                r.index = committed
+            });
+            return;
+        }
+
+        if self.log.match_term(index, snapshot.term) {
+            // 快照中的日志本地都有，只需要提交它们
+            self.log.commit_to(index);
+        } else {
+            self.log.restore(index, snapshot.term);
+            self.members = snapshot
+                .members
+                .iter()
+                .map(|m| (m.id, m.addr.clone()))
+                .collect();
+            self.snapshot = Some(snapshot);
+        }
+        self.send(m.from, RaftMessageType::AppendResponse, |r| r.index = index);
+    }
+
+    fn handle_append_response(&mut self, m: RaftMessage) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let last = self.log.last_index();
+        let pr = match self.progress.get_mut(&m.from) {
+            Some(pr) => pr,
+            None => return,
+        };
+        pr.recent_active = true;
+
+        if m.reject {
+            // 过期的拒绝
+            if m.index <= pr.matched {
+                return;
+            }
+            pr.next = m.index.min(m.reject_hint + 1).max(pr.matched + 1);
+            self.send_append(m.from);
+            return;
+        }
+
+        pr.pending_snapshot = false;
+        pr.matched = pr.matched.max(m.index);
+        pr.next = pr.next.max(m.index + 1);
+        let more = pr.next <= last;
+        if self.maybe_commit() {
+            self.broadcast_append();
+        } else if more {
+            self.send_append(m.from);
+        }
+    }
+
+    fn handle_heartbeat_response(&mut self, m: RaftMessage) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let last = self.log.last_index();
+        if let Some(pr) = self.progress.get_mut(&m.from) {
+            pr.recent_active = true;
+            if pr.matched < last && !pr.pending_snapshot {
+                // 之前发送的日志可能丢失了，从确认过的位置重新发送
+                pr.next = pr.matched + 1;
+                self.send_append(m.from);
+            }
+        }
+
+        if m.context != 0 {
+            if let Some(req) = self
+                .read_requests
+                .iter_mut()
+                .find(|r| r.context == m.context)
+            {
+                req.acks.insert(m.from);
+            }
+            self.release_reads();
+        }
+    }
+
+    fn apply_conf_change(&mut self, cc: ConfChange) {
+        match cc.change_type() {
+            ConfChangeType::AddNode => {
+                self.members.insert(cc.node_id, cc.addr);
+                if self.role == Role::Leader {
+                    let next = self.log.last_index() + 1;
+                    let pr = self.progress.entry(cc.node_id).or_default();
+                    pr.next = pr.next.max(next);
+                    pr.recent_active = true;
+                    self.send_append(cc.node_id);
+                }
+            }
+            ConfChangeType::RemoveNode => {
+                self.members.remove(&cc.node_id);
+                self.progress.remove(&cc.node_id);
+                if cc.node_id == self.id {
+                    self.become_follower(self.term, 0);
+                } else if self.maybe_commit() {
+                    // 成员变少之后，多数派可能已经确认了更多的日志
+                    self.broadcast_append();
+                }
+            }
+        }
+    }
+}
+
+impl RaftLog {
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term(self.last_index()).unwrap_or_default()
+    }
+
+    /// index 处日志的 term，日志不存在或者已经被压缩时返回 None
+    fn term(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index || index > self.last_index() {
+            return None;
+        }
+        Some(self.entries[(index - self.snapshot_index - 1) as usize].term)
+    }
+
+    fn match_term(&self, index: u64, term: u64) -> bool {
+        self.term(index) == Some(term)
+    }
+
+    /// 候选人的日志是否至少和自己的一样新
+    fn is_up_to_date(&self, term: u64, index: u64) -> bool {
+        term > self.last_term() || (term == self.last_term() && index >= self.last_index())
+    }
+
+    /// [from, to) 之间的日志，from 必须大于 snapshot_index
+    fn slice(&self, from: u64, to: u64) -> Vec<RaftEntry> {
+        if from >= to {
+            return Vec::new();
+        }
+        let start = (from - self.snapshot_index - 1) as usize;
+        let end = (to - self.snapshot_index - 1) as usize;
+        self.entries[start..end].to_vec()
+    }
+
+    fn unstable(&self) -> &[RaftEntry] {
+        let start = self.stabled.max(self.snapshot_index) - self.snapshot_index;
+        &self.entries[start as usize..]
+    }
+
+    /// 追加 leader 发来的日志，跳过已有的日志，遇到冲突时删除冲突的日志和它之后的所有日志
+    fn append(&mut self, entries: Vec<RaftEntry>) {
+        let conflict = entries
+            .iter()
+            .position(|e| !self.match_term(e.index, e.term));
+        if let Some(pos) = conflict {
+            let index = entries[pos].index;
+            // 已经提交的日志不会冲突，除非 leader 出了问题
+            if index <= self.committed {
+                return;
+            }
+            self.entries
+                .truncate((index - self.snapshot_index - 1) as usize);
+            self.stabled = self.stabled.min(index - 1);
+            self.entries.extend(entries.into_iter().skip(pos));
+        }
+    }
+
+    fn commit_to(&mut self, index: u64) {
+        let index = index.min(self.last_index());
+        if index > self.committed {
+            self.committed = index;
+        }
+    }
+
+    /// 使用快照替换所有日志
+    fn restore(&mut self, index: u64, term: u64) {
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.entries.clear();
+        self.committed = index;
+        self.applied = index;
+        self.stabled = index;
+    }
+
+    fn compact(&mut self, index: u64) {
+        if index <= self.snapshot_index {
+            return;
+        }
+        let term = self.term(index).unwrap_or_default();
+        self.entries.drain(..(index - self.snapshot_index) as usize);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+}
+
+impl ConfChange {
+    pub fn new(change_type: ConfChangeType, node_id: u64, addr: impl Into<String>) -> Self {
+        let mut cc = Self {
+            node_id,
+            addr: addr.into(),
+            ..Default::default()
+        };
+        cc.set_change_type(change_type);
+        cc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELECTION_TICKS: u64 = 10;
+    const HEARTBEAT_TICKS: u64 = 2;
+
+    /// 测试用的节点：状态机就是按顺序应用的日志内容
+    struct Node {
+        raft: Raft,
+        state: Vec<String>,
+        reads: Vec<(u64, u64)>,
+    }
+
+    /// 在进程内模拟网络，所有消息都按顺序投递，可以切断任意两个节点之间的连接
+    #[derive(Default)]
+    struct Network {
+        nodes: BTreeMap<u64, Node>,
+        cut: BTreeSet<(u64, u64)>,
+        queue: VecDeque<RaftMessage>,
+    }
+
+    impl Network {
+        fn new(n: u64) -> Self {
+            let peers: Vec<_> = (1..=n).map(|id| (id, format!("node{}", id))).collect();
+            let mut network = Self::default();
+            for id in 1..=n {
+                let raft = Raft::bootstrap(id, ELECTION_TICKS, HEARTBEAT_TICKS, &peers);
+                network.add(raft);
+            }
+            network
+        }
+
+        fn add(&mut self, raft: Raft) {
+            let id = raft.id();
+            let node = Node {
+                raft,
+                state: Vec::new(),
+                reads: Vec::new(),
+            };
+            self.nodes.insert(id, node);
+            self.process(id);
+        }
+
+        fn node(&mut self, id: u64) -> &mut Raft {
+            &mut self.nodes.get_mut(&id).unwrap().raft
+        }
+
+        fn state(&self, id: u64) -> &[String] {
+            &self.nodes[&id].state
+        }
+
+        /// 把 id 和其它所有节点隔离开
+        fn isolate(&mut self, id: u64) {
+            for other in 1..=self.nodes.keys().max().copied().unwrap_or_default() {
+                if other != id {
+                    self.cut.insert((id, other));
+                    self.cut.insert((other, id));
+                }
+            }
+        }
+
+        fn heal(&mut self) {
+            self.cut.clear();
+        }
+
+        /// 像真正的驱动程序一样处理 Ready
+        fn process(&mut self, id: u64) {
+            let node = self.nodes.get_mut(&id).unwrap();
+            while node.raft.has_ready() {
+                let ready = node.raft.ready();
+                if let Some(snapshot) = ready.snapshot {
+                    let data = String::from_utf8(snapshot.data.to_vec()).unwrap();
+                    node.state = data.lines().map(String::from).collect();
+                }
+                for entry in ready.committed {
+                    if entry.entry_type() == RaftEntryType::Normal && !entry.data.is_empty() {
+                        node.state
+                            .push(String::from_utf8(entry.data.to_vec()).unwrap());
+                    }
+                }
+                node.reads.extend(ready.reads);
+                for to in ready.snapshot_requests {
+                    let mut snapshot = node.raft.snapshot_meta();
+                    snapshot.data = node.state.join("\n").into();
+                    node.raft.send_snapshot(to, snapshot);
+                }
+                for m in ready.messages {
+                    if !self.cut.contains(&(m.from, m.to)) {
+                        self.queue.push_back(m);
+                    }
+                }
+            }
+        }
+
+        /// 投递所有消息，直到网络中没有消息
+        fn deliver(&mut self) {
+            while let Some(m) = self.queue.pop_front() {
+                let to = m.to;
+                if self.cut.contains(&(m.from, to)) || !self.nodes.contains_key(&to) {
+                    continue;
+                }
+                self.node(to).step(m);
+                self.process(to);
+            }
+        }
+
+        fn tick(&mut self, n: usize) {
+            for _ in 0..n {
+                let ids: Vec<u64> = self.nodes.keys().copied().collect();
+                for id in ids {
+                    self.node(id).tick();
+                    self.process(id);
+                }
+                self.deliver();
+            }
+        }
+
+        /// 等待选出 leader，返回 leader 的 id
+        fn elect(&mut self) -> u64 {
+            for _ in 0..100 {
+                self.tick(1);
+                if let Some(id) = self.leader() {
+                    return id;
+                }
+            }
+            panic!("no leader is elected");
+        }
+
+        /// 可以和多数节点通信的 leader
+        fn leader(&self) -> Option<u64> {
+            let leaders: Vec<_> = self
+                .nodes
+                .values()
+                .filter(|n| n.raft.role() == Role::Leader)
+                .map(|n| (n.raft.term(), n.raft.id()))
+                .collect();
+            leaders.into_iter().max().map(|(_, id)| id)
+        }
+
+        fn propose(&mut self, id: u64, data: &str) -> Result<(u64, u64), KvError> {
+            let res = self.node(id).propose(data.to_string().into());
+            self.process(id);
+            self.deliver();
+            res
+        }
+    }
+
+    #[test]
+    fn raft_should_elect_one_leader() {
+        let mut network = Network::new(3);
+        let leader = network.elect();
+
+        let leaders: Vec<_> = network
+            .nodes
+            .values()
+            .filter(|n| n.raft.role() == Role::Leader)
+            .collect();
+        assert_eq!(leaders.len(), 1);
+        let term = network.node(leader).term();
+        for id in 1..=3 {
+            assert_eq!(network.node(id).leader(), leader);
+            assert_eq!(network.node(id).term(), term);
+            assert_eq!(network.node(id).leader_addr(), format!("node{}", leader));
+        }
+
+        // 没有故障时，leader 保持不变
+        network.tick(100);
+        assert_eq!(network.leader(), Some(leader));
+        assert_eq!(network.node(leader).term(), term);
+    }
+
+    #[test]
+    fn raft_should_replicate_logs() {
+        let mut network = Network::new(5);
+        let leader = network.elect();
+        for i in 0..10 {
+            network.propose(leader, &format!("cmd{}", i)).unwrap();
+        }
+        network.tick(5);
+
+        let expected: Vec<_> = (0..10).map(|i| format!("cmd{}", i)).collect();
+        for id in 1..=5 {
+            assert_eq!(network.state(id), expected);
+        }
+
+        // follower 不接受写入，并返回 leader 的地址
+        let follower = if leader == 1 { 2 } else { 1 };
+        let err = network.propose(follower, "cmd").unwrap_err();
+        assert!(matches!(err, KvError::NotLeader(addr) if addr == format!("node{}", leader)));
+    }
+
+    #[test]
+    fn raft_should_survive_leader_partition() {
+        let mut network = Network::new(3);
+        let old = network.elect();
+        network.propose(old, "a").unwrap();
+        network.tick(5);
+
+        // 旧 leader 被隔离后，写入无法提交
+        network.isolate(old);
+        network.propose(old, "lost").unwrap();
+        let new = {
+            let mut leader = old;
+            for _ in 0..100 {
+                network.tick(1);
+                match network.leader() {
+                    Some(id) if id != old => {
+                        leader = id;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            leader
+        };
+        assert_ne!(new, old);
+        network.propose(new, "b").unwrap();
+        network.tick(5);
+        // 旧 leader 在一个选举周期内联系不上多数节点，会主动退位
+        assert_eq!(network.node(old).role(), Role::Follower);
+        assert_eq!(network.state(old), ["a"]);
+
+        // 网络恢复后，旧 leader 上没有提交的日志被覆盖
+        network.heal();
+        network.tick(20);
+        for id in 1..=3 {
+            assert_eq!(network.state(id), ["a", "b"]);
+        }
+        assert_eq!(network.leader(), Some(new));
+    }
+
+    #[test]
+    fn raft_minority_should_not_commit() {
+        let mut network = Network::new(5);
+        let leader = network.elect();
+        let followers: Vec<u64> = (1..=5).filter(|id| *id != leader).collect();
+        // leader 只能和一个 follower 通信
+        for id in &followers[1..] {
+            network.isolate(*id);
+        }
+        network.propose(leader, "a").unwrap();
+        network.tick(3);
+        assert!(network.state(leader).is_empty());
+
+        network.heal();
+        network.tick(30);
+        let leader = network.leader().unwrap();
+        network.propose(leader, "b").unwrap();
+        network.tick(5);
+        for id in 1..=5 {
+            assert_eq!(network.state(id).last().map(|s| s.as_str()), Some("b"));
+        }
+    }
+
+    #[test]
+    fn raft_should_stay_consistent_under_random_partitions() {
+        let mut network = Network::new(5);
+        let mut rng = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng
+        };
+
+        for round in 0..500 {
+            if round % 25 == 0 {
+                network.heal();
+                // 随机隔离最多两个节点
+                for _ in 0..next() % 3 {
+                    network.isolate(next() % 5 + 1);
+                }
+            }
+            if let Some(leader) = network.leader() {
+                let _ = network.propose(leader, &format!("cmd{}", round));
+            }
+            network.tick(1);
+
+            // 任何时候，所有节点已经应用的日志都是同一个序列的前缀
+            let longest = network
+                .nodes
+                .values()
+                .map(|n| n.state.clone())
+                .max_by_key(|s| s.len())
+                .unwrap();
+            for node in network.nodes.values() {
+                assert_eq!(node.state[..], longest[..node.state.len()]);
+            }
+        }
+
+        network.heal();
+        network.tick(50);
+        let leader = network.leader().unwrap();
+        network.propose(leader, "last").unwrap();
+        network.tick(5);
+        let state = network.state(leader).to_vec();
+        assert_eq!(state.last().unwrap(), "last");
+        assert!(state.len() > 100);
+        for id in 1..=5 {
+            assert_eq!(network.state(id), state);
+        }
+    }
+
+    #[test]
+    fn raft_should_send_snapshot_to_lagging_follower() {
+        let mut network = Network::new(3);
+        let leader = network.elect();
+        let lagging = if leader == 3 { 2 } else { 3 };
+        network.isolate(lagging);
+        for i in 0..100 {
+            network.propose(leader, &format!("cmd{}", i)).unwrap();
+        }
+        network.tick(1);
+        let applied = network.node(leader).applied();
+        network.node(leader).compact(applied);
+        assert_eq!(network.node(leader).first_index(), applied + 1);
+
+        network.heal();
+        network.tick(20);
+        let expected: Vec<_> = (0..100).map(|i| format!("cmd{}", i)).collect();
+        assert_eq!(network.state(lagging), expected);
+        assert_eq!(network.node(lagging).members().len(), 3);
+
+        // 安装快照之后，继续通过日志复制
+        network.propose(leader, "after").unwrap();
+        network.tick(3);
+        assert_eq!(network.state(lagging).last().unwrap(), "after");
+    }
+
+    #[test]
+    fn raft_should_change_membership() {
+        let mut network = Network::new(3);
+        let leader = network.elect();
+        network.propose(leader, "a").unwrap();
+
+        // 加入一个新节点，它从 leader 收到全部日志
+        let cc = ConfChange::new(ConfChangeType::AddNode, 4, "node4");
+        network.node(leader).propose_conf_change(cc).unwrap();
+        let cc = ConfChange::new(ConfChangeType::AddNode, 5, "node5");
+        let err = network.node(leader).propose_conf_change(cc).unwrap_err();
+        assert!(matches!(err, KvError::InvalidCommand(_)));
+        network.add(Raft::new(4, ELECTION_TICKS, HEARTBEAT_TICKS));
+        network.process(leader);
+        network.tick(5);
+        assert_eq!(network.state(4), ["a"]);
+        assert_eq!(network.node(4).members().len(), 4);
+        assert_eq!(network.node(leader).members().len(), 4);
+
+        // 删除 leader 之后，剩下的节点选出新的 leader
+        let cc = ConfChange::new(ConfChangeType::RemoveNode, leader, "");
+        network.node(leader).propose_conf_change(cc).unwrap();
+        network.process(leader);
+        network.tick(3);
+        assert_eq!(network.node(leader).role(), Role::Follower);
+        network.nodes.remove(&leader);
+
+        let new = network.elect();
+        assert_ne!(new, leader);
+        network.propose(new, "b").unwrap();
+        network.tick(3);
+        for id in network.nodes.keys().copied().collect::<Vec<_>>() {
+            assert_eq!(network.state(id), ["a", "b"]);
+            assert_eq!(network.node(id).members().len(), 3);
+        }
+    }
+
+    #[test]
+    fn raft_read_index_should_need_quorum() {
+        let mut network = Network::new(3);
+        let leader = network.elect();
+        network.propose(leader, "a").unwrap();
+        network.tick(3);
+
+        network.node(leader).read_index(42).unwrap();
+        network.process(leader);
+        // 收到多数节点的 heartbeat response 之前不能读取
+        assert!(network.nodes[&leader].reads.is_empty());
+        network.deliver();
+        let committed = network.node(leader).committed();
+        assert_eq!(network.nodes[&leader].reads, vec![(42, committed)]);
+
+        // 被隔离的 leader 无法确认自己的身份，读请求不会完成
+        network.isolate(leader);
+        network.node(leader).read_index(43).unwrap();
+        network.tick(3);
+        assert_eq!(network.nodes[&leader].reads.len(), 1);
+    }
+
+    #[test]
+    fn raft_should_restore_from_persisted_state() {
+        let mut network = Network::new(1);
+        let leader = network.elect();
+        network.propose(leader, "a").unwrap();
+        let snapshot = network.node(leader).snapshot_meta();
+        network.propose(leader, "b").unwrap();
+
+        let raft = network.node(leader);
+        let hard_state = raft.hard_state();
+        let entries = raft.log.entries.clone();
+        raft.compact(snapshot.index);
+        let compacted = raft.log.entries.clone();
+
+        // 从头重放所有日志
+        let mut restored = Raft::restore(
+            1,
+            ELECTION_TICKS,
+            HEARTBEAT_TICKS,
+            hard_state.clone(),
+            &RaftSnapshot::default(),
+            entries,
+        );
+        let ready = restored.ready();
+        let data: Vec<_> = ready.committed.iter().map(|e| e.data.clone()).collect();
+        assert_eq!(data.last().unwrap(), &Bytes::from("b"));
+        assert_eq!(restored.members().len(), 1);
+        assert_eq!(restored.applied(), hard_state.commit);
+
+        // 从快照之后开始重放
+        let mut restored = Raft::restore(
+            1,
+            ELECTION_TICKS,
+            HEARTBEAT_TICKS,
+            hard_state,
+            &snapshot,
+            compacted,
+        );
+        assert_eq!(restored.first_index(), snapshot.index + 1);
+        let ready = restored.ready();
+        let data: Vec<_> = ready.committed.iter().map(|e| e.data.clone()).collect();
+        assert_eq!(data, vec![Bytes::from("b")]);
+        assert_eq!(restored.members().len(), 1);
+
+        // 重启后重新选举，继续写入
+        network.nodes.clear();
+        network.add(restored);
+        let leader = network.elect();
+        network.propose(leader, "c").unwrap();
+        assert_eq!(network.state(leader), ["c"]);
+        assert_eq!(network.node(leader).first_index(), snapshot.index + 1);
+    }
+}
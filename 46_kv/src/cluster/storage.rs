@@ -0,0 +1,256 @@
+use bytes::Bytes;
+use prost::Message;
+use std::{convert::TryFrom, sync::Arc};
+
+use crate::{
+    AsyncStorage, DumpReader, DumpWriter, KvError, RaftEntry, RaftHardState, RaftSnapshot, Value,
+};
+
+/// 保存 Raft 状态的 table，客户端不能访问，也不会出现在快照里
+pub const RAFT_TABLE: &str = "__raft";
+
+const HARD_STATE_KEY: &str = "hard_state";
+/// 快照的元数据，快照的数据就是 Storage 中的其它 table
+const SNAPSHOT_KEY: &str = "snapshot";
+const LOG_PREFIX: &str = "log.";
+
+/// 把 Raft 的日志、hard state 和快照保存在 Storage 中
+pub(crate) struct RaftStorage<Store> {
+    store: Arc<Store>,
+    /// 已经持久化的日志的范围
+    first_index: u64,
+    last_index: u64,
+}
+
+/// 重启时从 Storage 中读出来的状态
+pub(crate) struct RaftState {
+    pub hard_state: RaftHardState,
+    pub snapshot: RaftSnapshot,
+    pub entries: Vec<RaftEntry>,
+}
+
+impl<Store: AsyncStorage> RaftStorage<Store> {
+    pub fn new(store: Arc<Store>) -> Self {
+        Self {
+            store,
+            first_index: 1,
+            last_index: 0,
+        }
+    }
+
+    /// 读取之前保存的状态，从来没有保存过时返回 None
+    pub async fn load(&mut self) -> Result<Option<RaftState>, KvError> {
+        let hard_state: RaftHardState = match self.get(HARD_STATE_KEY).await? {
+            Some(hs) => hs,
+            None => return Ok(None),
+        };
+        let snapshot: RaftSnapshot = self.get(SNAPSHOT_KEY).await?.unwrap_or_default();
+
+        let store = Arc::clone(&self.store);
+        let mut pairs = store.get_all(RAFT_TABLE.into()).await?;
+        pairs.retain(|p| p.key.starts_with(LOG_PREFIX.as_bytes()));
+        // key 中的 index 补齐了长度，按 key 排序就是按 index 排序
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut entries = Vec::with_capacity(pairs.len());
+        for pair in pairs {
+            let entry = RaftEntry::decode(Bytes::try_from(pair.value.unwrap_or_default())?)?;
+            if entry.index > snapshot.index {
+                entries.push(entry);
+            }
+        }
+
+        self.first_index = snapshot.index + 1;
+        self.last_index = entries.last().map(|e| e.index).unwrap_or(snapshot.index);
+        Ok(Some(RaftState {
+            hard_state,
+            snapshot,
+            entries,
+        }))
+    }
+
+    pub async fn save_hard_state(&self, hard_state: &RaftHardState) -> Result<(), KvError> {
+        self.set(HARD_STATE_KEY.into(), hard_state).await
+    }
+
+    /// 保存新的日志，并删除被覆盖的日志之后的旧日志
+    pub async fn append(&mut self, entries: &[RaftEntry]) -> Result<(), KvError> {
+        let last = match entries.last() {
+            Some(entry) => entry.index,
+            None => return Ok(()),
+        };
+        for entry in entries {
+            self.set(log_key(entry.index), entry).await?;
+        }
+        self.delete_logs(last + 1, self.last_index).await?;
+        self.last_index = last;
+        Ok(())
+    }
+
+    /// 状态机已经包含了 snapshot.index 之前的数据，保存快照的元数据并删除这之前的日志
+    pub async fn compact(&mut self, snapshot: &RaftSnapshot) -> Result<(), KvError> {
+        let meta = RaftSnapshot {
+            data: Bytes::new(),
+            ..snapshot.clone()
+        };
+        self.set(SNAPSHOT_KEY.into(), &meta).await?;
+        let end = snapshot.index.min(self.last_index);
+        self.delete_logs(self.first_index, end).await?;
+        self.first_index = snapshot.index + 1;
+        Ok(())
+    }
+
+    /// 用 leader 发来的快照替换状态机中的所有数据，并丢弃所有日志
+    pub async fn install_snapshot(&mut self, snapshot: &RaftSnapshot) -> Result<(), KvError> {
+        let store = Arc::clone(&self.store);
+        for table in store.clone().get_tables().await? {
+            if table == RAFT_TABLE {
+                continue;
+            }
+            for pair in store.clone().get_all(table.clone()).await? {
+                store.clone().del(table.clone(), pair.key).await?;
+            }
+        }
+
+        let mut reader = DumpReader::new(&snapshot.data[..]);
+        while let Some((table, pairs)) = reader.read_section().await? {
+            for pair in pairs {
+                let value = pair.value.unwrap_or_default();
+                store.clone().set(table.clone(), pair.key, value).await?;
+            }
+        }
+
+        let last = self.last_index;
+        self.compact(snapshot).await?;
+        self.delete_logs(snapshot.index + 1, last).await?;
+        self.last_index = snapshot.index;
+        Ok(())
+    }
+
+    /// 把状态机中的数据写入 meta 对应的快照，调用者保证这期间没有应用新的日志
+    pub async fn build_snapshot(&self, meta: RaftSnapshot) -> Result<RaftSnapshot, KvError> {
+        let store = Arc::clone(&self.store);
+        let mut writer = DumpWriter::new(Vec::new());
+        for table in store.clone().get_tables().await? {
+            if table == RAFT_TABLE {
+                continue;
+            }
+            let pairs = store.clone().get_all(table.clone()).await?;
+            if !pairs.is_empty() {
+                writer.write_section(&table, &pairs).await?;
+            }
+        }
+        let data = writer.finish().await?;
+        Ok(RaftSnapshot {
+            data: data.into(),
+            ..meta
+        })
+    }
+
+    async fn delete_logs(&self, from: u64, to: u64) -> Result<(), KvError> {
+        for index in from..=to {
+            let store = Arc::clone(&self.store);
+            store.del(RAFT_TABLE.into(), log_key(index).into()).await?;
+        }
+        Ok(())
+    }
+
+    async fn get<T: Message + Default>(&self, key: &'static str) -> Result<Option<T>, KvError> {
+        let store = Arc::clone(&self.store);
+        match store.get(RAFT_TABLE.into(), key.into()).await? {
+            Some(value) => Ok(Some(T::decode(Bytes::try_from(value)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: String, msg: &impl Message) -> Result<(), KvError> {
+        let store = Arc::clone(&self.store);
+        let value: Value = Bytes::from(msg.encode_to_vec()).into();
+        store.set(RAFT_TABLE.into(), key.into(), value).await?;
+        Ok(())
+    }
+}
+
+fn log_key(index: u64) -> String {
+    format!("{}{:020}", LOG_PREFIX, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, Storage};
+
+    fn entry(term: u64, index: u64) -> RaftEntry {
+        RaftEntry {
+            term,
+            index,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn raft_storage_should_persist_logs() {
+        let store = Arc::new(MemTable::new());
+        let mut storage = RaftStorage::new(Arc::clone(&store));
+        assert!(storage.load().await.unwrap().is_none());
+
+        let hard_state = RaftHardState {
+            term: 2,
+            vote: 1,
+            commit: 3,
+        };
+        storage.save_hard_state(&hard_state).await.unwrap();
+        storage
+            .append(&(1..=5).map(|i| entry(1, i)).collect::<Vec<_>>())
+            .await
+            .unwrap();
+        // 覆盖 index 3 之后的日志，4 和 5 应该被删除
+        storage.append(&[entry(2, 3)]).await.unwrap();
+        storage
+            .compact(&RaftSnapshot {
+                index: 1,
+                term: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let mut storage = RaftStorage::new(store);
+        let state = storage.load().await.unwrap().unwrap();
+        assert_eq!(state.hard_state, hard_state);
+        assert_eq!(state.snapshot.index, 1);
+        assert_eq!(state.entries, vec![entry(1, 2), entry(2, 3)]);
+    }
+
+    #[tokio::test]
+    async fn raft_storage_should_build_and_install_snapshot() {
+        let store = Arc::new(MemTable::new());
+        store.as_ref().set("t1", "k1".into(), "v1".into()).unwrap();
+        store.as_ref().set("t2", "k2".into(), 2.into()).unwrap();
+        let mut storage = RaftStorage::new(Arc::clone(&store));
+        storage.append(&[entry(1, 1)]).await.unwrap();
+
+        let meta = RaftSnapshot {
+            index: 10,
+            term: 3,
+            ..Default::default()
+        };
+        let snapshot = storage.build_snapshot(meta).await.unwrap();
+        assert_eq!(snapshot.index, 10);
+
+        let other = Arc::new(MemTable::new());
+        other.as_ref().set("t1", "k3".into(), "v3".into()).unwrap();
+        let mut storage = RaftStorage::new(Arc::clone(&other));
+        storage.append(&[entry(1, 1)]).await.unwrap();
+        storage.install_snapshot(&snapshot).await.unwrap();
+
+        assert_eq!(other.as_ref().get("t1", b"k1").unwrap(), Some("v1".into()));
+        assert_eq!(other.as_ref().get("t2", b"k2").unwrap(), Some(2.into()));
+        assert_eq!(other.as_ref().get("t1", b"k3").unwrap(), None);
+        // 快照里不包含 Raft 自己的状态
+        let state = storage.load().await;
+        assert!(state.unwrap().is_none());
+        let logs = other.as_ref().get_all(RAFT_TABLE).unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].key, SNAPSHOT_KEY);
+    }
+}
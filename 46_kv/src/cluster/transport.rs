@@ -0,0 +1,111 @@
+use dashmap::DashMap;
+use std::time::Duration;
+use tokio::{sync::mpsc, time};
+use tracing::{debug, warn};
+
+use crate::{
+    ClientConfig, ClientTlsConfig, ClusterConfig, CommandRequest, GeneralConfig, KvClient,
+    RaftMessage, ServerTlsConfig,
+};
+
+/// 每个节点最多缓存多少条还没发出去的消息，超过时丢弃新消息，由 Raft 负责重试
+const PEER_QUEUE_SIZE: usize = 1024;
+
+/// 连接失败后，等待多久再重新连接
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// 把 Raft 消息发给其它节点。发送不需要可靠，丢失的消息由 Raft 重试
+pub trait RaftTransport: Send + Sync + 'static {
+    /// 把消息发给地址是 addr 的节点 msg.to
+    fn send(&self, msg: RaftMessage, addr: &str);
+}
+
+/// 通过 KvClient 把消息作为 CommandRequest 发给其它节点，每个节点一个后台 task
+pub struct NetworkTransport {
+    config: ClientConfig,
+    peers: DashMap<u64, Peer>,
+}
+
+struct Peer {
+    addr: String,
+    tx: mpsc::Sender<RaftMessage>,
+}
+
+impl NetworkTransport {
+    /// 使用集群配置中的传输层，TLS 时使用服务器的证书做双向认证
+    pub fn new(cluster: &ClusterConfig, tls: &ServerTlsConfig) -> Self {
+        let identity = tls.ca.as_ref().map(|_| (tls.cert.clone(), tls.key.clone()));
+        let config = ClientConfig {
+            transport: cluster.transport,
+            namespace: None,
+            general: GeneralConfig {
+                addr: String::new(),
+            },
+            tls: ClientTlsConfig {
+                domain: cluster.domain.clone(),
+                identity,
+                ca: tls.ca.clone(),
+            },
+            compression: Default::default(),
+            frame: Default::default(),
+            trace: None,
+        };
+        Self {
+            config,
+            peers: DashMap::new(),
+        }
+    }
+
+    fn connect(&self, id: u64, addr: &str) -> mpsc::Sender<RaftMessage> {
+        let (tx, rx) = mpsc::channel(PEER_QUEUE_SIZE);
+        let mut config = self.config.clone();
+        config.general.addr = addr.into();
+        tokio::spawn(run_peer(id, config, rx));
+        tx
+    }
+}
+
+impl RaftTransport for NetworkTransport {
+    fn send(&self, msg: RaftMessage, addr: &str) {
+        let id = msg.to;
+        let mut peer = self.peers.entry(id).or_insert_with(|| Peer {
+            addr: addr.into(),
+            tx: self.connect(id, addr),
+        });
+        // 节点的地址变了，之前的 task 会在 tx 被 drop 之后退出
+        if peer.addr != addr {
+            peer.addr = addr.into();
+            peer.tx = self.connect(id, addr);
+        }
+        if peer.tx.try_send(msg).is_err() {
+            debug!("Queue of node {} is full, drop raft message", id);
+        }
+    }
+}
+
+async fn run_peer(id: u64, config: ClientConfig, mut rx: mpsc::Receiver<RaftMessage>) {
+    let mut client = None;
+    while let Some(msg) = rx.recv().await {
+        if client.is_none() {
+            match KvClient::connect(&config).await {
+                Ok(c) => client = Some(c),
+                Err(e) => {
+                    warn!(
+                        "Failed to connect node {} at {}: {:?}",
+                        id, config.general.addr, e
+                    );
+                    time::sleep(RECONNECT_DELAY).await;
+                    // 等待期间积压的消息已经过时了
+                    while rx.try_recv().is_ok() {}
+                    continue;
+                }
+            }
+        }
+
+        if let Some(c) = &client {
+            if let Err(e) = c.request(CommandRequest::new_raft(msg)).await {
+                debug!("Failed to send raft message to node {}: {:?}", id, e);
+            }
+        }
+    }
+}
@@ -1,6 +1,12 @@
-use crate::KvError;
+use crate::{
+    CompressionConfig, FrameConfig, KvError, Kvpair, NamespaceConfig, ScriptConfig,
+    ServerCompressionConfig, SlowlogConfig, SnapshotConfig, TraceConfig,
+};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::{env, fs};
+
+/// 指定客户端配置文件路径的环境变量
+pub const CLIENT_CONFIG_ENV: &str = "KV_CLIENT_CONFIG";
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ServerConfig {
@@ -8,12 +14,54 @@ pub struct ServerConfig {
     pub storage: StorageConfig,
     pub tls: ServerTlsConfig,
     pub log: LogConfig,
+    #[serde(default)]
+    pub compression: ServerCompressionConfig,
+    #[serde(default)]
+    pub frame: FrameConfig,
+    /// MemTable 的内存限制，对 SledDb 无效
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    /// EVAL 脚本的执行限制
+    #[serde(default)]
+    pub script: ScriptConfig,
+    /// 慢日志的阈值和长度
+    #[serde(default)]
+    pub slowlog: SlowlogConfig,
+    /// SNAPSHOT 固定的版本的有效期
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+    /// 客户端证书绑定的 namespace 和每个 namespace 的配额
+    #[serde(default)]
+    pub namespace: NamespaceConfig,
+    /// span 的导出方式，默认发送到 jaeger
+    #[serde(default)]
+    pub trace: TraceConfig,
+    /// 监听的地址，为空时使用 general.addr 上的 TLS
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<ListenerConfig>,
+    /// 集群配置，不配置时以单机模式运行
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<ClusterConfig>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ClientConfig {
+    /// 连接服务器使用的传输层，只有 tls 会用到 tls 配置。
+    /// toml 要求普通的值在 table 之前，所以放在最前面
+    #[serde(default)]
+    pub transport: Transport,
+    /// 新打开的 stream 使用的 namespace，不配置时使用默认 namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
     pub general: GeneralConfig,
     pub tls: ClientTlsConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub frame: FrameConfig,
+    /// span 的导出方式，不配置时不导出，请求中也不带 trace context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceConfig>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -21,6 +69,33 @@ pub struct GeneralConfig {
     pub addr: String,
 }
 
+/// 传输层协议
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// TCP 之上的 TLS
+    #[default]
+    Tls,
+    /// 明文 TCP，只能监听 loopback，除非 listener 设置了 allow_plaintext
+    Tcp,
+    /// Unix domain socket，addr 是 socket 文件的路径
+    Unix,
+    /// WebSocket，数据使用 binary message 传输。没有 TLS，和 tcp 一样只能监听 loopback
+    WebSocket,
+    /// QUIC，使用 tls 配置中的证书，每个请求 stream 对应一个 QUIC stream
+    Quic,
+}
+
+/// 服务器的一个监听地址
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ListenerConfig {
+    pub transport: Transport,
+    pub addr: String,
+    /// 允许明文的 tcp / websocket 监听 loopback 以外的地址，比如前面有负责 TLS 的代理时
+    #[serde(default)]
+    pub allow_plaintext: bool,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct LogConfig {
     pub path: String,
@@ -41,6 +116,104 @@ pub enum StorageConfig {
     SledDb(String),
 }
 
+/// MemTable 的内存限制
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct MemoryConfig {
+    /// 最多使用多少字节（近似值），0 表示不限制
+    #[serde(default)]
+    pub maxmemory: usize,
+    /// 超过限制时的淘汰策略
+    #[serde(default)]
+    pub policy: EvictionPolicy,
+}
+
+/// 内存淘汰策略，和 redis 的 maxmemory-policy 一致
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// 不淘汰，写入时返回错误
+    #[default]
+    Noeviction,
+    /// 淘汰最久没有访问的 key
+    AllkeysLru,
+    /// 淘汰访问次数最少的 key
+    AllkeysLfu,
+    /// 在用 HEXPIRE 设置了过期时间的 key 中，淘汰最快过期的
+    VolatileTtl,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Tls => "tls",
+            Transport::Tcp => "tcp",
+            Transport::Unix => "unix",
+            Transport::WebSocket => "websocket",
+            Transport::Quic => "quic",
+        }
+    }
+}
+
+impl EvictionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionPolicy::Noeviction => "noeviction",
+            EvictionPolicy::AllkeysLru => "allkeys-lru",
+            EvictionPolicy::AllkeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+/// Raft 集群的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// 节点 id，在集群内唯一，不能为 0
+    pub id: u64,
+    /// 为 false 时和 peers 一起创建新集群；为 true 时加入已有的集群，
+    /// 等待 leader 执行 CLUSTER ADD 之后发来数据
+    pub join: bool,
+    /// 节点之间通信使用的传输层，使用服务器 tls 配置中的证书
+    pub transport: Transport,
+    /// transport 为 tls 时，校验其它节点证书使用的域名
+    pub domain: String,
+    /// 逻辑时钟一次 tick 的毫秒数
+    pub tick_ms: u64,
+    /// 多少个 tick 没有收到 leader 的消息后发起选举
+    pub election_ticks: u64,
+    /// leader 每隔多少个 tick 发送一次心跳
+    pub heartbeat_ticks: u64,
+    /// 已应用的日志超过多少条时生成快照并压缩日志
+    pub snapshot_threshold: u64,
+    /// 新集群的初始成员（包括自己）；加入已有集群时是已知的成员
+    pub peers: Vec<PeerConfig>,
+}
+
+/// 集群中的一个节点
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PeerConfig {
+    pub id: u64,
+    /// 其它节点连接这个节点使用的地址
+    pub addr: String,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            join: false,
+            transport: Transport::Tls,
+            domain: String::new(),
+            tick_ms: 100,
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            snapshot_threshold: 10_000,
+            peers: Vec::new(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ServerTlsConfig {
     pub cert: String,
@@ -61,6 +234,69 @@ impl ServerConfig {
         let config: Self = toml::from_str(&config)?;
         Ok(config)
     }
+
+    /// 服务器实际需要监听的地址
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if self.listeners.is_empty() {
+            vec![ListenerConfig {
+                transport: Transport::Tls,
+                addr: self.general.addr.clone(),
+                allow_plaintext: false,
+            }]
+        } else {
+            self.listeners.clone()
+        }
+    }
+
+    /// INFO 命令返回的配置摘要，不包含证书和私钥
+    pub fn info(&self) -> Vec<Kvpair> {
+        let listeners: Vec<_> = self
+            .listeners()
+            .iter()
+            .map(|l| format!("{}://{}", l.transport.as_str(), l.addr))
+            .collect();
+        let storage = match &self.storage {
+            StorageConfig::MemTable => "memtable".to_string(),
+            StorageConfig::SledDb(path) => format!("sled://{}", path),
+        };
+        let compressions: Vec<_> = self
+            .compression
+            .algorithms
+            .iter()
+            .map(|c| c.as_str())
+            .collect();
+
+        vec![
+            Kvpair::new("listeners", listeners.join(",").into()),
+            Kvpair::new("storage", storage.into()),
+            Kvpair::new("tls_client_auth", self.tls.ca.is_some().into()),
+            Kvpair::new("compression", compressions.join(",").into()),
+            Kvpair::new(
+                "compression_threshold",
+                (self.compression.threshold as i64).into(),
+            ),
+            Kvpair::new("max_frame", (self.frame.max_size as i64).into()),
+            Kvpair::new("frame_checksum", self.frame.checksum.into()),
+            Kvpair::new("maxmemory", (self.memory.maxmemory as i64).into()),
+            Kvpair::new("maxmemory_policy", self.memory.policy.as_str().into()),
+            Kvpair::new(
+                "script_max_operations",
+                (self.script.max_operations as i64).into(),
+            ),
+            Kvpair::new("script_timeout_ms", (self.script.timeout_ms as i64).into()),
+            Kvpair::new("script_max_cached", (self.script.max_cached as i64).into()),
+            Kvpair::new(
+                "slowlog_slower_than_us",
+                (self.slowlog.slower_than_us as i64).into(),
+            ),
+            Kvpair::new("slowlog_max_len", (self.slowlog.max_len as i64).into()),
+            Kvpair::new("log_path", self.log.path.as_str().into()),
+            Kvpair::new(
+                "cluster_id",
+                (self.cluster.as_ref().map(|c| c.id).unwrap_or_default() as i64).into(),
+            ),
+        ]
+    }
 }
 
 impl ClientConfig {
@@ -69,11 +305,23 @@ impl ClientConfig {
         let config: Self = toml::from_str(&config)?;
         Ok(config)
     }
+
+    /// 优先从 path 加载，否则从环境变量 KV_CLIENT_CONFIG 指定的路径加载，都没有时返回 None
+    pub fn load_from(path: Option<&str>) -> Result<Option<Self>, KvError> {
+        match path
+            .map(String::from)
+            .or_else(|| env::var(CLIENT_CONFIG_ENV).ok())
+        {
+            Some(path) => Ok(Some(Self::load(&path)?)),
+            None => Ok(None),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn server_config_should_be_loaded() {
@@ -88,4 +336,145 @@ mod tests {
             toml::from_str(include_str!("../fixtures/client.conf"));
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn compression_config_should_be_loaded() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [tls]
+            domain = "kvserver.acme.inc"
+
+            [compression]
+            algorithm = "zstd"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.compression.algorithm, crate::Compression::Zstd);
+        assert_eq!(config.compression.threshold, 1436);
+    }
+
+    #[test]
+    fn listeners_config_should_be_loaded() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(
+            config.listeners(),
+            vec![ListenerConfig {
+                transport: Transport::Tls,
+                addr: config.general.addr.clone(),
+                allow_plaintext: false,
+            }]
+        );
+
+        let listeners: HashMap<String, Vec<ListenerConfig>> = toml::from_str(
+            r#"
+            [[listeners]]
+            transport = "unix"
+            addr = "/tmp/kv.sock"
+
+            [[listeners]]
+            transport = "websocket"
+            addr = "0.0.0.0:9528"
+            allow_plaintext = true
+            "#,
+        )
+        .unwrap();
+        config.listeners = listeners["listeners"].clone();
+        let transports: Vec<_> = config.listeners().iter().map(|l| l.transport).collect();
+        assert_eq!(transports, vec![Transport::Unix, Transport::WebSocket]);
+        assert!(config.listeners()[1].allow_plaintext);
+    }
+
+    #[test]
+    fn memory_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.memory, MemoryConfig::default());
+
+        let config: MemoryConfig = toml::from_str(
+            r#"
+            maxmemory = 1048576
+            policy = "allkeys-lru"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.maxmemory, 1024 * 1024);
+        assert_eq!(config.policy, EvictionPolicy::AllkeysLru);
+    }
+
+    #[test]
+    fn cluster_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.cluster, None);
+
+        let config: ClusterConfig = toml::from_str(
+            r#"
+            id = 2
+            transport = "tcp"
+
+            [[peers]]
+            id = 1
+            addr = "127.0.0.1:9527"
+
+            [[peers]]
+            id = 2
+            addr = "127.0.0.1:9537"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.id, 2);
+        assert_eq!(config.transport, Transport::Tcp);
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers[1].addr, "127.0.0.1:9537");
+        assert_eq!(config.election_ticks, 10);
+        assert!(!config.join);
+    }
+
+    #[test]
+    fn namespace_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.namespace, NamespaceConfig::default());
+
+        let config: NamespaceConfig = toml::from_str(
+            r#"
+            [clients]
+            awesome-device-id = "devices"
+
+            [default_quota]
+            max_keys = 1000
+
+            [quotas.devices]
+            max_keys = 10
+            max_bytes = 4096
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.clients["awesome-device-id"], "devices");
+        assert_eq!(config.default_quota.max_keys, 1000);
+        assert_eq!(config.default_quota.max_bytes, 0);
+        assert_eq!(config.quotas["devices"].max_bytes, 4096);
+    }
+
+    #[test]
+    fn server_config_info_should_not_contain_secrets() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        let info = config.info();
+        assert!(info.contains(&Kvpair::new("listeners", "tls://127.0.0.1:9527".into())));
+        assert!(info.contains(&Kvpair::new("storage", "sled:///tmp/kv_server".into())));
+
+        let output = format!("{:?}", info);
+        assert!(!output.contains("PRIVATE KEY"));
+        assert!(!output.contains("CERTIFICATE"));
+    }
+
+    #[test]
+    fn client_config_should_be_loaded_from_path() {
+        let config = ClientConfig::load_from(Some("fixtures/client.conf")).unwrap();
+        assert_eq!(config.unwrap().tls.domain, "kvserver.acme.inc");
+
+        let result = ClientConfig::load_from(Some("fixtures/not_exist.conf"));
+        assert!(result.is_err());
+    }
 }
@@ -6,14 +6,30 @@ pub enum KvError {
     NotFound(String),
     #[error("Frame is larger than max size")]
     FrameError,
+    #[error("Frame checksum mismatch")]
+    ChecksumError,
     #[error("Command is invalid: `{0}`")]
     InvalidCommand(String),
     #[error("Cannot convert value {0} to {1}")]
     ConvertError(String, &'static str),
     #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
     StorageError(&'static str, String, String, String),
+    #[error("Script error: {0}")]
+    ScriptError(String),
+    #[error("OOM command not allowed when used memory > 'maxmemory'")]
+    OutOfMemory,
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Quota of namespace `{0}` exceeded")]
+    QuotaExceeded(String),
+    #[error("Snapshot {0} is not pinned or has expired")]
+    SnapshotExpired(u64),
+    #[error("Not leader, leader is `{0}`")]
+    NotLeader(String),
     #[error("Certificate parse error: error to load {0} {0}")]
     CertifcateParseError(&'static str, &'static str),
+    #[error("Certificate error: {0}")]
+    CertificateError(String),
 
     #[error("Failed to encode protobuf message")]
     EncodeError(#[from] prost::EncodeError),
@@ -23,12 +39,22 @@ pub enum KvError {
     SledError(#[from] sled::Error),
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
+    #[error("Failed to generate certificate")]
+    CertifyError(#[from] certify::CertifyError),
     #[error("TLS error")]
     TlsError(#[from] tokio_rustls::rustls::TLSError),
     #[error("Yamux Connection error")]
     YamuxConnectionError(#[from] yamux::ConnectionError),
+    #[error("QUIC connect error")]
+    QuicConnectError(#[from] quinn::ConnectError),
+    #[error("QUIC connection error")]
+    QuicConnectionError(#[from] quinn::ConnectionError),
     #[error("Parse config error")]
     ConfigError(#[from] toml::de::Error),
+    #[error("JSON error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("CSV error")]
+    CsvError(#[from] csv::Error),
 
     #[error("Internal error: {0}")]
     Internal(String),
@@ -1,33 +1,45 @@
+mod backup;
+mod cli;
+mod cluster;
 mod config;
 mod error;
+mod loadgen;
 mod network;
 mod pb;
+mod pki;
 mod service;
 mod storage;
 
+pub use backup::*;
+pub use cli::*;
+pub use cluster::*;
 pub use config::*;
 pub use error::KvError;
+pub use loadgen::*;
 pub use network::*;
 pub use pb::abi::*;
+pub use pki::*;
 pub use service::*;
 pub use storage::*;
 
 use anyhow::Result;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::client;
+use futures::future;
+use std::sync::Arc;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{info, instrument, span};
+use tracing::{info, instrument, span, warn};
 
 /// 通过配置创建 KV 服务器
 #[instrument(skip_all)]
 pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
-    let acceptor =
-        TlsServerAcceptor::new(&config.tls.cert, &config.tls.key, config.tls.ca.as_deref())?;
-
-    let addr = &config.general.addr;
     match &config.storage {
-        StorageConfig::MemTable => start_tls_server(addr, MemTable::new(), acceptor).await?,
-        StorageConfig::SledDb(path) => start_tls_server(addr, SledDb::new(path), acceptor).await?,
+        StorageConfig::MemTable => {
+            let store = MemTable::with_memory(config.memory.clone());
+            start_server(config, Mvcc::new(store, config.snapshot.clone())).await?
+        }
+        StorageConfig::SledDb(path) => {
+            let store = Mvcc::new(SledDb::new(path), config.snapshot.clone());
+            start_server(config, store).await?
+        }
     };
 
     Ok(())
@@ -35,43 +47,111 @@ pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
 
 /// 通过配置创建 KV 客户端
 #[instrument(skip_all)]
-pub async fn start_client_with_config(
-    config: &ClientConfig,
-) -> Result<YamuxCtrl<client::TlsStream<TcpStream>>> {
-    let addr = &config.general.addr;
-    let tls = &config.tls;
-
-    let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
-    let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
+pub async fn start_client_with_config(config: &ClientConfig) -> Result<YamuxCtrl<BoxedStream>> {
+    let stream = TransportConnector::new(config)?.connect().await?;
 
     // 打开一个 stream
-    Ok(YamuxCtrl::new_client(stream, None))
+    Ok(YamuxCtrl::new_client(stream, None)
+        .with_compression(config.compression.clone())
+        .with_frame(config.frame.clone())
+        .with_namespace(config.namespace.clone()))
+}
+
+async fn start_server<Store: AsyncStorage>(config: &ServerConfig, store: Store) -> Result<()> {
+    let mut inner = ServiceInner::new(store)
+        .with_script(config.script.clone())
+        .with_slowlog(config.slowlog.clone())
+        .with_namespace(config.namespace.clone())
+        .with_config_info(config.info());
+    if let Some(cluster) = &config.cluster {
+        let transport = NetworkTransport::new(cluster, &config.tls);
+        let node = ClusterNode::start(cluster, Arc::clone(inner.store()), transport).await?;
+        info!("Start cluster node {}", cluster.id);
+        inner = inner.layer(move |cmd, next| node.clone().handle(cmd, next));
+    }
+    let service: Service<Store> = inner.into();
+    let listeners = config.listeners();
+
+    // 只有存在 TLS listener 时才加载证书
+    let acceptor = match listeners.iter().any(|l| l.transport == Transport::Tls) {
+        true => {
+            let tls = &config.tls;
+            Some(TlsServerAcceptor::new(
+                &tls.cert,
+                &tls.key,
+                tls.ca.as_deref(),
+            )?)
+        }
+        false => None,
+    };
+
+    let mut tasks = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        if listener.transport == Transport::Quic {
+            let listener = QuicListener::bind(&listener.addr, &config.tls).await?;
+            info!("Start listening on {} (Quic)", listener.local_addr()?);
+            tasks.push(tokio::spawn(serve_quic(
+                listener,
+                service.clone(),
+                config.clone(),
+            )));
+            continue;
+        }
+
+        let listener = TransportListener::bind(&listener, acceptor.clone()).await?;
+        info!(
+            "Start listening on {} ({:?})",
+            listener.local_addr()?,
+            listener.transport()
+        );
+        tasks.push(tokio::spawn(serve(
+            listener,
+            service.clone(),
+            config.clone(),
+        )));
+    }
+
+    // 任何一个 listener 出错都会让服务器退出
+    let (result, _, _) = future::select_all(tasks).await;
+    result??;
+    Ok(())
 }
 
-async fn start_tls_server<Store: Storage>(
-    addr: &str,
-    store: Store,
-    acceptor: TlsServerAcceptor,
-) -> Result<()> {
-    let service: Service<Store> = ServiceInner::new(store).into();
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start listening on {}", addr);
+/// 所有 listener 接受的连接都走同样的 yamux + ProstServerStream 流程
+async fn serve<Store: AsyncStorage>(
+    listener: TransportListener,
+    service: Service<Store>,
+    config: ServerConfig,
+) -> Result<(), KvError> {
     loop {
         let root = span!(tracing::Level::INFO, "server_process");
         let _enter = root.enter();
-        let tls = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
+        let (handshake, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
 
         let svc = service.clone();
+        let (compression, frame) = (config.compression.clone(), config.frame.clone());
         tokio::spawn(async move {
-            let stream = tls.accept(stream).await.unwrap();
+            let (stream, identity) = match handshake.await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept client {:?}: {:?}", addr, e);
+                    return;
+                }
+            };
+            let active = svc.stats().connect();
             YamuxCtrl::new_server(stream, None, move |stream| {
+                // active 跟随 yamux 连接一起释放，连接断开时连接数减一
+                let _active = &active;
                 let svc1 = svc.clone();
+                let (compression, frame, addr) = (compression.clone(), frame.clone(), addr.clone());
+                let identity = identity.clone();
                 async move {
-                    let stream = ProstServerStream::new(stream.compat(), svc1.clone());
+                    let stream = ProstServerStream::new(stream.compat(), svc1.clone())
+                        .with_compression(compression)
+                        .with_frame(frame)
+                        .with_client(addr)
+                        .with_identity(identity);
                     stream.process().await.unwrap();
                     Ok(())
                 }
@@ -79,3 +159,37 @@ async fn start_tls_server<Store: Storage>(
         });
     }
 }
+
+/// QUIC 连接上的每个双向 stream 都是一个独立的 ProstServerStream，不需要 yamux
+async fn serve_quic<Store: AsyncStorage>(
+    mut listener: QuicListener,
+    service: Service<Store>,
+    config: ServerConfig,
+) -> Result<(), KvError> {
+    while let Some(handshake) = listener.accept().await {
+        let svc = service.clone();
+        let (compression, frame) = (config.compression.clone(), config.frame.clone());
+        tokio::spawn(async move {
+            let mut conn = match handshake.await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept QUIC client: {:?}", e);
+                    return;
+                }
+            };
+            let addr = conn.remote_address().to_string();
+            info!("Client {:?} connected", addr);
+
+            let _active = svc.stats().connect();
+            while let Some(stream) = conn.accept_stream().await {
+                let stream = ProstServerStream::new(stream, svc.clone())
+                    .with_compression(compression.clone())
+                    .with_frame(frame.clone())
+                    .with_client(addr.clone())
+                    .with_identity(conn.identity());
+                tokio::spawn(async move { stream.process().await.unwrap() });
+            }
+        });
+    }
+    Ok(())
+}
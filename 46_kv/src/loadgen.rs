@@ -0,0 +1,760 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use comfy_table::{presets::UTF8_FULL, Table};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use hdrhistogram::Histogram;
+use rand::{
+    distributions::Uniform, prelude::Distribution, rngs::StdRng, Rng, RngCore, SeedableRng,
+};
+use rand_distr::Zipf;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::watch,
+    task::JoinHandle,
+    time,
+};
+use tracing::warn;
+
+use crate::{
+    CommandRequest, CommandResponse, KvError, Kvpair, PipelineClient, ProstClientStream, Value,
+};
+
+/// 延迟最多记录到一个小时，单位微秒
+const MAX_LATENCY_US: u64 = 3_600_000_000;
+/// 预先写入 key 时，一个 HMSET 写入多少个 key
+const PREFILL_BATCH: u64 = 100;
+/// 发送完所有请求之后，最多等待订阅者多久来收完消息
+const DELIVERY_GRACE: Duration = Duration::from_secs(1);
+
+/// 压测的负载
+#[derive(Clone, Debug)]
+pub struct Workload {
+    /// 读写的 table
+    pub table: String,
+    /// key 的数量
+    pub keys: u64,
+    /// 选择 key 时使用的分布
+    pub distribution: KeyDistribution,
+    /// HGET 占所有请求的比例
+    pub read_ratio: f64,
+    /// PUBLISH 占所有请求的比例，剩下的是 HSET
+    pub publish_ratio: f64,
+    /// value 的大小，单位字节，在这个范围内均匀分布
+    pub value_size: RangeInclusive<usize>,
+    /// 发送请求的连接数
+    pub connections: usize,
+    /// 每个连接上同时等待 response 的请求数
+    pub pipeline: usize,
+    /// 订阅 topic 的连接数，每个 PUBLISH 都会发给所有的订阅者
+    pub subscribers: usize,
+    /// PUBLISH 和订阅使用的 topic
+    pub topic: String,
+    /// 一共发送多少个请求，0 表示不限制
+    pub requests: u64,
+    /// 最长运行多久，None 表示不限制
+    pub duration: Option<Duration>,
+    /// 开始计时之前先写入所有的 key，这样 HGET 不会读到不存在的 key
+    pub prefill: bool,
+    /// 随机数种子，种子相同时每个连接发送的请求序列相同
+    pub seed: u64,
+}
+
+/// 选择 key 的分布
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyDistribution {
+    /// 每个 key 被选中的概率相同
+    Uniform,
+    /// 第 k 个 key 被选中的概率和 1 / k^s 成正比，s 越大越集中在少数几个 key 上
+    Zipf(f64),
+}
+
+/// 压测的结果
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    /// 从开始发送请求到收到所有 response 的时间，单位秒
+    pub elapsed_secs: f64,
+    pub requests: u64,
+    pub errors: u64,
+    /// 每秒完成的请求数
+    pub throughput: f64,
+    pub latency: Latency,
+    /// 每种命令各自的结果
+    pub operations: Vec<OperationReport>,
+    /// 订阅者收到消息的情况，没有订阅者时为 None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery: Option<DeliveryReport>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OperationReport {
+    pub name: &'static str,
+    pub requests: u64,
+    pub errors: u64,
+    pub throughput: f64,
+    pub latency: Latency,
+}
+
+/// PUBLISH 的消息从发出到被订阅者收到的情况
+#[derive(Clone, Debug, Serialize)]
+pub struct DeliveryReport {
+    pub subscribers: usize,
+    /// 成功的 PUBLISH 数乘以订阅者的数量
+    pub expected: u64,
+    pub delivered: u64,
+    pub throughput: f64,
+    pub latency: Latency,
+}
+
+/// 延迟分布，单位微秒
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Latency {
+    pub min_us: u64,
+    pub mean_us: f64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+    pub max_us: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operation {
+    Read,
+    Write,
+    Publish,
+}
+
+const OPERATIONS: [Operation; 3] = [Operation::Read, Operation::Write, Operation::Publish];
+
+/// 所有 worker 共享的状态
+struct Context {
+    /// 负载开始的时间，PUBLISH 的消息中带上相对它的时间，订阅者用来计算延迟
+    start: Instant,
+    deadline: Option<Instant>,
+    requests: u64,
+    issued: AtomicU64,
+}
+
+/// 生成请求的随机数发生器，每个 worker 一个
+struct Generator {
+    rng: StdRng,
+    workload: Arc<Workload>,
+    keys: KeySampler,
+    value_size: Uniform<usize>,
+    /// 预先生成的随机字节，value 是它的一段
+    payload: Bytes,
+}
+
+enum KeySampler {
+    Uniform(Uniform<u64>),
+    Zipf(Zipf<f64>),
+}
+
+/// 一个 worker 记录的延迟和错误
+struct Recorder {
+    latency: Vec<Histogram<u64>>,
+    errors: Vec<u64>,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            table: "bench".into(),
+            keys: 10_000,
+            distribution: KeyDistribution::Uniform,
+            read_ratio: 0.9,
+            publish_ratio: 0.0,
+            value_size: 64..=64,
+            connections: 4,
+            pipeline: 16,
+            subscribers: 0,
+            topic: "bench".into(),
+            requests: 100_000,
+            duration: None,
+            prefill: true,
+            seed: 0,
+        }
+    }
+}
+
+impl Workload {
+    /// 检查负载的参数
+    pub fn validate(&self) -> Result<(), KvError> {
+        let invalid = |msg: &str| {
+            Err(KvError::InvalidCommand(format!(
+                "invalid workload: {}",
+                msg
+            )))
+        };
+        let ratio = |r: f64| (0.0..=1.0).contains(&r);
+
+        if self.keys == 0 || self.connections == 0 || self.pipeline == 0 {
+            return invalid("keys, connections and pipeline must be positive");
+        }
+        if !ratio(self.read_ratio)
+            || !ratio(self.publish_ratio)
+            || self.read_ratio + self.publish_ratio > 1.0
+        {
+            return invalid("read and publish ratios must be in [0, 1] and sum up to at most 1");
+        }
+        if self.value_size.is_empty() {
+            return invalid("value size range is empty");
+        }
+        if self.requests == 0 && self.duration.is_none() {
+            return invalid("either requests or duration must be limited");
+        }
+        if let KeyDistribution::Zipf(s) = self.distribution {
+            if s.is_nan() || s <= 0.0 {
+                return invalid("zipf exponent must be positive");
+            }
+        }
+        Ok(())
+    }
+
+    fn key(&self, n: u64) -> String {
+        format!("key:{}", n)
+    }
+}
+
+/// 执行压测。connect 每次调用都打开一个新的 stream：connections 个 stream 用来发送请求，
+/// subscribers 个 stream 用来订阅 topic
+pub async fn run_workload<S, F, Fut>(workload: &Workload, connect: F) -> Result<Report, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<ProstClientStream<S>, KvError>>,
+{
+    workload.validate()?;
+    let workload = Arc::new(workload.clone());
+    let payload = random_payload(&workload);
+
+    let mut clients = Vec::with_capacity(workload.connections);
+    for _ in 0..workload.connections {
+        clients.push(connect().await?.into_pipeline());
+    }
+    if workload.prefill {
+        prefill(&workload, &clients, &payload).await?;
+    }
+
+    let start = Instant::now();
+    let (stop, stopped) = watch::channel(false);
+    let delivered = Arc::new(AtomicU64::new(0));
+    let mut subscribers = Vec::with_capacity(workload.subscribers);
+    for _ in 0..workload.subscribers {
+        let stream = connect().await?;
+        let subscriber = subscribe(&workload.topic, stream, start, &delivered, stopped.clone());
+        subscribers.push(subscriber.await?);
+    }
+
+    // 订阅者准备好之后才开始计时
+    let begin = Instant::now();
+    let ctx = Arc::new(Context {
+        start,
+        deadline: workload.duration.map(|d| begin + d),
+        requests: workload.requests,
+        issued: AtomicU64::new(0),
+    });
+    let mut workers = Vec::with_capacity(workload.connections * workload.pipeline);
+    for (i, client) in clients.iter().enumerate() {
+        for j in 0..workload.pipeline {
+            let seed = workload
+                .seed
+                .wrapping_add((i * workload.pipeline + j) as u64);
+            let gen = Generator::new(&workload, seed, payload.clone())?;
+            workers.push(tokio::spawn(worker(client.clone(), gen, ctx.clone())));
+        }
+    }
+
+    let mut recorder = Recorder::new();
+    for result in future::join_all(workers).await {
+        let r = result.map_err(|e| KvError::Internal(format!("worker failed: {}", e)))?;
+        recorder.merge(&r);
+    }
+    let elapsed = begin.elapsed();
+
+    let delivery = match subscribers.is_empty() {
+        true => None,
+        false => {
+            let publish = recorder.index(Operation::Publish);
+            let published = recorder.latency[publish].len() - recorder.errors[publish];
+            let expected = published * subscribers.len() as u64;
+            let _ = time::timeout(DELIVERY_GRACE, async {
+                while delivered.load(Ordering::Relaxed) < expected {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await;
+
+            let _ = stop.send(true);
+            let mut latency = histogram();
+            for result in future::join_all(subscribers).await {
+                let h =
+                    result.map_err(|e| KvError::Internal(format!("subscriber failed: {}", e)))?;
+                latency.add(&h).expect("histograms have the same bounds");
+            }
+            let delivered = latency.len();
+            Some(DeliveryReport {
+                subscribers: workload.subscribers,
+                expected,
+                delivered,
+                throughput: rate(delivered, elapsed),
+                latency: Latency::from(&latency),
+            })
+        }
+    };
+
+    Ok(recorder.report(elapsed, delivery))
+}
+
+/// 每个 key 写入一个 value，分批在所有连接上同时执行
+async fn prefill(
+    workload: &Arc<Workload>,
+    clients: &[PipelineClient],
+    payload: &Bytes,
+) -> Result<(), KvError> {
+    let mut gen = Generator::new(workload, workload.seed, payload.clone())?;
+    let batches: Vec<_> = (0..workload.keys)
+        .step_by(PREFILL_BATCH as usize)
+        .enumerate()
+        .map(|(i, from)| {
+            let to = (from + PREFILL_BATCH).min(workload.keys);
+            let pairs = (from..to)
+                .map(|n| Kvpair::new(workload.key(n), gen.value()))
+                .collect();
+            let client = clients[i % clients.len()].clone();
+            (client, CommandRequest::new_hmset(&workload.table, pairs))
+        })
+        .collect();
+
+    stream::iter(batches)
+        .map(|(client, cmd)| async move { client.execute(cmd).await?.into_result() })
+        .buffer_unordered(clients.len() * workload.pipeline)
+        .try_for_each(|_| future::ready(Ok(())))
+        .await
+}
+
+/// 订阅 topic，返回的 task 在收到 stop 之后结束，得到消息从发出到收到的延迟分布
+async fn subscribe<S>(
+    topic: &str,
+    stream: ProstClientStream<S>,
+    start: Instant,
+    delivered: &Arc<AtomicU64>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<JoinHandle<Histogram<u64>>, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut stream = stream
+        .execute_streaming(&CommandRequest::new_subscribe(topic))
+        .await?;
+    let delivered = delivered.clone();
+    Ok(tokio::spawn(async move {
+        let mut latency = histogram();
+        loop {
+            let res = tokio::select! {
+                res = stream.next() => res,
+                _ = stop.changed() => break,
+            };
+            let res = match res {
+                Some(Ok(res)) => res,
+                _ => break,
+            };
+            // 消息的第一个 value 是发出时相对 start 的时间
+            if let Some(Ok(sent)) = res.values.first().map(i64::try_from) {
+                let now = start.elapsed().as_micros() as i64;
+                latency.saturating_record(now.saturating_sub(sent).max(1) as u64);
+                delivered.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        latency
+    }))
+}
+
+async fn worker(client: PipelineClient, mut gen: Generator, ctx: Arc<Context>) -> Recorder {
+    let mut recorder = Recorder::new();
+    while ctx.next() {
+        let (op, cmd) = gen.request(ctx.start);
+        let start = Instant::now();
+        let res = client.execute(cmd).await;
+        let elapsed = start.elapsed();
+        match res {
+            Ok(res) => recorder.record(op, elapsed, succeeded(op, &res)),
+            // 连接断开之后所有的请求都会失败，没有必要继续
+            Err(e) => {
+                warn!("Failed to send request: {:?}", e);
+                recorder.record(op, elapsed, false);
+                break;
+            }
+        }
+    }
+    recorder
+}
+
+/// 读取不存在的 key 返回 404，对压测来说不算错误
+fn succeeded(op: Operation, res: &CommandResponse) -> bool {
+    res.status == 200 || (op == Operation::Read && res.status == 404)
+}
+
+impl Context {
+    /// 是否还可以发送下一个请求
+    fn next(&self) -> bool {
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            return false;
+        }
+        self.requests == 0 || self.issued.fetch_add(1, Ordering::Relaxed) < self.requests
+    }
+}
+
+impl Generator {
+    fn new(workload: &Arc<Workload>, seed: u64, payload: Bytes) -> Result<Self, KvError> {
+        let keys = match workload.distribution {
+            KeyDistribution::Uniform => KeySampler::Uniform(Uniform::new(0, workload.keys)),
+            KeyDistribution::Zipf(s) => KeySampler::Zipf(
+                Zipf::new(workload.keys, s)
+                    .map_err(|e| KvError::InvalidCommand(format!("invalid workload: {}", e)))?,
+            ),
+        };
+        Ok(Self {
+            rng: StdRng::seed_from_u64(seed),
+            workload: workload.clone(),
+            keys,
+            value_size: Uniform::from(workload.value_size.clone()),
+            payload,
+        })
+    }
+
+    fn request(&mut self, start: Instant) -> (Operation, CommandRequest) {
+        let workload = self.workload.clone();
+        let r: f64 = self.rng.gen();
+        if r < workload.read_ratio {
+            let key = workload.key(self.key());
+            (
+                Operation::Read,
+                CommandRequest::new_hget(&workload.table, key),
+            )
+        } else if r < workload.read_ratio + workload.publish_ratio {
+            let sent = start.elapsed().as_micros() as i64;
+            let data = vec![sent.into(), self.value()];
+            (
+                Operation::Publish,
+                CommandRequest::new_publish(&workload.topic, data),
+            )
+        } else {
+            let key = workload.key(self.key());
+            let cmd = CommandRequest::new_hset(&workload.table, key, self.value());
+            (Operation::Write, cmd)
+        }
+    }
+
+    fn key(&mut self) -> u64 {
+        match &self.keys {
+            KeySampler::Uniform(d) => d.sample(&mut self.rng),
+            // zipf 生成的是 [1, n] 之间的排名，排名第一的是 key:0
+            KeySampler::Zipf(d) => d.sample(&mut self.rng) as u64 - 1,
+        }
+    }
+
+    fn value(&mut self) -> Value {
+        let size = self.value_size.sample(&mut self.rng);
+        self.payload.slice(..size).into()
+    }
+}
+
+fn random_payload(workload: &Workload) -> Bytes {
+    let mut payload = vec![0u8; *workload.value_size.end()];
+    StdRng::seed_from_u64(workload.seed).fill_bytes(&mut payload);
+    payload.into()
+}
+
+fn histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).expect("histogram bounds are valid")
+}
+
+fn rate(count: u64, elapsed: Duration) -> f64 {
+    match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => count as f64 / secs,
+        _ => 0.0,
+    }
+}
+
+impl Recorder {
+    fn new() -> Self {
+        Self {
+            latency: OPERATIONS.iter().map(|_| histogram()).collect(),
+            errors: vec![0; OPERATIONS.len()],
+        }
+    }
+
+    fn index(&self, op: Operation) -> usize {
+        OPERATIONS.iter().position(|o| *o == op).unwrap()
+    }
+
+    fn record(&mut self, op: Operation, elapsed: Duration, ok: bool) {
+        let i = self.index(op);
+        self.latency[i].saturating_record((elapsed.as_micros() as u64).max(1));
+        if !ok {
+            self.errors[i] += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &Recorder) {
+        for i in 0..OPERATIONS.len() {
+            self.latency[i]
+                .add(&other.latency[i])
+                .expect("histograms have the same bounds");
+            self.errors[i] += other.errors[i];
+        }
+    }
+
+    fn report(&self, elapsed: Duration, delivery: Option<DeliveryReport>) -> Report {
+        let mut total = histogram();
+        let mut operations = Vec::new();
+        for (i, op) in OPERATIONS.iter().enumerate() {
+            let latency = &self.latency[i];
+            if latency.is_empty() {
+                continue;
+            }
+            total.add(latency).expect("histograms have the same bounds");
+            operations.push(OperationReport {
+                name: op.name(),
+                requests: latency.len(),
+                errors: self.errors[i],
+                throughput: rate(latency.len(), elapsed),
+                latency: Latency::from(latency),
+            });
+        }
+
+        Report {
+            elapsed_secs: elapsed.as_secs_f64(),
+            requests: total.len(),
+            errors: self.errors.iter().sum(),
+            throughput: rate(total.len(), elapsed),
+            latency: Latency::from(&total),
+            operations,
+            delivery,
+        }
+    }
+}
+
+impl Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::Read => "hget",
+            Operation::Write => "hset",
+            Operation::Publish => "publish",
+        }
+    }
+}
+
+impl From<&Histogram<u64>> for Latency {
+    fn from(h: &Histogram<u64>) -> Self {
+        if h.is_empty() {
+            return Self::default();
+        }
+        Self {
+            min_us: h.min(),
+            mean_us: h.mean(),
+            p50_us: h.value_at_quantile(0.5),
+            p90_us: h.value_at_quantile(0.9),
+            p99_us: h.value_at_quantile(0.99),
+            p999_us: h.value_at_quantile(0.999),
+            max_us: h.max(),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests in {:.2}s, {} errors, {:.1} req/s",
+            self.requests, self.elapsed_secs, self.errors, self.throughput
+        )?;
+
+        let ms = |us: f64| format!("{:.3}", us / 1000.0);
+        let mut table = Table::new();
+        table.load_preset(UTF8_FULL).set_header(vec![
+            "", "count", "errors", "rate/s", "mean ms", "p50 ms", "p90 ms", "p99 ms", "p99.9 ms",
+            "max ms",
+        ]);
+        let mut row = |name: &str, count: u64, errors: String, rate: f64, l: &Latency| {
+            table.add_row(vec![
+                name.to_string(),
+                count.to_string(),
+                errors,
+                format!("{:.1}", rate),
+                ms(l.mean_us),
+                ms(l.p50_us as f64),
+                ms(l.p90_us as f64),
+                ms(l.p99_us as f64),
+                ms(l.p999_us as f64),
+                ms(l.max_us as f64),
+            ]);
+        };
+        for op in &self.operations {
+            row(
+                op.name,
+                op.requests,
+                op.errors.to_string(),
+                op.throughput,
+                &op.latency,
+            );
+        }
+        row(
+            "total",
+            self.requests,
+            self.errors.to_string(),
+            self.throughput,
+            &self.latency,
+        );
+        if let Some(d) = &self.delivery {
+            let name = format!("delivery ({} subscribers)", d.subscribers);
+            let missing = format!("{} missing", d.expected.saturating_sub(d.delivered));
+            row(&name, d.delivered, missing, d.throughput, &d.latency);
+        }
+        write!(f, "{}", table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ProstServerStream, Service, ServiceInner};
+    use tokio::io::DuplexStream;
+
+    #[tokio::test]
+    async fn workload_should_send_all_requests() {
+        let service = service();
+        let workload = Workload {
+            keys: 100,
+            read_ratio: 0.5,
+            value_size: 8..=32,
+            connections: 2,
+            pipeline: 4,
+            requests: 500,
+            ..Default::default()
+        };
+        let report = run_workload(&workload, || connect(&service)).await.unwrap();
+
+        assert_eq!(report.requests, 500);
+        assert_eq!(report.errors, 0);
+        assert!(report.throughput > 0.0);
+        let names: Vec<_> = report.operations.iter().map(|op| op.name).collect();
+        assert_eq!(names, ["hget", "hset"]);
+        let sum: u64 = report.operations.iter().map(|op| op.requests).sum();
+        assert_eq!(sum, 500);
+        assert!(report.delivery.is_none());
+
+        // 预先写入了所有的 key
+        let res = service
+            .execute(CommandRequest::new_hgetall("bench"))
+            .next()
+            .await;
+        assert_eq!(res.unwrap().pairs.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn subscribers_should_receive_all_messages() {
+        let workload = Workload {
+            read_ratio: 0.0,
+            publish_ratio: 1.0,
+            connections: 1,
+            pipeline: 2,
+            subscribers: 3,
+            requests: 50,
+            prefill: false,
+            ..Default::default()
+        };
+        let service = service();
+        let report = run_workload(&workload, || connect(&service)).await.unwrap();
+
+        let delivery = report.delivery.unwrap();
+        assert_eq!(delivery.subscribers, 3);
+        assert_eq!(delivery.expected, 150);
+        assert_eq!(delivery.delivered, 150);
+        assert!(delivery.latency.max_us >= delivery.latency.min_us);
+    }
+
+    #[test]
+    fn zipf_should_prefer_first_keys() {
+        let workload = Arc::new(Workload {
+            keys: 1000,
+            distribution: KeyDistribution::Zipf(1.2),
+            ..Default::default()
+        });
+        let mut gen = Generator::new(&workload, 1, random_payload(&workload)).unwrap();
+        let hits = (0..10_000).filter(|_| gen.key() < 10).count();
+        // 均匀分布时只有 1% 左右落在前 10 个 key 上
+        assert!(hits > 5_000, "{}", hits);
+        assert!((0..10_000).all(|_| gen.key() < 1000));
+    }
+
+    #[test]
+    fn invalid_workload_should_be_rejected() {
+        let invalid = [
+            Workload {
+                read_ratio: 0.8,
+                publish_ratio: 0.3,
+                ..Default::default()
+            },
+            Workload {
+                connections: 0,
+                ..Default::default()
+            },
+            Workload {
+                requests: 0,
+                duration: None,
+                ..Default::default()
+            },
+            Workload {
+                distribution: KeyDistribution::Zipf(0.0),
+                ..Default::default()
+            },
+        ];
+        for workload in invalid {
+            assert!(workload.validate().is_err(), "{:?}", workload);
+        }
+        assert!(Workload::default().validate().is_ok());
+    }
+
+    #[test]
+    fn report_should_be_formatted_as_text_and_json() {
+        let mut recorder = Recorder::new();
+        recorder.record(Operation::Read, Duration::from_micros(100), true);
+        recorder.record(Operation::Write, Duration::from_micros(300), false);
+        let report = recorder.report(Duration::from_secs(1), None);
+        assert_eq!(report.requests, 2);
+        assert_eq!(report.errors, 1);
+
+        let text = report.to_string();
+        assert!(text.starts_with("2 requests in 1.00s, 1 errors"));
+        assert!(text.contains("hget") && text.contains("total"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["operations"][1]["name"], "hset");
+        assert_eq!(json["operations"][1]["latency"]["max_us"], 300);
+        assert!(json.get("delivery").is_none());
+    }
+
+    fn service() -> Service {
+        ServiceInner::new(MemTable::new()).into()
+    }
+
+    async fn connect(service: &Service) -> Result<ProstClientStream<DuplexStream>, KvError> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(ProstServerStream::new(server, service.clone()).process());
+        Ok(ProstClientStream::new(client))
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    convert::TryFrom,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream, Future, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::Compat;
+use tracing::warn;
+
+use crate::{
+    script_sha, BoxedStream, ClientConfig, CommandRequest, CommandResponse, KvError,
+    ProstClientStream, TransportConnector, Value, YamuxCtrl,
+};
+
+/// 连接池中最多保留多少个空闲的 stream
+const MAX_IDLE_STREAMS: usize = 16;
+
+type ClientStream = ProstClientStream<Compat<yamux::Stream>>;
+type Connector<S> =
+    Box<dyn Fn() -> BoxFuture<'static, Result<YamuxCtrl<S>, KvError>> + Send + Sync>;
+
+/// 带类型的异步 KV 客户端。
+/// 复用 yamux stream，连接断开后在下一次调用时自动重连，可以 clone 后在多个 task 中使用
+pub struct KvClient<S> {
+    inner: Arc<ClientInner<S>>,
+}
+
+struct ClientInner<S> {
+    connector: Connector<S>,
+    conn: Mutex<Connection<S>>,
+    // 保证同一时间只有一个 task 在重连
+    reconnecting: tokio::sync::Mutex<()>,
+}
+
+struct Connection<S> {
+    ctrl: Option<YamuxCtrl<S>>,
+    // 每次重连后加一，用来丢弃属于旧连接的 stream
+    generation: u64,
+    idle: Vec<ClientStream>,
+}
+
+/// 订阅的结果，每个 publish 的 value 都会被转换成 T
+pub struct Subscription<T> {
+    pub id: u32,
+    inner: Pin<Box<dyn Stream<Item = Result<T, KvError>> + Send>>,
+}
+
+impl<S> Clone for KvClient<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl KvClient<BoxedStream> {
+    /// 使用客户端配置中的传输层连接服务器
+    pub async fn connect(config: &ClientConfig) -> Result<Self, KvError> {
+        let connector = TransportConnector::new(config)?;
+        let config = config.clone();
+
+        let f = move || {
+            let connector = connector.clone();
+            let config = config.clone();
+            async move {
+                let stream = connector.connect().await?;
+                Ok(YamuxCtrl::new_client(stream, None)
+                    .with_compression(config.compression)
+                    .with_frame(config.frame)
+                    .with_namespace(config.namespace))
+            }
+        };
+        Self::with_connector(f).await
+    }
+}
+
+impl<S> KvClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// 使用自定义的 connector 创建客户端，connector 负责建立连接并创建 YamuxCtrl，
+    /// 重连时也会调用它
+    pub async fn with_connector<F, Fut>(f: F) -> Result<Self, KvError>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<YamuxCtrl<S>, KvError>> + Send + 'static,
+    {
+        let client = Self {
+            inner: Arc::new(ClientInner {
+                connector: Box::new(move || Box::pin(f())),
+                conn: Mutex::new(Connection {
+                    ctrl: None,
+                    generation: 0,
+                    idle: Vec::new(),
+                }),
+                reconnecting: tokio::sync::Mutex::new(()),
+            }),
+        };
+        client.inner.reconnect(0).await?;
+        Ok(client)
+    }
+
+    /// 获取一个值，key 不存在时返回 None。
+    /// key 可以是 &str、String，也可以是 &[u8]、Bytes 这样的二进制数据
+    pub async fn get<T>(&self, table: &str, key: impl AsRef<[u8]>) -> Result<Option<T>, KvError>
+    where
+        T: TryFrom<Value, Error = KvError>,
+    {
+        let cmd = CommandRequest::new_hget(table, to_key(key));
+        match self.call(&cmd, true).await {
+            Ok(res) => first_value(res).map(T::try_from).transpose(),
+            Err(KvError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 获取多个值，不存在的 key 对应 None
+    pub async fn mget<T, K>(&self, table: &str, keys: &[K]) -> Result<Vec<Option<T>>, KvError>
+    where
+        T: TryFrom<Value, Error = KvError>,
+        K: AsRef<[u8]>,
+    {
+        let keys = keys.iter().map(to_key).collect();
+        let cmd = CommandRequest::new_hmget(table, keys);
+        let res = self.call(&cmd, true).await?;
+        res.values
+            .into_iter()
+            .map(|v| non_nil(v).map(T::try_from).transpose())
+            .collect()
+    }
+
+    /// 设置一个值，返回之前的值
+    pub async fn set(
+        &self,
+        table: &str,
+        key: impl AsRef<[u8]>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hset(table, to_key(key), value.into());
+        // 写操作在连接断开时可能已经执行过了，不自动重试
+        let res = self.call(&cmd, false).await?;
+        Ok(first_value(res))
+    }
+
+    /// 删除一个值，返回被删除的值
+    pub async fn del(&self, table: &str, key: impl AsRef<[u8]>) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hdel(table, to_key(key));
+        let res = self.call(&cmd, false).await?;
+        Ok(first_value(res))
+    }
+
+    /// key 是否存在
+    pub async fn exists(&self, table: &str, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        let cmd = CommandRequest::new_hexist(table, to_key(key));
+        let res = self.call(&cmd, true).await?;
+        match first_value(res) {
+            Some(v) => bool::try_from(v),
+            None => Err(KvError::Internal("Didn't get any value".into())),
+        }
+    }
+
+    /// 给 key 设置过期时间，返回 key 是否存在
+    pub async fn expire(
+        &self,
+        table: &str,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        let cmd = CommandRequest::new_hexpire(table, to_key(key), ttl);
+        let res = self.call(&cmd, false).await?;
+        match first_value(res) {
+            Some(v) => bool::try_from(v),
+            None => Err(KvError::Internal("Didn't get any value".into())),
+        }
+    }
+
+    /// 向 topic 发布数据
+    pub async fn publish(&self, topic: &str, data: Vec<Value>) -> Result<(), KvError> {
+        let cmd = CommandRequest::new_publish(topic, data);
+        self.call(&cmd, false).await?;
+        Ok(())
+    }
+
+    /// 订阅 topic，每个订阅独占一个 stream
+    pub async fn subscribe<T>(&self, topic: &str) -> Result<Subscription<T>, KvError>
+    where
+        T: TryFrom<Value, Error = KvError> + Send + 'static,
+    {
+        let (stream, generation) = self.inner.open_stream().await?;
+        let cmd = CommandRequest::new_subscribe(topic);
+        let result = match stream.execute_streaming(&cmd).await {
+            Ok(v) => v,
+            Err(e) => {
+                self.inner.mark_broken(generation);
+                return Err(e);
+            }
+        };
+
+        let id = result.id;
+        let inner = stream::unfold(
+            result,
+            |mut r| async move { r.next().await.map(|v| (v, r)) },
+        );
+        let inner = inner.flat_map(|res| {
+            let items: Vec<_> = match res.and_then(CommandResponse::into_result) {
+                Ok(res) => res.values.into_iter().map(T::try_from).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(items)
+        });
+
+        Ok(Subscription {
+            id,
+            inner: Box::pin(inner),
+        })
+    }
+
+    /// 取消订阅
+    pub async fn unsubscribe(&self, topic: &str, id: u32) -> Result<(), KvError> {
+        let cmd = CommandRequest::new_unsubscribe(topic, id);
+        self.call(&cmd, false).await?;
+        Ok(())
+    }
+
+    /// 在服务器上执行脚本。先只发送脚本的 hash，服务器没有缓存时再发送完整的脚本
+    pub async fn eval(&self, script: &str, args: Vec<Value>) -> Result<Vec<Value>, KvError> {
+        let cmd = CommandRequest::new_evalsha(script_sha(script), args.clone());
+        // 脚本可能有写操作，不自动重试
+        let res = match self.call(&cmd, false).await {
+            Err(KvError::NotFound(_)) => {
+                let cmd = CommandRequest::new_eval(script, args);
+                self.call(&cmd, false).await?
+            }
+            res => res?,
+        };
+        Ok(res.values)
+    }
+
+    /// 执行任意一个只返回一个 response 的命令，不会自动重试
+    pub async fn request(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.call(&cmd, false).await
+    }
+
+    /// 执行一个命令，把非 2xx 的 response 转换成 KvError。
+    /// 只读命令在连接断开时会重连并重试一次
+    async fn call(&self, cmd: &CommandRequest, retry: bool) -> Result<CommandResponse, KvError> {
+        let res = match self.inner.execute(cmd).await {
+            Err(e) if retry => {
+                warn!("Failed to execute {:?}: {:?}, retrying", cmd, e);
+                self.inner.execute(cmd).await
+            }
+            v => v,
+        };
+        res?.into_result()
+    }
+}
+
+impl<S> ClientInner<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let (mut stream, generation) = self.get_stream().await?;
+        match stream.execute_unary(cmd).await {
+            Ok(res) => {
+                self.put_stream(stream, generation);
+                Ok(res)
+            }
+            Err(e) => {
+                // stream 上的错误都是传输层的错误，认为连接已经不可用
+                self.mark_broken(generation);
+                Err(e)
+            }
+        }
+    }
+
+    /// 优先从连接池中取 stream，没有时打开一个新的
+    async fn get_stream(&self) -> Result<(ClientStream, u64), KvError> {
+        {
+            let mut conn = self.conn.lock().unwrap();
+            if let Some(stream) = conn.idle.pop() {
+                return Ok((stream, conn.generation));
+            }
+        }
+        self.open_stream().await
+    }
+
+    fn put_stream(&self, stream: ClientStream, generation: u64) {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.generation == generation && conn.idle.len() < MAX_IDLE_STREAMS {
+            conn.idle.push(stream);
+        }
+    }
+
+    async fn open_stream(&self) -> Result<(ClientStream, u64), KvError> {
+        let current = {
+            let conn = self.conn.lock().unwrap();
+            (conn.ctrl.clone(), conn.generation)
+        };
+
+        let (mut ctrl, generation) = match current {
+            (Some(ctrl), generation) => (ctrl, generation),
+            (None, generation) => self.reconnect(generation).await?,
+        };
+
+        match ctrl.open_stream().await {
+            Ok(stream) => Ok((stream, generation)),
+            Err(e) => {
+                self.mark_broken(generation);
+                Err(e)
+            }
+        }
+    }
+
+    /// 连接已经断开时重新建立连接。generation 是调用者看到的连接，
+    /// 如果在等待期间别的 task 已经重连了，直接使用新的连接
+    async fn reconnect(&self, generation: u64) -> Result<(YamuxCtrl<S>, u64), KvError> {
+        let _guard = self.reconnecting.lock().await;
+        {
+            let conn = self.conn.lock().unwrap();
+            if let (Some(ctrl), true) = (&conn.ctrl, conn.generation != generation) {
+                return Ok((ctrl.clone(), conn.generation));
+            }
+        }
+
+        let ctrl = (self.connector)().await?;
+
+        let mut conn = self.conn.lock().unwrap();
+        conn.generation += 1;
+        conn.ctrl = Some(ctrl.clone());
+        conn.idle.clear();
+        Ok((ctrl, conn.generation))
+    }
+
+    /// 标记连接已断开，下一次调用时会重连
+    fn mark_broken(&self, generation: u64) {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.generation != generation {
+            return;
+        }
+        conn.idle.clear();
+        if let Some(mut ctrl) = conn.ctrl.take() {
+            // 确保旧的 yamux 连接被关闭，而不是留在后台
+            tokio::spawn(async move { ctrl.close().await });
+        }
+    }
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = Result<T, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// Value::default() 表示不存在的值
+fn non_nil(v: Value) -> Option<Value> {
+    v.value.is_some().then_some(v)
+}
+
+fn first_value(res: CommandResponse) -> Option<Value> {
+    res.values.into_iter().next().and_then(non_nil)
+}
+
+fn to_key(key: impl AsRef<[u8]>) -> Bytes {
+    Bytes::copy_from_slice(key.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Compression, CompressionConfig, FrameConfig, MemTable, ProstServerStream, Service,
+        ServiceInner,
+    };
+    use anyhow::Result;
+    use bytes::Bytes;
+    use std::{
+        net::{Shutdown, SocketAddr},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time,
+    };
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+    #[tokio::test]
+    async fn kv_client_typed_api_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        let client = connect(addr).await?;
+
+        assert_eq!(client.set("t1", "k1", 42).await?, None);
+        assert_eq!(client.set("t1", "k2", "hello").await?, None);
+        assert_eq!(client.set("t1", "k1", 43).await?, Some(42.into()));
+
+        assert_eq!(client.get::<i64>("t1", "k1").await?, Some(43));
+        assert_eq!(client.get::<String>("t1", "k2").await?.unwrap(), "hello");
+        assert_eq!(client.get::<i64>("t1", "k3").await?, None);
+        assert!(matches!(
+            client.get::<Bytes>("t1", "k1").await,
+            Err(KvError::ConvertError(_, "Binary"))
+        ));
+
+        let values: Vec<Option<i64>> = client.mget("t1", &["k1", "k3"]).await?;
+        assert_eq!(values, vec![Some(43), None]);
+
+        assert!(client.exists("t1", "k1").await?);
+        assert_eq!(client.del("t1", "k1").await?, Some(43.into()));
+        assert!(!client.exists("t1", "k1").await?);
+        assert_eq!(client.del("t1", "k1").await?, None);
+
+        // 二进制 key，比如 UUID 的 16 个字节
+        let id = [0x9fu8, 0, 0xff, b':', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        assert_eq!(client.set("t1", id, 1).await?, None);
+        assert_eq!(client.get::<i64>("t1", &id[..]).await?, Some(1));
+        let values: Vec<Option<i64>> = client.mget("t1", &[&id[..], b"k3"]).await?;
+        assert_eq!(values, vec![Some(1), None]);
+        assert!(client.exists("t1", Bytes::copy_from_slice(&id)).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kv_client_eval_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        let client = connect(addr).await?;
+
+        let script = r#"let v = get("t1", "n"); set("t1", "n", v + ARGS[0]); v + ARGS[0]"#;
+        client.set("t1", "n", 1).await?;
+        // 第一次服务器没有缓存，会发送完整的脚本；第二次只发送 hash
+        assert_eq!(client.eval(script, vec![2.into()]).await?, vec![3.into()]);
+        assert_eq!(client.eval(script, vec![3.into()]).await?, vec![6.into()]);
+        assert!(client.eval("let x = ", vec![]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kv_client_should_map_errors() -> Result<()> {
+        let addr = start_server().await?;
+        let client = connect(addr).await?;
+
+        let result = client.unsubscribe("chat", 9527).await;
+        match result {
+            Err(KvError::NotFound(msg)) => assert_eq!(msg, "subscription 9527"),
+            v => panic!("unexpected result: {:?}", v.map(|_| ())),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kv_client_subscribe_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        let client = connect(addr).await?;
+
+        let mut sub = client.subscribe::<i64>("chat").await?;
+        assert!(sub.id > 0);
+
+        client.publish("chat", vec![1.into(), 2.into()]).await?;
+        assert_eq!(sub.next().await.unwrap()?, 1);
+        assert_eq!(sub.next().await.unwrap()?, 2);
+
+        client.unsubscribe("chat", sub.id).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kv_client_with_compression_and_checksum_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        for algorithm in Compression::ALL {
+            let config = CompressionConfig {
+                algorithm,
+                threshold: 64,
+            };
+            let frame = FrameConfig {
+                checksum: true,
+                ..Default::default()
+            };
+            let client = connect_with(addr, config, frame).await?;
+
+            let value = Bytes::from(vec![1u8; 4096]);
+            client.set("t1", "k1", value.clone()).await?;
+            assert_eq!(client.get::<Bytes>("t1", "k1").await?, Some(value));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kv_client_should_reuse_streams() -> Result<()> {
+        let addr = start_server().await?;
+        let client = connect(addr).await?;
+
+        for i in 0..10 {
+            client.set("t1", "k1", i).await?;
+        }
+        assert_eq!(client.inner.conn.lock().unwrap().idle.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kv_client_should_reconnect() -> Result<()> {
+        let addr = start_server().await?;
+
+        // 记录每个连接，以便在测试中从外部把它断开
+        let sockets = Arc::new(Mutex::new(Vec::new()));
+        let count = Arc::new(AtomicUsize::new(0));
+        let (sockets1, count1) = (sockets.clone(), count.clone());
+        let f = move || {
+            let (sockets, count) = (sockets1.clone(), count1.clone());
+            async move {
+                let stream = TcpStream::connect(addr).await?.into_std()?;
+                sockets.lock().unwrap().push(stream.try_clone()?);
+                count.fetch_add(1, Ordering::SeqCst);
+                Ok(YamuxCtrl::new_client(TcpStream::from_std(stream)?, None))
+            }
+        };
+        let client = KvClient::with_connector(f).await?;
+
+        client.set("t1", "k1", "v1").await?;
+        for s in sockets.lock().unwrap().iter() {
+            s.shutdown(Shutdown::Both)?;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+
+        // 只读命令会自动重连并重试
+        let v: Option<String> = client.get("t1", "k1").await?;
+        assert_eq!(v.unwrap(), "v1");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    async fn connect(addr: SocketAddr) -> Result<KvClient<TcpStream>, KvError> {
+        connect_with(addr, Default::default(), Default::default()).await
+    }
+
+    async fn connect_with(
+        addr: SocketAddr,
+        compression: CompressionConfig,
+        frame: FrameConfig,
+    ) -> Result<KvClient<TcpStream>, KvError> {
+        let f = move || {
+            let (compression, frame) = (compression.clone(), frame.clone());
+            async move {
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                Ok(YamuxCtrl::new_client(stream, None)
+                    .with_compression(compression)
+                    .with_frame(frame))
+            }
+        };
+        KvClient::with_connector(f).await
+    }
+
+    async fn start_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                stream.set_nodelay(true).unwrap();
+                let svc = service.clone();
+                YamuxCtrl::new_server(stream, None, move |s| {
+                    let svc = svc.clone();
+                    async move {
+                        // 连接被强制断开时 process 会返回错误，这里忽略它
+                        let _ = ProstServerStream::new(s.compat(), svc).process().await;
+                        Ok(())
+                    }
+                });
+            }
+        });
+
+        Ok(addr)
+    }
+}
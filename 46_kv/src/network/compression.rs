@@ -0,0 +1,234 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use bytes::{BufMut, BytesMut};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+
+use crate::KvError;
+
+/// 如果 payload 超过了 1436 字节，就做压缩
+pub const COMPRESSION_LIMIT: usize = 1436;
+/// 压缩算法占用长度 4 字节中最高的两个 bit。
+/// gzip 使用最高位，和只支持 gzip 的旧版本兼容
+const COMPRESSION_SHIFT: usize = 30;
+const COMPRESSION_MASK: usize = 0b11 << COMPRESSION_SHIFT;
+/// zstd 解压时允许的最大 window（8M），默认压缩级别使用的 window 远小于它
+const ZSTD_WINDOW_LOG_MAX: u32 = 23;
+
+/// frame 使用的压缩算法
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+/// 压缩相关的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// 客户端希望使用的算法，会在打开 stream 时和服务器协商
+    pub algorithm: Compression,
+    /// payload 超过这个大小才压缩
+    pub threshold: usize,
+}
+
+/// 服务器端的压缩配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ServerCompressionConfig {
+    /// 允许客户端协商使用的算法，不在其中时退回到 gzip
+    pub algorithms: Vec<Compression>,
+    /// payload 超过这个大小才压缩
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: Compression::Gzip,
+            threshold: COMPRESSION_LIMIT,
+        }
+    }
+}
+
+impl Default for ServerCompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithms: Compression::ALL.to_vec(),
+            threshold: COMPRESSION_LIMIT,
+        }
+    }
+}
+
+impl Compression {
+    pub const ALL: [Compression; 4] = [
+        Compression::Zstd,
+        Compression::Lz4,
+        Compression::Gzip,
+        Compression::None,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// 写入 frame 头部的 bit
+    pub(crate) fn to_header(self) -> usize {
+        let flag = match self {
+            Compression::None => 0b00,
+            Compression::Gzip => 0b10,
+            Compression::Lz4 => 0b01,
+            Compression::Zstd => 0b11,
+        };
+        flag << COMPRESSION_SHIFT
+    }
+
+    /// 从 frame 头部取出压缩算法
+    pub(crate) fn from_header(header: usize) -> Self {
+        match (header & COMPRESSION_MASK) >> COMPRESSION_SHIFT {
+            0b00 => Compression::None,
+            0b10 => Compression::Gzip,
+            0b01 => Compression::Lz4,
+            _ => Compression::Zstd,
+        }
+    }
+
+    /// 把 data 压缩后写入 buf
+    pub fn compress(&self, data: &[u8], buf: &mut BytesMut) -> Result<(), KvError> {
+        match self {
+            Compression::None => buf.put_slice(data),
+            Compression::Gzip => {
+                // 处理 gzip 压缩，具体可以参考 flate2 文档
+                let mut encoder = GzEncoder::new(buf.writer(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?;
+            }
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(buf.writer());
+                encoder.write_all(data)?;
+                encoder
+                    .finish()
+                    .map_err(|e| KvError::Internal(format!("lz4: {}", e)))?;
+            }
+            Compression::Zstd => zstd::stream::copy_encode(data, buf.writer(), 0)?,
+        }
+        Ok(())
+    }
+
+    /// 解压缩 data，结果追加到 buf 中。解压后超过 limit 字节时返回错误，避免压缩炸弹
+    pub fn decompress(&self, data: &[u8], buf: &mut Vec<u8>, limit: usize) -> Result<(), KvError> {
+        // 多读一个字节，用来判断是否超出了 limit
+        let take = limit as u64 + 1;
+        let n = match self {
+            Compression::None => data.take(take).read_to_end(buf)?,
+            Compression::Gzip => GzDecoder::new(data).take(take).read_to_end(buf)?,
+            Compression::Lz4 => lz4_flex::frame::FrameDecoder::new(data)
+                .take(take)
+                .read_to_end(buf)?,
+            Compression::Zstd => {
+                let mut decoder = zstd::stream::read::Decoder::new(data)?;
+                // 限制 window 大小，否则恶意的 frame 可以让 decoder 分配上百 M 内存
+                decoder.window_log_max(ZSTD_WINDOW_LOG_MAX)?;
+                decoder.take(take).read_to_end(buf)?
+            }
+        };
+
+        if n > limit {
+            return Err(KvError::FrameError);
+        }
+        Ok(())
+    }
+}
+
+impl ServerCompressionConfig {
+    /// 从客户端给出的算法（按优先级排列）中选出第一个服务器允许的
+    pub fn negotiate(&self, offered: &[String]) -> Compression {
+        offered
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .find(|c| self.algorithms.contains(c))
+            .unwrap_or(Compression::Gzip)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Compression::ALL
+            .iter()
+            .find(|c| c.as_str() == s)
+            .copied()
+            .ok_or_else(|| KvError::ConvertError(s.into(), "Compression"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_decompress_should_work() {
+        let data = b"hello world! ".repeat(200);
+        for c in Compression::ALL {
+            let mut buf = BytesMut::new();
+            c.compress(&data, &mut buf).unwrap();
+            if c != Compression::None {
+                assert!(buf.len() < data.len());
+            }
+
+            let mut result = Vec::new();
+            c.decompress(&buf, &mut result, data.len()).unwrap();
+            assert_eq!(result, data);
+
+            // 解压后超出 limit 要报错
+            let mut result = Vec::new();
+            let err = c.decompress(&buf, &mut result, data.len() - 1);
+            assert!(matches!(err, Err(KvError::FrameError)));
+        }
+    }
+
+    #[test]
+    fn compression_header_should_work() {
+        for c in Compression::ALL {
+            let header = 1024 | c.to_header();
+            assert_eq!(Compression::from_header(header), c);
+        }
+        // gzip 和旧版本的 compression bit 一致
+        assert_eq!(Compression::Gzip.to_header(), 1 << 31);
+    }
+
+    #[test]
+    fn server_should_negotiate_allowed_compression() {
+        let config = ServerCompressionConfig {
+            algorithms: vec![Compression::Lz4, Compression::None],
+            ..Default::default()
+        };
+        let offer = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(config.negotiate(&offer(&["zstd", "lz4"])), Compression::Lz4);
+        assert_eq!(
+            config.negotiate(&offer(&["brotli", "none"])),
+            Compression::None
+        );
+        assert_eq!(config.negotiate(&offer(&["zstd"])), Compression::Gzip);
+    }
+}
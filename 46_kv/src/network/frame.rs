@@ -1,115 +1,220 @@
-use std::io::{Read, Write};
+use std::cmp::min;
 
-use crate::{CommandRequest, CommandResponse, KvError};
+use crate::{CommandRequest, CommandResponse, Compression, KvError, Kvpair, TableHeader};
 use bytes::{Buf, BufMut, BytesMut};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
+use super::compression::COMPRESSION_LIMIT;
+
 /// 长度整个占用 4 个字节
 pub const LEN_LEN: usize = 4;
-/// 长度占 31 bit，所以最大的 frame 是 2G
-const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
-/// 如果 payload 超过了 1436 字节，就做压缩
-const COMPRESSION_LIMIT: usize = 1436;
-/// 代表压缩的 bit（整个长度 4 字节的最高位）
-const COMPRESSION_BIT: usize = 1 << 31;
+/// 代表 frame 最后 4 字节是 CRC32C 校验和的 bit
+const CHECKSUM_BIT: usize = 1 << 29;
+/// 长度占 29 bit，所以最大的 frame 是 512M - 1，再大就会和 CHECKSUM_BIT 冲突
+const MAX_FRAME: usize = CHECKSUM_BIT - 1;
+/// 默认允许接收的最大 frame 是 64M
+pub const DEFAULT_MAX_FRAME: usize = 64 * 1024 * 1024;
+/// CRC32C 校验和的长度
+const CHECKSUM_LEN: usize = 4;
+/// 每次最多从 stream 读取 64k，这样内存随着数据真正到达才增长，
+/// 而不是一开始就按 header 里声称的长度分配
+pub(crate) const READ_CHUNK: usize = 64 * 1024;
+
+/// frame 相关的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FrameConfig {
+    /// 允许接收的最大 frame，解压缩后的大小同样受这个限制
+    pub max_size: usize,
+    /// 是否在 frame 后附加 CRC32C。客户端在协商时请求；
+    /// 服务器为 true 时，所有协商过的 stream 都会启用
+    pub checksum: bool,
+}
+
+/// encode frame 时使用的参数
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameOptions {
+    /// 压缩算法
+    pub compression: Compression,
+    /// payload 超过这个大小才压缩
+    pub threshold: usize,
+    /// 是否附加 CRC32C 校验和
+    pub checksum: bool,
+}
+
+impl Default for FrameConfig {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_FRAME,
+            checksum: false,
+        }
+    }
+}
+
+impl Default for FrameOptions {
+    fn default() -> Self {
+        Self {
+            compression: Compression::Gzip,
+            threshold: COMPRESSION_LIMIT,
+            checksum: false,
+        }
+    }
+}
 
 /// 处理 Frame 的 encode/decode
 pub trait FrameCoder
 where
     Self: Message + Sized + Default,
 {
-    /// 把一个 Message encode 成一个 frame
+    /// 把一个 Message encode 成一个 frame，超过 1436 字节时使用 gzip 压缩
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, &FrameOptions::default())
+    }
+
+    /// 把一个 Message 按照 options encode 成一个 frame，追加到 buf 中
+    fn encode_frame_with(&self, buf: &mut BytesMut, options: &FrameOptions) -> Result<(), KvError> {
         let size = self.encoded_len();
 
-        if size > MAX_FRAME {
+        if size >= CHECKSUM_BIT {
             return Err(KvError::FrameError);
         }
 
-        // 我们先写入长度，如果需要压缩，再重写压缩后的长度
-        buf.put_u32(size as _);
+        // 先写入一个占位的 header，等 payload 写完再回填
+        let start = buf.len();
+        buf.put_u32(0);
 
-        if size > COMPRESSION_LIMIT {
+        let compression = match size > options.threshold {
+            true => options.compression,
+            false => Compression::None,
+        };
+
+        if compression != Compression::None {
             let mut buf1 = Vec::with_capacity(size);
             self.encode(&mut buf1)?;
-
-            // BytesMut 支持逻辑上的 split（之后还能 unsplit）
-            // 所以我们先把长度这 4 字节拿走，清除
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
-
-            // 处理 gzip 压缩，具体可以参考 flate2 文档
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&buf1[..])?;
-
-            // 压缩完成后，从 gzip encoder 中把 BytesMut 再拿回来
-            let payload = encoder.finish()?.into_inner();
-            debug!("Encode a frame: size {}({})", size, payload.len());
-
-            // 写入压缩后的长度
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
-
-            // 把 BytesMut 再合并回来
-            buf.unsplit(payload);
-
-            Ok(())
+            compression.compress(&buf1[..], buf)?;
         } else {
             self.encode(buf)?;
-            Ok(())
         }
+
+        if options.checksum {
+            let crc = crc32c::crc32c(&buf[start + LEN_LEN..]);
+            buf.put_u32(crc);
+        }
+
+        let len = buf.len() - start - LEN_LEN;
+        debug!("Encode a frame: size {}({}) {}", size, len, compression);
+        let header = match encode_header(len, compression, options.checksum) {
+            Ok(v) => v,
+            Err(e) => {
+                buf.truncate(start);
+                return Err(e);
+            }
+        };
+
+        // 回填 header
+        buf[start..start + LEN_LEN].copy_from_slice(&header.to_be_bytes());
+        Ok(())
     }
 
     /// 把一个完整的 frame decode 成一个 Message
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
-        // 先取 4 字节，从中拿出长度和 compression bit
+        Self::decode_frame_with(buf, MAX_FRAME)
+    }
+
+    /// 把一个完整的 frame decode 成一个 Message，解压缩后不能超过 max_size
+    fn decode_frame_with(buf: &mut BytesMut, max_size: usize) -> Result<Self, KvError> {
+        if buf.len() < LEN_LEN {
+            return Err(KvError::FrameError);
+        }
+
+        // 先取 4 字节，从中拿出长度，压缩算法和是否有校验和
         let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
+        let (len, compression, checksum) = decode_header(header);
+        debug!("Got a frame: msg len {}, compression {}", len, compression);
 
-        if compressed {
+        if len >= CHECKSUM_BIT || len > buf.len() {
+            return Err(KvError::FrameError);
+        }
+        let mut data = buf.split_to(len);
+
+        if checksum {
+            if len < CHECKSUM_LEN {
+                return Err(KvError::FrameError);
+            }
+            let trailer = data.split_off(len - CHECKSUM_LEN);
+            if crc32c::crc32c(&data[..]) != (&trailer[..]).get_u32() {
+                return Err(KvError::ChecksumError);
+            }
+        }
+
+        if compression != Compression::None {
             // 解压缩
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut buf1)?;
-            buf.advance(len);
+            let mut buf1 = Vec::with_capacity(min(data.len() * 2, max_size));
+            compression.decompress(&data[..], &mut buf1, max_size)?;
 
             // decode 成相应的消息
-            Ok(Self::decode(&buf1[..buf1.len()])?)
+            Ok(Self::decode(&buf1[..])?)
+        } else if data.len() > max_size {
+            Err(KvError::FrameError)
         } else {
-            let msg = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(msg)
+            Ok(Self::decode(data)?)
         }
     }
 }
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
+impl FrameCoder for Kvpair {}
+impl FrameCoder for TableHeader {}
+
+/// 把长度，压缩算法和是否带有校验和组合成 header。
+/// 长度必须小于 CHECKSUM_BIT，否则会把校验和的 bit 置上，同时长度被截断
+pub fn encode_header(len: usize, compression: Compression, checksum: bool) -> Result<u32, KvError> {
+    if len >= CHECKSUM_BIT {
+        return Err(KvError::FrameError);
+    }
 
-pub fn decode_header(header: usize) -> (usize, bool) {
-    let len = header & !COMPRESSION_BIT;
-    let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
-    (len, compressed)
+    let mut header = compression.to_header() | len;
+    if checksum {
+        header |= CHECKSUM_BIT;
+    }
+    Ok(header as u32)
+}
+
+/// 从 header 中拆出长度，压缩算法，以及是否带有校验和
+pub fn decode_header(header: usize) -> (usize, Compression, bool) {
+    let len = header & (CHECKSUM_BIT - 1);
+    let checksum = header & CHECKSUM_BIT == CHECKSUM_BIT;
+    (len, Compression::from_header(header), checksum)
 }
 
-/// 从 stream 中读取一个完整的 frame
-pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
+/// 从 stream 中读取一个完整的 frame，超过 max_size 的 frame 在分配内存之前就会被拒绝
+pub async fn read_frame<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    max_size: usize,
+) -> Result<(), KvError>
 where
     S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
-    let (len, _compressed) = decode_header(header);
-    // 如果没有这么大的内存，就分配至少一个 frame 的内存，保证它可用
-    buf.reserve(LEN_LEN + len);
+    let (len, _compression, _checksum) = decode_header(header);
+    if len > max_size {
+        return Err(KvError::FrameError);
+    }
+
     buf.put_u32(header as _);
-    // advance_mut 是 unsafe 的原因是，从当前位置 pos 到 pos + len，
-    // 这段内存目前没有初始化。我们就是为了 reserve 这段内存，然后从 stream
-    // 里读取，读取完，它就是初始化的。所以，我们这么用是安全的
-    unsafe { buf.advance_mut(len) };
-    stream.read_exact(&mut buf[LEN_LEN..]).await?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = min(remaining, READ_CHUNK);
+        let start = buf.len();
+        buf.resize(start + n, 0);
+        stream.read_exact(&mut buf[start..]).await?;
+        remaining -= n;
+    }
     Ok(())
 }
 
@@ -118,6 +223,7 @@ mod tests {
     use super::*;
     use crate::{utils::DummyStream, Value};
     use bytes::Bytes;
+    use proptest::prelude::*;
 
     #[test]
     fn command_request_encode_decode_should_work() {
@@ -163,6 +269,43 @@ mod tests {
         assert_eq!(res, res1);
     }
 
+    #[test]
+    fn command_response_encode_decode_with_compression_should_work() {
+        let value: Value = Bytes::from(vec![0u8; 4096]).into();
+        let res: CommandResponse = value.into();
+
+        for c in Compression::ALL {
+            let mut buf = BytesMut::new();
+            let options = FrameOptions {
+                compression: c,
+                threshold: 1024,
+                checksum: false,
+            };
+            res.encode_frame_with(&mut buf, &options).unwrap();
+
+            let header = (&buf[..LEN_LEN]).get_u32() as usize;
+            assert_eq!(decode_header(header).1, c);
+
+            let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
+            assert_eq!(res, res1);
+        }
+    }
+
+    #[test]
+    fn small_frame_should_not_be_compressed() {
+        let mut buf = BytesMut::new();
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        let options = FrameOptions {
+            compression: Compression::Zstd,
+            threshold: 1024,
+            checksum: false,
+        };
+        cmd.encode_frame_with(&mut buf, &options).unwrap();
+
+        let header = (&buf[..LEN_LEN]).get_u32() as usize;
+        assert_eq!(decode_header(header).1, Compression::None);
+    }
+
     #[tokio::test]
     async fn read_frame_should_work() {
         let mut buf = BytesMut::new();
@@ -171,12 +314,144 @@ mod tests {
         let mut stream = DummyStream { buf };
 
         let mut data = BytesMut::new();
-        read_frame(&mut stream, &mut data).await.unwrap();
+        read_frame(&mut stream, &mut data, DEFAULT_MAX_FRAME)
+            .await
+            .unwrap();
 
         let cmd1 = CommandRequest::decode_frame(&mut data).unwrap();
         assert_eq!(cmd, cmd1);
     }
 
+    #[test]
+    fn checksum_frame_should_work() {
+        let value: Value = Bytes::from(vec![0u8; 4096]).into();
+        let res: CommandResponse = value.into();
+        let options = FrameOptions {
+            checksum: true,
+            ..Default::default()
+        };
+
+        let mut buf = BytesMut::new();
+        res.encode_frame_with(&mut buf, &options).unwrap();
+        let header = (&buf[..LEN_LEN]).get_u32() as usize;
+        assert!(decode_header(header).2);
+
+        let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(res, res1);
+    }
+
+    #[test]
+    fn corrupted_frame_should_fail_checksum() {
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        let options = FrameOptions {
+            checksum: true,
+            ..Default::default()
+        };
+
+        let mut buf = BytesMut::new();
+        cmd.encode_frame_with(&mut buf, &options).unwrap();
+        // 翻转 payload 中的一个 bit
+        buf[LEN_LEN + 2] ^= 0x01;
+
+        let err = CommandRequest::decode_frame(&mut buf);
+        assert!(matches!(err, Err(KvError::ChecksumError)));
+    }
+
+    #[test]
+    fn truncated_frame_should_be_rejected() {
+        let mut buf = BytesMut::new();
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        cmd.encode_frame(&mut buf).unwrap();
+        buf.truncate(buf.len() - 1);
+
+        let err = CommandRequest::decode_frame(&mut buf);
+        assert!(matches!(err, Err(KvError::FrameError)));
+        let err = CommandRequest::decode_frame(&mut BytesMut::from(&[0u8, 1][..]));
+        assert!(matches!(err, Err(KvError::FrameError)));
+    }
+
+    #[test]
+    fn decompressed_frame_over_limit_should_be_rejected() {
+        let value: Value = Bytes::from(vec![0u8; 64 * 1024]).into();
+        let res: CommandResponse = value.into();
+        let mut buf = BytesMut::new();
+        res.encode_frame(&mut buf).unwrap();
+
+        // 压缩后很小，但是解压后超过了限制
+        assert!(buf.len() < 1024);
+        let err = CommandResponse::decode_frame_with(&mut buf, 1024);
+        assert!(matches!(err, Err(KvError::FrameError)));
+    }
+
+    #[test]
+    fn header_should_reject_len_at_checksum_bit() {
+        for c in Compression::ALL {
+            for checksum in [false, true] {
+                let header = encode_header(MAX_FRAME, c, checksum).unwrap();
+                assert_eq!(decode_header(header as usize), (MAX_FRAME, c, checksum));
+
+                let err = encode_header(1 << 29, c, checksum);
+                assert!(matches!(err, Err(KvError::FrameError)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn read_frame_should_reject_oversized_frame() {
+        // header 声称有 500M 数据
+        let mut buf = BytesMut::new();
+        buf.put_u32(500 * 1024 * 1024);
+        let mut stream = DummyStream { buf };
+
+        let mut data = BytesMut::new();
+        let err = read_frame(&mut stream, &mut data, DEFAULT_MAX_FRAME).await;
+        assert!(matches!(err, Err(KvError::FrameError)));
+        assert_eq!(data.capacity(), 0);
+    }
+
+    #[tokio::test]
+    async fn read_frame_should_not_trust_declared_length() {
+        // header 声称有 32M 数据，但实际只有几个字节
+        let mut buf = BytesMut::new();
+        buf.put_u32(32 * 1024 * 1024);
+        buf.put_slice(b"hello");
+        let mut stream = DummyStream { buf };
+
+        let mut data = BytesMut::new();
+        let err = read_frame(&mut stream, &mut data, DEFAULT_MAX_FRAME).await;
+        assert!(matches!(err, Err(KvError::IoError(_))));
+        assert!(data.capacity() <= 2 * READ_CHUNK);
+    }
+
+    proptest! {
+        #[test]
+        fn decode_frame_should_not_panic_on_random_data(data: Vec<u8>) {
+            let mut buf = BytesMut::from(&data[..]);
+            let _ = CommandRequest::decode_frame_with(&mut buf, 64 * 1024);
+        }
+
+        #[test]
+        fn decode_frame_should_not_panic_on_random_payload(
+            header in any::<u32>(),
+            payload: Vec<u8>,
+        ) {
+            // 让长度和 payload 一致，这样才能走到解压缩和校验和的逻辑
+            let header = (header as usize & !(CHECKSUM_BIT - 1)) | payload.len();
+            let mut buf = BytesMut::new();
+            buf.put_u32(header as _);
+            buf.put_slice(&payload);
+            let _ = CommandResponse::decode_frame_with(&mut buf, 64 * 1024);
+        }
+
+        #[test]
+        fn read_frame_should_not_panic_on_random_data(data: Vec<u8>) {
+            let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let mut stream = DummyStream { buf: BytesMut::from(&data[..]) };
+            let mut buf = BytesMut::new();
+            let _ = rt.block_on(read_frame(&mut stream, &mut buf, 64 * 1024));
+        }
+    }
+
     fn is_compressed(data: &[u8]) -> bool {
         if let [v] = data[..1] {
             v >> 7 == 1
@@ -1,24 +1,53 @@
+mod client;
+mod compression;
 mod frame;
 mod multiplex;
+mod pipeline;
+mod quic;
 mod stream;
 mod stream_result;
 mod tls;
+mod trace;
+mod transport;
+mod websocket;
 
-pub use frame::{read_frame, FrameCoder};
+pub use client::{KvClient, Subscription};
+pub use compression::{Compression, CompressionConfig, ServerCompressionConfig};
+pub use frame::{read_frame, FrameCoder, FrameConfig, FrameOptions, DEFAULT_MAX_FRAME};
 pub use multiplex::YamuxCtrl;
-pub use stream::ProstStream;
+pub use pipeline::PipelineClient;
+pub use quic::{QuicConnection, QuicCtrl, QuicListener, QuicStream};
+pub use stream::{ProstStream, SplitProstStream};
 pub use stream_result::StreamResult;
 pub use tls::{TlsClientConnector, TlsServerAcceptor};
-
-use crate::{CommandRequest, CommandResponse, KvError, Service, Storage};
-use futures::{SinkExt, StreamExt};
+pub use trace::{TraceConfig, TraceExporter};
+pub use transport::{AsyncStream, BoxedStream, Handshake, TransportConnector, TransportListener};
+pub use websocket::WsStream;
+
+use crate::{
+    command_request::RequestData, AsyncStorage, CommandRequest, CommandResponse, KvError, Service,
+    Value,
+};
+use futures::{stream::SelectAll, SinkExt, StreamExt};
+use std::{convert::TryFrom, sync::Arc};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::info;
+use tracing::{info, info_span, instrument};
+
+/// 一个 stream 上最多同时执行多少个带 id 的请求，达到上限后暂停读取新的请求
+const MAX_PIPELINED: usize = 128;
 
 /// 处理服务器端的某个 accept 下来的 socket 的读写
 pub struct ProstServerStream<S, Store> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service<Store>,
+    compression: ServerCompressionConfig,
+    frame: FrameConfig,
+    // 客户端的地址，记录在慢日志中
+    client: Option<Arc<str>>,
+    // 客户端证书的 CN
+    identity: Option<Arc<str>>,
+    // 当前使用的 namespace，None 是默认 namespace
+    namespace: Option<Arc<str>>,
 }
 
 /// 处理客户端 socket 的读写
@@ -29,24 +58,135 @@ pub struct ProstClientStream<S> {
 impl<S, Store> ProstServerStream<S, Store>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    Store: Storage,
+    Store: AsyncStorage,
 {
     pub fn new(stream: S, service: Service<Store>) -> Self {
         Self {
             inner: ProstStream::new(stream),
             service,
+            compression: Default::default(),
+            frame: Default::default(),
+            client: None,
+            identity: None,
+            namespace: None,
         }
     }
 
+    /// 设置客户端的地址
+    pub fn with_client(mut self, client: impl Into<String>) -> Self {
+        self.client = Some(Arc::from(client.into()));
+        self
+    }
+
+    /// 设置客户端证书的 CN，证书绑定了 namespace 时 stream 一开始就使用这个 namespace
+    pub fn with_identity(mut self, identity: Option<String>) -> Self {
+        self.namespace = self.service.namespaces().bound(identity.as_deref());
+        self.identity = identity.map(Arc::from);
+        self
+    }
+
+    /// 设置允许协商的压缩算法和压缩阈值
+    pub fn with_compression(mut self, config: ServerCompressionConfig) -> Self {
+        self.inner.set_threshold(config.threshold);
+        self.compression = config;
+        self
+    }
+
+    /// 设置最大 frame 和是否启用校验和
+    pub fn with_frame(mut self, config: FrameConfig) -> Self {
+        self.inner.set_max_frame(config.max_size);
+        self.frame = config;
+        self
+    }
+
     pub async fn process(mut self) -> Result<(), KvError> {
+        let _active = self.service.stats().open_stream();
         let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
-            info!("Got a new command: {:?}", cmd);
-            let mut res = self.service.execute(cmd);
-            while let Some(data) = res.next().await {
-                stream.send(&data).await.unwrap();
+        let mut first = true;
+        // 带 id 的请求并发执行，它们的 response stream 合并在一起，先产生的先发送。
+        // 发送 response 的时候不会读取新的请求，客户端需要在发送请求的同时读取 response
+        // （PipelineClient 的读写在不同的 task 中），否则双方都在等对方读取时会卡住
+        let mut pipelined = SelectAll::new();
+        loop {
+            tokio::select! {
+                cmd = stream.next(), if pipelined.len() < MAX_PIPELINED => {
+                    let cmd = match cmd {
+                        Some(Ok(cmd)) => cmd,
+                        _ => break,
+                    };
+                    info!("Got a new command: {:?}", cmd);
+
+                    // 第一个请求是 HELLO 时，协商之后的压缩算法和校验和
+                    if let (true, Some(RequestData::Hello(hello))) = (first, &cmd.request_data) {
+                        let compression = self.compression.negotiate(&hello.compressions);
+                        let checksum = hello.checksum || self.frame.checksum;
+                        let values = vec![Value::from(compression.as_str()), checksum.into()];
+                        stream.send(&values.into()).await?;
+                        stream.set_compression(compression);
+                        stream.set_checksum(checksum);
+                        first = false;
+                        continue;
+                    }
+                    first = false;
+
+                    // SELECT 只影响之后的请求，已经在执行的请求继续使用之前的 namespace
+                    if let Some(RequestData::Select(select)) = &cmd.request_data {
+                        let identity = self.identity.as_deref();
+                        let selected = self.service.namespaces().select(identity, &select.namespace);
+                        let mut res = match selected {
+                            Ok(namespace) => {
+                                self.namespace = namespace;
+                                CommandResponse::ok()
+                            }
+                            Err(e) => e.into(),
+                        };
+                        res.id = cmd.id;
+                        stream.send(&res).await?;
+                        self.service.after_send(&res);
+                        continue;
+                    }
+
+                    // 客户端带了 trace context 时，处理请求的 span 和客户端的 span 属于同一个 trace
+                    let id = cmd.id;
+                    let span = info_span!("server_request", id);
+                    trace::set_parent(&span, &cmd);
+                    let (client, namespace) = (self.client.clone(), self.namespace.clone());
+                    let service = &self.service;
+                    let mut res = span.in_scope(|| service.execute_in(cmd, client, namespace));
+                    if id != 0 {
+                        let res = res.map(move |data| {
+                            let mut data = Arc::try_unwrap(data).unwrap_or_else(|v| (*v).clone());
+                            data.id = id;
+                            data
+                        });
+                        pipelined.push(res);
+                        continue;
+                    }
+
+                    while let Some(data) = res.next().await {
+                        stream.send(&data).await?;
+                        self.service.after_send(&data);
+                    }
+                }
+                // 达到 MAX_PIPELINED 时读取请求的分支被禁用，这个分支不能因为模式不匹配而被禁用，
+                // 否则 select! 的所有分支都被禁用。所有的 response stream 都结束时，
+                // pipelined 变空，下一轮循环重新开始读取请求
+                data = pipelined.next(), if !pipelined.is_empty() => {
+                    if let Some(data) = data {
+                        stream.send(&data).await?;
+                        self.service.after_send(&data);
+                    }
+                }
             }
         }
+
+        // 客户端不再发送命令后，把还在执行的请求的 response 发完
+        while let Some(data) = pipelined.next().await {
+            stream.send(&data).await?;
+            self.service.after_send(&data);
+        }
+        // 主动关闭 stream，让客户端知道所有 response 都已发送
+        stream.close().await?;
         // info!("Client {:?} disconnected", self.addr);
         Ok(())
     }
@@ -62,12 +202,79 @@ where
         }
     }
 
+    /// 设置压缩的阈值
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.inner.set_threshold(threshold);
+    }
+
+    /// 设置允许接收的最大 frame
+    pub fn set_max_frame(&mut self, max_frame: usize) {
+        self.inner.set_max_frame(max_frame);
+    }
+
+    /// 按照客户端的配置设置新打开的 stream，需要时和服务器协商
+    pub async fn configure(
+        &mut self,
+        compression: &CompressionConfig,
+        frame: &FrameConfig,
+    ) -> Result<(), KvError> {
+        self.set_max_frame(frame.max_size);
+
+        // 使用 gzip 且不需要校验和时和旧版本一样，不需要协商
+        match (compression.algorithm, frame.checksum) {
+            (Compression::Gzip, false) => self.set_threshold(compression.threshold),
+            _ => {
+                self.negotiate(compression, frame.checksum).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 和服务器协商压缩算法和是否使用校验和，应该在 stream 上发送的第一个请求。
+    /// 旧版本的服务器不认识 HELLO，此时继续使用 gzip，不使用校验和
+    pub async fn negotiate(
+        &mut self,
+        config: &CompressionConfig,
+        checksum: bool,
+    ) -> Result<FrameOptions, KvError> {
+        let cmd = CommandRequest::new_hello(vec![config.algorithm.to_string()], checksum);
+        let res = self.execute_unary(&cmd).await?;
+
+        let mut options = FrameOptions {
+            threshold: config.threshold,
+            ..Default::default()
+        };
+        if let Ok(res) = res.into_result() {
+            let mut values = res.values.into_iter();
+            if let Some(v) = values.next() {
+                options.compression = String::try_from(v)?.parse()?;
+            }
+            // 只支持压缩协商的服务器不会返回校验和
+            if let Some(v) = values.next() {
+                options.checksum = bool::try_from(v)?;
+            }
+        }
+
+        self.inner.set_threshold(options.threshold);
+        self.inner.set_compression(options.compression);
+        self.inner.set_checksum(options.checksum);
+        Ok(options)
+    }
+
+    /// 切换这个 stream 使用的 namespace，空字符串表示默认 namespace
+    pub async fn select(&mut self, namespace: &str) -> Result<(), KvError> {
+        let cmd = CommandRequest::new_select(namespace);
+        self.execute_unary(&cmd).await?.into_result()?;
+        Ok(())
+    }
+
+    #[instrument(name = "client_request", skip_all)]
     pub async fn execute_unary(
         &mut self,
         cmd: &CommandRequest,
     ) -> Result<CommandResponse, KvError> {
         let stream = &mut self.inner;
-        stream.send(cmd).await?;
+        stream.send(&trace::traced(cmd)).await?;
 
         match stream.next().await {
             Some(v) => v,
@@ -75,14 +282,34 @@ where
         }
     }
 
+    #[instrument(name = "client_request", skip_all)]
     pub async fn execute_streaming(self, cmd: &CommandRequest) -> Result<StreamResult, KvError> {
         let mut stream = self.inner;
 
-        stream.send(cmd).await?;
+        stream.send(&trace::traced(cmd)).await?;
         stream.close().await?;
 
         StreamResult::new(stream).await
     }
+
+    /// 转换成 PipelineClient，之后可以在这个 stream 上同时发出多个请求
+    pub fn into_pipeline(self) -> PipelineClient {
+        PipelineClient::new(self.inner)
+    }
+
+    /// 发送 DUMP 这样会返回多个 CommandResponse 的命令，服务器发送完后会关闭 stream
+    #[instrument(name = "client_request", skip_all)]
+    pub async fn execute_dump(
+        self,
+        cmd: &CommandRequest,
+    ) -> Result<ProstStream<S, CommandResponse, CommandRequest>, KvError> {
+        let mut stream = self.inner;
+
+        stream.send(&trace::traced(cmd)).await?;
+        stream.close().await?;
+
+        Ok(stream)
+    }
 }
 
 #[cfg(test)]
@@ -142,7 +369,7 @@ mod tests {
     use std::net::SocketAddr;
 
     use super::*;
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::{assert_res_ok, Kvpair, MemTable, NamespaceConfig, ServiceInner, Value};
     use anyhow::Result;
     use bytes::Bytes;
     use tokio::net::{TcpListener, TcpStream};
@@ -199,6 +426,132 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_server_dump_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        client.execute_unary(&cmd).await?;
+
+        let cmd = CommandRequest::new_dump(vec![]);
+        let data: Vec<_> = client.execute_dump(&cmd).await?.collect().await;
+        assert_eq!(data.len(), 1);
+        let res = data[0].as_ref().unwrap();
+        assert_res_ok(res, &["t1".into()], &[Kvpair::new("k1", "v1".into())]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_negotiate_compression_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let config = CompressionConfig {
+            algorithm: Compression::Zstd,
+            threshold: 64,
+        };
+        let options = client.negotiate(&config, true).await?;
+        assert_eq!(options.compression, Compression::Zstd);
+        assert!(options.checksum);
+
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        let cmd = CommandRequest::new_hset("t2", "k2", v.clone());
+        client.execute_unary(&cmd).await?;
+
+        let cmd = CommandRequest::new_hget("t2", "k2");
+        let res = client.execute_unary(&cmd).await?;
+        assert_res_ok(&res, &[v], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn negotiate_with_old_server_should_fallback_to_gzip() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        // 模拟不认识 HELLO 的旧版本服务器
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = ProstStream::<_, CommandRequest, CommandResponse>::new(stream);
+            while let Some(Ok(_)) = stream.next().await {
+                let res: CommandResponse = KvError::InvalidCommand("no data".into()).into();
+                stream.send(&res).await.unwrap();
+            }
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let config = CompressionConfig {
+            algorithm: Compression::Lz4,
+            ..Default::default()
+        };
+        let options = client.negotiate(&config, true).await?;
+        assert_eq!(options.compression, Compression::Gzip);
+        assert!(!options.checksum);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn after_send_hook_should_be_called() -> anyhow::Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_after_send(move |res: &CommandResponse| tx.send(res.clone()).unwrap())
+            .into();
+
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+
+        let mut client = ProstClientStream::new(client);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute_unary(&cmd).await?;
+        assert_eq!(rx.recv().await, Some(res));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn select_should_switch_namespace_of_stream() -> anyhow::Result<()> {
+        let mut config = NamespaceConfig::default();
+        config.clients.insert("device".into(), "devices".into());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_namespace(config)
+            .into();
+
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service.clone()).process());
+        let mut client = ProstClientStream::new(client);
+        client.select("a").await?;
+        let cmd = CommandRequest::new_hset("t1", "k1", "a".into());
+        client.execute_unary(&cmd).await?;
+        client.select("").await?;
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("a/t1", "k1"))
+            .await?;
+        assert_res_ok(&res, &["a".into()], &[]);
+        assert!(client.select("a/b").await.is_err());
+
+        // 证书绑定了 namespace 的客户端不能切换到其它 namespace
+        let (client, server) = tokio::io::duplex(4096);
+        let server = ProstServerStream::new(server, service).with_identity(Some("device".into()));
+        tokio::spawn(server.process());
+        let mut client = ProstClientStream::new(client);
+        let cmd = CommandRequest::new_hset("t1", "k1", "device".into());
+        client.execute_unary(&cmd).await?;
+        let res = client.select("a").await;
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_res_ok(&res, &["device".into()], &[]);
+
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -5,15 +5,33 @@ use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompat
 use tracing::instrument;
 use yamux::{Config, Connection, ConnectionError, Control, Mode, WindowUpdateMode};
 
-use crate::ProstClientStream;
+use crate::{CompressionConfig, FrameConfig, KvError, ProstClientStream};
 
 /// Yamux 控制结构
 pub struct YamuxCtrl<S> {
     /// yamux control，用于创建新的 stream
     ctrl: Control,
+    /// 新打开的 stream 使用的压缩配置
+    compression: CompressionConfig,
+    /// 新打开的 stream 使用的 frame 配置
+    frame: FrameConfig,
+    /// 新打开的 stream 使用的 namespace
+    namespace: Option<String>,
     _conn: PhantomData<S>,
 }
 
+impl<S> Clone for YamuxCtrl<S> {
+    fn clone(&self) -> Self {
+        Self {
+            ctrl: self.ctrl.clone(),
+            compression: self.compression.clone(),
+            frame: self.frame.clone(),
+            namespace: self.namespace.clone(),
+            _conn: PhantomData,
+        }
+    }
+}
+
 impl<S> YamuxCtrl<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -62,17 +80,53 @@ where
 
         Self {
             ctrl,
+            compression: Default::default(),
+            frame: Default::default(),
+            namespace: None,
             _conn: PhantomData::default(),
         }
     }
 
+    /// 设置压缩配置，之后打开的 stream 会和服务器协商压缩算法
+    pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = config;
+        self
+    }
+
+    /// 设置最大 frame 和是否启用校验和
+    pub fn with_frame(mut self, config: FrameConfig) -> Self {
+        self.frame = config;
+        self
+    }
+
+    /// 设置 namespace，之后打开的 stream 会先切换到这个 namespace
+    pub fn with_namespace(mut self, namespace: Option<String>) -> Self {
+        self.set_namespace(namespace);
+        self
+    }
+
+    /// 修改之后打开的 stream 使用的 namespace，已经打开的 stream 不受影响
+    pub fn set_namespace(&mut self, namespace: Option<String>) {
+        self.namespace = namespace;
+    }
+
     #[instrument(skip_all)]
     /// 打开一个新的 stream
     pub async fn open_stream(
         &mut self,
-    ) -> Result<ProstClientStream<Compat<yamux::Stream>>, ConnectionError> {
+    ) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
         let stream = self.ctrl.open_stream().await?;
-        Ok(ProstClientStream::new(stream.compat()))
+        let mut stream = ProstClientStream::new(stream.compat());
+        stream.configure(&self.compression, &self.frame).await?;
+        if let Some(namespace) = &self.namespace {
+            stream.select(namespace).await?;
+        }
+        Ok(stream)
+    }
+
+    /// 关闭 yamux 连接，所有打开的 stream 都会被关闭
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.ctrl.close().await
     }
 }
 
@@ -85,7 +139,7 @@ mod tests {
         assert_res_ok,
         network::tls::tls_utils::{tls_acceptor, tls_connector},
         utils::DummyStream,
-        CommandRequest, KvError, MemTable, ProstServerStream, Service, ServiceInner, Storage,
+        AsyncStorage, CommandRequest, KvError, MemTable, ProstServerStream, Service, ServiceInner,
         TlsServerAcceptor,
     };
     use anyhow::Result;
@@ -100,7 +154,7 @@ mod tests {
         f: impl Fn(server::TlsStream<TcpStream>, Service) + Send + Sync + 'static,
     ) -> Result<SocketAddr, KvError>
     where
-        Store: Storage,
+        Store: AsyncStorage,
         Service: From<ServiceInner<Store>>,
     {
         let listener = TcpListener::bind(addr).await.unwrap();
@@ -129,7 +183,7 @@ mod tests {
         store: Store,
     ) -> Result<SocketAddr, KvError>
     where
-        Store: Storage,
+        Store: AsyncStorage,
         Service: From<ServiceInner<Store>>,
     {
         let f = |stream, service: Service| {
@@ -0,0 +1,390 @@
+use std::collections::BTreeMap;
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, WriteHalf},
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
+
+use super::trace::traced;
+use crate::{CommandRequest, CommandResponse, KvError, ProstStream};
+
+/// 最多有多少个还没发送出去的请求
+const MAX_QUEUED: usize = 128;
+
+/// 最多有多少个已经发出、还在等待 response 的请求
+const MAX_PENDING: usize = 128;
+
+type Call = (
+    CommandRequest,
+    oneshot::Sender<Result<CommandResponse, KvError>>,
+);
+
+/// 在一个 stream 上同时发出多个请求，按 request id 把 response 交给对应的调用者。
+/// 可以 clone 后在多个 task 中使用，只适合一个请求对应一个 response 的命令
+#[derive(Clone)]
+pub struct PipelineClient {
+    tx: mpsc::Sender<Call>,
+}
+
+impl PipelineClient {
+    /// 接管 stream，在后台 task 里负责读写。读和写在不同的 task 中，
+    /// 发送大的请求时也会继续读取 response，不会和正在发送 response 的服务器互相等待
+    pub fn new<S>(stream: ProstStream<S, CommandResponse, CommandRequest>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(MAX_QUEUED);
+        tokio::spawn(run(stream, rx));
+        Self { tx }
+    }
+
+    /// 发送一个请求并等待它的 response，不需要等待之前的请求完成。cmd 中的 id 会被覆盖
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let (tx, rx) = oneshot::channel();
+        // 请求在后台 task 中发送，需要在这里带上调用者的 trace context
+        let cmd = traced(&cmd).into_owned();
+        self.tx.send((cmd, tx)).await.map_err(|_| closed())?;
+        rx.await.map_err(|_| closed())?
+    }
+}
+
+async fn run<S>(
+    stream: ProstStream<S, CommandResponse, CommandRequest>,
+    mut rx: mpsc::Receiver<Call>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut stream, writer) = stream.into_split();
+    // 发出的请求数量受 MAX_PENDING 限制，这里不需要再限制
+    let (wtx, wrx) = mpsc::unbounded_channel();
+    tokio::spawn(write(writer, wrx));
+
+    let mut pending = BTreeMap::new();
+    let mut next_id = 0u32;
+    let mut closed = false;
+
+    while !(closed && pending.is_empty()) {
+        tokio::select! {
+            call = rx.recv(), if !closed && pending.len() < MAX_PENDING => {
+                let (cmd, tx) = match call {
+                    Some(call) => call,
+                    // 所有 PipelineClient 都已经 drop，等已经发出的请求完成后退出
+                    None => {
+                        closed = true;
+                        continue;
+                    }
+                };
+                next_id = allocate_id(&pending, next_id);
+                // 写请求的 task 出错退出了
+                if wtx.send(cmd.with_id(next_id)).is_err() {
+                    let _ = tx.send(Err(closed_stream()));
+                    break;
+                }
+                pending.insert(next_id, tx);
+            }
+            res = stream.next() => {
+                let res = match res {
+                    Some(Ok(res)) => res,
+                    Some(Err(e)) => {
+                        warn!("Failed to read response: {:?}", e);
+                        break;
+                    }
+                    None => break,
+                };
+                // 旧版本的服务器不认识 id，会按顺序返回 id 为 0 的 response
+                let tx = match res.id {
+                    0 => pending.keys().next().copied().and_then(|id| pending.remove(&id)),
+                    id => pending.remove(&id),
+                };
+                match tx {
+                    Some(tx) => {
+                        let _ = tx.send(Ok(res));
+                    }
+                    None => warn!("Got a response for unknown request: {:?}", res),
+                }
+            }
+        }
+    }
+
+    // stream 出错或者被关闭后，还在等待的调用都会得到错误
+    for (_, tx) in pending {
+        let _ = tx.send(Err(closed_stream()));
+    }
+    // 写请求的 task 发完剩下的请求后会关闭 stream
+}
+
+async fn write<S>(
+    mut stream: ProstStream<WriteHalf<S>, CommandResponse, CommandRequest>,
+    mut rx: mpsc::UnboundedReceiver<CommandRequest>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    while let Some(cmd) = rx.recv().await {
+        if let Err(e) = stream.send(&cmd).await {
+            warn!("Failed to send request: {:?}", e);
+            return;
+        }
+    }
+    let _ = stream.close().await;
+}
+
+/// 分配 last 之后的下一个 id。0 表示不使用 pipeline，分配时跳过；
+/// id 回绕之后还要跳过仍在等待 response 的 id，pending 的数量有上限，总能找到空闲的 id
+fn allocate_id<T>(pending: &BTreeMap<u32, T>, last: u32) -> u32 {
+    let mut id = last;
+    loop {
+        id = id.wrapping_add(1).max(1);
+        if !pending.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
+fn closed() -> KvError {
+    KvError::Internal("Pipeline is closed".into())
+}
+
+fn closed_stream() -> KvError {
+    KvError::Internal("Stream is closed before getting a response".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, network::MAX_PIPELINED, MemTable, ProstServerStream, Service, ServiceInner,
+        Value,
+    };
+    use anyhow::Result;
+    use bytes::Bytes;
+    use futures::future::join_all;
+    use std::{
+        ops::RangeInclusive,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        io::DuplexStream,
+        sync::{Notify, Semaphore},
+        time,
+    };
+
+    #[tokio::test]
+    async fn pipeline_client_should_match_responses_by_id() -> Result<()> {
+        // 第一个请求执行得最慢，后面的请求会先返回
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(|cmd: CommandRequest, next| async move {
+                if cmd.id == 1 {
+                    time::sleep(Duration::from_millis(50)).await;
+                }
+                next.run(cmd).await
+            })
+            .into();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+
+        let client = PipelineClient::new(ProstStream::new(client));
+        let calls = (0..10).map(|i| {
+            let client = client.clone();
+            async move {
+                let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+                client.execute(cmd).await
+            }
+        });
+        for res in join_all(calls).await {
+            assert_res_ok(&res?, &[Value::default()], &[]);
+        }
+
+        let res = client.execute(CommandRequest::new_hget("t1", "k3")).await?;
+        assert_res_ok(&res, &[3.into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_respond_out_of_order_with_ids() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(|cmd: CommandRequest, next| async move {
+                if cmd.id == 1 {
+                    time::sleep(Duration::from_millis(50)).await;
+                }
+                next.run(cmd).await
+            })
+            .into();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        stream
+            .send(&CommandRequest::new_hget("t1", "k1").with_id(1))
+            .await?;
+        stream
+            .send(&CommandRequest::new_hget("t1", "k2").with_id(2))
+            .await?;
+        stream.close().await?;
+
+        let ids: Vec<_> = stream.map(|res| res.unwrap().id).collect().await;
+        assert_eq!(ids, vec![2, 1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipeline_client_should_not_deadlock_with_large_payloads() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+
+        // 请求和 response 都远大于 duplex 的缓冲区，双方都要边写边读
+        let value: Vec<u8> = (0..256 * 1024).map(|_| rand::random::<u8>()).collect();
+        let value = Bytes::from(value);
+        let client = PipelineClient::new(ProstStream::new(client));
+        let calls = (0..16).map(|i| {
+            let (client, value) = (client.clone(), value.clone());
+            async move {
+                let key = format!("k{}", i / 2);
+                let cmd = match i % 2 {
+                    0 => CommandRequest::new_hset("t1", key, value.into()),
+                    _ => CommandRequest::new_hget("t1", key),
+                };
+                client.execute(cmd).await
+            }
+        });
+        let res = time::timeout(Duration::from_secs(10), join_all(calls)).await?;
+        for res in res {
+            res?;
+        }
+        Ok(())
+    }
+
+    type ClientStream = ProstStream<DuplexStream, CommandResponse, CommandRequest>;
+
+    /// 执行请求之前先拿到 gate 的 permit 的服务，received 记录开始执行的请求数量
+    struct Gated {
+        received: Arc<AtomicUsize>,
+        notify: Arc<Notify>,
+        gate: Arc<Semaphore>,
+    }
+
+    impl Gated {
+        fn start() -> (Self, ClientStream) {
+            let gated = Self {
+                received: Arc::new(AtomicUsize::new(0)),
+                notify: Arc::new(Notify::new()),
+                gate: Arc::new(Semaphore::new(0)),
+            };
+            let (counter, notify, permits) = (
+                gated.received.clone(),
+                gated.notify.clone(),
+                gated.gate.clone(),
+            );
+            let service: Service = ServiceInner::new(MemTable::new())
+                .layer(move |cmd: CommandRequest, next| {
+                    let (counter, notify, permits) =
+                        (counter.clone(), notify.clone(), permits.clone());
+                    async move {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        notify.notify_one();
+                        let _permit = permits.acquire().await.unwrap();
+                        next.run(cmd).await
+                    }
+                })
+                .into();
+            let (client, server) = tokio::io::duplex(1024 * 1024);
+            tokio::spawn(ProstServerStream::new(server, service).process());
+            (gated, ProstStream::new(client))
+        }
+
+        /// 等到服务器开始执行 n 个请求
+        async fn wait_for(&self, n: usize) {
+            let wait = async {
+                while self.received.load(Ordering::SeqCst) < n {
+                    self.notify.notified().await;
+                }
+            };
+            time::timeout(Duration::from_secs(5), wait).await.unwrap();
+        }
+
+        fn received(&self) -> usize {
+            self.received.load(Ordering::SeqCst)
+        }
+    }
+
+    async fn send_pipelined(stream: &mut ClientStream, ids: RangeInclusive<usize>) -> Result<()> {
+        for id in ids {
+            let cmd = CommandRequest::new_hget("t1", "k1").with_id(id as u32);
+            stream.send(&cmd).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_limit_pipelined_requests() -> Result<()> {
+        let (gated, mut stream) = Gated::start();
+        let total = MAX_PIPELINED + 10;
+        send_pipelined(&mut stream, 1..=total).await?;
+
+        // 达到上限后服务器不再读取新的请求，没有请求执行完之前数量不会再增加
+        gated.wait_for(MAX_PIPELINED).await;
+        assert_eq!(gated.received(), MAX_PIPELINED);
+        // 执行完一个请求，服务器正好再读取一个
+        gated.gate.add_permits(1);
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.status, 404);
+        gated.wait_for(MAX_PIPELINED + 1).await;
+        assert_eq!(gated.received(), MAX_PIPELINED + 1);
+
+        gated.gate.add_permits(total);
+        stream.close().await?;
+        let responses = time::timeout(Duration::from_secs(5), stream.count()).await?;
+        assert_eq!(responses, total - 1);
+        assert_eq!(gated.received(), total);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_resume_reading_after_pipelined_requests_finish() -> Result<()> {
+        let (gated, mut stream) = Gated::start();
+        send_pipelined(&mut stream, 1..=MAX_PIPELINED).await?;
+        gated.wait_for(MAX_PIPELINED).await;
+
+        // 在服务器读取下一个请求之前，所有还在执行的请求都结束了
+        gated.gate.add_permits(MAX_PIPELINED);
+        let wait = (&mut stream).take(MAX_PIPELINED).count();
+        let responses = time::timeout(Duration::from_secs(5), wait).await?;
+        assert_eq!(responses, MAX_PIPELINED);
+
+        send_pipelined(&mut stream, 1..=1).await?;
+        gated.gate.add_permits(1);
+        let res = time::timeout(Duration::from_secs(5), stream.next()).await?;
+        assert_eq!(res.unwrap()?.id, 1);
+        assert_eq!(gated.received(), MAX_PIPELINED + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn allocate_id_should_skip_pending_ids_after_wrapping() {
+        let pending: BTreeMap<u32, ()> = vec![(1, ()), (2, ()), (4, ())].into_iter().collect();
+        assert_eq!(allocate_id(&pending, 0), 3);
+        assert_eq!(allocate_id(&pending, u32::MAX - 1), u32::MAX);
+        assert_eq!(allocate_id(&pending, u32::MAX), 3);
+        assert_eq!(allocate_id(&pending, 3), 5);
+    }
+
+    #[tokio::test]
+    async fn pipeline_client_should_fail_pending_calls_on_close() -> Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let client = PipelineClient::new(ProstStream::new(client));
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let handle = tokio::spawn(async move { client.execute(cmd).await });
+        time::sleep(Duration::from_millis(10)).await;
+        drop(server);
+
+        assert!(handle.await?.is_err());
+        Ok(())
+    }
+}
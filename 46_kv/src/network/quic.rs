@@ -0,0 +1,211 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{future::BoxFuture, StreamExt};
+use quinn::{
+    Connection, Endpoint, Incoming, IncomingBiStreams, NewConnection, RecvStream, SendStream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::lookup_host,
+};
+use tracing::instrument;
+
+use crate::{
+    network::tls::{common_name, quic_client_config, quic_server_config},
+    ClientConfig, CompressionConfig, FrameConfig, KvError, ProstClientStream, ServerTlsConfig,
+};
+
+/// QUIC 的一个双向 stream，承载一个 ProstStream
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+/// QUIC 服务器，每个连接上的每个双向 stream 都是一个独立的 ProstServerStream
+pub struct QuicListener {
+    endpoint: Endpoint,
+    incoming: Incoming,
+}
+
+/// 服务器端已经完成握手的 QUIC 连接
+pub struct QuicConnection {
+    remote: SocketAddr,
+    identity: Option<String>,
+    streams: IncomingBiStreams,
+}
+
+/// QUIC 客户端，和 YamuxCtrl 一样用来打开新的 stream。
+/// 每个 stream 独立传输，不会因为丢包而互相阻塞
+pub struct QuicCtrl {
+    // 需要持有 endpoint，否则连接会被关闭
+    _endpoint: Endpoint,
+    conn: Connection,
+    /// 新打开的 stream 使用的压缩配置
+    compression: CompressionConfig,
+    /// 新打开的 stream 使用的 frame 配置
+    frame: FrameConfig,
+    /// 新打开的 stream 使用的 namespace
+    namespace: Option<String>,
+}
+
+impl QuicStream {
+    pub fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self { send, recv }
+    }
+}
+
+impl QuicListener {
+    /// 在 addr 上监听 UDP，使用和 TLS 相同的证书
+    pub async fn bind(addr: &str, tls: &ServerTlsConfig) -> Result<Self, KvError> {
+        let addr = resolve(addr).await?;
+        let crypto = quic_server_config(&tls.cert, &tls.key, tls.ca.as_deref())?;
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+        let (endpoint, incoming) = Endpoint::server(config, addr)?;
+        Ok(Self { endpoint, incoming })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, KvError> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// 接受一个连接，返回握手的 future。endpoint 关闭后返回 None
+    pub async fn accept(&mut self) -> Option<BoxFuture<'static, Result<QuicConnection, KvError>>> {
+        let connecting = self.incoming.next().await?;
+        Some(Box::pin(async move {
+            let NewConnection {
+                connection,
+                bi_streams,
+                ..
+            } = connecting.await?;
+            // quinn 用 rustls 0.20 校验客户端证书，peer_identity 里是证书链
+            let identity = connection
+                .peer_identity()
+                .and_then(|certs| certs.downcast::<Vec<rustls::Certificate>>().ok())
+                .and_then(|certs| common_name(&certs.first()?.0));
+            Ok(QuicConnection {
+                remote: connection.remote_address(),
+                identity,
+                streams: bi_streams,
+            })
+        }))
+    }
+}
+
+impl QuicConnection {
+    pub fn remote_address(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// 客户端证书的 CN，没有使用客户端证书时返回 None
+    pub fn identity(&self) -> Option<String> {
+        self.identity.clone()
+    }
+
+    /// 等待客户端打开新的 stream，连接关闭后返回 None
+    pub async fn accept_stream(&mut self) -> Option<QuicStream> {
+        match self.streams.next().await? {
+            Ok((send, recv)) => Some(QuicStream::new(send, recv)),
+            Err(_) => None,
+        }
+    }
+}
+
+impl QuicCtrl {
+    /// 使用客户端配置中的地址和证书建立 QUIC 连接
+    #[instrument(name = "quic_ctrl_connect", skip_all)]
+    pub async fn connect(config: &ClientConfig) -> Result<Self, KvError> {
+        let tls = &config.tls;
+        let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+        let crypto = quic_client_config(identity, tls.ca.as_deref())?;
+
+        let addr = resolve(&config.general.addr).await?;
+        let local: SocketAddr = match addr.is_ipv6() {
+            true => "[::]:0".parse().unwrap(),
+            false => "0.0.0.0:0".parse().unwrap(),
+        };
+        let endpoint = Endpoint::client(local)?;
+
+        let client = quinn::ClientConfig::new(Arc::new(crypto));
+        let NewConnection { connection, .. } =
+            endpoint.connect_with(client, addr, &tls.domain)?.await?;
+
+        Ok(Self {
+            _endpoint: endpoint,
+            conn: connection,
+            compression: config.compression.clone(),
+            frame: config.frame.clone(),
+            namespace: config.namespace.clone(),
+        })
+    }
+
+    /// 设置压缩配置，之后打开的 stream 会和服务器协商压缩算法
+    pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = config;
+        self
+    }
+
+    /// 设置最大 frame 和是否启用校验和
+    pub fn with_frame(mut self, config: FrameConfig) -> Self {
+        self.frame = config;
+        self
+    }
+
+    #[instrument(skip_all)]
+    /// 打开一个新的双向 stream
+    pub async fn open_stream(&mut self) -> Result<ProstClientStream<QuicStream>, KvError> {
+        let (send, recv) = self.conn.open_bi().await?;
+        let mut stream = ProstClientStream::new(QuicStream::new(send, recv));
+        stream.configure(&self.compression, &self.frame).await?;
+        if let Some(namespace) = &self.namespace {
+            stream.select(namespace).await?;
+        }
+        Ok(stream)
+    }
+
+    /// 关闭 QUIC 连接，所有打开的 stream 都会被关闭
+    pub fn close(&self) {
+        self.conn.close(0u32.into(), b"");
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+async fn resolve(addr: &str) -> Result<SocketAddr, KvError> {
+    lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| KvError::Internal(format!("Cannot resolve address {}", addr)))
+}
@@ -1,13 +1,23 @@
-use bytes::BytesMut;
-use futures::{ready, FutureExt, Sink, Stream};
+use bytes::{Buf, BytesMut};
+use futures::{ready, Sink, Stream};
 use std::{
+    cmp::min,
+    io,
     marker::PhantomData,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio_util::io::poll_read_buf;
 
-use crate::{read_frame, FrameCoder, KvError};
+use super::frame::{decode_header, LEN_LEN, READ_CHUNK};
+use crate::{Compression, FrameCoder, FrameOptions, KvError, DEFAULT_MAX_FRAME};
+
+/// ProstStream::into_split 拆出来的读和写两半
+pub type SplitProstStream<S, In, Out> = (
+    ProstStream<ReadHalf<S>, In, Out>,
+    ProstStream<WriteHalf<S>, In, Out>,
+);
 
 /// 处理 KV server prost frame 的 stream
 pub struct ProstStream<S, In, Out> {
@@ -17,8 +27,12 @@ pub struct ProstStream<S, In, Out> {
     wbuf: BytesMut,
     // 写入了多少字节
     written: usize,
-    // 读缓存
+    // 读缓存，可能包含还没读完的 frame 或者多个 frame
     rbuf: BytesMut,
+    // 发送时使用的压缩算法，阈值和校验和
+    options: FrameOptions,
+    // 允许接收的最大 frame
+    max_frame: usize,
 
     // 类型占位符
     _in: PhantomData<In>,
@@ -27,36 +41,62 @@ pub struct ProstStream<S, In, Out> {
 
 impl<S, In, Out> Stream for ProstStream<S, In, Out>
 where
-    S: AsyncRead + AsyncWrite + Unpin + Send,
+    S: AsyncRead + Unpin + Send,
     In: Unpin + Send + FrameCoder,
     Out: Unpin + Send,
 {
     /// 当调用 next() 时，得到 Result<In, KvError>
     type Item = Result<In, KvError>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // 上一次调用结束后 rbuf 应该为空
-        assert!(self.rbuf.is_empty());
-
-        // 从 rbuf 中分离出 rest（摆脱对 self 的引用）
-        let mut rest = self.rbuf.split_off(0);
-
-        // 使用 read_frame 来获取数据
-        let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let max_frame = this.max_frame;
 
-        // 拿到一个 frame 的数据，把 buffer 合并回去
-        self.rbuf.unsplit(rest);
+        // 读到的数据都放在 rbuf 里，返回 Pending 时不会丢失，下次 poll 接着读。
+        // 这样 next() 的 future 可以被安全地取消（比如在 select! 中）
+        loop {
+            let needed = match this.rbuf.len() >= LEN_LEN {
+                true => {
+                    let header = (&this.rbuf[..LEN_LEN]).get_u32() as usize;
+                    let (len, _, _) = decode_header(header);
+                    // 在分配内存之前检查 frame 的大小
+                    if len > max_frame {
+                        return Poll::Ready(Some(Err(KvError::FrameError)));
+                    }
+                    if this.rbuf.len() >= LEN_LEN + len {
+                        // 拿到一个完整的 frame，调用 decode_frame 获取解包后的数据
+                        let mut frame = this.rbuf.split_to(LEN_LEN + len);
+                        return Poll::Ready(Some(In::decode_frame_with(&mut frame, max_frame)));
+                    }
+                    LEN_LEN + len - this.rbuf.len()
+                }
+                false => LEN_LEN - this.rbuf.len(),
+            };
 
-        // 调用 decode_frame 获取解包后的数据
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+            this.rbuf.reserve(min(needed, READ_CHUNK));
+            let n = ready!(poll_read_buf(
+                Pin::new(&mut this.stream),
+                cx,
+                &mut this.rbuf
+            ))?;
+            if n == 0 {
+                // 还没读到任何数据对方就关闭了，说明 stream 正常结束
+                return match this.rbuf.is_empty() {
+                    true => Poll::Ready(None),
+                    false => {
+                        let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                        Poll::Ready(Some(Err(e.into())))
+                    }
+                };
+            }
+        }
     }
 }
 
 /// 当调用 send() 时，会把 Out 发出去
 impl<S, In, Out> Sink<&Out> for ProstStream<S, In, Out>
 where
-    S: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncWrite + Unpin,
     In: Unpin + Send,
     Out: Unpin + Send + FrameCoder,
 {
@@ -69,7 +109,7 @@ where
 
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
+        item.encode_frame_with(&mut this.wbuf, &this.options)?;
 
         Ok(())
     }
@@ -117,10 +157,59 @@ where
             written: 0,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
+            options: FrameOptions::default(),
+            max_frame: DEFAULT_MAX_FRAME,
             _in: PhantomData::default(),
             _out: PhantomData::default(),
         }
     }
+
+    /// 设置发送时使用的压缩算法，接收时总是根据 frame 头部解压缩
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.options.compression = compression;
+    }
+
+    /// 设置压缩的阈值
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.options.threshold = threshold;
+    }
+
+    /// 设置发送时是否附加 CRC32C 校验和，接收时总是根据 frame 头部校验
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.options.checksum = checksum;
+    }
+
+    /// 设置允许接收的最大 frame
+    pub fn set_max_frame(&mut self, max_frame: usize) {
+        self.max_frame = max_frame;
+    }
+
+    /// 拆成读和写两半，交给不同的 task 之后，写的时候也可以继续读。
+    /// 已经读到的数据、还没写完的数据和 frame 的设置都会保留
+    pub fn into_split(self) -> SplitProstStream<S, In, Out> {
+        let (reader, writer) = tokio::io::split(self.stream);
+        let reader = ProstStream {
+            stream: reader,
+            wbuf: BytesMut::new(),
+            written: 0,
+            rbuf: self.rbuf,
+            options: self.options,
+            max_frame: self.max_frame,
+            _in: PhantomData::default(),
+            _out: PhantomData::default(),
+        };
+        let writer = ProstStream {
+            stream: writer,
+            wbuf: self.wbuf,
+            written: self.written,
+            rbuf: BytesMut::new(),
+            options: self.options,
+            max_frame: self.max_frame,
+            _in: PhantomData::default(),
+            _out: PhantomData::default(),
+        };
+        (reader, writer)
+    }
 }
 
 #[cfg(test)]
@@ -151,4 +240,71 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn prost_stream_next_should_be_cancel_safe() -> Result<()> {
+        use std::time::Duration;
+        use tokio::{io::AsyncWriteExt, time};
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(server);
+
+        let cmd1 = CommandRequest::new_hdel("t1", "k1");
+        let cmd2 = CommandRequest::new_hget("t2", "k2");
+        let mut buf = BytesMut::new();
+        cmd1.encode_frame(&mut buf)?;
+        cmd2.encode_frame(&mut buf)?;
+
+        // 只写入半个 frame，等待超时会取消 next() 的 future
+        let (first, rest) = buf.split_at(5);
+        client.write_all(first).await?;
+        let result = time::timeout(Duration::from_millis(10), stream.next()).await;
+        assert!(result.is_err());
+
+        // 之前读到的数据没有丢失，一次写入的两个 frame 都能读出来
+        client.write_all(rest).await?;
+        assert_eq!(stream.next().await.unwrap()?, cmd1);
+        assert_eq!(stream.next().await.unwrap()?, cmd2);
+
+        // 在 frame 中间关闭是错误
+        client.write_all(first).await?;
+        drop(client);
+        assert!(stream.next().await.unwrap().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_end_on_eof() -> Result<()> {
+        let stream = DummyStream::default();
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        assert!(stream.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_reject_frame_over_max_size() -> Result<()> {
+        let stream = DummyStream::default();
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        stream.set_max_frame(16);
+
+        let cmd = CommandRequest::new_hset("table", "key", "a long long value".into());
+        stream.send(&cmd).await?;
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(KvError::FrameError))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_with_checksum_should_work() -> Result<()> {
+        let stream = DummyStream::default();
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        stream.set_checksum(true);
+
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        stream.send(&cmd).await?;
+        assert_eq!(stream.next().await.unwrap()?, cmd);
+        Ok(())
+    }
 }
@@ -3,7 +3,9 @@ use std::sync::Arc;
 
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
-use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore, Session,
+};
 use tokio_rustls::webpki::DNSNameRef;
 use tokio_rustls::TlsConnector;
 use tokio_rustls::{
@@ -124,6 +126,100 @@ impl TlsServerAcceptor {
         let acceptor = TlsAcceptor::from(self.inner.clone());
         Ok(acceptor.accept(stream).await?)
     }
+
+    /// 客户端证书的 CN，没有使用客户端证书时返回 None
+    pub fn peer_identity<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+        let certs = stream.get_ref().1.get_peer_certificates()?;
+        common_name(&certs.first()?.0)
+    }
+}
+
+/// 从 DER 编码的证书中读取 subject 的 CN
+pub(crate) fn common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(String::from)
+}
+
+/// QUIC 使用的服务器 TLS 配置，和 TLS 使用同一份证书。
+/// quinn 依赖 rustls 0.20，这里的 `rustls` 和 tokio-rustls 使用的 0.19 不是同一个 crate
+pub(crate) fn quic_server_config(
+    cert: &str,
+    key: &str,
+    client_ca: Option<&str>,
+) -> Result<rustls::ServerConfig, KvError> {
+    let certs = load_quic_certs(cert)?;
+    let key = rustls::PrivateKey(load_key(key)?.0);
+
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        None => builder.with_no_client_auth(),
+        Some(cert) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in load_quic_certs(cert)? {
+                roots
+                    .add(&cert)
+                    .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
+            }
+            builder
+                .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(roots))
+        }
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
+    config.alpn_protocols = vec![Vec::from(ALPN_KV)];
+    Ok(config)
+}
+
+/// QUIC 使用的客户端 TLS 配置，和 TlsClientConnector 一样加载证书
+pub(crate) fn quic_client_config(
+    identity: Option<(&str, &str)>,
+    server_ca: Option<&str>,
+) -> Result<rustls::ClientConfig, KvError> {
+    let mut roots = rustls::RootCertStore::empty();
+    if let Some(cert) = server_ca {
+        for cert in load_quic_certs(cert)? {
+            roots
+                .add(&cert)
+                .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
+        }
+    } else {
+        // 加载本地信任的根证书链，转换成 rustls 0.20 的格式
+        let store = match rustls_native_certs::load_native_certs() {
+            Ok(store) | Err((Some(store), _)) => store,
+            Err((None, error)) => return Err(error.into()),
+        };
+        roots.add_server_trust_anchors(store.roots.iter().map(|anchor| {
+            let anchor = anchor.to_trust_anchor();
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+    }
+
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let mut config = match identity {
+        Some((cert, key)) => builder
+            .with_single_cert(load_quic_certs(cert)?, rustls::PrivateKey(load_key(key)?.0))
+            .map_err(|_| KvError::CertifcateParseError("client", "cert"))?,
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![Vec::from(ALPN_KV)];
+    Ok(config)
+}
+
+fn load_quic_certs(cert: &str) -> Result<Vec<rustls::Certificate>, KvError> {
+    let certs = load_certs(cert)?;
+    Ok(certs
+        .into_iter()
+        .map(|c| rustls::Certificate(c.0))
+        .collect())
 }
 
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
@@ -185,6 +281,7 @@ pub mod tls_utils {
 #[cfg(test)]
 mod tests {
     use super::tls_utils::tls_acceptor;
+    use super::{common_name, load_certs};
     use crate::network::tls::tls_utils::tls_connector;
     use anyhow::Result;
     use std::net::SocketAddr;
@@ -222,6 +319,16 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn common_name_should_be_read_from_cert() {
+        let certs = load_certs(include_str!("../../fixtures/client.cert")).unwrap();
+        assert_eq!(
+            common_name(&certs[0].0),
+            Some("awesome-device-id".to_string())
+        );
+        assert_eq!(common_name(b"not a cert"), None);
+    }
+
     #[tokio::test]
     async fn tls_with_bad_domain_should_not_work() -> Result<()> {
         let addr = start_server(false).await?;
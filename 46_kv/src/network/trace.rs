@@ -0,0 +1,183 @@
+use std::borrow::Cow;
+
+use opentelemetry::{
+    propagation::{Extractor, Injector, TextMapPropagator},
+    sdk::{
+        export::trace::stdout, propagation::TraceContextPropagator, trace as sdktrace, Resource,
+    },
+    trace::TraceContextExt,
+    Context, KeyValue,
+};
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{CommandRequest, KvError, TraceContext};
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// span 的导出配置
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TraceConfig {
+    pub exporter: TraceExporter,
+}
+
+/// 把 span 导出到哪里
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    /// 发送到本机的 jaeger agent
+    #[default]
+    Jaeger,
+    /// 输出到 stdout，不需要 jaeger 也能查看 span 和它们的 parent
+    Stdout,
+    /// 不导出 span，请求中也不会带上 trace context
+    None,
+}
+
+impl TraceConfig {
+    /// 按配置创建 tracer，交给 tracing_opentelemetry::layer() 使用
+    pub fn tracer(&self, service_name: &'static str) -> Result<Option<sdktrace::Tracer>, KvError> {
+        let tracer = match self.exporter {
+            TraceExporter::Jaeger => opentelemetry_jaeger::new_pipeline()
+                .with_service_name(service_name)
+                .install_simple(),
+            TraceExporter::Stdout => {
+                let resource = Resource::new(vec![KeyValue::new("service.name", service_name)]);
+                let config = sdktrace::config().with_resource(resource);
+                Ok(stdout::new_pipeline()
+                    .with_trace_config(config)
+                    .install_simple())
+            }
+            TraceExporter::None => return Ok(None),
+        };
+        tracer
+            .map(Some)
+            .map_err(|e| KvError::Internal(format!("failed to install tracer: {}", e)))
+    }
+}
+
+/// 带上当前 span 的 trace context 的请求。
+/// 当前 span 没有被 opentelemetry 记录时（比如没有安装 exporter）原样返回
+pub(crate) fn traced(cmd: &CommandRequest) -> Cow<'_, CommandRequest> {
+    let cx = Span::current().context();
+    if !cx.span().span_context().is_valid() {
+        return Cow::Borrowed(cmd);
+    }
+
+    let mut trace = TraceContext::default();
+    TraceContextPropagator::new().inject_context(&cx, &mut trace);
+    let mut cmd = cmd.clone();
+    cmd.trace = Some(trace);
+    Cow::Owned(cmd)
+}
+
+/// 把请求中的 trace context 设置为 span 的 parent，请求中没有 trace context 时什么都不做
+pub(crate) fn set_parent(span: &Span, cmd: &CommandRequest) {
+    if let Some(trace) = &cmd.trace {
+        let cx: Context = TraceContextPropagator::new().extract(trace);
+        if cx.span().span_context().is_valid() {
+            span.set_parent(cx);
+        }
+    }
+}
+
+impl Injector for TraceContext {
+    fn set(&mut self, key: &str, value: String) {
+        match key {
+            TRACEPARENT => self.traceparent = value,
+            TRACESTATE => self.tracestate = value,
+            _ => {}
+        }
+    }
+}
+
+impl Extractor for TraceContext {
+    fn get(&self, key: &str) -> Option<&str> {
+        let value = match key {
+            TRACEPARENT => &self.traceparent,
+            TRACESTATE => &self.tracestate,
+            _ => return None,
+        };
+        Some(value.as_str()).filter(|v| !v.is_empty())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        vec![TRACEPARENT, TRACESTATE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::{sdk::trace::TracerProvider, trace::TracerProvider as _};
+    use tracing::info_span;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    fn span_trace_id(span: &Span) -> opentelemetry::trace::TraceId {
+        span.context().span().span_context().trace_id()
+    }
+
+    #[test]
+    fn trace_context_should_be_propagated() {
+        let provider = TracerProvider::builder().build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test", None));
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let cmd = CommandRequest::new_hget("t1", "k1");
+            // 不在 span 中时不会带上 trace context
+            assert!(traced(&cmd).trace.is_none());
+
+            let client = info_span!("client_request");
+            let traced = client.in_scope(|| traced(&cmd).into_owned());
+            let trace = traced.trace.as_ref().unwrap();
+            assert!(trace.traceparent.starts_with("00-"));
+            assert!(trace.traceparent.contains(&span_trace_id(&client).to_hex()));
+
+            let server = info_span!(parent: None, "server_request");
+            set_parent(&server, &traced);
+            assert_eq!(span_trace_id(&server), span_trace_id(&client));
+        });
+    }
+
+    #[test]
+    fn trace_config_should_be_loaded() {
+        let config: TraceConfig = toml::from_str(r#"exporter = "stdout""#).unwrap();
+        assert_eq!(config.exporter, TraceExporter::Stdout);
+        assert_eq!(TraceConfig::default().exporter, TraceExporter::Jaeger);
+        let config = TraceConfig {
+            exporter: TraceExporter::None,
+        };
+        assert!(config.tracer("test").unwrap().is_none());
+    }
+
+    #[test]
+    fn request_without_trace_context_should_be_ignored() {
+        let provider = TracerProvider::builder().build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test", None));
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!(parent: None, "server_request");
+            let trace_id = span_trace_id(&span);
+            assert_ne!(trace_id, opentelemetry::trace::TraceId::invalid());
+
+            // 没有 trace context 时 span 保持自己的 trace id
+            set_parent(&span, &CommandRequest::new_hget("t1", "k1"));
+            assert_eq!(span_trace_id(&span), trace_id);
+
+            // trace context 无法解析时也一样
+            let mut cmd = CommandRequest::new_hget("t1", "k1");
+            cmd.trace = Some(TraceContext {
+                traceparent: "invalid".into(),
+                tracestate: String::new(),
+            });
+            set_parent(&span, &cmd);
+            assert_eq!(span_trace_id(&span), trace_id);
+        });
+        assert_eq!(TraceContext::default().get(TRACEPARENT), None);
+    }
+}
@@ -0,0 +1,330 @@
+use std::{fs, io, os::unix::fs::FileTypeExt};
+
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tokio_tungstenite::{accept_async, client_async};
+use tracing::warn;
+
+use crate::{
+    ClientConfig, KvError, ListenerConfig, TlsClientConnector, TlsServerAcceptor, Transport,
+    WsStream,
+};
+
+/// 可以跑 yamux 的 stream
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// 屏蔽了具体传输层的 stream
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// TLS / WebSocket 握手，在 accept 之后单独执行，避免阻塞 accept 循环。
+/// 握手成功后返回 stream 和客户端证书的 CN
+pub type Handshake = BoxFuture<'static, Result<(BoxedStream, Option<String>), KvError>>;
+
+/// 服务器端的 listener，支持 TLS / TCP / Unix socket / WebSocket
+pub struct TransportListener {
+    transport: Transport,
+    inner: Inner,
+    acceptor: Option<TlsServerAcceptor>,
+}
+
+/// 客户端的 connector，和 TransportListener 对应
+#[derive(Clone)]
+pub struct TransportConnector {
+    transport: Transport,
+    addr: String,
+    tls: Option<TlsClientConnector>,
+}
+
+enum Inner {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),
+}
+
+impl TransportListener {
+    /// 监听 config 中的地址，TLS listener 需要提供 acceptor
+    pub async fn bind(
+        config: &ListenerConfig,
+        acceptor: Option<TlsServerAcceptor>,
+    ) -> Result<Self, KvError> {
+        let addr = &config.addr;
+        let inner = match config.transport {
+            Transport::Quic => return Err(not_stream_transport()),
+            Transport::Unix => {
+                remove_stale_socket(addr).await?;
+                Inner::Unix(UnixListener::bind(addr)?, addr.clone())
+            }
+            transport => {
+                let listener = TcpListener::bind(addr).await?;
+                // 除了 TLS 之外的 transport 都是明文的
+                if transport != Transport::Tls && !listener.local_addr()?.ip().is_loopback() {
+                    if !config.allow_plaintext {
+                        return Err(KvError::Internal(format!(
+                            "plaintext {} listener on {} is not loopback, use TLS or set allow_plaintext",
+                            transport.as_str(),
+                            addr
+                        )));
+                    }
+                    warn!(
+                        "Plaintext {} listener on {} is not loopback",
+                        transport.as_str(),
+                        addr
+                    );
+                }
+                Inner::Tcp(listener)
+            }
+        };
+
+        if config.transport == Transport::Tls && acceptor.is_none() {
+            return Err(KvError::Internal(
+                "TLS listener requires a TLS config".into(),
+            ));
+        }
+
+        Ok(Self {
+            transport: config.transport,
+            inner,
+            acceptor,
+        })
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// 实际监听的地址，监听 0 端口时可以用它拿到分配的端口
+    pub fn local_addr(&self) -> Result<String, KvError> {
+        match &self.inner {
+            Inner::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            Inner::Unix(_, path) => Ok(path.clone()),
+        }
+    }
+
+    /// 接受一个连接，返回握手的 future 和客户端地址
+    pub async fn accept(&self) -> Result<(Handshake, String), KvError> {
+        let (stream, addr) = match &self.inner {
+            Inner::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                let handshake: Handshake = Box::pin(async move { Ok((boxed(stream), None)) });
+                return Ok((handshake, path.clone()));
+            }
+            Inner::Tcp(listener) => listener.accept().await?,
+        };
+        stream.set_nodelay(true)?;
+
+        let handshake: Handshake = match self.transport {
+            Transport::Tls => {
+                let acceptor = self.acceptor.clone().expect("checked in bind");
+                Box::pin(async move {
+                    let stream = acceptor.accept(stream).await?;
+                    let identity = TlsServerAcceptor::peer_identity(&stream);
+                    Ok((boxed(stream), identity))
+                })
+            }
+            Transport::WebSocket => Box::pin(async move {
+                let stream = accept_async(stream).await.map_err(ws_error)?;
+                Ok((boxed(WsStream::new(stream)), None))
+            }),
+            _ => Box::pin(async move { Ok((boxed(stream), None)) }),
+        };
+        Ok((handshake, addr.to_string()))
+    }
+}
+
+impl TransportConnector {
+    /// 根据客户端配置创建 connector，只有 TLS 会加载证书
+    pub fn new(config: &ClientConfig) -> Result<Self, KvError> {
+        let tls = match config.transport {
+            Transport::Tls => {
+                let tls = &config.tls;
+                let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+                Some(TlsClientConnector::new(
+                    &tls.domain,
+                    identity,
+                    tls.ca.as_deref(),
+                )?)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            transport: config.transport,
+            addr: config.general.addr.clone(),
+            tls,
+        })
+    }
+
+    /// 连接服务器，完成 TLS / WebSocket 握手
+    pub async fn connect(&self) -> Result<BoxedStream, KvError> {
+        match self.transport {
+            Transport::Unix => return Ok(boxed(UnixStream::connect(&self.addr).await?)),
+            Transport::Quic => return Err(not_stream_transport()),
+            _ => {}
+        }
+
+        let stream = TcpStream::connect(&self.addr).await?;
+        // 请求通常很小，关闭 Nagle 算法以降低延迟
+        stream.set_nodelay(true)?;
+
+        match (self.transport, &self.tls) {
+            (Transport::Tls, Some(tls)) => Ok(boxed(tls.connect(stream).await?)),
+            (Transport::WebSocket, _) => {
+                let url = format!("ws://{}/", self.addr);
+                let (stream, _) = client_async(url, stream).await.map_err(ws_error)?;
+                Ok(boxed(WsStream::new(stream)))
+            }
+            _ => Ok(boxed(stream)),
+        }
+    }
+}
+
+/// 上次退出时留下的 socket 文件会导致 bind 失败，需要删除。
+/// 只有连接被拒绝时才说明没有服务器在监听，否则会抢走正在运行的服务器的 socket
+async fn remove_stale_socket(path: &str) -> Result<(), KvError> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(KvError::Internal(format!(
+            "another server is listening on {}",
+            path
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(fs::remove_file(path)?),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn boxed(stream: impl AsyncStream + 'static) -> BoxedStream {
+    Box::new(stream)
+}
+
+fn not_stream_transport() -> KvError {
+    KvError::Internal("QUIC is not a byte stream transport, use QuicListener / QuicCtrl".into())
+}
+
+fn ws_error(e: tokio_tungstenite::tungstenite::Error) -> KvError {
+    KvError::Internal(format!("websocket: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GeneralConfig;
+    use anyhow::Result;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn transports_should_work() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sock = dir.path().join("kv.sock").to_string_lossy().to_string();
+
+        for (transport, addr) in [
+            (Transport::Tcp, "127.0.0.1:0".to_string()),
+            (Transport::Unix, sock),
+            (Transport::WebSocket, "127.0.0.1:0".to_string()),
+        ] {
+            let config = ListenerConfig {
+                transport,
+                addr,
+                allow_plaintext: false,
+            };
+            let listener = TransportListener::bind(&config, None).await?;
+            let addr = listener.local_addr()?;
+
+            tokio::spawn(async move {
+                let (handshake, _) = listener.accept().await.unwrap();
+                let (mut stream, _) = handshake.await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.flush().await.unwrap();
+            });
+
+            let connector = TransportConnector {
+                transport,
+                addr,
+                tls: None,
+            };
+            let mut stream = connector.connect().await?;
+            stream.write_all(b"hello").await?;
+            stream.flush().await?;
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"hello", "{:?}", transport);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_listener_without_acceptor_should_fail() {
+        let config = ListenerConfig {
+            transport: Transport::Tls,
+            addr: "127.0.0.1:0".into(),
+            allow_plaintext: false,
+        };
+        assert!(TransportListener::bind(&config, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn plaintext_transports_should_only_listen_on_loopback() {
+        for transport in [Transport::Tcp, Transport::WebSocket] {
+            let mut config = ListenerConfig {
+                transport,
+                addr: "0.0.0.0:0".into(),
+                allow_plaintext: false,
+            };
+            let e = TransportListener::bind(&config, None).await.err().unwrap();
+            assert!(e.to_string().contains("not loopback"), "{:?}", transport);
+
+            config.allow_plaintext = true;
+            assert!(TransportListener::bind(&config, None).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn unix_listener_should_not_take_over_running_server() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sock = dir.path().join("kv.sock").to_string_lossy().to_string();
+        let config = ListenerConfig {
+            transport: Transport::Unix,
+            addr: sock.clone(),
+            allow_plaintext: false,
+        };
+
+        let running = TransportListener::bind(&config, None).await?;
+        let e = TransportListener::bind(&config, None).await.err().unwrap();
+        assert!(e.to_string().contains("another server"));
+
+        // 服务器退出后留下的 socket 文件可以被删除
+        drop(running);
+        assert!(fs::metadata(&sock).is_ok());
+        TransportListener::bind(&config, None).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn non_tls_connector_should_not_load_certs() {
+        let config = ClientConfig {
+            general: GeneralConfig {
+                addr: "/tmp/kv.sock".into(),
+            },
+            transport: Transport::Unix,
+            namespace: None,
+            tls: crate::ClientTlsConfig {
+                domain: "kvserver.acme.inc".into(),
+                identity: None,
+                ca: Some("invalid".into()),
+            },
+            compression: Default::default(),
+            frame: Default::default(),
+            trace: None,
+        };
+        let connector = TransportConnector::new(&config).unwrap();
+        assert!(connector.tls.is_none());
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{Error as WsError, Message},
+    WebSocketStream,
+};
+
+/// 把 WebSocket 包装成 AsyncRead / AsyncWrite，这样就可以和 TCP 一样跑 yamux。
+/// 写入的数据作为 binary message 发送，读取时把收到的 binary message 拼接起来
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    // 上一个 message 中还没有读完的数据
+    buf: Bytes,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            buf: Bytes::new(),
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.buf.is_empty() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.buf = data.into(),
+                // 对方关闭了连接，当作 EOF
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(WsError::ConnectionClosed)) | Some(Err(WsError::AlreadyClosed)) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+                // ping / pong 由 tungstenite 处理，text message 不是我们的协议，忽略
+                Some(Ok(_)) => {}
+            }
+        }
+
+        let n = this.buf.len().min(buf.remaining());
+        buf.put_slice(&this.buf[..n]);
+        this.buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = Pin::new(&mut self.get_mut().inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(to_io_error)?;
+        inner
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.get_mut().inner).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed) | Err(WsError::AlreadyClosed) => {
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => Poll::Ready(Err(to_io_error(e))),
+        }
+    }
+}
+
+fn to_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::{accept_async, client_async};
+
+    #[tokio::test]
+    async fn ws_stream_should_work() -> Result<()> {
+        let (client, server) = duplex(4096);
+
+        let server = tokio::spawn(async move {
+            let mut stream = WsStream::new(accept_async(server).await.unwrap());
+            let mut buf = vec![0u8; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let (stream, _) = client_async("ws://localhost/", client).await?;
+        let mut stream = WsStream::new(stream);
+        // 分两次写，读的一方要能拼起来
+        stream.write_all(b"hello ").await?;
+        stream.write_all(b"world").await?;
+        stream.flush().await?;
+
+        let mut buf = vec![0u8; 11];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world");
+
+        server.await?;
+        // 服务器关闭后读到 EOF
+        assert_eq!(stream.read(&mut buf).await?, 0);
+        Ok(())
+    }
+}
@@ -1,180 +1,510 @@
 /// 来自客户端的命令请求
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(
-        oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"
-    )]
+    /// 请求 id，非 0 时服务器会并发执行同一个 stream 上的请求，并在 response 中带回这个 id，
+    /// 此时 response 可能不按请求的顺序返回；为 0 时和之前一样按顺序处理
+    #[prost(uint32, tag="16")]
+    pub id: u32,
+    /// 发起请求的客户端 span 的 W3C trace context，服务器用它作为处理请求的 span 的 parent
+    #[prost(message, optional, tag="25")]
+    pub trace: ::core::option::Option<TraceContext>,
+    /// 非 0 时，读命令（HGET/HGETALL/HMGET/HEXIST/HMEXIST）读取 SNAPSHOT 固定的这个版本的数据
+    #[prost(uint64, tag="28")]
+    pub as_of: u64,
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 17, 18, 19, 20, 21, 22, 23, 24, 26, 27, 29, 30")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
-        #[prost(message, tag = "1")]
+        #[prost(message, tag="1")]
         Hget(super::Hget),
-        #[prost(message, tag = "2")]
+        #[prost(message, tag="2")]
         Hgetall(super::Hgetall),
-        #[prost(message, tag = "3")]
+        #[prost(message, tag="3")]
         Hmget(super::Hmget),
-        #[prost(message, tag = "4")]
+        #[prost(message, tag="4")]
         Hset(super::Hset),
-        #[prost(message, tag = "5")]
+        #[prost(message, tag="5")]
         Hmset(super::Hmset),
-        #[prost(message, tag = "6")]
+        #[prost(message, tag="6")]
         Hdel(super::Hdel),
-        #[prost(message, tag = "7")]
+        #[prost(message, tag="7")]
         Hmdel(super::Hmdel),
-        #[prost(message, tag = "8")]
+        #[prost(message, tag="8")]
         Hexist(super::Hexist),
-        #[prost(message, tag = "9")]
+        #[prost(message, tag="9")]
         Hmexist(super::Hmexist),
-        #[prost(message, tag = "10")]
+        #[prost(message, tag="10")]
         Subscribe(super::Subscribe),
-        #[prost(message, tag = "11")]
+        #[prost(message, tag="11")]
         Unsubscribe(super::Unsubscribe),
-        #[prost(message, tag = "12")]
+        #[prost(message, tag="12")]
         Publish(super::Publish),
+        #[prost(message, tag="13")]
+        Dump(super::Dump),
+        #[prost(message, tag="14")]
+        Hello(super::Hello),
+        #[prost(message, tag="15")]
+        Eval(super::Eval),
+        #[prost(message, tag="17")]
+        Info(super::Info),
+        #[prost(message, tag="18")]
+        SlowlogGet(super::SlowlogGet),
+        #[prost(message, tag="19")]
+        SlowlogReset(super::SlowlogReset),
+        #[prost(message, tag="20")]
+        Raft(super::RaftMessage),
+        #[prost(message, tag="21")]
+        ClusterAdd(super::ClusterAdd),
+        #[prost(message, tag="22")]
+        ClusterRemove(super::ClusterRemove),
+        #[prost(message, tag="23")]
+        ClusterStatus(super::ClusterStatus),
+        #[prost(message, tag="24")]
+        Select(super::Select),
+        #[prost(message, tag="26")]
+        Snapshot(super::Snapshot),
+        #[prost(message, tag="27")]
+        SnapshotRelease(super::SnapshotRelease),
+        #[prost(message, tag="29")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag="30")]
+        ScriptFlush(super::ScriptFlush),
     }
 }
 /// 服务器的响应
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码；复用 HTTP 2xx/4xx/5xx 状态码
-    #[prost(uint32, tag = "1")]
+    #[prost(uint32, tag="1")]
     pub status: u32,
     /// 如果不是 2xx，message 里包含详细的信息
-    #[prost(string, tag = "2")]
+    #[prost(string, tag="2")]
     pub message: ::prost::alloc::string::String,
     /// 成功返回的 values
-    #[prost(message, repeated, tag = "3")]
+    #[prost(message, repeated, tag="3")]
     pub values: ::prost::alloc::vec::Vec<Value>,
     /// 成功返回的 kv pairs
-    #[prost(message, repeated, tag = "4")]
+    #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 对应请求的 id
+    #[prost(uint32, tag="5")]
+    pub id: u32,
+    /// SLOWLOG GET 返回的慢日志
+    #[prost(message, repeated, tag="6")]
+    pub slowlog: ::prost::alloc::vec::Vec<SlowlogEntry>,
 }
-/// 从 table 中获取一个 key，返回 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+/// 从 table 中获取一个 key，返回 value。
+/// key 可以是任意字节，bytes 和 string 的编码方式相同，旧的客户端发送的 string key 仍然有效
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
 }
 /// 从 table 中获取所有的 Kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一组 key，返回它们的 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes="bytes", repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 /// 返回的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
 pub mod value {
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Value {
-        #[prost(string, tag = "1")]
+        #[prost(string, tag="1")]
         String(::prost::alloc::string::String),
-        #[prost(bytes, tag = "2")]
+        #[prost(bytes, tag="2")]
         Binary(::prost::bytes::Bytes),
-        #[prost(int64, tag = "3")]
+        #[prost(int64, tag="3")]
         Integer(i64),
-        #[prost(double, tag = "4")]
+        #[prost(double, tag="4")]
         Float(f64),
-        #[prost(bool, tag = "5")]
+        #[prost(bool, tag="5")]
         Bool(bool),
     }
 }
 /// 返回的 kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
-    #[prost(string, tag = "1")]
-    pub key: ::prost::alloc::string::String,
-    #[prost(message, optional, tag = "2")]
+    #[prost(bytes="bytes", tag="1")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, optional, tag="2")]
     pub value: ::core::option::Option<Value>,
 }
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(message, optional, tag = "2")]
+    #[prost(message, optional, tag="2")]
     pub pair: ::core::option::Option<Kvpair>,
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(message, repeated, tag = "2")]
+    #[prost(message, repeated, tag="2")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
 }
 /// 从 table 中删除一个 key，返回它之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes="bytes", repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 /// 查看 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
 }
 /// 查看一组 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes="bytes", repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 /// subscribe 到某个主题，任何发布到这个主题的数据都会被收到
 /// 成功后，第一个返回的 CommandResponse，我们返回一个唯一的 subscription id
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Subscribe {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
 }
 /// 取消对某个主题的订阅
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Unsubscribe {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
-    #[prost(uint32, tag = "2")]
+    #[prost(uint32, tag="2")]
     pub id: u32,
 }
 /// 发布数据到某个主题
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Publish {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
-    #[prost(message, repeated, tag = "2")]
+    #[prost(message, repeated, tag="2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
 }
+/// 导出一组 table 的数据（tables 为空时导出所有 table），用于在线备份
+/// 服务器会按 table 分段返回一串 CommandResponse，每段的 values[0] 是 table 名，
+/// pairs 是这一段的数据
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Dump {
+    #[prost(string, repeated, tag="1")]
+    pub tables: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 备份文件由若干段组成，每段以 TableHeader 开头，之后跟着 count 个 Kvpair
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TableHeader {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub count: u64,
+}
+/// 打开 stream 后的第一个请求，用来协商压缩算法和是否使用校验和。compressions 按客户端的
+/// 优先级排列，服务器在 values[0] 中返回选中的算法，values[1] 返回是否启用校验和。
+/// 旧版本的服务器会返回 400，此时继续使用 gzip
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    #[prost(string, repeated, tag="1")]
+    pub compressions: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bool, tag="2")]
+    pub checksum: bool,
+}
+/// 在服务器上原子地执行一段 Rhai 脚本，脚本里可以调用 get/set/del/publish，参数在 ARGS 数组里。
+/// script 为空时执行之前缓存的、hash 为 sha 的脚本，没有缓存时返回 404。
+/// 脚本的返回值放在 values 里，返回数组时会展开成多个 value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Eval {
+    #[prost(string, tag="1")]
+    pub script: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub sha: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// 清空服务器缓存的脚本，之后 EVALSHA 需要重新用 EVAL 发送脚本
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScriptFlush {
+}
+/// 查看服务器的运行状态，section 为空时返回所有信息。
+/// 结果放在 pairs 里，key 的格式是 <section>.<name>，比如 server.version
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Info {
+    #[prost(string, tag="1")]
+    pub section: ::prost::alloc::string::String,
+}
+/// 获取最近的 count 条慢日志，新的在前面；count 为 0 时返回全部
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogGet {
+    #[prost(uint32, tag="1")]
+    pub count: u32,
+}
+/// 清空慢日志
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogReset {
+}
+/// 一条慢日志
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogEntry {
+    /// 递增的 id
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+    /// 开始执行的时间，unix 时间戳（秒）
+    #[prost(uint64, tag="2")]
+    pub timestamp: u64,
+    /// 执行时间，单位微秒
+    #[prost(uint64, tag="3")]
+    pub duration_us: u64,
+    /// 发起命令的客户端地址
+    #[prost(string, tag="4")]
+    pub client: ::prost::alloc::string::String,
+    /// 命令的内容，过长时会被截断
+    #[prost(string, tag="5")]
+    pub command: ::prost::alloc::string::String,
+}
+/// 集群中的节点之间传递的 Raft 消息，只在集群内部使用
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftMessage {
+    #[prost(enumeration="RaftMessageType", tag="1")]
+    pub msg_type: i32,
+    #[prost(uint64, tag="2")]
+    pub from: u64,
+    #[prost(uint64, tag="3")]
+    pub to: u64,
+    #[prost(uint64, tag="4")]
+    pub term: u64,
+    /// Append 时是前一条日志的 term 和 index；Vote 时是候选人最后一条日志的 term 和 index；
+    /// AppendResponse 时 index 是 follower 已经和 leader 一致的最后一条日志
+    #[prost(uint64, tag="5")]
+    pub log_term: u64,
+    #[prost(uint64, tag="6")]
+    pub index: u64,
+    #[prost(message, repeated, tag="7")]
+    pub entries: ::prost::alloc::vec::Vec<RaftEntry>,
+    #[prost(uint64, tag="8")]
+    pub commit: u64,
+    #[prost(bool, tag="9")]
+    pub reject: bool,
+    /// 拒绝 Append 时，follower 最后一条日志的 index，leader 从这里开始重试
+    #[prost(uint64, tag="10")]
+    pub reject_hint: u64,
+    #[prost(message, optional, tag="11")]
+    pub snapshot: ::core::option::Option<RaftSnapshot>,
+    /// ReadIndex 使用的上下文，heartbeat response 会带回这个值
+    #[prost(uint64, tag="12")]
+    pub context: u64,
+}
+/// Raft 日志中的一条记录
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(uint64, tag="2")]
+    pub index: u64,
+    #[prost(enumeration="RaftEntryType", tag="3")]
+    pub entry_type: i32,
+    /// NORMAL 是编码后的 CommandRequest，为空时是 leader 当选后写入的空日志；
+    /// CONF_CHANGE 是编码后的 ConfChange
+    #[prost(bytes="bytes", tag="4")]
+    pub data: ::prost::bytes::Bytes,
+}
+/// 成员变更，一次只增加或者删除一个节点
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConfChange {
+    #[prost(enumeration="ConfChangeType", tag="1")]
+    pub change_type: i32,
+    #[prost(uint64, tag="2")]
+    pub node_id: u64,
+    /// 节点之间通信使用的地址
+    #[prost(string, tag="3")]
+    pub addr: ::prost::alloc::string::String,
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftMember {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+    #[prost(string, tag="2")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// 状态机在 index 处的快照，data 是备份文件格式的数据
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftSnapshot {
+    #[prost(uint64, tag="1")]
+    pub index: u64,
+    #[prost(uint64, tag="2")]
+    pub term: u64,
+    #[prost(message, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<RaftMember>,
+    #[prost(bytes="bytes", tag="4")]
+    pub data: ::prost::bytes::Bytes,
+}
+/// 需要持久化的 Raft 状态
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftHardState {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(uint64, tag="2")]
+    pub vote: u64,
+    #[prost(uint64, tag="3")]
+    pub commit: u64,
+}
+/// 往集群中加入一个节点，只能发给 leader
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClusterAdd {
+    #[prost(uint64, tag="1")]
+    pub node_id: u64,
+    #[prost(string, tag="2")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// 从集群中删除一个节点，只能发给 leader
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClusterRemove {
+    #[prost(uint64, tag="1")]
+    pub node_id: u64,
+}
+/// 查看集群的状态，结果放在 pairs 里
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClusterStatus {
+}
+/// 切换当前 stream 使用的 namespace，之后的命令只能看到这个 namespace 中的 table 和 topic。
+/// namespace 为空时切换回默认 namespace。客户端证书绑定了 namespace 时不能切换到其它 namespace
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Select {
+    #[prost(string, tag="1")]
+    pub namespace: ::prost::alloc::string::String,
+}
+/// W3C trace context（https://www.w3.org/TR/trace-context/），两个字段和 HTTP header 的内容一致
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TraceContext {
+    #[prost(string, tag="1")]
+    pub traceparent: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub tracestate: ::prost::alloc::string::String,
+}
+/// 固定存储当前的版本，values[0] 中返回版本号。之后的读命令可以在 as_of 中带上这个版本号，
+/// 读到固定时的数据。版本在 SNAPSHOT_RELEASE 或者一段时间没有被读取之后失效
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Snapshot {
+}
+/// 释放 SNAPSHOT 固定的版本，values[0] 中返回这个版本之前是否被固定
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotRelease {
+    #[prost(uint64, tag="1")]
+    pub version: u64,
+}
+/// 给 table 中的 key 设置过期时间，单位毫秒，values[0] 中返回 key 是否存在。
+/// 再次写入这个 key 会清除过期时间。volatile-ttl 淘汰策略只淘汰设置了过期时间的 key
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(uint64, tag="3")]
+    pub ttl_ms: u64,
+}
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum RaftMessageType {
+    Vote = 0,
+    VoteResponse = 1,
+    Append = 2,
+    AppendResponse = 3,
+    Heartbeat = 4,
+    HeartbeatResponse = 5,
+    Snapshot = 6,
+}
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum RaftEntryType {
+    Normal = 0,
+    ConfChange = 1,
+}
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ConfChangeType {
+    AddNode = 0,
+    RemoveNode = 1,
+}
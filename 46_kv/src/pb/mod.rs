@@ -1,6 +1,9 @@
 pub mod abi;
 
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    time::Duration,
+};
 
 use abi::{command_request::RequestData, *};
 use bytes::Bytes;
@@ -10,12 +13,13 @@ use prost::Message;
 use crate::KvError;
 
 impl CommandRequest {
-    pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_hget(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
         Self {
             request_data: Some(RequestData::Hget(Hget {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -24,24 +28,27 @@ impl CommandRequest {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_hmget(table: impl Into<String>, keys: Vec<Bytes>) -> Self {
         Self {
             request_data: Some(RequestData::Hmget(Hmget {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+    pub fn new_hset(table: impl Into<String>, key: impl Into<Bytes>, value: Value) -> Self {
         Self {
             request_data: Some(RequestData::Hset(Hset {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
             })),
+            ..Default::default()
         }
     }
 
@@ -51,48 +58,66 @@ impl CommandRequest {
                 table: table.into(),
                 pairs,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_hdel(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
         Self {
             request_data: Some(RequestData::Hdel(Hdel {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmdel(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_hmdel(table: impl Into<String>, keys: Vec<Bytes>) -> Self {
         Self {
             request_data: Some(RequestData::Hmdel(Hmdel {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_hexist(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
         Self {
             request_data: Some(RequestData::Hexist(Hexist {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmexist(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_hmexist(table: impl Into<String>, keys: Vec<Bytes>) -> Self {
         Self {
             request_data: Some(RequestData::Hmexist(Hmexist {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
+        }
+    }
+
+    /// 给 key 设置过期时间，再次写入这个 key 会清除过期时间
+    pub fn new_hexpire(table: impl Into<String>, key: impl Into<Bytes>, ttl: Duration) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpire(Hexpire {
+                table: table.into(),
+                key: key.into(),
+                ttl_ms: ttl.as_millis() as u64,
+            })),
+            ..Default::default()
         }
     }
 
     pub fn new_subscribe(name: impl Into<String>) -> Self {
         Self {
             request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            ..Default::default()
         }
     }
 
@@ -102,6 +127,7 @@ impl CommandRequest {
                 topic: name.into(),
                 id,
             })),
+            ..Default::default()
         }
     }
 
@@ -111,9 +137,149 @@ impl CommandRequest {
                 topic: name.into(),
                 data,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_dump(tables: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Dump(Dump { tables })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hello(compressions: Vec<String>, checksum: bool) -> Self {
+        Self {
+            request_data: Some(RequestData::Hello(Hello {
+                compressions,
+                checksum,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_eval(script: impl Into<String>, args: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Eval(Eval {
+                script: script.into(),
+                sha: String::new(),
+                args,
+            })),
+            ..Default::default()
         }
     }
 
+    /// 执行服务器上缓存的脚本，sha 是 `script_sha` 返回的值
+    pub fn new_evalsha(sha: impl Into<String>, args: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Eval(Eval {
+                script: String::new(),
+                sha: sha.into(),
+                args,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_script_flush() -> Self {
+        Self {
+            request_data: Some(RequestData::ScriptFlush(ScriptFlush {})),
+            ..Default::default()
+        }
+    }
+
+    /// 查看服务器状态，section 为空时返回所有信息
+    pub fn new_info(section: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Info(Info {
+                section: section.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_slowlog_get(count: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::SlowlogGet(SlowlogGet { count })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_slowlog_reset() -> Self {
+        Self {
+            request_data: Some(RequestData::SlowlogReset(SlowlogReset {})),
+            ..Default::default()
+        }
+    }
+
+    /// 集群内部的节点之间传递 Raft 消息
+    pub fn new_raft(msg: RaftMessage) -> Self {
+        Self {
+            request_data: Some(RequestData::Raft(msg)),
+            ..Default::default()
+        }
+    }
+
+    /// 把节点加入集群，addr 是其它节点连接它使用的地址
+    pub fn new_cluster_add(node_id: u64, addr: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::ClusterAdd(ClusterAdd {
+                node_id,
+                addr: addr.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_cluster_remove(node_id: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::ClusterRemove(ClusterRemove { node_id })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_cluster_status() -> Self {
+        Self {
+            request_data: Some(RequestData::ClusterStatus(ClusterStatus {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_select(namespace: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Select(Select {
+                namespace: namespace.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_snapshot() -> Self {
+        Self {
+            request_data: Some(RequestData::Snapshot(Snapshot {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_snapshot_release(version: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::SnapshotRelease(SnapshotRelease { version })),
+            ..Default::default()
+        }
+    }
+
+    /// 读取 SNAPSHOT 固定的 version 版本的数据，只对读命令有效
+    pub fn with_as_of(mut self, version: u64) -> Self {
+        self.as_of = version;
+        self
+    }
+
+    /// 设置请求 id，服务器会并发处理带 id 的请求，response 中带回同样的 id
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
+
     /// 转换成 string 做错误处理
     pub fn format(&self) -> String {
         format!("{:?}", self)
@@ -140,6 +306,41 @@ impl CommandResponse {
     pub fn format(&self) -> String {
         format!("{:?}", self)
     }
+
+    /// 把非 2xx 的 response 转换成对应的 KvError
+    pub fn into_result(self) -> Result<Self, KvError> {
+        // 服务器返回的 message 是 KvError 的 Display，去掉前缀避免重复
+        let msg = |prefix: &str| {
+            let msg = self.message.strip_prefix(prefix).unwrap_or(&self.message);
+            msg.to_string()
+        };
+
+        match StatusCode::from_u16(self.status as _) {
+            Ok(status) if status.is_success() => Ok(self),
+            Ok(StatusCode::NOT_FOUND) => Err(KvError::NotFound(msg("Not found: "))),
+            Ok(StatusCode::BAD_REQUEST) => {
+                let msg = msg("Command is invalid: ");
+                Err(KvError::InvalidCommand(msg.trim_matches('`').into()))
+            }
+            Ok(StatusCode::FORBIDDEN) => {
+                let msg = msg("Permission denied: ");
+                Err(KvError::PermissionDenied(msg))
+            }
+            Ok(StatusCode::GONE) => {
+                let msg = msg("Snapshot ");
+                let version = msg.split(' ').next().and_then(|v| v.parse().ok());
+                Err(KvError::SnapshotExpired(version.unwrap_or_default()))
+            }
+            Ok(StatusCode::MISDIRECTED_REQUEST) => {
+                let msg = msg("Not leader, leader is ");
+                Err(KvError::NotLeader(msg.trim_matches('`').into()))
+            }
+            _ => Err(KvError::Internal(format!(
+                "{} ({})",
+                self.message, self.status
+            ))),
+        }
+    }
 }
 
 impl Value {
@@ -151,7 +352,7 @@ impl Value {
 
 impl Kvpair {
     /// 创建一个新的 kv pair
-    pub fn new(key: impl Into<String>, value: Value) -> Self {
+    pub fn new(key: impl Into<Bytes>, value: Value) -> Self {
         Self {
             key: key.into(),
             value: Some(value),
@@ -159,6 +360,16 @@ impl Kvpair {
     }
 }
 
+impl TableHeader {
+    /// 创建备份文件中一段数据的头
+    pub fn new(table: impl Into<String>, count: usize) -> Self {
+        Self {
+            table: table.into(),
+            count: count as _,
+        }
+    }
+}
+
 /// 从 String 转换成 Value
 impl From<String> for Value {
     fn from(s: String) -> Self {
@@ -228,13 +439,20 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::OutOfMemory => result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _,
+            KvError::ScriptError(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::NotLeader(_) => result.status = StatusCode::MISDIRECTED_REQUEST.as_u16() as _,
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::SnapshotExpired(_) => result.status = StatusCode::GONE.as_u16() as _,
+            KvError::QuotaExceeded(_) => {
+                result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _
+            }
             _ => {}
         }
 
@@ -252,6 +470,17 @@ impl From<Vec<Value>> for CommandResponse {
     }
 }
 
+/// 从 Vec<SlowlogEntry> 转换成 CommandResponse
+impl From<Vec<SlowlogEntry>> for CommandResponse {
+    fn from(v: Vec<SlowlogEntry>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            slowlog: v,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<bool> for Value {
     fn from(b: bool) -> Self {
         Self {
@@ -301,6 +530,17 @@ impl TryFrom<Value> for f64 {
     }
 }
 
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v.format(), "String")),
+        }
+    }
+}
+
 impl TryFrom<Value> for Bytes {
     type Error = KvError;
 
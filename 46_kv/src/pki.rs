@@ -0,0 +1,324 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use certify::{load_ca, CertInfo};
+use serde::Serialize;
+use x509_parser::{extensions::GeneralName, pem::parse_x509_pem};
+
+use crate::{
+    ClientConfig, ClientTlsConfig, GeneralConfig, KvError, LogConfig, RotationConfig, ServerConfig,
+    ServerTlsConfig, StorageConfig,
+};
+
+/// 证书 subject 中的国家、组织和 CN
+#[derive(Debug, Clone)]
+pub struct CertSubject {
+    pub country: String,
+    pub organization: String,
+    pub common_name: String,
+}
+
+/// PEM 格式的证书和私钥
+#[derive(Debug, Clone)]
+pub struct CertPem {
+    pub cert: String,
+    pub key: String,
+}
+
+/// 证书的用途
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CertKind {
+    Ca,
+    Server,
+    Client,
+    Unknown,
+}
+
+/// 证书的概要信息，用于查看和检查证书是否快要过期
+#[derive(Debug, Clone, Serialize)]
+pub struct CertSummary {
+    pub subject: String,
+    pub issuer: String,
+    pub kind: CertKind,
+    pub sans: Vec<String>,
+    /// 生效和过期时间（unix 秒）
+    pub not_before: i64,
+    pub not_after: i64,
+    pub expires: String,
+    /// 距离过期的天数，已过期时为负数
+    pub days_left: i64,
+}
+
+impl CertKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CertKind::Ca => "ca",
+            CertKind::Server => "server",
+            CertKind::Client => "client",
+            CertKind::Unknown => "unknown",
+        }
+    }
+}
+
+impl CertSubject {
+    pub fn new(
+        country: impl Into<String>,
+        organization: impl Into<String>,
+        common_name: impl Into<String>,
+    ) -> Self {
+        Self {
+            country: country.into(),
+            organization: organization.into(),
+            common_name: common_name.into(),
+        }
+    }
+
+    fn info(&self, domains: &[&str], ips: &[&str], days: i64) -> CertInfo {
+        CertInfo::new(
+            domains,
+            ips,
+            &self.country,
+            &self.organization,
+            &self.common_name,
+            Some(days),
+        )
+    }
+}
+
+/// 生成一个自签名的 CA 证书
+pub fn generate_ca_cert(subject: &CertSubject, days: i64) -> Result<CertPem, KvError> {
+    let ca = subject.info(&[], &[], days).ca_cert(None)?;
+    Ok(CertPem {
+        cert: ca.serialize_pem()?,
+        key: ca.serialize_private_key_pem(),
+    })
+}
+
+/// 用 CA 签发服务器证书，sans 中的 IP 地址写成 IP SAN，其它的写成 DNS SAN
+pub fn issue_server_cert(
+    ca: &CertPem,
+    subject: &CertSubject,
+    sans: &[String],
+    days: i64,
+) -> Result<CertPem, KvError> {
+    if sans.is_empty() {
+        return Err(KvError::CertificateError(
+            "server cert requires at least one SAN".into(),
+        ));
+    }
+    // certify 解析 IP 失败时会 panic，所以在这里先分好类
+    let (ips, domains): (Vec<&str>, Vec<&str>) = sans
+        .iter()
+        .map(|s| s.as_str())
+        .partition(|s| s.parse::<IpAddr>().is_ok());
+
+    let cert = subject.info(&domains, &ips, days).server_cert(None)?;
+    let (cert, key) = load_ca(&ca.cert, &ca.key)?.sign_cert(&cert)?;
+    Ok(CertPem { cert, key })
+}
+
+/// 用 CA 签发客户端证书，subject 的 CN 就是服务器看到的客户端身份
+pub fn issue_client_cert(
+    ca: &CertPem,
+    subject: &CertSubject,
+    days: i64,
+) -> Result<CertPem, KvError> {
+    let cert = subject.info(&[], &[], days).client_cert(None)?;
+    let (cert, key) = load_ca(&ca.cert, &ca.key)?.sign_cert(&cert)?;
+    Ok(CertPem { cert, key })
+}
+
+/// 解析 PEM 格式的证书，读取 subject，用途，SAN 和有效期
+pub fn inspect_cert(pem: &str) -> Result<CertSummary, KvError> {
+    let (_, pem) = parse_x509_pem(pem.as_bytes())
+        .map_err(|e| KvError::CertificateError(format!("invalid PEM: {:?}", e)))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| KvError::CertificateError(format!("invalid certificate: {:?}", e)))?;
+
+    let tbs = &cert.tbs_certificate;
+    let kind = match (tbs.is_ca(), tbs.extended_key_usage()) {
+        (true, _) => CertKind::Ca,
+        (false, Some((_, eku))) if eku.server_auth => CertKind::Server,
+        (false, Some((_, eku))) if eku.client_auth => CertKind::Client,
+        _ => CertKind::Unknown,
+    };
+
+    let sans = tbs
+        .subject_alternative_name()
+        .map(|(_, san)| san.general_names.iter().filter_map(format_san).collect())
+        .unwrap_or_default();
+
+    let validity = cert.validity();
+    let not_after = validity.not_after.timestamp();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+
+    Ok(CertSummary {
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        kind,
+        sans,
+        not_before: validity.not_before.timestamp(),
+        not_after,
+        expires: validity.not_after.to_rfc2822(),
+        days_left: (not_after - now).div_euclid(24 * 3600),
+    })
+}
+
+fn format_san(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DNSName(dns) => Some(dns.to_string()),
+        GeneralName::IPAddress(&[a, b, c, d]) => Some(Ipv4Addr::new(a, b, c, d).to_string()),
+        GeneralName::IPAddress(ip) if ip.len() == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(ip);
+            Some(Ipv6Addr::from(octets).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// 用签发的服务器证书生成服务器配置。client_ca 存在时要求客户端提供证书
+pub fn server_config(
+    addr: impl Into<String>,
+    storage: StorageConfig,
+    server: &CertPem,
+    client_ca: Option<&CertPem>,
+) -> ServerConfig {
+    ServerConfig {
+        general: GeneralConfig { addr: addr.into() },
+        storage,
+        tls: ServerTlsConfig {
+            cert: server.cert.clone(),
+            key: server.key.clone(),
+            ca: client_ca.map(|ca| ca.cert.clone()),
+        },
+        log: LogConfig {
+            path: "/tmp/kv-log".into(),
+            rotation: RotationConfig::Daily,
+        },
+        compression: Default::default(),
+        frame: Default::default(),
+        memory: Default::default(),
+        script: Default::default(),
+        slowlog: Default::default(),
+        snapshot: Default::default(),
+        namespace: Default::default(),
+        trace: Default::default(),
+        listeners: Default::default(),
+        cluster: None,
+    }
+}
+
+/// 生成客户端配置，domain 需要是服务器证书中的一个 DNS SAN
+pub fn client_config(
+    addr: impl Into<String>,
+    domain: impl Into<String>,
+    ca: &CertPem,
+    identity: Option<&CertPem>,
+) -> ClientConfig {
+    ClientConfig {
+        general: GeneralConfig { addr: addr.into() },
+        transport: Default::default(),
+        namespace: None,
+        tls: ClientTlsConfig {
+            domain: domain.into(),
+            identity: identity.map(|id| (id.cert.clone(), id.key.clone())),
+            ca: Some(ca.cert.clone()),
+        },
+        compression: Default::default(),
+        frame: Default::default(),
+        trace: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TlsClientConnector, TlsServerAcceptor};
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    fn subject(cn: &str) -> CertSubject {
+        CertSubject::new("CN", "Acme Inc.", cn)
+    }
+
+    #[test]
+    fn issued_certs_should_be_inspectable() -> Result<()> {
+        let ca = generate_ca_cert(&subject("Acme CA"), 3650)?;
+        let sans = vec!["kvserver.acme.inc".to_string(), "10.0.0.1".to_string()];
+        let server = issue_server_cert(&ca, &subject("Acme KV server"), &sans, 365)?;
+        let client = issue_client_cert(&ca, &subject("device-1"), 30)?;
+
+        let summary = inspect_cert(&ca.cert)?;
+        assert_eq!(summary.kind, CertKind::Ca);
+        assert!(summary.subject.contains("CN=Acme CA"));
+        assert!((3649..=3650).contains(&summary.days_left));
+
+        let summary = inspect_cert(&server.cert)?;
+        assert_eq!(summary.kind, CertKind::Server);
+        assert!(summary.issuer.contains("CN=Acme CA"));
+        assert_eq!(summary.sans, sans);
+        assert!((364..=365).contains(&summary.days_left));
+
+        let summary = inspect_cert(&client.cert)?;
+        assert_eq!(summary.kind, CertKind::Client);
+        assert!(summary.subject.contains("CN=device-1"));
+        assert!(summary.sans.is_empty());
+        assert!((29..=30).contains(&summary.days_left));
+        Ok(())
+    }
+
+    #[test]
+    fn bad_input_should_be_rejected() -> Result<()> {
+        let ca = generate_ca_cert(&subject("Acme CA"), 3650)?;
+        assert!(issue_server_cert(&ca, &subject("server"), &[], 365).is_err());
+        assert!(inspect_cert("not a cert").is_err());
+        assert!(inspect_cert(&ca.key).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generated_configs_should_connect() -> Result<()> {
+        let ca = generate_ca_cert(&subject("Acme CA"), 3650)?;
+        let sans = vec!["kvserver.acme.inc".to_string()];
+        let server = issue_server_cert(&ca, &subject("Acme KV server"), &sans, 365)?;
+        let client = issue_client_cert(&ca, &subject("device-1"), 30)?;
+
+        let server_config =
+            server_config("127.0.0.1:0", StorageConfig::MemTable, &server, Some(&ca));
+        let client_config = client_config("127.0.0.1:0", "kvserver.acme.inc", &ca, Some(&client));
+        // 生成的配置需要能被正常地序列化和加载
+        let server_config: ServerConfig = toml::from_str(&toml::to_string_pretty(&server_config)?)?;
+        let client_config: ClientConfig = toml::from_str(&toml::to_string_pretty(&client_config)?)?;
+
+        let tls = &server_config.tls;
+        let acceptor = TlsServerAcceptor::new(&tls.cert, &tls.key, tls.ca.as_deref())?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let identity = TlsServerAcceptor::peer_identity(&stream).unwrap();
+            stream.write_all(identity.as_bytes()).await.unwrap();
+        });
+
+        let tls = &client_config.tls;
+        let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+        let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
+        let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        let mut buf = [0; 8];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"device-1");
+        Ok(())
+    }
+}
@@ -19,10 +19,10 @@ async fn main() -> Result<()> {
     };
     let config: ServerConfig = toml::from_str(&config)?;
 
-    let tracer = opentelemetry_jaeger::new_pipeline()
-        .with_service_name("kv-server")
-        .install_simple()?;
-    let opentelemetry = tracing_opentelemetry::layer().with_tracer(tracer);
+    let opentelemetry = config
+        .trace
+        .tracer("kv-server")?
+        .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
 
     // 添加
     let log = &config.log;
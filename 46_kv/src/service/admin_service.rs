@@ -0,0 +1,159 @@
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+
+use crate::{
+    storage::is_snapshot_unsupported, AsyncStorage, CommandResponse, Dump, KvError, Kvpair,
+    StreamingResponse,
+};
+
+/// 在线备份时，每个 CommandResponse 里最多放多少个 kv pair
+const DUMP_CHUNK_SIZE: usize = 1024;
+
+#[async_trait]
+pub trait AdminService {
+    /// 处理管理命令，返回 Response 流
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> StreamingResponse;
+}
+
+#[async_trait]
+impl AdminService for Dump {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> StreamingResponse {
+        // 存储支持 snapshot 时，所有 table 都从同一个版本读取，备份期间的写入不会混进来；
+        // 不支持时只能保证每个 table 自身是一致的
+        let snapshot = match store.clone().snapshot().await {
+            Ok(version) => Some(Snapshot::new(store.clone(), version)),
+            Err(e) if is_snapshot_unsupported(&e) => None,
+            Err(e) => return error_response(e),
+        };
+
+        let tables = match self.tables.is_empty() {
+            true => store.clone().get_tables().await,
+            false => Ok(self.tables),
+        };
+        let tables = match tables {
+            Ok(tables) => tables.into_iter(),
+            Err(e) => return error_response(e),
+        };
+
+        // 每次只读取一个 table，发送完再读下一个
+        let state = (tables, store, snapshot);
+        let responses = stream::unfold(state, |(mut tables, store, snapshot)| async move {
+            let table = tables.next()?;
+            let pairs = match snapshot.as_ref().map(|s| s.version) {
+                Some(version) => store.clone().get_all_at(table.clone(), version).await,
+                None => store.clone().get_all(table.clone()).await,
+            };
+
+            let responses = match pairs {
+                Ok(pairs) => to_responses(&table, pairs),
+                Err(e) => {
+                    // 出错后不再继续读取剩下的 table
+                    tables = Vec::new().into_iter();
+                    vec![Arc::new(e.into())]
+                }
+            };
+            Some((stream::iter(responses), (tables, store, snapshot)))
+        });
+
+        Box::pin(responses.flatten())
+    }
+}
+
+/// 备份使用的 snapshot，stream 结束或者被提前丢弃时释放
+struct Snapshot<Store: AsyncStorage> {
+    store: Arc<Store>,
+    version: u64,
+}
+
+impl<Store: AsyncStorage> Snapshot<Store> {
+    fn new(store: Arc<Store>, version: u64) -> Self {
+        Self { store, version }
+    }
+}
+
+impl<Store: AsyncStorage> Drop for Snapshot<Store> {
+    fn drop(&mut self) {
+        // 没有 runtime 时放弃释放，Mvcc 会在 snapshot 过期后自动回收
+        if let Ok(handle) = Handle::try_current() {
+            let store = self.store.clone();
+            let version = self.version;
+            handle.spawn(async move {
+                let _ = store.release(version).await;
+            });
+        }
+    }
+}
+
+fn to_responses(table: &str, pairs: Vec<Kvpair>) -> Vec<Arc<CommandResponse>> {
+    pairs
+        .chunks(DUMP_CHUNK_SIZE)
+        .map(|chunk| {
+            let mut res: CommandResponse = chunk.to_vec().into();
+            res.values = vec![table.into()];
+            Arc::new(res)
+        })
+        .collect()
+}
+
+fn error_response(e: KvError) -> StreamingResponse {
+    Box::pin(stream::once(async { Arc::new(e.into()) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, dispatch, dispatch_admin, CommandRequest, MemTable, Mvcc, SnapshotConfig,
+        Storage, Value,
+    };
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn dispatch_dump_should_work() {
+        let store = Arc::new(MemTable::new());
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store).await;
+        dispatch(CommandRequest::new_hset("t2", "k1", 1.into()), &store).await;
+
+        let cmd = CommandRequest::new_dump(vec![]);
+        let mut data: Vec<_> = dispatch_admin(cmd, &store).await.collect().await;
+        data.sort_by(|a, b| a.values.partial_cmp(&b.values).unwrap());
+
+        assert_eq!(data.len(), 2);
+        assert_res_ok(&data[0], &["t1".into()], &[Kvpair::new("k1", "v1".into())]);
+        assert_res_ok(&data[1], &["t2".into()], &[Kvpair::new("k1", 1.into())]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_dump_should_split_large_table() {
+        let store = Arc::new(MemTable::new());
+        for i in 0..DUMP_CHUNK_SIZE + 1 {
+            Storage::set(&*store, "t1", format!("k{}", i).into(), Value::default()).unwrap();
+        }
+
+        let cmd = CommandRequest::new_dump(vec!["t1".into()]);
+        let data: Vec<_> = dispatch_admin(cmd, &store).await.collect().await;
+
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].pairs.len(), DUMP_CHUNK_SIZE);
+        assert_eq!(data[1].pairs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dispatch_dump_should_not_see_writes_after_start() {
+        let store = Arc::new(Mvcc::new(MemTable::new(), SnapshotConfig::default()));
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store).await;
+
+        let cmd = CommandRequest::new_dump(vec![]);
+        let stream = dispatch_admin(cmd, &store).await;
+
+        // 备份开始之后的写入不会出现在结果里
+        dispatch(CommandRequest::new_hset("t1", "k1", "v2".into()), &store).await;
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2".into()), &store).await;
+        let data: Vec<_> = stream.collect().await;
+
+        assert_eq!(data.len(), 1);
+        assert_res_ok(&data[0], &["t1".into()], &[Kvpair::new("k1", "v1".into())]);
+    }
+}
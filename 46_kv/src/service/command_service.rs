@@ -1,41 +1,53 @@
 use crate::*;
+use async_trait::async_trait;
+use std::{sync::Arc, time::Duration};
 
+#[async_trait]
 impl CommandService for Hget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get(&self.table, &self.key) {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        match store.get(self.table.clone(), self.key.clone()).await {
             Ok(Some(v)) => v.into(),
-            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+            Ok(None) => {
+                let key = format_key(&self.key);
+                KvError::NotFound(format!("table {}, key {}", self.table, key)).into()
+            }
             Err(e) => e.into(),
         }
     }
 }
 
+#[async_trait]
 impl CommandService for Hmget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.get(&self.table, key) {
-                Ok(Some(v)) => v,
-                _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys {
+            match store.clone().get(self.table.clone(), key).await {
+                Ok(Some(v)) => values.push(v),
+                _ => values.push(Value::default()),
+            }
+        }
+        values.into()
     }
 }
 
+#[async_trait]
 impl CommandService for Hgetall {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        match store.get_all(self.table).await {
             Ok(v) => v.into(),
             Err(e) => e.into(),
         }
     }
 }
 
+#[async_trait]
 impl CommandService for Hset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
         match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+            Some(v) => match store
+                .set(self.table, v.key, v.value.unwrap_or_default())
+                .await
+            {
                 Ok(Some(v)) => v.into(),
                 Ok(None) => Value::default().into(),
                 Err(e) => e.into(),
@@ -45,27 +57,29 @@ impl CommandService for Hset {
     }
 }
 
+#[async_trait]
 impl CommandService for Hmset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        let pairs = self.pairs;
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
         let table = self.table;
-        pairs
-            .into_iter()
-            .map(|pair| {
-                let result = store.set(&table, pair.key, pair.value.unwrap_or_default());
-                match result {
-                    Ok(Some(v)) => v,
-                    _ => Value::default(),
-                }
-            })
-            .collect::<Vec<_>>()
-            .into()
+        let mut values = Vec::with_capacity(self.pairs.len());
+        for pair in self.pairs {
+            let result = store
+                .clone()
+                .set(table.clone(), pair.key, pair.value.unwrap_or_default())
+                .await;
+            match result {
+                Ok(Some(v)) => values.push(v),
+                _ => values.push(Value::default()),
+            }
+        }
+        values.into()
     }
 }
 
+#[async_trait]
 impl CommandService for Hdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.del(&self.table, &self.key) {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        match store.del(self.table, self.key).await {
             Ok(Some(v)) => v.into(),
             Ok(None) => Value::default().into(),
             Err(e) => e.into(),
@@ -73,38 +87,52 @@ impl CommandService for Hdel {
     }
 }
 
+#[async_trait]
 impl CommandService for Hmdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.del(&self.table, key) {
-                Ok(Some(v)) => v,
-                _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys {
+            match store.clone().del(self.table.clone(), key).await {
+                Ok(Some(v)) => values.push(v),
+                _ => values.push(Value::default()),
+            }
+        }
+        values.into()
     }
 }
 
+#[async_trait]
 impl CommandService for Hexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.contains(&self.table, &self.key) {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        match store.contains(self.table, self.key).await {
             Ok(v) => Value::from(v).into(),
             Err(e) => e.into(),
         }
     }
 }
 
+#[async_trait]
 impl CommandService for Hmexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.contains(&self.table, key) {
-                Ok(v) => v.into(),
-                _ => Value::default(),
-            })
-            .collect::<Vec<Value>>()
-            .into()
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys {
+            match store.clone().contains(self.table.clone(), key).await {
+                Ok(v) => values.push(v.into()),
+                _ => values.push(Value::default()),
+            }
+        }
+        values.into()
+    }
+}
+
+#[async_trait]
+impl CommandService for Hexpire {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        let ttl = Duration::from_millis(self.ttl_ms);
+        match store.expire(self.table, self.key, ttl).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
@@ -112,52 +140,54 @@ impl CommandService for Hmexist {
 mod tests {
     use super::*;
 
-    #[test]
-    fn hget_should_work() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hget_should_work() {
+        let store = Arc::new(MemTable::new());
         let cmd = CommandRequest::new_hset("score", "u1", 10.into());
-        dispatch(cmd, &store);
+        dispatch(cmd, &store).await;
         let cmd = CommandRequest::new_hget("score", "u1");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[10.into()], &[]);
     }
 
-    #[test]
-    fn hget_with_non_exist_key_should_return_404() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hget_with_non_exist_key_should_return_404() {
+        let store = Arc::new(MemTable::new());
         let cmd = CommandRequest::new_hget("score", "u1");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_error(&res, 404, "Not found");
     }
 
-    #[test]
-    fn hmget_should_work() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hmget_should_work() {
+        let store = Arc::new(MemTable::new());
 
         set_key_pairs(
             "user",
             vec![("u1", "Tyr"), ("u2", "Lindsey"), ("u3", "Rosie")],
             &store,
-        );
+        )
+        .await;
 
         let cmd = CommandRequest::new_hmget("user", vec!["u1".into(), "u4".into(), "u3".into()]);
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         let values = &["Tyr".into(), Value::default(), "Rosie".into()];
         assert_res_ok(&res, values, &[]);
     }
 
-    #[test]
-    fn hgetall_should_work() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hgetall_should_work() {
+        let store = Arc::new(MemTable::new());
 
         set_key_pairs(
             "score",
             vec![("u1", 10), ("u2", 8), ("u3", 11), ("u1", 6)],
             &store,
-        );
+        )
+        .await;
 
         let cmd = CommandRequest::new_hgetall("score");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         let pairs = &[
             Kvpair::new("u1", 6.into()),
             Kvpair::new("u2", 8.into()),
@@ -166,82 +196,101 @@ mod tests {
         assert_res_ok(&res, &[], pairs);
     }
 
-    #[test]
-    fn hset_should_work() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hset_should_work() {
+        let store = Arc::new(MemTable::new());
         let cmd = CommandRequest::new_hset("t1", "hello", "world".into());
-        let res = dispatch(cmd.clone(), &store);
+        let res = dispatch(cmd.clone(), &store).await;
         assert_res_ok(&res, &[Value::default()], &[]);
 
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &["world".into()], &[]);
     }
 
-    #[test]
-    fn hmset_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "world")], &store);
+    #[tokio::test]
+    async fn hmset_should_work() {
+        let store = Arc::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "world")], &store).await;
         let pairs = vec![
             Kvpair::new("u1", 10.1.into()),
             Kvpair::new("u2", 8.1.into()),
         ];
         let cmd = CommandRequest::new_hmset("t1", pairs);
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &["world".into(), Value::default()], &[]);
     }
 
-    #[test]
-    fn hdel_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1")], &store);
+    #[tokio::test]
+    async fn hdel_should_work() {
+        let store = Arc::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
         let cmd = CommandRequest::new_hdel("t1", "u2");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[Value::default()], &[]);
 
         let cmd = CommandRequest::new_hdel("t1", "u1");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &["v1".into()], &[]);
     }
 
-    #[test]
-    fn hmdel_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+    #[tokio::test]
+    async fn hmdel_should_work() {
+        let store = Arc::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store).await;
 
         let cmd = CommandRequest::new_hmdel("t1", vec!["u1".into(), "u3".into()]);
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &["v1".into(), Value::default()], &[]);
     }
 
-    #[test]
-    fn hexist_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1")], &store);
+    #[tokio::test]
+    async fn hexist_should_work() {
+        let store = Arc::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
         let cmd = CommandRequest::new_hexist("t1", "u2");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[false.into()], &[]);
 
         let cmd = CommandRequest::new_hexist("t1", "u1");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[true.into()], &[]);
     }
 
-    #[test]
-    fn hmexist_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+    #[tokio::test]
+    async fn hmexist_should_work() {
+        let store = Arc::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store).await;
 
         let cmd = CommandRequest::new_hmexist("t1", vec!["u1".into(), "u3".into()]);
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[true.into(), false.into()], &[]);
     }
 
-    fn set_key_pairs<T: Into<Value>>(table: &str, pairs: Vec<(&str, T)>, store: &impl Storage) {
-        pairs
-            .into_iter()
-            .map(|(k, v)| CommandRequest::new_hset(table, k, v.into()))
-            .for_each(|cmd| {
-                dispatch(cmd, store);
-            });
+    #[tokio::test]
+    async fn hexpire_should_work() {
+        let store = Arc::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
+
+        let cmd = CommandRequest::new_hexpire("t1", "u2", Duration::from_secs(1));
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        let cmd = CommandRequest::new_hexpire("t1", "u1", Duration::from_millis(0));
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let cmd = CommandRequest::new_hexist("t1", "u1");
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[false.into()], &[]);
+    }
+
+    async fn set_key_pairs<T: Into<Value>>(
+        table: &str,
+        pairs: Vec<(&'static str, T)>,
+        store: &Arc<impl AsyncStorage>,
+    ) {
+        for (k, v) in pairs {
+            dispatch(CommandRequest::new_hset(table, k, v.into()), store).await;
+        }
     }
 }
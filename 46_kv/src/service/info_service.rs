@@ -0,0 +1,251 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use crate::{AsyncStorage, CommandResponse, Info, KvError, Kvpair, Service, SlowlogGet};
+
+/// INFO 支持的 section
+const SECTIONS: &[&str] = &[
+    "server", "config", "clients", "stats", "storage", "pubsub", "slowlog",
+];
+
+/// 服务器运行时的计数器
+#[derive(Debug)]
+pub struct ServerStats {
+    started: Instant,
+    connections: AtomicUsize,
+    total_connections: AtomicU64,
+    streams: AtomicUsize,
+    total_streams: AtomicU64,
+    commands: AtomicU64,
+}
+
+/// 连接或者 stream 存活期间持有，drop 时计数减一
+pub struct ActiveGuard {
+    stats: Arc<ServerStats>,
+    stream: bool,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            connections: Default::default(),
+            total_connections: Default::default(),
+            streams: Default::default(),
+            total_streams: Default::default(),
+            commands: Default::default(),
+        }
+    }
+}
+
+impl ServerStats {
+    /// 接受一个新的连接
+    pub fn connect(self: &Arc<Self>) -> ActiveGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard {
+            stats: Arc::clone(self),
+            stream: false,
+        }
+    }
+
+    /// 开始处理一个新的 stream
+    pub fn open_stream(self: &Arc<Self>) -> ActiveGuard {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        self.total_streams.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard {
+            stats: Arc::clone(self),
+            stream: true,
+        }
+    }
+
+    /// 执行了一个命令
+    pub fn command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 当前的连接数
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// 当前的 stream 数
+    pub fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        match self.stream {
+            true => self.stats.streams.fetch_sub(1, Ordering::Relaxed),
+            false => self.stats.connections.fetch_sub(1, Ordering::Relaxed),
+        };
+    }
+}
+
+impl<Store: AsyncStorage> Service<Store> {
+    /// 处理 INFO，每个 section 里的 key 加上 "<section>." 前缀
+    pub(crate) async fn info(&self, cmd: Info) -> CommandResponse {
+        let sections: Vec<&str> = match cmd.section.to_lowercase().as_str() {
+            "" | "all" => SECTIONS.to_vec(),
+            s => match SECTIONS.iter().find(|v| **v == s) {
+                Some(s) => vec![*s],
+                None => {
+                    let e = KvError::InvalidCommand(format!("unknown INFO section {}", s));
+                    return e.into();
+                }
+            },
+        };
+
+        let mut pairs = Vec::new();
+        for section in sections {
+            let data = match self.info_section(section).await {
+                Ok(data) => data,
+                Err(e) => return e.into(),
+            };
+            pairs.extend(data.into_iter().map(|mut pair| {
+                pair.key = format!("{}.{}", section, String::from_utf8_lossy(&pair.key)).into();
+                pair
+            }));
+        }
+        pairs.into()
+    }
+
+    /// 处理 SLOWLOG GET
+    pub(crate) fn slowlog_get(&self, cmd: SlowlogGet) -> CommandResponse {
+        self.inner.slowlog.get(cmd.count as _).into()
+    }
+
+    /// 处理 SLOWLOG RESET
+    pub(crate) fn slowlog_reset(&self) -> CommandResponse {
+        self.inner.slowlog.reset();
+        CommandResponse::ok()
+    }
+
+    async fn info_section(&self, section: &str) -> Result<Vec<Kvpair>, KvError> {
+        let stats = &self.inner.stats;
+        let count = |n: u64| (n as i64).into();
+        let data = match section {
+            "server" => vec![
+                Kvpair::new("version", env!("CARGO_PKG_VERSION").into()),
+                Kvpair::new("uptime_secs", count(stats.started.elapsed().as_secs())),
+                Kvpair::new("process_id", count(std::process::id() as _)),
+            ],
+            "config" => self.inner.config_info.clone(),
+            "clients" => vec![
+                Kvpair::new("connections", count(stats.connections() as _)),
+                Kvpair::new(
+                    "total_connections",
+                    count(stats.total_connections.load(Ordering::Relaxed)),
+                ),
+                Kvpair::new("streams", count(stats.streams() as _)),
+                Kvpair::new(
+                    "total_streams",
+                    count(stats.total_streams.load(Ordering::Relaxed)),
+                ),
+            ],
+            "stats" => vec![Kvpair::new(
+                "commands",
+                count(stats.commands.load(Ordering::Relaxed)),
+            )],
+            "storage" => Arc::clone(&self.inner.store).stats().await?,
+            "pubsub" => vec![
+                Kvpair::new("topics", count(self.broadcaster.topic_count() as _)),
+                Kvpair::new(
+                    "subscriptions",
+                    count(self.broadcaster.subscription_count() as _),
+                ),
+            ],
+            "slowlog" => {
+                let slowlog = &self.inner.slowlog;
+                vec![
+                    Kvpair::new("len", count(slowlog.len() as _)),
+                    Kvpair::new("slower_than_us", count(slowlog.config().slower_than_us)),
+                    Kvpair::new("max_len", count(slowlog.config().max_len as _)),
+                ]
+            }
+            _ => unreachable!(),
+        };
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, CommandRequest, MemTable, ServiceInner, SlowlogConfig, Value};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn info_should_return_all_sections() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_config_info(vec![Kvpair::new("storage", "memtable".into())])
+            .into();
+        let _conn = service.stats().connect();
+        execute(&service, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+
+        let res = execute(&service, CommandRequest::new_info("")).await;
+        let get = |key: &str| {
+            res.pairs
+                .iter()
+                .find(|p| p.key == key)
+                .and_then(|p| p.value.clone())
+        };
+        assert_eq!(
+            get("server.version"),
+            Some(env!("CARGO_PKG_VERSION").into())
+        );
+        assert_eq!(get("config.storage"), Some("memtable".into()));
+        assert_eq!(get("clients.connections"), Some(1.into()));
+        // INFO 自己也算一个命令
+        assert_eq!(get("stats.commands"), Some(2.into()));
+        assert_eq!(get("storage.keys"), Some(1.into()));
+        assert_eq!(get("pubsub.topics"), Some(0.into()));
+        assert_eq!(get("slowlog.len"), Some(0.into()));
+    }
+
+    #[tokio::test]
+    async fn info_should_filter_by_section() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        let res = execute(&service, CommandRequest::new_info("PubSub")).await;
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.as_ref()).collect();
+        assert_eq!(keys, vec![&b"pubsub.topics"[..], b"pubsub.subscriptions"]);
+
+        let res = execute(&service, CommandRequest::new_info("unknown")).await;
+        assert_res_error(&res, 400, "unknown INFO section");
+    }
+
+    #[tokio::test]
+    async fn slowlog_should_record_commands_with_client() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_slowlog(SlowlogConfig {
+                slower_than_us: 0,
+                max_len: 10,
+            })
+            .into();
+        let cmd = CommandRequest::new_hset("t1", "k1", Value::default());
+        let client = Some(Arc::from("127.0.0.1:1234"));
+        service.execute_from(cmd, client).next().await;
+
+        let res = execute(&service, CommandRequest::new_slowlog_get(0)).await;
+        assert_eq!(res.slowlog.len(), 1);
+        assert_eq!(res.slowlog[0].client, "127.0.0.1:1234");
+        assert!(res.slowlog[0].command.contains("Hset"));
+
+        execute(&service, CommandRequest::new_slowlog_reset()).await;
+        let res = execute(&service, CommandRequest::new_slowlog_get(0)).await;
+        assert!(res.slowlog.is_empty());
+    }
+
+    async fn execute(service: &Service, cmd: CommandRequest) -> CommandResponse {
+        let res = service.execute(cmd).next().await.unwrap();
+        (*res).clone()
+    }
+}
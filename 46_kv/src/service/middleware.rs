@@ -0,0 +1,127 @@
+use futures::{future::BoxFuture, stream, Future};
+use std::sync::Arc;
+
+use crate::{CommandRequest, CommandResponse, StreamingResponse};
+
+/// 中间件：拿到请求和链上的下一步，返回 response stream。
+/// 可以修改请求后调用 `next.run()`，也可以直接返回 response 拒绝请求，
+/// 或者对 `next.run()` 返回的 stream 再做一层包装
+pub type Middleware =
+    Arc<dyn Fn(CommandRequest, Next) -> BoxFuture<'static, StreamingResponse> + Send + Sync>;
+
+/// 中间件链的终点，也就是 Service 真正处理命令的地方
+pub(crate) type Endpoint = Arc<dyn Fn(CommandRequest) -> StreamingResponse + Send + Sync>;
+
+/// 中间件链上的下一步
+pub struct Next {
+    chain: Arc<Vec<Middleware>>,
+    index: usize,
+    endpoint: Endpoint,
+}
+
+impl Next {
+    pub(crate) fn new(chain: Arc<Vec<Middleware>>, endpoint: Endpoint) -> Self {
+        Self {
+            chain,
+            index: 0,
+            endpoint,
+        }
+    }
+
+    /// 把请求交给下一个中间件，没有更多中间件时交给 Service 处理
+    pub async fn run(mut self, cmd: CommandRequest) -> StreamingResponse {
+        match self.chain.get(self.index).cloned() {
+            Some(middleware) => {
+                self.index += 1;
+                middleware(cmd, self).await
+            }
+            None => (self.endpoint)(cmd),
+        }
+    }
+}
+
+/// 把 async 闭包包装成 Middleware
+pub fn middleware<F, Fut>(f: F) -> Middleware
+where
+    F: Fn(CommandRequest, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = StreamingResponse> + Send + 'static,
+{
+    Arc::new(move |cmd, next| Box::pin(f(cmd, next)))
+}
+
+/// 只包含一个 response 的 stream，中间件拒绝请求时使用
+pub fn once_response(res: CommandResponse) -> StreamingResponse {
+    Box::pin(stream::once(async { Arc::new(res) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, MemTable, Service, ServiceInner, Value};
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn middleware_should_run_in_order_and_capture_state() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let c = counter.clone();
+        let service: Service = ServiceInner::new(MemTable::default())
+            // 有状态的中间件：统计请求数
+            .layer(move |cmd, next: Next| {
+                c.fetch_add(1, Ordering::SeqCst);
+                next.run(cmd)
+            })
+            // 修改请求：所有 HSET 都写入 t2
+            .layer(|mut cmd, next: Next| async move {
+                if let Some(crate::command_request::RequestData::Hset(ref mut p)) = cmd.request_data
+                {
+                    p.table = "t2".into();
+                }
+                next.run(cmd).await
+            })
+            .into();
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        res.next().await.unwrap();
+        let mut res = service.execute(CommandRequest::new_hget("t2", "k1"));
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn middleware_should_short_circuit_and_wrap_response() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .layer(|cmd: CommandRequest, next: Next| async move {
+                match cmd.request_data {
+                    Some(crate::command_request::RequestData::Hdel(_)) => {
+                        once_response(CommandResponse {
+                            status: 403,
+                            message: "HDEL is not allowed".into(),
+                            ..Default::default()
+                        })
+                    }
+                    _ => next.run(cmd).await,
+                }
+            })
+            // 包装 response stream：在每个 response 里附加一个值
+            .layer(|cmd, next: Next| async move {
+                let res = next.run(cmd).await;
+                let res = res.map(|data| {
+                    let mut data = (*data).clone();
+                    data.values.push("wrapped".into());
+                    Arc::new(data)
+                });
+                Box::pin(res) as StreamingResponse
+            })
+            .into();
+
+        let mut res = service.execute(CommandRequest::new_hdel("t1", "k1"));
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 403, "not allowed");
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &[Value::default(), "wrapped".into()], &[]);
+    }
+}
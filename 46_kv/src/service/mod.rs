@@ -1,21 +1,39 @@
 use crate::{
-    command_request::RequestData, CommandRequest, CommandResponse, KvError, MemTable, Storage,
+    command_request::RequestData, AsyncStorage, CommandRequest, CommandResponse, KvError, Kvpair,
+    MemTable,
 };
-use futures::stream;
-use std::sync::Arc;
-use tracing::{debug, instrument};
+use async_trait::async_trait;
+use futures::{stream, Future, StreamExt};
+use std::{sync::Arc, time::Instant};
+use tokio::sync::RwLock;
+use tracing::{debug, instrument, Instrument};
 
+mod admin_service;
 mod command_service;
+mod info_service;
+mod middleware;
+mod namespace;
+mod script_service;
+mod slowlog;
+mod snapshot_service;
 mod topic;
 mod topic_service;
 
+pub use admin_service::AdminService;
+pub use info_service::{ActiveGuard, ServerStats};
+pub use middleware::{middleware, once_response, Middleware, Next};
+pub use namespace::{NamespaceConfig, Namespaces, QuotaConfig, NAMESPACE_SEPARATOR};
+pub use script_service::{script_sha, ScriptConfig, Scripts};
+pub use slowlog::{Slowlog, SlowlogConfig};
+pub use snapshot_service::dispatch_at;
 pub use topic::{Broadcaster, Topic};
 pub use topic_service::{StreamingResponse, TopicService};
 
 /// 对 Command 的处理的抽象
+#[async_trait]
 pub trait CommandService {
     /// 处理 Command，返回 Response
-    fn execute(self, store: &impl Storage) -> CommandResponse;
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse;
 }
 
 /// 事件通知（不可变事件）
@@ -28,7 +46,13 @@ pub trait NotifyMut<Arg> {
     fn notify(&self, arg: &mut Arg);
 }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
+/// 事件回调，可以捕获状态（比如 metrics registry）
+pub type Hook<Arg> = Box<dyn Fn(&Arg) + Send + Sync>;
+
+/// 可以修改参数的事件回调
+pub type HookMut<Arg> = Box<dyn Fn(&mut Arg) + Send + Sync>;
+
+impl<Arg> Notify<Arg> for Vec<Hook<Arg>> {
     #[inline]
     fn notify(&self, arg: &Arg) {
         for f in self {
@@ -37,7 +61,7 @@ impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
     }
 }
 
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
+impl<Arg> NotifyMut<Arg> for Vec<HookMut<Arg>> {
     #[inline]
     fn notify(&self, arg: &mut Arg) {
         for f in self {
@@ -63,46 +87,108 @@ impl<Store> Clone for Service<Store> {
 
 /// Service 内部数据结构
 pub struct ServiceInner<Store> {
-    store: Store,
-    on_received: Vec<fn(&CommandRequest)>,
-    on_executed: Vec<fn(&CommandResponse)>,
-    on_before_send: Vec<fn(&mut CommandResponse)>,
-    on_after_send: Vec<fn()>,
+    store: Arc<Store>,
+    /// 普通命令持有读锁，脚本持有写锁，保证脚本执行的原子性
+    barrier: RwLock<()>,
+    scripts: Scripts,
+    slowlog: Slowlog,
+    namespaces: Namespaces,
+    stats: Arc<ServerStats>,
+    /// INFO 中 config section 的内容
+    config_info: Vec<Kvpair>,
+    on_received: Vec<Hook<CommandRequest>>,
+    on_executed: Vec<Hook<CommandResponse>>,
+    on_before_send: Vec<HookMut<CommandResponse>>,
+    on_after_send: Vec<Hook<CommandResponse>>,
+    /// 按注册顺序执行的中间件，先注册的在外层
+    middlewares: Arc<Vec<Middleware>>,
 }
 
-impl<Store: Storage> ServiceInner<Store> {
+impl<Store: AsyncStorage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
-            store,
+            store: Arc::new(store),
+            barrier: RwLock::new(()),
+            scripts: Default::default(),
+            slowlog: Default::default(),
+            namespaces: Default::default(),
+            stats: Default::default(),
+            config_info: Vec::new(),
             on_received: Vec::new(),
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
+            middlewares: Default::default(),
         }
     }
 
-    pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
-        self.on_received.push(f);
+    pub fn fn_received(mut self, f: impl Fn(&CommandRequest) + Send + Sync + 'static) -> Self {
+        self.on_received.push(Box::new(f));
+        self
+    }
+
+    pub fn fn_executed(mut self, f: impl Fn(&CommandResponse) + Send + Sync + 'static) -> Self {
+        self.on_executed.push(Box::new(f));
         self
     }
 
-    pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
-        self.on_executed.push(f);
+    pub fn fn_before_send(
+        mut self,
+        f: impl Fn(&mut CommandResponse) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_before_send.push(Box::new(f));
         self
     }
 
-    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse)) -> Self {
-        self.on_before_send.push(f);
+    /// response 写入 stream 之后调用
+    pub fn fn_after_send(mut self, f: impl Fn(&CommandResponse) + Send + Sync + 'static) -> Self {
+        self.on_after_send.push(Box::new(f));
         self
     }
 
-    pub fn fn_after_send(mut self, f: fn()) -> Self {
-        self.on_after_send.push(f);
+    /// 设置脚本的执行限制
+    pub fn with_script(mut self, config: ScriptConfig) -> Self {
+        self.scripts = Scripts::new(config);
+        self
+    }
+
+    /// 设置慢日志的阈值和长度
+    pub fn with_slowlog(mut self, config: SlowlogConfig) -> Self {
+        self.slowlog = Slowlog::new(config);
+        self
+    }
+
+    /// 设置客户端证书绑定的 namespace 和每个 namespace 的配额
+    pub fn with_namespace(mut self, config: NamespaceConfig) -> Self {
+        self.namespaces = Namespaces::new(config);
+        self
+    }
+
+    /// 设置 INFO 返回的配置摘要，调用者需要保证其中不包含密钥
+    pub fn with_config_info(mut self, info: Vec<Kvpair>) -> Self {
+        self.config_info = info;
+        self
+    }
+
+    /// 服务使用的存储，集群模式下 Raft 也会把提交的命令应用到这里
+    pub fn store(&self) -> &Arc<Store> {
+        &self.store
+    }
+
+    /// 添加一个 async 中间件，可以修改请求、直接返回 response 或者包装 response stream
+    pub fn layer<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(CommandRequest, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = StreamingResponse> + Send + 'static,
+    {
+        Arc::get_mut(&mut self.middlewares)
+            .expect("middlewares are not shared before Service is built")
+            .push(middleware(f));
         self
     }
 }
 
-impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
+impl<Store: AsyncStorage> From<ServiceInner<Store>> for Service<Store> {
     fn from(inner: ServiceInner<Store>) -> Self {
         Self {
             inner: Arc::new(inner),
@@ -111,15 +197,151 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     }
 }
 
-impl<Store: Storage> Service<Store> {
-    #[instrument(name = "service_execute", skip_all)]
+impl<Store: AsyncStorage> Service<Store> {
     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+        self.execute_in(cmd, None, None)
+    }
+
+    /// 执行命令，client 是发起命令的客户端，执行得慢的命令会和它一起记录在慢日志中
+    pub fn execute_from(&self, cmd: CommandRequest, client: Option<Arc<str>>) -> StreamingResponse {
+        if self.inner.middlewares.is_empty() {
+            return self.execute_inner(cmd, client);
+        }
+
+        let service = self.clone();
+        let endpoint = Arc::new(move |cmd| service.execute_inner(cmd, client.clone()));
+        let next = Next::new(Arc::clone(&self.inner.middlewares), endpoint);
+        // 中间件是 async 的，先等待它返回 stream，再把这个 stream 展开
+        Box::pin(stream::once(next.run(cmd).in_current_span()).flatten())
+    }
+
+    /// 在 namespace 中执行命令，namespace 为 None 时使用默认 namespace
+    #[instrument(name = "service_execute", skip_all)]
+    pub fn execute_in(
+        &self,
+        cmd: CommandRequest,
+        client: Option<Arc<str>>,
+        namespace: Option<Arc<str>>,
+    ) -> StreamingResponse {
+        // 默认 namespace 可以直接写其它 namespace 的 table，这些写入也要记到它们的用量里
+        if namespace.is_none() && !self.inner.namespaces.affects_usage(&cmd) {
+            return self.execute_from(cmd, client);
+        }
+
+        let service = self.clone();
+        Box::pin(
+            stream::once(
+                async move {
+                    let next = |cmd| service.execute_from(cmd, client);
+                    let namespaces = &service.inner.namespaces;
+                    let store = &service.inner.store;
+                    match namespace {
+                        Some(namespace) => namespaces.execute(&namespace, cmd, store, next).await,
+                        None => namespaces.execute_default(cmd, store, next).await,
+                    }
+                }
+                .in_current_span(),
+            )
+            .flatten(),
+        )
+    }
+
+    /// 网络层用它处理 SELECT 和客户端证书绑定的 namespace
+    pub fn namespaces(&self) -> &Namespaces {
+        &self.inner.namespaces
+    }
+
+    /// response 发送出去之后，由网络层调用
+    pub fn after_send(&self, res: &CommandResponse) {
+        self.inner.on_after_send.notify(res);
+    }
+
+    /// 服务器的运行计数，网络层用它记录连接和 stream 数
+    pub fn stats(&self) -> &Arc<ServerStats> {
+        &self.inner.stats
+    }
+
+    fn execute_inner(&self, cmd: CommandRequest, client: Option<Arc<str>>) -> StreamingResponse {
+        // 存储是异步的，先等待命令执行完拿到 stream，再把这个 stream 展开。
+        // 命令在 poll 的时候才执行，需要带上当前的 span
+        let service = self.clone();
+        let fut = async move { service.execute_async(cmd, client).await };
+        Box::pin(stream::once(fut.in_current_span()).flatten())
+    }
+
+    async fn execute_async(
+        &self,
+        cmd: CommandRequest,
+        client: Option<Arc<str>>,
+    ) -> StreamingResponse {
         debug!("Got request: {:?}", cmd);
         self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd.clone(), &self.inner.store);
+        self.inner.stats.command();
+
+        // INFO、SLOWLOG 和 SCRIPT FLUSH 只访问服务器自身的状态，不记录到慢日志中
+        match cmd.request_data {
+            Some(RequestData::Info(param)) => return once_response(self.info(param).await),
+            Some(RequestData::SlowlogGet(param)) => return once_response(self.slowlog_get(param)),
+            Some(RequestData::SlowlogReset(_)) => return once_response(self.slowlog_reset()),
+            Some(RequestData::ScriptFlush(_)) => {
+                self.inner.scripts.flush();
+                return once_response(CommandResponse::ok());
+            }
+            _ => {}
+        }
+
+        let start = Instant::now();
+        let res = self.execute_command(&cmd).await;
+        self.inner
+            .slowlog
+            .record(&cmd, start.elapsed(), client.as_deref());
+        res
+    }
+
+    async fn execute_command(&self, cmd: &CommandRequest) -> StreamingResponse {
+        let mut res = {
+            let _guard = self.inner.barrier.read().await;
+            dispatch(cmd.clone(), &self.inner.store).await
+        };
 
         if res == CommandResponse::default() {
-            dispatch_stream(cmd, Arc::clone(&self.broadcaster))
+            match &cmd.request_data {
+                // 备份从 snapshot 里读取，不需要阻塞其它命令
+                Some(RequestData::Dump(_)) => dispatch_admin(cmd.clone(), &self.inner.store).await,
+                // 脚本执行期间不允许其它命令访问存储，保证原子性
+                Some(RequestData::Eval(param)) => {
+                    let _guard = self.inner.barrier.write().await;
+                    let store = Arc::clone(&self.inner.store);
+                    let broadcaster = Arc::clone(&self.broadcaster);
+                    let res = self
+                        .inner
+                        .scripts
+                        .execute(param.clone(), store, broadcaster)
+                        .await;
+                    once_response(res)
+                }
+                // HELLO 由网络层在 stream 开始时处理，走到这里说明客户端发送的时机不对
+                Some(RequestData::Hello(_)) => {
+                    let res = KvError::InvalidCommand("HELLO must be the first request".into());
+                    Box::pin(stream::once(async { Arc::new(res.into()) }))
+                }
+                // SELECT 修改的是 stream 的状态，只能由网络层处理
+                Some(RequestData::Select(_)) => {
+                    let res = KvError::InvalidCommand("SELECT must be sent on a stream".into());
+                    once_response(res.into())
+                }
+                // 集群模式下由 ClusterNode 中间件处理
+                Some(
+                    RequestData::Raft(_)
+                    | RequestData::ClusterAdd(_)
+                    | RequestData::ClusterRemove(_)
+                    | RequestData::ClusterStatus(_),
+                ) => {
+                    let res = KvError::InvalidCommand("cluster mode is not enabled".into());
+                    once_response(res.into())
+                }
+                _ => dispatch_stream(cmd.clone(), Arc::clone(&self.broadcaster)),
+            }
         } else {
             debug!("Executed response: {:?}", res);
             self.inner.on_executed.notify(&res);
@@ -133,24 +355,44 @@ impl<Store: Storage> Service<Store> {
     }
 }
 
-/// 从 Request 中得到 Response，目前处理所有 HGET/HSET/HDEL/HEXIST
-pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+/// 从 Request 中得到 Response，目前处理所有 HGET/HSET/HDEL/HEXIST/HEXPIRE 和 SNAPSHOT
+pub async fn dispatch(cmd: CommandRequest, store: &Arc<impl AsyncStorage>) -> CommandResponse {
+    if cmd.as_of != 0 {
+        return dispatch_at(cmd, store).await;
+    }
+
+    let store = Arc::clone(store);
     match cmd.request_data {
-        Some(RequestData::Hget(param)) => param.execute(store),
-        Some(RequestData::Hgetall(param)) => param.execute(store),
-        Some(RequestData::Hmget(param)) => param.execute(store),
-        Some(RequestData::Hset(param)) => param.execute(store),
-        Some(RequestData::Hmset(param)) => param.execute(store),
-        Some(RequestData::Hdel(param)) => param.execute(store),
-        Some(RequestData::Hmdel(param)) => param.execute(store),
-        Some(RequestData::Hexist(param)) => param.execute(store),
-        Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Hget(param)) => param.execute(store).await,
+        Some(RequestData::Hgetall(param)) => param.execute(store).await,
+        Some(RequestData::Hmget(param)) => param.execute(store).await,
+        Some(RequestData::Hset(param)) => param.execute(store).await,
+        Some(RequestData::Hmset(param)) => param.execute(store).await,
+        Some(RequestData::Hdel(param)) => param.execute(store).await,
+        Some(RequestData::Hmdel(param)) => param.execute(store).await,
+        Some(RequestData::Hexist(param)) => param.execute(store).await,
+        Some(RequestData::Hmexist(param)) => param.execute(store).await,
+        Some(RequestData::Hexpire(param)) => param.execute(store).await,
+        Some(RequestData::Snapshot(param)) => param.execute(store).await,
+        Some(RequestData::SnapshotRelease(param)) => param.execute(store).await,
         None => KvError::InvalidCommand("Request has no data".into()).into(),
         // 处理不了的返回一个啥都不包括的 Response，这样后续可以用 dispatch_stream 处理
         _ => CommandResponse::default(),
     }
 }
 
+/// 从 Request 中得到 Response，目前处理 DUMP
+pub async fn dispatch_admin(
+    cmd: CommandRequest,
+    store: &Arc<impl AsyncStorage>,
+) -> StreamingResponse {
+    match cmd.request_data {
+        Some(RequestData::Dump(param)) => param.execute(Arc::clone(store)).await,
+        // 如果走到这里，就是代码逻辑的问题，直接 crash 出来
+        _ => unreachable!(),
+    }
+}
+
 /// 从 Request 中得到 Response，目前处理所有 PUBLISH/SUBSCRIBE/UNSUBSCRIBE
 pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingResponse {
     match cmd.request_data {
@@ -164,12 +406,13 @@ pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingRespo
 
 #[cfg(test)]
 mod tests {
+    use bytes::Bytes;
     use http::StatusCode;
     use tokio_stream::StreamExt;
     use tracing::info;
 
     use super::*;
-    use crate::{MemTable, Value};
+    use crate::{MemTable, Storage, Value};
 
     #[tokio::test]
     async fn service_should_works() {
@@ -194,6 +437,63 @@ mod tests {
         assert_res_ok(&data, &["v1".into()], &[]);
     }
 
+    #[tokio::test]
+    async fn service_with_async_storage_should_work() {
+        // 一个原生的异步存储，不需要实现 Storage
+        struct AsyncStore(MemTable);
+
+        #[async_trait]
+        impl AsyncStorage for AsyncStore {
+            async fn get(
+                self: Arc<Self>,
+                table: String,
+                key: Bytes,
+            ) -> Result<Option<Value>, KvError> {
+                tokio::task::yield_now().await;
+                Storage::get(&self.0, &table, &key)
+            }
+
+            async fn set(
+                self: Arc<Self>,
+                table: String,
+                key: Bytes,
+                value: Value,
+            ) -> Result<Option<Value>, KvError> {
+                tokio::task::yield_now().await;
+                Storage::set(&self.0, &table, key, value)
+            }
+
+            async fn contains(self: Arc<Self>, table: String, key: Bytes) -> Result<bool, KvError> {
+                Storage::contains(&self.0, &table, &key)
+            }
+
+            async fn del(
+                self: Arc<Self>,
+                table: String,
+                key: Bytes,
+            ) -> Result<Option<Value>, KvError> {
+                Storage::del(&self.0, &table, &key)
+            }
+
+            async fn get_all(self: Arc<Self>, table: String) -> Result<Vec<Kvpair>, KvError> {
+                Storage::get_all(&self.0, &table)
+            }
+
+            async fn get_tables(self: Arc<Self>) -> Result<Vec<String>, KvError> {
+                Storage::get_tables(&self.0)
+            }
+        }
+
+        let service: Service<AsyncStore> = ServiceInner::new(AsyncStore(MemTable::new())).into();
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+    }
+
     #[tokio::test]
     async fn event_registration_should_work() {
         fn b(cmd: &CommandRequest) {
@@ -205,8 +505,8 @@ mod tests {
         fn d(res: &mut CommandResponse) {
             res.status = StatusCode::CREATED.as_u16() as _;
         }
-        fn e() {
-            info!("Data is sent");
+        fn e(res: &CommandResponse) {
+            info!("Data is sent: {:?}", res);
         }
 
         let service: Service = ServiceInner::new(MemTable::default())
@@ -226,7 +526,7 @@ mod tests {
 }
 
 #[cfg(test)]
-use crate::{Kvpair, Value};
+use crate::Value;
 
 // 测试成功返回的结果
 #[cfg(test)]
@@ -0,0 +1,706 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::{stream, StreamExt};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::{
+    command_request::RequestData, AsyncStorage, CommandRequest, CommandResponse, Dump, Hdel,
+    Hexist, Hexpire, Hget, Hgetall, Hmdel, Hmexist, Hmget, Hmset, Hset, KvError, Publish,
+    StreamingResponse, Subscribe, Unsubscribe, Value,
+};
+
+/// namespace 和 table / topic 名字之间的分隔符。
+/// namespace ns 中的 table t 在存储中的名字是 `ns/t`，默认 namespace 可以用这个名字直接访问它
+pub const NAMESPACE_SEPARATOR: char = '/';
+
+/// namespace 名字的最大长度
+const MAX_NAMESPACE_LEN: usize = 64;
+
+/// namespace 的配置
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct NamespaceConfig {
+    /// 客户端证书的 CN 到 namespace 的映射，这些客户端只能使用绑定的 namespace
+    pub clients: HashMap<String, String>,
+    /// 没有在 quotas 中单独配置的 namespace 使用的配额
+    pub default_quota: QuotaConfig,
+    /// 每个 namespace 的配额
+    pub quotas: HashMap<String, QuotaConfig>,
+}
+
+/// 一个 namespace 的配额，只对在这个 namespace 中执行的写命令生效
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// 最多有多少个 key，0 表示不限制
+    pub max_keys: u64,
+    /// key 和 value 最多占用多少字节（按 protobuf 编码计算），0 表示不限制
+    pub max_bytes: u64,
+}
+
+/// 管理 namespace 的绑定关系和配额
+#[derive(Debug, Default)]
+pub struct Namespaces {
+    config: NamespaceConfig,
+    /// 每个 namespace 的用量，第一次写入时从存储中统计。
+    /// 同一个 namespace 的写命令在锁里串行执行，保证配额检查和写入之间没有其它写入
+    usage: DashMap<String, Arc<Mutex<Option<Cached>>>>,
+    /// 在默认 namespace 中执行 EVAL 之后加一。脚本可以写任意的 table，之后所有缓存的用量都要重新统计
+    generation: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    keys: u64,
+    bytes: u64,
+}
+
+/// 缓存的用量，以及统计时的 epoch。epoch 变了说明有不经过 namespace 的修改，需要重新统计
+#[derive(Debug, Clone, Copy)]
+struct Cached {
+    usage: Usage,
+    epoch: u64,
+}
+
+/// 一个写命令对用量的影响
+#[derive(Debug, Default)]
+struct Delta {
+    keys: i64,
+    bytes: i64,
+}
+
+impl QuotaConfig {
+    fn is_unlimited(&self) -> bool {
+        self.max_keys == 0 && self.max_bytes == 0
+    }
+}
+
+impl Namespaces {
+    pub fn new(config: NamespaceConfig) -> Self {
+        Self {
+            config,
+            usage: DashMap::new(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> &NamespaceConfig {
+        &self.config
+    }
+
+    /// 客户端证书绑定的 namespace，没有绑定时使用默认 namespace
+    pub fn bound(&self, identity: Option<&str>) -> Option<Arc<str>> {
+        identity
+            .and_then(|id| self.config.clients.get(id))
+            .map(|ns| Arc::from(ns.as_str()))
+    }
+
+    /// 处理 SELECT，返回新的 namespace，空字符串表示默认 namespace
+    pub fn select(&self, identity: Option<&str>, name: &str) -> Result<Option<Arc<str>>, KvError> {
+        if !name.is_empty() {
+            validate(name)?;
+        }
+        if let Some(bound) = self.bound(identity) {
+            if bound.as_ref() != name {
+                return Err(KvError::PermissionDenied(format!(
+                    "client `{}` is bound to namespace `{}`",
+                    identity.unwrap_or_default(),
+                    bound
+                )));
+            }
+        }
+        Ok(match name.is_empty() {
+            true => None,
+            false => Some(Arc::from(name)),
+        })
+    }
+
+    /// 在 namespace 中执行命令：把 table 和 topic 换成存储中的名字之后交给 next 执行
+    pub async fn execute<Store, F>(
+        &self,
+        namespace: &str,
+        mut cmd: CommandRequest,
+        store: &Arc<Store>,
+        next: F,
+    ) -> StreamingResponse
+    where
+        Store: AsyncStorage,
+        F: FnOnce(CommandRequest) -> StreamingResponse,
+    {
+        let data = match cmd.request_data.as_mut() {
+            Some(data) => data,
+            None => return next(cmd),
+        };
+
+        match data {
+            RequestData::Hget(Hget { table, .. })
+            | RequestData::Hgetall(Hgetall { table })
+            | RequestData::Hmget(Hmget { table, .. })
+            | RequestData::Hexist(Hexist { table, .. })
+            | RequestData::Hmexist(Hmexist { table, .. }) => *table = qualify(namespace, table),
+            RequestData::Hset(Hset { table, .. })
+            | RequestData::Hmset(Hmset { table, .. })
+            | RequestData::Hdel(Hdel { table, .. })
+            | RequestData::Hmdel(Hmdel { table, .. })
+            | RequestData::Hexpire(Hexpire { table, .. }) => {
+                *table = qualify(namespace, table);
+                return self.write(namespace, cmd, store, next, true).await;
+            }
+            RequestData::Subscribe(Subscribe { topic })
+            | RequestData::Unsubscribe(Unsubscribe { topic, .. })
+            | RequestData::Publish(Publish { topic, .. }) => *topic = qualify(namespace, topic),
+            RequestData::Dump(Dump { tables }) => {
+                let prefix = qualify(namespace, "");
+                *tables = match tables.is_empty() {
+                    true => match namespace_tables(&prefix, store).await {
+                        Ok(tables) => tables,
+                        Err(e) => return once(e.into()),
+                    },
+                    false => tables.iter().map(|t| qualify(namespace, t)).collect(),
+                };
+                // DUMP 的 tables 为空表示所有 table，namespace 中没有 table 时直接返回
+                if tables.is_empty() {
+                    return Box::pin(stream::empty());
+                }
+                return Box::pin(next(cmd).map(move |res| strip_table(res, &prefix)));
+            }
+            // 脚本中的 table 名字没法在执行前改写
+            RequestData::Eval(_) => {
+                let e = KvError::InvalidCommand("EVAL is not supported in namespace".into());
+                return once(e.into());
+            }
+            // 影响整个服务器的命令只能在默认 namespace 中执行
+            RequestData::SlowlogReset(_)
+            | RequestData::ScriptFlush(_)
+            | RequestData::Raft(_)
+            | RequestData::ClusterAdd(_)
+            | RequestData::ClusterRemove(_) => {
+                let e = KvError::PermissionDenied(format!(
+                    "command is not allowed in namespace `{}`",
+                    namespace
+                ));
+                return once(e.into());
+            }
+            RequestData::Hello(_)
+            | RequestData::Info(_)
+            | RequestData::SlowlogGet(_)
+            | RequestData::ClusterStatus(_)
+            | RequestData::Select(_)
+            | RequestData::Snapshot(_)
+            | RequestData::SnapshotRelease(_) => {}
+        }
+        next(cmd)
+    }
+
+    /// 默认 namespace 中的命令是否会修改其它 namespace 的用量：用 `ns/t` 写其它 namespace 的 table，
+    /// 或者执行可以写任意 table 的 EVAL。没有配置配额时不需要关心
+    pub fn affects_usage(&self, cmd: &CommandRequest) -> bool {
+        if !self.has_quota() {
+            return false;
+        }
+        match &cmd.request_data {
+            Some(RequestData::Eval(_)) => true,
+            _ => write_table(cmd).is_some_and(|t| t.contains(NAMESPACE_SEPARATOR)),
+        }
+    }
+
+    /// 在默认 namespace 中执行 affects_usage 的命令。写其它 namespace 的 table（比如在线恢复备份）
+    /// 不受配额限制，但会记到那个 namespace 的用量里
+    pub async fn execute_default<Store, F>(
+        &self,
+        cmd: CommandRequest,
+        store: &Arc<Store>,
+        next: F,
+    ) -> StreamingResponse
+    where
+        Store: AsyncStorage,
+        F: FnOnce(CommandRequest) -> StreamingResponse,
+    {
+        if let Some(RequestData::Eval(_)) = cmd.request_data {
+            let res: Vec<_> = next(cmd).collect().await;
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            return Box::pin(stream::iter(res));
+        }
+
+        let namespace = write_table(&cmd)
+            .and_then(|t| t.split_once(NAMESPACE_SEPARATOR))
+            .map(|(ns, _)| ns.to_owned());
+        match namespace {
+            Some(namespace) => self.write(&namespace, cmd, store, next, false).await,
+            None => next(cmd),
+        }
+    }
+
+    /// 执行写命令，有配额时先检查写入之后会不会超过配额；enforce 为 false 时只记录用量
+    async fn write<Store, F>(
+        &self,
+        namespace: &str,
+        cmd: CommandRequest,
+        store: &Arc<Store>,
+        next: F,
+        enforce: bool,
+    ) -> StreamingResponse
+    where
+        Store: AsyncStorage,
+        F: FnOnce(CommandRequest) -> StreamingResponse,
+    {
+        let quota = self.quota(namespace);
+        if quota.is_unlimited() {
+            return next(cmd);
+        }
+
+        let state = self.usage.entry(namespace.into()).or_default().clone();
+        let mut cached = state.lock().await;
+        let epoch = self.epoch(store).await;
+        let current = match *cached {
+            Some(c) if c.epoch == epoch => c.usage,
+            _ => match Usage::load(&qualify(namespace, ""), store).await {
+                Ok(usage) => cached.insert(Cached { usage, epoch }).usage,
+                Err(e) => return once(e.into()),
+            },
+        };
+
+        let delta = match Delta::of(&cmd, store).await {
+            Ok(delta) => delta,
+            Err(e) => return once(e.into()),
+        };
+        let updated = current.apply(&delta);
+        if enforce
+            && ((delta.keys > 0 && quota.max_keys > 0 && updated.keys > quota.max_keys)
+                || (delta.bytes > 0 && quota.max_bytes > 0 && updated.bytes > quota.max_bytes))
+        {
+            debug!("Namespace {} is full: {:?}", namespace, current);
+            return once(KvError::QuotaExceeded(namespace.into()).into());
+        }
+
+        // 写命令只返回一个 response，等它执行完再释放锁
+        let res = next(cmd).next().await;
+        match res {
+            Some(res) => {
+                if res.status == http::StatusCode::OK.as_u16() as u32 {
+                    *cached = Some(Cached {
+                        usage: updated,
+                        epoch,
+                    });
+                }
+                Box::pin(stream::once(async { res }))
+            }
+            None => Box::pin(stream::empty()),
+        }
+    }
+
+    /// 存储自己删除的 key（淘汰，过期）和 EVAL 都会改变 epoch
+    async fn epoch<Store: AsyncStorage>(&self, store: &Arc<Store>) -> u64 {
+        Arc::clone(store).removed_keys().await + self.generation.load(Ordering::SeqCst)
+    }
+
+    fn has_quota(&self) -> bool {
+        !self.config.default_quota.is_unlimited()
+            || self.config.quotas.values().any(|q| !q.is_unlimited())
+    }
+
+    /// namespace 的配额，没有单独配置时使用 default_quota
+    fn quota(&self, namespace: &str) -> QuotaConfig {
+        self.config
+            .quotas
+            .get(namespace)
+            .copied()
+            .unwrap_or(self.config.default_quota)
+    }
+}
+
+impl Usage {
+    /// 统计存储中 prefix 开头的所有 table 的用量
+    async fn load<Store: AsyncStorage>(prefix: &str, store: &Arc<Store>) -> Result<Self, KvError> {
+        let mut usage = Usage::default();
+        for table in namespace_tables(prefix, store).await? {
+            for pair in Arc::clone(store).get_all(table).await? {
+                usage.keys += 1;
+                usage.bytes += entry_size(&pair.key, pair.value.as_ref()) as u64;
+            }
+        }
+        Ok(usage)
+    }
+
+    fn apply(&self, delta: &Delta) -> Self {
+        let add = |v: u64, d: i64| (v as i64).saturating_add(d).max(0) as u64;
+        Self {
+            keys: add(self.keys, delta.keys),
+            bytes: add(self.bytes, delta.bytes),
+        }
+    }
+}
+
+impl Delta {
+    /// 读出要写入的 key 现在的值，计算写命令执行之后用量的变化
+    async fn of<Store: AsyncStorage>(
+        cmd: &CommandRequest,
+        store: &Arc<Store>,
+    ) -> Result<Self, KvError> {
+        let (table, writes): (&str, Vec<(&Bytes, Option<&Value>)>) = match &cmd.request_data {
+            Some(RequestData::Hset(Hset { table, pair })) => (
+                table,
+                pair.iter()
+                    .map(|p| (&p.key, Some(p.value.as_ref().unwrap_or(&NULL))))
+                    .collect(),
+            ),
+            Some(RequestData::Hmset(Hmset { table, pairs })) => (
+                table,
+                pairs
+                    .iter()
+                    .map(|p| (&p.key, Some(p.value.as_ref().unwrap_or(&NULL))))
+                    .collect(),
+            ),
+            Some(RequestData::Hdel(Hdel { table, key })) => (table, vec![(key, None)]),
+            Some(RequestData::Hmdel(Hmdel { table, keys })) => {
+                (table, keys.iter().map(|k| (k, None)).collect())
+            }
+            _ => return Ok(Self::default()),
+        };
+
+        // 同一个命令里可能多次写同一个 key，记录每个 key 在命令执行过程中的大小
+        let mut sizes: HashMap<&Bytes, Option<usize>> = HashMap::new();
+        let mut delta = Self::default();
+        for (key, value) in writes {
+            let old = match sizes.get(key) {
+                Some(size) => *size,
+                None => Arc::clone(store)
+                    .get(table.into(), key.clone())
+                    .await?
+                    .map(|v| entry_size(key, Some(&v))),
+            };
+            let new = value.map(|v| entry_size(key, Some(v)));
+            delta.keys += new.is_some() as i64 - old.is_some() as i64;
+            delta.bytes += new.unwrap_or_default() as i64 - old.unwrap_or_default() as i64;
+            sizes.insert(key, new);
+        }
+        Ok(delta)
+    }
+}
+
+/// 写命令写入的 table
+fn write_table(cmd: &CommandRequest) -> Option<&str> {
+    match &cmd.request_data {
+        Some(RequestData::Hset(Hset { table, .. }))
+        | Some(RequestData::Hmset(Hmset { table, .. }))
+        | Some(RequestData::Hdel(Hdel { table, .. }))
+        | Some(RequestData::Hmdel(Hmdel { table, .. }))
+        | Some(RequestData::Hexpire(Hexpire { table, .. })) => Some(table),
+        _ => None,
+    }
+}
+
+/// Hset 没有带 value 时，存储中写入的是空的 Value
+static NULL: Value = Value { value: None };
+
+/// 检查 namespace 的名字，只允许字母、数字、`_` 和 `-`
+pub fn validate(name: &str) -> Result<(), KvError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAMESPACE_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    match valid {
+        true => Ok(()),
+        false => Err(KvError::InvalidCommand(format!(
+            "invalid namespace: {}",
+            name
+        ))),
+    }
+}
+
+/// namespace 中的 table 或 topic 在存储中的名字
+pub fn qualify(namespace: &str, name: &str) -> String {
+    format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, name)
+}
+
+async fn namespace_tables<Store: AsyncStorage>(
+    prefix: &str,
+    store: &Arc<Store>,
+) -> Result<Vec<String>, KvError> {
+    let mut tables = Arc::clone(store).get_tables().await?;
+    tables.retain(|t| t.starts_with(prefix));
+    Ok(tables)
+}
+
+fn entry_size(key: &[u8], value: Option<&Value>) -> usize {
+    key.len() + value.map(|v| v.encoded_len()).unwrap_or_default()
+}
+
+/// DUMP 的 response 中 values[0] 是 table 的名字，去掉 namespace 前缀
+fn strip_table(res: Arc<CommandResponse>, prefix: &str) -> Arc<CommandResponse> {
+    let mut res = Arc::try_unwrap(res).unwrap_or_else(|v| (*v).clone());
+    if let Some(table) = res.values.first_mut() {
+        if let Ok(name) = String::try_from(table.clone()) {
+            if let Some(name) = name.strip_prefix(prefix) {
+                *table = name.into();
+            }
+        }
+    }
+    Arc::new(res)
+}
+
+fn once(res: CommandResponse) -> StreamingResponse {
+    Box::pin(stream::once(async { Arc::new(res) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok, Kvpair, MemTable, Service, ServiceInner, Storage,
+    };
+    use std::time::Duration;
+
+    fn service(config: NamespaceConfig) -> Service {
+        ServiceInner::new(MemTable::new())
+            .with_namespace(config)
+            .into()
+    }
+
+    async fn execute(service: &Service, ns: &str, cmd: CommandRequest) -> Vec<CommandResponse> {
+        let ns = (!ns.is_empty()).then(|| Arc::from(ns));
+        let res = service.execute_in(cmd, None, ns);
+        res.map(|r| (*r).clone()).collect().await
+    }
+
+    #[tokio::test]
+    async fn namespaces_should_isolate_tables() {
+        let service = service(Default::default());
+        execute(
+            &service,
+            "a",
+            CommandRequest::new_hset("t1", "k1", "a".into()),
+        )
+        .await;
+        execute(
+            &service,
+            "b",
+            CommandRequest::new_hset("t1", "k1", "b".into()),
+        )
+        .await;
+        execute(
+            &service,
+            "",
+            CommandRequest::new_hset("t1", "k1", "root".into()),
+        )
+        .await;
+
+        let res = execute(&service, "a", CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_ok(&res[0], &["a".into()], &[]);
+        let res = execute(&service, "b", CommandRequest::new_hgetall("t1")).await;
+        assert_res_ok(&res[0], &[], &[Kvpair::new("k1", "b".into())]);
+        // 默认 namespace 可以通过 ns/table 访问其它 namespace 的数据
+        let res = execute(&service, "", CommandRequest::new_hget("a/t1", "k1")).await;
+        assert_res_ok(&res[0], &["a".into()], &[]);
+
+        let res = execute(&service, "a", CommandRequest::new_dump(vec![])).await;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].values, vec!["t1".into()]);
+        let res = execute(&service, "c", CommandRequest::new_dump(vec![])).await;
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test]
+    async fn namespaces_should_isolate_topics() {
+        let service = service(Default::default());
+        let mut sub = service.execute_in(
+            CommandRequest::new_subscribe("lobby"),
+            None,
+            Some("a".into()),
+        );
+        let id = sub.next().await.unwrap();
+        assert_eq!(id.status, 200);
+
+        execute(
+            &service,
+            "b",
+            CommandRequest::new_publish("lobby", vec!["b".into()]),
+        )
+        .await;
+        execute(
+            &service,
+            "a",
+            CommandRequest::new_publish("lobby", vec!["a".into()]),
+        )
+        .await;
+        let data = sub.next().await.unwrap();
+        assert_res_ok(&data, &["a".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn namespace_quota_should_be_enforced() {
+        let mut config = NamespaceConfig::default();
+        config.quotas.insert(
+            "a".into(),
+            QuotaConfig {
+                max_keys: 2,
+                max_bytes: 0,
+            },
+        );
+        config.default_quota.max_bytes = 16;
+        let service = service(config);
+
+        let pairs = vec![Kvpair::new("k1", 1.into()), Kvpair::new("k2", 2.into())];
+        let res = execute(&service, "a", CommandRequest::new_hmset("t1", pairs)).await;
+        assert_eq!(res[0].status, 200);
+        let res = execute(
+            &service,
+            "a",
+            CommandRequest::new_hset("t2", "k3", 3.into()),
+        )
+        .await;
+        assert_res_error(&res[0], 507, "Quota of namespace `a` exceeded");
+        // 覆盖已有的 key 和删除不会增加 key 的数量
+        let res = execute(
+            &service,
+            "a",
+            CommandRequest::new_hset("t1", "k1", 10.into()),
+        )
+        .await;
+        assert_eq!(res[0].status, 200);
+        execute(&service, "a", CommandRequest::new_hdel("t1", "k2")).await;
+        let res = execute(
+            &service,
+            "a",
+            CommandRequest::new_hset("t2", "k3", 3.into()),
+        )
+        .await;
+        assert_eq!(res[0].status, 200);
+
+        // 没有单独配置的 namespace 使用 default_quota
+        let value: Value = "0123456789".into();
+        let res = execute(&service, "b", CommandRequest::new_hset("t1", "k1", value)).await;
+        assert_eq!(res[0].status, 200);
+        let res = execute(
+            &service,
+            "b",
+            CommandRequest::new_hset("t1", "k2", "v".into()),
+        )
+        .await;
+        assert_res_error(&res[0], 507, "exceeded");
+    }
+
+    #[tokio::test]
+    async fn namespace_usage_should_be_loaded_from_store() {
+        let store = MemTable::new();
+        store.set("a/t1", "k1".into(), 1.into()).unwrap();
+        store.set("b/t1", "k1".into(), 1.into()).unwrap();
+        let mut config = NamespaceConfig::default();
+        config.default_quota.max_keys = 1;
+        let service: Service = ServiceInner::new(store).with_namespace(config).into();
+
+        let res = execute(
+            &service,
+            "a",
+            CommandRequest::new_hset("t1", "k2", 2.into()),
+        )
+        .await;
+        assert_res_error(&res[0], 507, "exceeded");
+        let res = execute(
+            &service,
+            "c",
+            CommandRequest::new_hset("t1", "k2", 2.into()),
+        )
+        .await;
+        assert_eq!(res[0].status, 200);
+    }
+
+    #[tokio::test]
+    async fn default_namespace_writes_should_count_toward_usage() {
+        let mut config = NamespaceConfig::default();
+        config.default_quota.max_keys = 2;
+        let service = service(config);
+
+        let res = execute(
+            &service,
+            "a",
+            CommandRequest::new_hset("t1", "k1", 1.into()),
+        )
+        .await;
+        assert_eq!(res[0].status, 200);
+        // 默认 namespace 写入不受配额限制，但会记到 namespace a 的用量里
+        let pairs = vec![Kvpair::new("k2", 2.into()), Kvpair::new("k3", 3.into())];
+        let res = execute(&service, "", CommandRequest::new_hmset("a/t1", pairs)).await;
+        assert_eq!(res[0].status, 200);
+        let res = execute(&service, "a", CommandRequest::new_hdel("t1", "k3")).await;
+        assert_eq!(res[0].status, 200);
+        let res = execute(
+            &service,
+            "a",
+            CommandRequest::new_hset("t1", "k4", 4.into()),
+        )
+        .await;
+        assert_res_error(&res[0], 507, "exceeded");
+    }
+
+    #[tokio::test]
+    async fn expired_keys_should_be_removed_from_usage() {
+        let mut config = NamespaceConfig::default();
+        config.default_quota.max_keys = 2;
+        let service = service(config);
+
+        let pairs = vec![Kvpair::new("k1", 1.into()), Kvpair::new("k2", 2.into())];
+        let res = execute(&service, "a", CommandRequest::new_hmset("t1", pairs)).await;
+        assert_eq!(res[0].status, 200);
+        let cmd = CommandRequest::new_hexpire("t1", "k1", Duration::from_millis(10));
+        let res = execute(&service, "a", cmd).await;
+        assert_eq!(res[0].status, 200);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // 过期的 key 被存储删除之后，缓存的用量需要重新统计
+        let res = execute(&service, "a", CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res[0].status, 404);
+        let res = execute(
+            &service,
+            "a",
+            CommandRequest::new_hset("t1", "k3", 3.into()),
+        )
+        .await;
+        assert_eq!(res[0].status, 200);
+    }
+
+    #[tokio::test]
+    async fn server_commands_should_be_rejected_in_namespace() {
+        let service = service(Default::default());
+        let res = execute(&service, "a", CommandRequest::new_eval("1", vec![])).await;
+        assert_res_error(&res[0], 400, "EVAL is not supported");
+        let res = execute(&service, "a", CommandRequest::new_slowlog_reset()).await;
+        assert_res_error(&res[0], 403, "not allowed");
+    }
+
+    #[test]
+    fn select_should_respect_bound_namespace() {
+        let mut config = NamespaceConfig::default();
+        config.clients.insert("device".into(), "a".into());
+        let namespaces = Namespaces::new(config);
+
+        assert_eq!(namespaces.bound(Some("device")), Some("a".into()));
+        assert_eq!(namespaces.bound(Some("other")), None);
+        assert_eq!(namespaces.select(None, "b").unwrap(), Some("b".into()));
+        assert_eq!(namespaces.select(None, "").unwrap(), None);
+        assert_eq!(
+            namespaces.select(Some("device"), "a").unwrap(),
+            Some("a".into())
+        );
+        assert!(matches!(
+            namespaces.select(Some("device"), "b"),
+            Err(KvError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            namespaces.select(None, "a/b"),
+            Err(KvError::InvalidCommand(_))
+        ));
+    }
+}
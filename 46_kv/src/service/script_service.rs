@@ -0,0 +1,467 @@
+use bytes::Bytes;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::runtime::Handle;
+
+use crate::{value, AsyncStorage, Broadcaster, CommandResponse, Eval, KvError, Topic, Value};
+
+/// 服务器端脚本的限制
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ScriptConfig {
+    /// 一个脚本最多执行多少步，0 表示不限制
+    pub max_operations: u64,
+    /// 一个脚本最多执行多少毫秒
+    pub timeout_ms: u64,
+    /// 最多缓存多少个编译好的脚本，超过时淘汰最久没有使用的，0 表示不缓存
+    pub max_cached: usize,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            max_operations: 100_000,
+            timeout_ms: 1000,
+            max_cached: 1000,
+        }
+    }
+}
+
+/// 脚本的 hash，执行过一次 EVAL 之后，可以用它来执行缓存的脚本
+pub fn script_sha(script: &str) -> String {
+    format!("{:x}", Sha256::digest(script.as_bytes()))
+}
+
+/// 执行 EVAL，缓存编译好的脚本
+#[derive(Default)]
+pub struct Scripts {
+    config: ScriptConfig,
+    cache: Mutex<ScriptCache>,
+}
+
+/// 按照 LRU 淘汰的脚本缓存
+#[derive(Default)]
+struct ScriptCache {
+    scripts: HashMap<String, Cached>,
+    /// 逻辑时钟，每次访问加一
+    clock: u64,
+}
+
+struct Cached {
+    ast: Arc<AST>,
+    /// 最后一次访问时的逻辑时钟
+    last_used: u64,
+}
+
+impl Scripts {
+    pub fn new(config: ScriptConfig) -> Self {
+        Self {
+            config,
+            cache: Default::default(),
+        }
+    }
+
+    /// 执行脚本。调用者需要保证执行期间没有其它命令在访问存储，这样脚本里的多次读写是原子的。
+    /// 脚本出错时，之前已经执行的写入不会回滚
+    pub async fn execute<Store: AsyncStorage>(
+        &self,
+        cmd: Eval,
+        store: Arc<Store>,
+        topic: Arc<Broadcaster>,
+    ) -> CommandResponse {
+        match self.eval(cmd, store, topic).await {
+            Ok(values) => values.into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    async fn eval<Store: AsyncStorage>(
+        &self,
+        cmd: Eval,
+        store: Arc<Store>,
+        topic: Arc<Broadcaster>,
+    ) -> Result<Vec<Value>, KvError> {
+        let ast = self.load(&cmd)?;
+        let args: Array = cmd.args.into_iter().map(to_dynamic).collect();
+        let config = self.config.clone();
+
+        // rhai 是同步执行的，放到 blocking 线程里，再在里面等待异步的存储
+        let handle = Handle::current();
+        tokio::task::spawn_blocking(move || run(&ast, args, &config, store, topic, handle))
+            .await
+            .map_err(|e| KvError::Internal(format!("script task failed: {}", e)))?
+    }
+
+    /// 清空缓存的脚本
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().scripts.clear();
+    }
+
+    /// 拿到编译好的脚本，新的脚本编译后放入缓存
+    fn load(&self, cmd: &Eval) -> Result<Arc<AST>, KvError> {
+        if cmd.script.is_empty() {
+            let cached = self.cache.lock().unwrap().get(&cmd.sha);
+            return cached.ok_or_else(|| KvError::NotFound(format!("script {}", cmd.sha)));
+        }
+
+        let sha = script_sha(&cmd.script);
+        let cached = self.cache.lock().unwrap().get(&sha);
+        if let Some(ast) = cached {
+            return Ok(ast);
+        }
+        // 编译可能很慢，不在锁里进行。同一个脚本被同时编译两次也没有关系
+        let ast = Engine::new()
+            .compile(&cmd.script)
+            .map_err(|e| KvError::ScriptError(e.to_string()))?;
+        let ast = Arc::new(ast);
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(sha, ast.clone(), self.config.max_cached);
+        Ok(ast)
+    }
+}
+
+impl ScriptCache {
+    fn get(&mut self, sha: &str) -> Option<Arc<AST>> {
+        self.clock += 1;
+        let cached = self.scripts.get_mut(sha)?;
+        cached.last_used = self.clock;
+        Some(cached.ast.clone())
+    }
+
+    /// 放入缓存，满了的时候先淘汰最久没有使用的脚本
+    fn insert(&mut self, sha: String, ast: Arc<AST>, max: usize) {
+        if max == 0 {
+            return;
+        }
+        if !self.scripts.contains_key(&sha) && self.scripts.len() >= max {
+            let oldest = self
+                .scripts
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(sha, _)| sha.clone());
+            if let Some(oldest) = oldest {
+                self.scripts.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        let last_used = self.clock;
+        self.scripts.insert(sha, Cached { ast, last_used });
+    }
+}
+
+fn run<Store: AsyncStorage>(
+    ast: &AST,
+    args: Array,
+    config: &ScriptConfig,
+    store: Arc<Store>,
+    topic: Arc<Broadcaster>,
+    handle: Handle,
+) -> Result<Vec<Value>, KvError> {
+    let mut engine = Engine::new();
+    engine.set_max_operations(config.max_operations);
+    let start = Instant::now();
+    let timeout = Duration::from_millis(config.timeout_ms);
+    engine.on_progress(move |_| match start.elapsed() > timeout {
+        true => Some("timeout".into()),
+        false => None,
+    });
+
+    let (s, h) = (store.clone(), handle.clone());
+    engine.register_fn("get", move |table: &str, key: Dynamic| {
+        let v = h.block_on(s.clone().get(table.into(), to_key(key)?));
+        v.map(|v| v.map_or(Dynamic::UNIT, to_dynamic))
+            .map_err(to_script_error)
+    });
+
+    let (s, h) = (store.clone(), handle.clone());
+    engine.register_fn("set", move |table: &str, key: Dynamic, value: Dynamic| {
+        let value = to_value(value).map_err(to_script_error)?;
+        let v = h.block_on(s.clone().set(table.into(), to_key(key)?, value));
+        v.map(|v| v.map_or(Dynamic::UNIT, to_dynamic))
+            .map_err(to_script_error)
+    });
+
+    let (s, h) = (store, handle);
+    engine.register_fn("del", move |table: &str, key: Dynamic| {
+        let v = h.block_on(s.clone().del(table.into(), to_key(key)?));
+        v.map(|v| v.map_or(Dynamic::UNIT, to_dynamic))
+            .map_err(to_script_error)
+    });
+
+    engine.register_fn("publish", move |name: &str, value: Dynamic| {
+        let value = to_value(value).map_err(to_script_error)?;
+        let data: CommandResponse = vec![value].into();
+        topic.clone().publish(name.into(), Arc::new(data));
+        Ok::<_, Box<EvalAltResult>>(())
+    });
+
+    let mut scope = Scope::new();
+    scope.push("ARGS", args);
+    let result: Dynamic = engine
+        .eval_ast_with_scope(&mut scope, ast)
+        .map_err(|e| KvError::ScriptError(e.to_string()))?;
+
+    if result.is_unit() {
+        Ok(vec![])
+    } else if result.is_array() {
+        result
+            .into_array()
+            .unwrap()
+            .into_iter()
+            .map(to_value)
+            .collect()
+    } else {
+        Ok(vec![to_value(result)?])
+    }
+}
+
+fn to_script_error(e: KvError) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
+
+fn to_dynamic(v: Value) -> Dynamic {
+    match v.value {
+        Some(value::Value::String(s)) => s.into(),
+        Some(value::Value::Binary(b)) => Dynamic::from_blob(b.to_vec()),
+        Some(value::Value::Integer(i)) => i.into(),
+        Some(value::Value::Float(f)) => f.into(),
+        Some(value::Value::Bool(b)) => b.into(),
+        None => Dynamic::UNIT,
+    }
+}
+
+/// 脚本中的 key 可以是 string 或者 blob
+fn to_key(key: Dynamic) -> Result<Bytes, Box<EvalAltResult>> {
+    if key.is_blob() {
+        Ok(Bytes::from(key.into_blob().unwrap()))
+    } else if key.is_string() {
+        Ok(key.to_string().into())
+    } else {
+        let e = KvError::ConvertError(key.type_name().into(), "Key");
+        Err(to_script_error(e))
+    }
+}
+
+fn to_value(v: Dynamic) -> Result<Value, KvError> {
+    let type_name = v.type_name();
+    if v.is_unit() {
+        Ok(Value::default())
+    } else if v.is_string() || v.is_char() {
+        Ok(v.to_string().into())
+    } else if let Ok(i) = v.as_int() {
+        Ok(i.into())
+    } else if let Ok(f) = v.as_float() {
+        Ok(f.into())
+    } else if let Ok(b) = v.as_bool() {
+        Ok(b.into())
+    } else if v.is_blob() {
+        Ok(Bytes::from(v.into_blob().unwrap()).into())
+    } else {
+        Err(KvError::ConvertError(type_name.into(), "Value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok, command_request::RequestData, CommandRequest, MemTable,
+        Service, ServiceInner, Storage,
+    };
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn eval_should_read_and_write_storage() {
+        let (scripts, store, topic) = setup(ScriptConfig::default());
+        Storage::set(&*store, "t1", "counter".into(), 41.into()).unwrap();
+
+        // 读改写：counter 加上参数后返回新值和旧值
+        let script = r#"
+            let old = get("t1", "counter");
+            set("t1", "counter", old + ARGS[0]);
+            del("t1", "tmp");
+            [get("t1", "counter"), old]
+        "#;
+        let cmd = eval(CommandRequest::new_eval(script, vec![1.into()]));
+        let res = scripts.execute(cmd, store.clone(), topic).await;
+        assert_res_ok(&res, &[42.into(), 41.into()], &[]);
+        assert_eq!(
+            Storage::get(&*store, "t1", b"counter").unwrap(),
+            Some(42.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn eval_should_accept_blob_keys() {
+        let (scripts, store, topic) = setup(ScriptConfig::default());
+        let script = r#"
+            let key = blob(2, 0xff);
+            set("t1", key, 1);
+            get("t1", key)
+        "#;
+        let cmd = eval(CommandRequest::new_eval(script, vec![]));
+        let res = scripts.execute(cmd, store.clone(), topic.clone()).await;
+        assert_res_ok(&res, &[1.into()], &[]);
+        assert_eq!(
+            Storage::get(&*store, "t1", b"\xff\xff").unwrap(),
+            Some(1.into())
+        );
+
+        let cmd = eval(CommandRequest::new_eval(r#"get("t1", true)"#, vec![]));
+        let res = scripts.execute(cmd, store, topic).await;
+        assert_res_error(&res, 400, "Cannot convert");
+    }
+
+    #[tokio::test]
+    async fn eval_should_publish() {
+        let (scripts, store, topic) = setup(ScriptConfig::default());
+        let mut rx = topic.clone().subscribe("lobby".into());
+        // 第一个消息是订阅的 id
+        rx.recv().await.unwrap();
+
+        let cmd = eval(CommandRequest::new_eval(
+            r#"publish("lobby", "hello")"#,
+            vec![],
+        ));
+        let res = scripts.execute(cmd, store, topic).await;
+        assert_res_ok(&res, &[], &[]);
+        let data = rx.recv().await.unwrap();
+        assert_eq!(data.values, vec!["hello".into()]);
+    }
+
+    #[tokio::test]
+    async fn evalsha_should_use_cached_script() {
+        let (scripts, store, topic) = setup(ScriptConfig::default());
+        let script = "ARGS[0] * 2";
+
+        let cmd = eval(CommandRequest::new_evalsha(
+            script_sha(script),
+            vec![1.into()],
+        ));
+        let res = scripts.execute(cmd, store.clone(), topic.clone()).await;
+        assert_res_error(&res, 404, "Not found");
+
+        let cmd = eval(CommandRequest::new_eval(script, vec![1.into()]));
+        let res = scripts.execute(cmd, store.clone(), topic.clone()).await;
+        assert_res_ok(&res, &[2.into()], &[]);
+
+        let cmd = eval(CommandRequest::new_evalsha(
+            script_sha(script),
+            vec![2.into()],
+        ));
+        let res = scripts.execute(cmd, store, topic).await;
+        assert_res_ok(&res, &[4.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn script_cache_should_be_bounded() {
+        let config = ScriptConfig {
+            max_cached: 2,
+            ..Default::default()
+        };
+        let (scripts, store, topic) = setup(config);
+        for script in ["1", "2"] {
+            let cmd = eval(CommandRequest::new_eval(script, vec![]));
+            scripts.execute(cmd, store.clone(), topic.clone()).await;
+        }
+        // 用一次 "1"，缓存满了之后淘汰的是最久没有使用的 "2"
+        let cmd = eval(CommandRequest::new_evalsha(script_sha("1"), vec![]));
+        let res = scripts.execute(cmd, store.clone(), topic.clone()).await;
+        assert_res_ok(&res, &[1.into()], &[]);
+        let cmd = eval(CommandRequest::new_eval("3", vec![]));
+        scripts.execute(cmd, store.clone(), topic.clone()).await;
+
+        let evalsha = |script| eval(CommandRequest::new_evalsha(script_sha(script), vec![]));
+        let res = scripts
+            .execute(evalsha("2"), store.clone(), topic.clone())
+            .await;
+        assert_res_error(&res, 404, "Not found");
+        let res = scripts
+            .execute(evalsha("1"), store.clone(), topic.clone())
+            .await;
+        assert_res_ok(&res, &[1.into()], &[]);
+        let res = scripts
+            .execute(evalsha("3"), store.clone(), topic.clone())
+            .await;
+        assert_res_ok(&res, &[3.into()], &[]);
+
+        scripts.flush();
+        let res = scripts.execute(evalsha("1"), store, topic).await;
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn eval_should_enforce_budget() {
+        let config = ScriptConfig {
+            max_operations: 1000,
+            timeout_ms: 1000,
+            ..Default::default()
+        };
+        let (scripts, store, topic) = setup(config);
+        let cmd = eval(CommandRequest::new_eval("loop {}", vec![]));
+        let res = scripts.execute(cmd, store.clone(), topic.clone()).await;
+        assert_res_error(&res, 400, "Too many operations");
+
+        let config = ScriptConfig {
+            max_operations: 0,
+            timeout_ms: 10,
+            ..Default::default()
+        };
+        let (scripts, store, topic) = setup(config);
+        let cmd = eval(CommandRequest::new_eval("loop {}", vec![]));
+        let res = scripts.execute(cmd, store, topic).await;
+        assert_res_error(&res, 400, "terminated");
+    }
+
+    #[tokio::test]
+    async fn eval_with_bad_script_should_fail() {
+        let (scripts, store, topic) = setup(ScriptConfig::default());
+        let cmd = eval(CommandRequest::new_eval("let x = ", vec![]));
+        let res = scripts.execute(cmd, store.clone(), topic.clone()).await;
+        assert_res_error(&res, 400, "Script error");
+
+        // 返回 map 不能转换成 Value
+        let cmd = eval(CommandRequest::new_eval("#{a: 1}", vec![]));
+        let res = scripts.execute(cmd, store, topic).await;
+        assert_res_error(&res, 500, "Cannot convert");
+    }
+
+    #[tokio::test]
+    async fn service_should_execute_eval() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let cmd = CommandRequest::new_eval(
+            r#"set("t1", "k1", ARGS[0]); get("t1", "k1")"#,
+            vec!["v1".into()],
+        );
+        let mut res = service.execute(cmd);
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+
+        let sha = script_sha(r#"set("t1", "k1", ARGS[0]); get("t1", "k1")"#);
+        let mut res = service.execute(CommandRequest::new_script_flush());
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        let mut res = service.execute(CommandRequest::new_evalsha(sha, vec!["v2".into()]));
+        assert_res_error(&res.next().await.unwrap(), 404, "Not found");
+    }
+
+    fn eval(cmd: CommandRequest) -> Eval {
+        match cmd.request_data {
+            Some(RequestData::Eval(eval)) => eval,
+            _ => unreachable!(),
+        }
+    }
+
+    fn setup(config: ScriptConfig) -> (Scripts, Arc<MemTable>, Arc<Broadcaster>) {
+        (
+            Scripts::new(config),
+            Arc::new(MemTable::new()),
+            Arc::new(Broadcaster::default()),
+        )
+    }
+}
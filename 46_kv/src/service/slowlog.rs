@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{CommandRequest, SlowlogEntry};
+
+/// 慢日志中记录的命令最多保留多少个字符
+const MAX_COMMAND_LEN: usize = 256;
+
+/// 慢日志的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SlowlogConfig {
+    /// 执行时间超过多少微秒的命令会被记录，0 表示记录所有命令
+    pub slower_than_us: u64,
+    /// 最多保留多少条，超过时丢弃最老的；0 表示不记录
+    pub max_len: usize,
+}
+
+impl Default for SlowlogConfig {
+    fn default() -> Self {
+        Self {
+            slower_than_us: 10_000,
+            max_len: 128,
+        }
+    }
+}
+
+/// 记录执行时间超过阈值的命令
+#[derive(Debug, Default)]
+pub struct Slowlog {
+    config: SlowlogConfig,
+    inner: Mutex<SlowlogInner>,
+}
+
+#[derive(Debug, Default)]
+struct SlowlogInner {
+    next_id: u64,
+    entries: VecDeque<SlowlogEntry>,
+}
+
+impl Slowlog {
+    pub fn new(config: SlowlogConfig) -> Self {
+        Self {
+            config,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &SlowlogConfig {
+        &self.config
+    }
+
+    /// 命令执行完后调用，执行时间超过阈值时记录下来
+    pub fn record(&self, cmd: &CommandRequest, duration: Duration, client: Option<&str>) {
+        if self.config.max_len == 0 || duration.as_micros() < self.config.slower_than_us as u128 {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .checked_sub(duration)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        let mut command = format!("{:?}", cmd.request_data);
+        if command.len() > MAX_COMMAND_LEN {
+            let mut end = MAX_COMMAND_LEN;
+            while !command.is_char_boundary(end) {
+                end -= 1;
+            }
+            command.truncate(end);
+            command.push_str("...");
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let entry = SlowlogEntry {
+            id: inner.next_id,
+            timestamp,
+            duration_us: duration.as_micros() as _,
+            client: client.unwrap_or_default().into(),
+            command,
+        };
+        inner.next_id += 1;
+        inner.entries.push_front(entry);
+        inner.entries.truncate(self.config.max_len);
+    }
+
+    /// 最近的 count 条慢日志，新的在前面；count 为 0 时返回全部
+    pub fn get(&self, count: usize) -> Vec<SlowlogEntry> {
+        let inner = self.inner.lock().unwrap();
+        let count = match count {
+            0 => inner.entries.len(),
+            n => n,
+        };
+        inner.entries.iter().take(count).cloned().collect()
+    }
+
+    /// 当前的慢日志条数
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 清空慢日志
+    pub fn reset(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowlog_should_only_record_slow_commands() {
+        let slowlog = Slowlog::new(SlowlogConfig {
+            slower_than_us: 1000,
+            max_len: 2,
+        });
+        let cmd = CommandRequest::new_hget("t1", "k1");
+
+        slowlog.record(&cmd, Duration::from_micros(999), Some("127.0.0.1:1234"));
+        assert!(slowlog.is_empty());
+
+        for i in 0..3 {
+            let cmd = CommandRequest::new_hget("t1", format!("k{}", i));
+            slowlog.record(&cmd, Duration::from_millis(i + 1), Some("127.0.0.1:1234"));
+        }
+
+        // 只保留最新的两条，新的在前面
+        let entries = slowlog.get(0);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, 2);
+        assert_eq!(entries[0].duration_us, 3000);
+        assert_eq!(entries[0].client, "127.0.0.1:1234");
+        assert!(entries[0].command.contains("k2"));
+        assert_eq!(entries[1].id, 1);
+        assert_eq!(slowlog.get(1).len(), 1);
+
+        slowlog.reset();
+        assert!(slowlog.is_empty());
+    }
+
+    #[test]
+    fn slowlog_should_truncate_long_commands() {
+        let slowlog = Slowlog::new(SlowlogConfig {
+            slower_than_us: 0,
+            ..Default::default()
+        });
+        let cmd = CommandRequest::new_hset("t1", "k1", "中".repeat(1000).into());
+        slowlog.record(&cmd, Duration::from_micros(1), None);
+
+        let command = &slowlog.get(0)[0].command;
+        assert!(command.len() <= MAX_COMMAND_LEN + 3);
+        assert!(command.ends_with("..."));
+    }
+}
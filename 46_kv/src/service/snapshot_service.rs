@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::sync::Arc;
+
+use crate::{
+    command_request::RequestData, format_key, AsyncStorage, CommandRequest, CommandResponse,
+    CommandService, KvError, Snapshot, SnapshotRelease, Value,
+};
+
+#[async_trait]
+impl CommandService for Snapshot {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        match store.snapshot().await {
+            Ok(version) => Value::from(version as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for SnapshotRelease {
+    async fn execute<Store: AsyncStorage>(self, store: Arc<Store>) -> CommandResponse {
+        match store.release(self.version).await {
+            Ok(released) => Value::from(released).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 在 cmd.as_of 版本上执行读命令。和最新数据上的读取不同，snapshot 过期时返回错误而不是空值
+pub async fn dispatch_at(cmd: CommandRequest, store: &Arc<impl AsyncStorage>) -> CommandResponse {
+    let version = cmd.as_of;
+    let store = Arc::clone(store);
+    let result = match cmd.request_data {
+        Some(RequestData::Hget(param)) => {
+            match store
+                .get_at(param.table.clone(), param.key.clone(), version)
+                .await
+            {
+                Ok(Some(v)) => Ok(v.into()),
+                Ok(None) => Err(KvError::NotFound(format!(
+                    "table {}, key {}",
+                    param.table,
+                    format_key(&param.key)
+                ))),
+                Err(e) => Err(e),
+            }
+        }
+        Some(RequestData::Hgetall(param)) => store
+            .get_all_at(param.table, version)
+            .await
+            .map(|pairs| pairs.into()),
+        Some(RequestData::Hmget(param)) => get_many(&store, param.table, param.keys, version)
+            .await
+            .map(|values| {
+                values
+                    .into_iter()
+                    .map(|v| v.unwrap_or_default())
+                    .collect::<Vec<_>>()
+                    .into()
+            }),
+        Some(RequestData::Hexist(param)) => store
+            .get_at(param.table, param.key, version)
+            .await
+            .map(|v| Value::from(v.is_some()).into()),
+        Some(RequestData::Hmexist(param)) => get_many(&store, param.table, param.keys, version)
+            .await
+            .map(|values| {
+                values
+                    .into_iter()
+                    .map(|v| Value::from(v.is_some()))
+                    .collect::<Vec<_>>()
+                    .into()
+            }),
+        None => Err(KvError::InvalidCommand("Request has no data".into())),
+        _ => Err(KvError::InvalidCommand(
+            "as_of is only supported by HGET/HGETALL/HMGET/HEXIST/HMEXIST".into(),
+        )),
+    };
+    result.unwrap_or_else(|e| e.into())
+}
+
+async fn get_many<Store: AsyncStorage>(
+    store: &Arc<Store>,
+    table: String,
+    keys: Vec<Bytes>,
+    version: u64,
+) -> Result<Vec<Option<Value>>, KvError> {
+    let mut values = Vec::with_capacity(keys.len());
+    for key in keys {
+        values.push(store.clone().get_at(table.clone(), key, version).await?);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, dispatch, Kvpair, MemTable, Mvcc};
+
+    #[tokio::test]
+    async fn reads_as_of_snapshot_should_work() {
+        let store = Arc::new(Mvcc::new(MemTable::new(), Default::default()));
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store).await;
+        let res = dispatch(CommandRequest::new_snapshot(), &store).await;
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        dispatch(CommandRequest::new_hset("t1", "k1", "v2".into()), &store).await;
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2".into()), &store).await;
+
+        let cmd = CommandRequest::new_hget("t1", "k1").with_as_of(1);
+        assert_res_ok(&dispatch(cmd, &store).await, &["v1".into()], &[]);
+        let cmd = CommandRequest::new_hget("t1", "k2").with_as_of(1);
+        assert_res_error(&dispatch(cmd, &store).await, 404, "Not found");
+        let cmd = CommandRequest::new_hgetall("t1").with_as_of(1);
+        let pairs = [Kvpair::new("k1", "v1".into())];
+        assert_res_ok(&dispatch(cmd, &store).await, &[], &pairs);
+        let cmd = CommandRequest::new_hmget("t1", vec!["k1".into(), "k2".into()]).with_as_of(1);
+        let values = ["v1".into(), Value::default()];
+        assert_res_ok(&dispatch(cmd, &store).await, &values, &[]);
+        let cmd = CommandRequest::new_hmexist("t1", vec!["k1".into(), "k2".into()]).with_as_of(1);
+        assert_res_ok(
+            &dispatch(cmd, &store).await,
+            &[true.into(), false.into()],
+            &[],
+        );
+
+        // 写命令不能带版本
+        let cmd = CommandRequest::new_hset("t1", "k1", "v3".into()).with_as_of(1);
+        assert_res_error(&dispatch(cmd, &store).await, 400, "as_of");
+
+        let res = dispatch(CommandRequest::new_snapshot_release(1), &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+        let cmd = CommandRequest::new_hget("t1", "k1").with_as_of(1);
+        assert_res_error(&dispatch(cmd, &store).await, 410, "Snapshot 1");
+    }
+
+    #[tokio::test]
+    async fn snapshot_should_fail_on_unversioned_storage() {
+        let store = Arc::new(MemTable::new());
+        let res = dispatch(CommandRequest::new_snapshot(), &store).await;
+        assert_res_error(&res, 400, "snapshot is not supported");
+    }
+}
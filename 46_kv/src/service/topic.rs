@@ -110,6 +110,16 @@ impl Topic for Arc<Broadcaster> {
 }
 
 impl Broadcaster {
+    /// 当前的主题数量
+    pub fn topic_count(&self) -> usize {
+        self.topics.len()
+    }
+
+    /// 当前的订阅数量
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+
     pub fn remove_subscription(&self, name: String, id: u32) -> Option<u32> {
         if let Some(v) = self.topics.get_mut(&name) {
             // 在 topics 表里找到 topic 的 subscription id，删除
@@ -157,6 +167,8 @@ mod tests {
         let id2 = get_id(&mut stream2).await;
 
         assert!(id1 != id2);
+        assert_eq!(b.topic_count(), 1);
+        assert_eq!(b.subscription_count(), 2);
 
         let res1 = stream1.recv().await.unwrap();
         let res2 = stream2.recv().await.unwrap();
@@ -1,10 +1,52 @@
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use crate::{value, EvictionPolicy, KvError, Kvpair, MemoryConfig, Storage, StorageIter, Value};
+use bytes::Bytes;
 use dashmap::{mapref::one::Ref, DashMap};
+use indexmap::IndexSet;
+use rand::seq::index::sample;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// 每个 entry 除了 key 和 value 之外大致的额外开销（DashMap 的 slot，访问信息等）
+const ENTRY_OVERHEAD: usize = 64;
+/// 每次淘汰随机抽取多少个 key，从中选出最应该淘汰的那个，和 redis 的 maxmemory-samples 一样
+const EVICTION_SAMPLES: usize = 5;
 
 /// 使用 DashMap 构建的 MemTable，实现了 Storage trait
-#[derive(Clone, Debug, Default)]
+#[derive(Debug, Default)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, DashMap<Bytes, Entry>>,
+    /// 内存限制和淘汰策略
+    memory: MemoryConfig,
+    /// 当前使用的内存（近似值）
+    used: AtomicUsize,
+    /// 因为内存限制被淘汰的 key 的数量
+    evicted: AtomicU64,
+    /// 过期之后被删除的 key 的数量
+    expired: AtomicU64,
+    /// 逻辑时钟，每次访问加一，LRU 用它判断谁最久没有被访问
+    clock: AtomicU64,
+    /// 有内存限制时，写入和淘汰在这个锁里串行执行，这样检查限制和写入之间不会有其它写入。
+    /// 锁里是可以被淘汰的 key，淘汰时从中随机抽样：allkeys 策略下是所有的 key，
+    /// volatile-ttl 下是设置了过期时间的 key
+    candidates: Mutex<Candidates>,
+}
+
+type Candidates = IndexSet<(String, Bytes)>;
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    /// 最后一次访问时的逻辑时钟
+    last_access: AtomicU64,
+    /// 访问次数，LFU 使用
+    hits: AtomicU64,
+    /// 过期时间，volatile-ttl 只淘汰设置了过期时间的 key
+    expire_at: Option<Instant>,
 }
 
 impl MemTable {
@@ -13,8 +55,31 @@ impl MemTable {
         Self::default()
     }
 
+    /// 创建一个有内存限制的 MemTable
+    pub fn with_memory(memory: MemoryConfig) -> Self {
+        Self {
+            memory,
+            ..Default::default()
+        }
+    }
+
+    /// 当前使用的内存（近似值）
+    pub fn used_memory(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// 因为内存限制被淘汰的 key 的数量
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// 过期之后被删除的 key 的数量
+    pub fn expired_keys(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
     /// 如果名为 name 的 hash table 不存在，则创建，否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<Bytes, Entry>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -23,47 +88,318 @@ impl MemTable {
             }
         }
     }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// 有内存限制时拿到写入的锁；没有限制时写入不需要串行
+    fn lock(&self) -> Option<MutexGuard<'_, Candidates>> {
+        match self.memory.maxmemory {
+            0 => None,
+            _ => Some(self.candidates.lock().unwrap()),
+        }
+    }
+
+    /// key 设置过期时间之后是否可以被淘汰
+    fn evictable(&self, has_ttl: bool) -> bool {
+        match self.memory.policy {
+            EvictionPolicy::Noeviction => false,
+            EvictionPolicy::AllkeysLru | EvictionPolicy::AllkeysLfu => true,
+            EvictionPolicy::VolatileTtl => has_ttl,
+        }
+    }
+
+    /// 删除已经过期的 key，返回是否删除
+    fn remove_expired(&self, table: &str, key: &[u8]) -> bool {
+        // 先用读锁检查，大部分 key 没有过期时间，不需要拿写锁
+        let expired = self
+            .tables
+            .get(table)
+            .is_some_and(|t| t.get(key).is_some_and(|e| e.is_expired()));
+        if !expired {
+            return false;
+        }
+
+        let mut candidates = self.lock();
+        let removed = match self.tables.get(table) {
+            Some(t) => t.remove_if(key, |_, e| e.is_expired()),
+            None => None,
+        };
+        match removed {
+            Some((k, e)) => {
+                self.release(entry_size(k.len(), &e.value));
+                self.expired.fetch_add(1, Ordering::Relaxed);
+                if let Some(candidates) = candidates.as_mut() {
+                    candidates.swap_remove(&(table.to_owned(), k));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    /// 写入 incoming 字节、覆盖 existing 字节之前，按照淘汰策略腾出空间。
+    /// 调用者需要持有写入的锁。table / key 是正在写入的 key，不会被淘汰
+    fn reserve(
+        &self,
+        candidates: &mut Candidates,
+        incoming: usize,
+        existing: usize,
+        table: &str,
+        key: &[u8],
+    ) -> Result<(), KvError> {
+        let max = self.memory.maxmemory;
+        while self.used_memory() + incoming > max + existing {
+            if !self.evict_one(candidates, table, key) {
+                return Err(KvError::OutOfMemory);
+            }
+        }
+        Ok(())
+    }
+
+    /// 从候选的 key 中随机抽取（不重复）EVICTION_SAMPLES 个，按照淘汰策略删除其中最应该淘汰的那个，
+    /// 没有可以淘汰的 key 时返回 false。调用者需要持有写入的锁
+    fn evict_one(&self, candidates: &mut Candidates, skip_table: &str, skip_key: &[u8]) -> bool {
+        let policy = self.memory.policy;
+        let is_skipped =
+            |(table, key): &(String, Bytes)| table == skip_table && key[..] == *skip_key;
+        let mut rng = rand::thread_rng();
+
+        loop {
+            // 只剩下正在写入的 key 时，没有可以淘汰的 key
+            if candidates.is_empty() || (candidates.len() == 1 && is_skipped(&candidates[0])) {
+                return false;
+            }
+
+            let mut victim: Option<((u64, u64), (String, Bytes))> = None;
+            let mut stale = Vec::new();
+            let amount = EVICTION_SAMPLES.min(candidates.len());
+            for index in sample(&mut rng, candidates.len(), amount) {
+                let candidate = &candidates[index];
+                if is_skipped(candidate) {
+                    continue;
+                }
+                let (table, key) = candidate;
+                let score = self
+                    .tables
+                    .get(table)
+                    .and_then(|t| t.get(key).and_then(|e| e.score(policy)));
+                match score {
+                    Some(score) if victim.as_ref().is_none_or(|(s, _)| score < *s) => {
+                        victim = Some((score, candidate.clone()))
+                    }
+                    Some(_) => {}
+                    // 已经不在存储中，或者不再参与淘汰的 key，从候选中去掉
+                    None => stale.push(candidate.clone()),
+                }
+            }
+            for candidate in &stale {
+                candidates.swap_remove(candidate);
+            }
+
+            if let Some((_, victim)) = victim {
+                candidates.swap_remove(&victim);
+                let (table, key) = victim;
+                if let Some(table) = self.tables.get(&table) {
+                    if let Some((k, e)) = table.remove(&key) {
+                        self.release(entry_size(k.len(), &e.value));
+                        self.evicted.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                return true;
+            }
+        }
+    }
+}
+
+impl Entry {
+    fn new(value: Value, now: u64) -> Self {
+        Self {
+            value,
+            last_access: AtomicU64::new(now),
+            hits: AtomicU64::new(0),
+            expire_at: None,
+        }
+    }
+
+    fn touch(&self, now: u64) {
+        self.last_access.store(now, Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expire_at.is_some_and(|t| t <= Instant::now())
+    }
+
+    /// 淘汰时的排序依据，越小越先被淘汰；返回 None 表示这个 key 不参与淘汰
+    fn score(&self, policy: EvictionPolicy) -> Option<(u64, u64)> {
+        let last_access = self.last_access.load(Ordering::Relaxed);
+        match policy {
+            EvictionPolicy::AllkeysLru => Some((last_access, 0)),
+            // 访问次数相同时，淘汰最久没有访问的
+            EvictionPolicy::AllkeysLfu => Some((self.hits.load(Ordering::Relaxed), last_access)),
+            EvictionPolicy::VolatileTtl => {
+                let now = Instant::now();
+                self.expire_at
+                    .map(|t| (t.saturating_duration_since(now).as_millis() as u64, 0))
+            }
+            EvictionPolicy::Noeviction => None,
+        }
+    }
+}
+
+/// 一个 key / value 大致占用的内存
+fn entry_size(key_len: usize, value: &Value) -> usize {
+    let value_size = match &value.value {
+        Some(value::Value::String(s)) => s.len(),
+        Some(value::Value::Binary(b)) => b.len(),
+        Some(value::Value::Integer(_)) | Some(value::Value::Float(_)) => 8,
+        Some(value::Value::Bool(_)) => 1,
+        None => 0,
+    };
+    key_len + value_size + ENTRY_OVERHEAD
 }
 
 impl Storage for MemTable {
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.remove_expired(table, key);
         let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        let value = table.get(key).map(|e| {
+            e.touch(self.tick());
+            e.value.clone()
+        });
+        Ok(value)
     }
 
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+    fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
+        let size = entry_size(key.len(), &value);
+        let mut candidates = self.lock();
+        if let Some(candidates) = candidates.as_mut() {
+            // 淘汰时会访问其它 table，不能持有 table 的锁
+            let existing = self
+                .tables
+                .get(table)
+                .and_then(|t| t.get(&key).map(|e| entry_size(key.len(), &e.value)));
+            self.reserve(candidates, size, existing.unwrap_or(0), table, &key)?;
+        }
+
+        let key_len = key.len();
+        let entry = Entry::new(value, self.tick());
+        let old = self.get_or_create_table(table).insert(key.clone(), entry);
+        // 新旧 value 的差值一次性记到用量里，其它线程不会看到同时算上新旧 value 的用量
+        let old_size = old.as_ref().map_or(0, |e| entry_size(key_len, &e.value));
+        if size >= old_size {
+            self.used.fetch_add(size - old_size, Ordering::Relaxed);
+        } else {
+            self.release(old_size - size);
+        }
+
+        if let Some(candidates) = candidates.as_mut() {
+            // 重新写入会清除过期时间
+            let candidate = (table.to_owned(), key);
+            match self.evictable(false) {
+                true => candidates.insert(candidate),
+                false => candidates.swap_remove(&candidate),
+            };
+        }
+
+        Ok(old.and_then(|e| match e.is_expired() {
+            true => None,
+            false => Some(e.value),
+        }))
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        self.remove_expired(table, key);
         let table = self.get_or_create_table(table);
         Ok(table.contains_key(key))
     }
 
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v)| v))
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let mut candidates = self.lock();
+        let removed = self.get_or_create_table(table).remove(key);
+        Ok(removed.and_then(|(k, e)| {
+            self.release(entry_size(k.len(), &e.value));
+            if let Some(candidates) = candidates.as_mut() {
+                candidates.swap_remove(&(table.to_owned(), k));
+            }
+            match e.is_expired() {
+                true => None,
+                false => Some(e.value),
+            }
+        }))
+    }
+
+    fn expire(&self, table: &str, key: &[u8], ttl: Duration) -> Result<bool, KvError> {
+        self.remove_expired(table, key);
+        let mut candidates = self.lock();
+        let found = match self.get_or_create_table(table).get_mut(key) {
+            Some(mut entry) if !entry.is_expired() => {
+                entry.expire_at = Some(Instant::now() + ttl);
+                true
+            }
+            _ => false,
+        };
+
+        if let Some(candidates) = candidates.as_mut() {
+            if found && self.evictable(true) {
+                candidates.insert((table.to_owned(), Bytes::copy_from_slice(key)));
+            }
+        }
+        Ok(found)
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
         let table = self.get_or_create_table(table);
         Ok(table
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .filter(|v| !v.is_expired())
+            .map(|v| Kvpair::new(v.key().clone(), v.value.clone()))
             .collect())
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        // 使用 clone() 来获取 table 的 snapshot
-        let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
+        // 复制一份数据作为 table 的 snapshot
+        let table = self.get_or_create_table(table);
+        let data: Vec<_> = table
+            .iter()
+            .filter(|v| !v.is_expired())
+            .map(|v| (v.key().clone(), v.value.clone()))
+            .collect();
+        let iter = StorageIter::new(data.into_iter());
         Ok(Box::new(iter))
     }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self.tables.iter().map(|v| v.key().clone()).collect())
+    }
+
+    fn stats(&self) -> Result<Vec<Kvpair>, KvError> {
+        let keys: usize = self.tables.iter().map(|t| t.value().len()).sum();
+        Ok(vec![
+            Kvpair::new("engine", "memtable".into()),
+            Kvpair::new("tables", (self.tables.len() as i64).into()),
+            Kvpair::new("keys", (keys as i64).into()),
+            Kvpair::new("used_memory", (self.used_memory() as i64).into()),
+            Kvpair::new("maxmemory", (self.memory.maxmemory as i64).into()),
+            Kvpair::new("maxmemory_policy", self.memory.policy.as_str().into()),
+            Kvpair::new("evicted_keys", (self.evicted_keys() as i64).into()),
+            Kvpair::new("expired_keys", (self.expired_keys() as i64).into()),
+        ])
+    }
+
+    fn removed_keys(&self) -> u64 {
+        self.evicted_keys() + self.expired_keys()
+    }
 }
 
-impl From<(String, Value)> for Kvpair {
-    fn from(data: (String, Value)) -> Self {
+impl From<(Bytes, Value)> for Kvpair {
+    fn from(data: (Bytes, Value)) -> Self {
         Kvpair::new(data.0, data.1)
     }
 }
@@ -71,6 +407,7 @@ impl From<(String, Value)> for Kvpair {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{sync::Arc, thread};
 
     #[test]
     fn get_or_create_table_should_work() {
@@ -79,4 +416,146 @@ mod tests {
         store.get_or_create_table("t1");
         assert!(store.tables.contains_key("t1"));
     }
+
+    #[test]
+    fn used_memory_should_be_tracked() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.used_memory(), entry_size(2, &"v1".into()));
+        store.set("t1", "k1".into(), "value1".into()).unwrap();
+        assert_eq!(store.used_memory(), entry_size(2, &"value1".into()));
+        store.del("t1", b"k1").unwrap();
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[test]
+    fn noeviction_should_return_error_when_full() {
+        let store = memtable(EvictionPolicy::Noeviction, 2);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        let result = store.set("t1", "k3".into(), "v3".into());
+        assert!(matches!(result, Err(KvError::OutOfMemory)));
+
+        // 读和删除不受影响，删除之后可以再写入
+        assert_eq!(store.get("t1", b"k1").unwrap(), Some("v1".into()));
+        store.del("t1", b"k1").unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert_eq!(store.evicted_keys(), 0);
+    }
+
+    #[test]
+    fn allkeys_lru_should_evict_least_recently_used() {
+        let store = memtable(EvictionPolicy::AllkeysLru, 2);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        store.get("t1", b"k1").unwrap();
+
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert!(store.contains("t1", b"k1").unwrap());
+        assert!(!store.contains("t2", b"k2").unwrap());
+        assert_eq!(store.evicted_keys(), 1);
+    }
+
+    #[test]
+    fn allkeys_lfu_should_evict_least_frequently_used() {
+        let store = memtable(EvictionPolicy::AllkeysLfu, 2);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.get("t1", b"k1").unwrap();
+        store.get("t1", b"k1").unwrap();
+        store.get("t1", b"k2").unwrap();
+        // k2 访问得更晚，但次数更少
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert!(store.contains("t1", b"k1").unwrap());
+        assert!(!store.contains("t1", b"k2").unwrap());
+    }
+
+    #[test]
+    fn volatile_ttl_should_evict_key_expiring_first() {
+        let store = memtable(EvictionPolicy::VolatileTtl, 3);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert!(store.expire("t1", b"k2", Duration::from_secs(100)).unwrap());
+        assert!(store.expire("t1", b"k3", Duration::from_secs(10)).unwrap());
+
+        store.set("t1", "k4".into(), "v4".into()).unwrap();
+        assert!(!store.contains("t1", b"k3").unwrap());
+        store.set("t1", "k5".into(), "v5".into()).unwrap();
+        assert!(!store.contains("t1", b"k2").unwrap());
+
+        // 没有设置过期时间的 key 不会被淘汰
+        let result = store.set("t1", "k6".into(), "v6".into());
+        assert!(matches!(result, Err(KvError::OutOfMemory)));
+    }
+
+    #[test]
+    fn expired_key_should_not_be_returned() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert!(store.expire("t1", b"k1", Duration::from_millis(0)).unwrap());
+        assert!(!store.expire("t1", b"k3", Duration::from_secs(1)).unwrap());
+
+        assert_eq!(store.get("t1", b"k1").unwrap(), None);
+        assert!(!store.contains("t1", b"k1").unwrap());
+        assert_eq!(store.get_all("t1").unwrap().len(), 1);
+        assert_eq!(store.used_memory(), entry_size(2, &"v2".into()));
+    }
+
+    #[test]
+    fn memory_bound_should_hold_under_load() {
+        let max = 64 * 1024;
+        for policy in [EvictionPolicy::AllkeysLru, EvictionPolicy::AllkeysLfu] {
+            let store = Arc::new(MemTable::with_memory(MemoryConfig {
+                maxmemory: max,
+                policy,
+            }));
+
+            let handles: Vec<_> = (0..4)
+                .map(|t| {
+                    let store = store.clone();
+                    thread::spawn(move || {
+                        for i in 0..2000 {
+                            let table = format!("t{}", i % 3);
+                            let value = Value::from(Bytes::from(vec![0u8; i % 100]));
+                            store
+                                .set(&table, format!("k{}-{}", t, i).into(), value)
+                                .unwrap();
+                            // 其它线程同时在写入，限制在任何时候都要成立
+                            assert!(store.used_memory() <= max, "{:?}", policy);
+                            store
+                                .get(&table, format!("k{}-{}", t, i / 2).as_bytes())
+                                .unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+
+            assert!(store.used_memory() <= max, "{:?}", policy);
+            assert!(store.evicted_keys() > 0);
+            // 记录的内存和实际数据一致
+            let actual: usize = store
+                .tables
+                .iter()
+                .flat_map(|t| {
+                    t.iter()
+                        .map(|e| entry_size(e.key().len(), &e.value))
+                        .collect::<Vec<_>>()
+                })
+                .sum();
+            assert_eq!(store.used_memory(), actual);
+        }
+    }
+
+    /// 最多能放下 n 个 "kx" => "vx" 的 MemTable
+    fn memtable(policy: EvictionPolicy, n: usize) -> MemTable {
+        MemTable::with_memory(MemoryConfig {
+            maxmemory: entry_size(2, &"v1".into()) * n,
+            policy,
+        })
+    }
 }
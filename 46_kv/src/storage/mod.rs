@@ -1,25 +1,230 @@
 mod memory;
+mod mvcc;
 mod sleddb;
 
 pub use memory::MemTable;
+pub use mvcc::{Mvcc, SnapshotConfig};
 pub use sleddb::SledDb;
 
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::{sync::Arc, time::Duration};
+
 use crate::{KvError, Kvpair, Value};
 
-/// 对存储的抽象，我们不关心数据存在哪儿，但需要定义外界如何和存储打交道
+/// 对存储的抽象，我们不关心数据存在哪儿，但需要定义外界如何和存储打交道。
+/// table 是 string，key 可以是任意字节
 pub trait Storage: Send + Sync + 'static {
     /// 从一个 HashTable 里获取一个 key 的 value
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError>;
     /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
+    fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError>;
     /// 查看 HashTable 中是否有 key
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError>;
     /// 从 HashTable 中删除一个 key
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError>;
+    /// 给 key 设置过期时间，返回 key 是否存在。再次 set 这个 key 会清除过期时间
+    fn expire(&self, _table: &str, _key: &[u8], _ttl: Duration) -> Result<bool, KvError> {
+        Err(expire_unsupported())
+    }
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 返回所有 table 的名字
+    fn get_tables(&self) -> Result<Vec<String>, KvError>;
+    /// 存储的统计信息，INFO 命令会把它们放在 storage section 中
+    fn stats(&self) -> Result<Vec<Kvpair>, KvError> {
+        Ok(vec![])
+    }
+    /// 存储自己删除的 key 的数量（淘汰，过期），namespace 用它判断缓存的用量是否还准确
+    fn removed_keys(&self) -> u64 {
+        0
+    }
+    /// 固定当前的版本并返回版本号，之后可以用它读取固定时的数据
+    fn snapshot(&self) -> Result<u64, KvError> {
+        Err(snapshot_unsupported())
+    }
+    /// 释放 snapshot 固定的版本，返回这个版本之前是否被固定
+    fn release(&self, _version: u64) -> Result<bool, KvError> {
+        Err(snapshot_unsupported())
+    }
+    /// 读取 key 在 version 版本时的 value
+    fn get_at(&self, _table: &str, _key: &[u8], _version: u64) -> Result<Option<Value>, KvError> {
+        Err(snapshot_unsupported())
+    }
+    /// 返回 table 在 version 版本时所有的 kv pair
+    fn get_all_at(&self, _table: &str, _version: u64) -> Result<Vec<Kvpair>, KvError> {
+        Err(snapshot_unsupported())
+    }
+}
+
+/// 异步的存储抽象，用于访问远程服务或者做异步磁盘 IO 的存储。
+/// 方法拿到的是 `Arc<Self>` 和 owned 参数，这样实现者可以把工作交给其它 task。
+/// 所有的 Storage 都自动实现了这个 trait，调用会放在 spawn_blocking 中执行
+#[async_trait]
+pub trait AsyncStorage: Send + Sync + 'static {
+    /// 从一个 HashTable 里获取一个 key 的 value
+    async fn get(self: Arc<Self>, table: String, key: Bytes) -> Result<Option<Value>, KvError>;
+    /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
+    async fn set(
+        self: Arc<Self>,
+        table: String,
+        key: Bytes,
+        value: Value,
+    ) -> Result<Option<Value>, KvError>;
+    /// 查看 HashTable 中是否有 key
+    async fn contains(self: Arc<Self>, table: String, key: Bytes) -> Result<bool, KvError>;
+    /// 从 HashTable 中删除一个 key
+    async fn del(self: Arc<Self>, table: String, key: Bytes) -> Result<Option<Value>, KvError>;
+    /// 给 key 设置过期时间，返回 key 是否存在
+    async fn expire(
+        self: Arc<Self>,
+        _table: String,
+        _key: Bytes,
+        _ttl: Duration,
+    ) -> Result<bool, KvError> {
+        Err(expire_unsupported())
+    }
+    /// 遍历 HashTable，返回所有 kv pair
+    async fn get_all(self: Arc<Self>, table: String) -> Result<Vec<Kvpair>, KvError>;
+    /// 返回所有 table 的名字
+    async fn get_tables(self: Arc<Self>) -> Result<Vec<String>, KvError>;
+    /// 存储的统计信息，INFO 命令会把它们放在 storage section 中
+    async fn stats(self: Arc<Self>) -> Result<Vec<Kvpair>, KvError> {
+        Ok(vec![])
+    }
+    /// 存储自己删除的 key 的数量（淘汰，过期）
+    async fn removed_keys(self: Arc<Self>) -> u64 {
+        0
+    }
+    /// 固定当前的版本并返回版本号，之后可以用它读取固定时的数据
+    async fn snapshot(self: Arc<Self>) -> Result<u64, KvError> {
+        Err(snapshot_unsupported())
+    }
+    /// 释放 snapshot 固定的版本，返回这个版本之前是否被固定
+    async fn release(self: Arc<Self>, _version: u64) -> Result<bool, KvError> {
+        Err(snapshot_unsupported())
+    }
+    /// 读取 key 在 version 版本时的 value
+    async fn get_at(
+        self: Arc<Self>,
+        _table: String,
+        _key: Bytes,
+        _version: u64,
+    ) -> Result<Option<Value>, KvError> {
+        Err(snapshot_unsupported())
+    }
+    /// 返回 table 在 version 版本时所有的 kv pair
+    async fn get_all_at(
+        self: Arc<Self>,
+        _table: String,
+        _version: u64,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        Err(snapshot_unsupported())
+    }
+}
+
+// 同步的 Storage 可能会阻塞（比如 sled flush），放到 blocking 线程池里执行，
+// 避免卡住 tokio 的 worker
+#[async_trait]
+impl<S: Storage> AsyncStorage for S {
+    async fn get(self: Arc<Self>, table: String, key: Bytes) -> Result<Option<Value>, KvError> {
+        blocking(move || Storage::get(&*self, &table, &key)).await
+    }
+
+    async fn set(
+        self: Arc<Self>,
+        table: String,
+        key: Bytes,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        blocking(move || Storage::set(&*self, &table, key, value)).await
+    }
+
+    async fn contains(self: Arc<Self>, table: String, key: Bytes) -> Result<bool, KvError> {
+        blocking(move || Storage::contains(&*self, &table, &key)).await
+    }
+
+    async fn del(self: Arc<Self>, table: String, key: Bytes) -> Result<Option<Value>, KvError> {
+        blocking(move || Storage::del(&*self, &table, &key)).await
+    }
+
+    async fn expire(
+        self: Arc<Self>,
+        table: String,
+        key: Bytes,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        blocking(move || Storage::expire(&*self, &table, &key, ttl)).await
+    }
+
+    async fn get_all(self: Arc<Self>, table: String) -> Result<Vec<Kvpair>, KvError> {
+        blocking(move || Storage::get_all(&*self, &table)).await
+    }
+
+    async fn get_tables(self: Arc<Self>) -> Result<Vec<String>, KvError> {
+        blocking(move || Storage::get_tables(&*self)).await
+    }
+
+    async fn stats(self: Arc<Self>) -> Result<Vec<Kvpair>, KvError> {
+        blocking(move || Storage::stats(&*self)).await
+    }
+
+    async fn removed_keys(self: Arc<Self>) -> u64 {
+        // 只是读计数器，不需要放到 spawn_blocking 中
+        Storage::removed_keys(&*self)
+    }
+
+    async fn snapshot(self: Arc<Self>) -> Result<u64, KvError> {
+        blocking(move || Storage::snapshot(&*self)).await
+    }
+
+    async fn release(self: Arc<Self>, version: u64) -> Result<bool, KvError> {
+        blocking(move || Storage::release(&*self, version)).await
+    }
+
+    async fn get_at(
+        self: Arc<Self>,
+        table: String,
+        key: Bytes,
+        version: u64,
+    ) -> Result<Option<Value>, KvError> {
+        blocking(move || Storage::get_at(&*self, &table, &key, version)).await
+    }
+
+    async fn get_all_at(
+        self: Arc<Self>,
+        table: String,
+        version: u64,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        blocking(move || Storage::get_all_at(&*self, &table, version)).await
+    }
+}
+
+const SNAPSHOT_UNSUPPORTED: &str = "snapshot is not supported by the storage, use Mvcc";
+
+fn snapshot_unsupported() -> KvError {
+    KvError::InvalidCommand(SNAPSHOT_UNSUPPORTED.into())
+}
+
+/// 判断错误是否是因为存储不支持 snapshot
+pub(crate) fn is_snapshot_unsupported(e: &KvError) -> bool {
+    matches!(e, KvError::InvalidCommand(msg) if msg == SNAPSHOT_UNSUPPORTED)
+}
+
+fn expire_unsupported() -> KvError {
+    KvError::InvalidCommand("expire is not supported by the storage, use MemTable".into())
+}
+
+async fn blocking<T, F>(f: F) -> Result<T, KvError>
+where
+    F: FnOnce() -> Result<T, KvError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| KvError::Internal(format!("storage task failed: {}", e)))?
 }
 
 /// 提供 Storage iterator，这样 trait 的实现者只需要
@@ -71,6 +276,12 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn memtable_get_tables_should_work() {
+        let store = MemTable::new();
+        test_get_tables(store);
+    }
+
     #[test]
     fn sleddb_basic_interface_should_work() {
         let dir = tempdir().unwrap();
@@ -92,6 +303,62 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn sleddb_get_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_get_tables(store);
+    }
+
+    #[test]
+    fn memtable_binary_keys_should_work() {
+        let store = MemTable::new();
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn sleddb_binary_keys_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn memtable_stats_should_work() {
+        let store = MemTable::new();
+        test_stats(store);
+    }
+
+    #[test]
+    fn sleddb_stats_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_stats(store);
+    }
+
+    #[tokio::test]
+    async fn async_storage_adapter_should_work() {
+        let store = Arc::new(MemTable::new());
+        let v = store
+            .clone()
+            .set("t1".into(), "k1".into(), "v1".into())
+            .await;
+        assert!(v.unwrap().is_none());
+
+        let v = store.clone().get("t1".into(), "k1".into()).await;
+        assert_eq!(v.unwrap(), Some("v1".into()));
+        let v = store.clone().contains("t1".into(), "k1".into()).await;
+        assert!(v.unwrap());
+        let pairs = store.clone().get_all("t1".into()).await.unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("k1", "v1".into())]);
+        assert_eq!(store.clone().get_tables().await.unwrap(), vec!["t1"]);
+
+        let v = store.clone().del("t1".into(), "k1".into()).await;
+        assert_eq!(v.unwrap(), Some("v1".into()));
+        // 同步接口可以看到异步接口做的修改
+        assert!(Storage::get(&*store, "t1", b"k1").unwrap().is_none());
+    }
+
     fn test_basi_interface(store: impl Storage) {
         // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
         let v = store.set("t1", "hello".into(), "world".into());
@@ -101,25 +368,25 @@ mod tests {
         assert_eq!(v1.unwrap(), Some("world".into()));
 
         // get 存在的 key 会得到最新的值
-        let v = store.get("t1", "hello");
+        let v = store.get("t1", b"hello");
         assert_eq!(v.unwrap(), Some("world1".into()));
 
         // get 不存在的 key 或者 table 会得到 None
-        assert_eq!(None, store.get("t1", "hello1").unwrap());
-        assert!(store.get("t2", "hello1").unwrap().is_none());
+        assert_eq!(None, store.get("t1", b"hello1").unwrap());
+        assert!(store.get("t2", b"hello1").unwrap().is_none());
 
         // contains 纯在的 key 返回 true，否则 false
-        assert!(store.contains("t1", "hello").unwrap());
-        assert!(!store.contains("t1", "hello1").unwrap());
-        assert!(!store.contains("t2", "hello").unwrap());
+        assert!(store.contains("t1", b"hello").unwrap());
+        assert!(!store.contains("t1", b"hello1").unwrap());
+        assert!(!store.contains("t2", b"hello").unwrap());
 
         // del 存在的 key 返回之前的值
-        let v = store.del("t1", "hello");
+        let v = store.del("t1", b"hello");
         assert_eq!(v.unwrap(), Some("world1".into()));
 
         // del 不存在的 key 或 table 返回 None
-        assert_eq!(None, store.del("t1", "hello1").unwrap());
-        assert_eq!(None, store.del("t2", "hello").unwrap());
+        assert_eq!(None, store.del("t1", b"hello1").unwrap());
+        assert_eq!(None, store.del("t2", b"hello").unwrap());
     }
 
     fn test_get_all(store: impl Storage) {
@@ -149,4 +416,55 @@ mod tests {
             ]
         )
     }
+
+    fn test_get_tables(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        store.set("t3", "k1".into(), "v1".into()).unwrap();
+        let mut tables = store.get_tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["t1", "t2", "t3"]);
+    }
+
+    fn test_binary_keys(store: impl Storage) {
+        // 类似 UUID 的二进制 key，包含 ':'、0 和不是合法 UTF-8 的字节
+        let k1 = Bytes::from_static(b"\x00\x9f:\xff\x10:k");
+        let k2 = Bytes::from_static(b"\x00\x9f");
+        store.set("t1", k1.clone(), "v1".into()).unwrap();
+        store.set("t1", k2.clone(), "v2".into()).unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        store.set("t2", k1.clone(), "v4".into()).unwrap();
+
+        assert_eq!(store.get("t1", &k1).unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", &k2).unwrap(), Some("v2".into()));
+        // string key 和它的 UTF-8 字节是同一个 key
+        assert_eq!(store.get("t1", b"k3").unwrap(), Some("v3".into()));
+        assert!(store.contains("t2", &k1).unwrap());
+
+        let mut data = store.get_all("t1").unwrap();
+        data.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new(k2, "v2".into()),
+                Kvpair::new(k1.clone(), "v1".into()),
+                Kvpair::new("k3", "v3".into()),
+            ]
+        );
+        let mut tables = store.get_tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["t1", "t2"]);
+
+        assert_eq!(store.del("t1", &k1).unwrap(), Some("v1".into()));
+        assert!(!store.contains("t1", &k1).unwrap());
+        assert!(store.contains("t2", &k1).unwrap());
+    }
+
+    fn test_stats(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        let stats = store.stats().unwrap();
+        assert!(stats.contains(&Kvpair::new("keys", 2.into())));
+    }
 }
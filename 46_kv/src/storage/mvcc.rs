@@ -0,0 +1,512 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, MutexGuard, RwLock, RwLockReadGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvError, Kvpair, Storage, Value};
+
+/// 旧的 value 按 table 和 key 的 hash 分散到这么多个 stripe 中，同一个 stripe 的写入会互相等待
+const STRIPES: usize = 64;
+
+/// snapshot 的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// 固定的版本多久没有被读取就自动释放，单位秒
+    pub ttl: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self { ttl: 60 }
+    }
+}
+
+/// 给任意 Storage 加上多版本读取。
+/// 每次写入都会得到一个递增的提交序号，snapshot 把当前的序号固定下来作为版本号。
+/// 有版本被固定时，写入会把被覆盖的 value 和覆盖它的序号记下来，读取某个版本时
+/// 使用这个版本之后第一次写入之前的 value；不再被任何版本需要的旧 value 会被回收。
+/// 不经过 Mvcc 的修改（比如 MemTable 的过期和淘汰）不会被记录
+#[derive(Debug)]
+pub struct Mvcc<S> {
+    inner: S,
+    config: SnapshotConfig,
+    /// 最后一次写入的序号
+    version: AtomicU64,
+    /// 写入时拿读锁，snapshot 拿写锁，保证固定版本时没有执行到一半的写入
+    gate: RwLock<()>,
+    /// 按版本读取和 snapshot 时拿读锁，回收旧 value 时拿写锁
+    gc: RwLock<()>,
+    /// 被固定的版本
+    pinned: Mutex<BTreeMap<u64, Pin>>,
+    /// 被固定的版本的数量，写入时用它判断要不要记录旧的 value
+    pins: AtomicUsize,
+    /// 有旧的 value 等待回收
+    garbage: AtomicBool,
+    /// table -> key -> 按序号排列的旧 value
+    stripes: Vec<Mutex<History>>,
+}
+
+type History = HashMap<String, HashMap<Bytes, Vec<Version>>>;
+
+/// 序号为 seq 的写入之前的 value，None 表示那时 key 不存在
+#[derive(Debug)]
+struct Version {
+    seq: u64,
+    value: Option<Value>,
+}
+
+#[derive(Debug)]
+struct Pin {
+    /// snapshot 的次数，同一个版本可能被固定多次
+    refs: usize,
+    expire_at: Instant,
+}
+
+impl<S: Storage> Mvcc<S> {
+    pub fn new(inner: S, config: SnapshotConfig) -> Self {
+        Self {
+            inner,
+            config,
+            version: AtomicU64::new(0),
+            gate: RwLock::new(()),
+            gc: RwLock::new(()),
+            pinned: Mutex::new(BTreeMap::new()),
+            pins: AtomicUsize::new(0),
+            garbage: AtomicBool::new(false),
+            stripes: (0..STRIPES).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    /// 最后一次写入的序号
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.config.ttl)
+    }
+
+    fn stripe(&self, table: &str, key: &[u8]) -> MutexGuard<'_, History> {
+        let mut hasher = DefaultHasher::new();
+        (table, key).hash(&mut hasher);
+        self.stripes[hasher.finish() as usize % STRIPES]
+            .lock()
+            .unwrap()
+    }
+
+    /// 执行一次写入，f 返回被覆盖的 value
+    fn write<F>(&self, table: &str, key: &[u8], f: F) -> Result<Option<Value>, KvError>
+    where
+        F: FnOnce() -> Result<Option<Value>, KvError>,
+    {
+        let _gate = self.gate.read().unwrap();
+        if self.pins.load(Ordering::SeqCst) > 0 || self.garbage.load(Ordering::SeqCst) {
+            // 写入不等待正在进行的读取，拿不到锁就留给下一次回收
+            self.expire_pins();
+            if let Ok(_gc) = self.gc.try_write() {
+                self.collect();
+            }
+        }
+
+        // 写入和记录旧的 value 在同一个锁里完成，读取拿到锁之后总能看到写入对应的记录
+        let mut history = self.stripe(table, key);
+        let old = f()?;
+        let seq = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.pins.load(Ordering::SeqCst) > 0 {
+            let versions = history
+                .entry(table.into())
+                .or_default()
+                .entry(Bytes::copy_from_slice(key))
+                .or_default();
+            versions.push(Version {
+                seq,
+                value: old.clone(),
+            });
+        }
+        Ok(old)
+    }
+
+    /// 确认 version 仍然被固定并延长它的有效期。返回的锁在读取结束之前阻止回收
+    fn read_at(&self, version: u64) -> Result<RwLockReadGuard<'_, ()>, KvError> {
+        self.expire_pins();
+        let gc = self.gc.read().unwrap();
+        let mut pinned = self.pinned.lock().unwrap();
+        match pinned.get_mut(&version) {
+            Some(pin) => {
+                pin.expire_at = Instant::now() + self.ttl();
+                Ok(gc)
+            }
+            None => Err(KvError::SnapshotExpired(version)),
+        }
+    }
+
+    /// 释放过期的版本
+    fn expire_pins(&self) {
+        let now = Instant::now();
+        let mut pinned = self.pinned.lock().unwrap();
+        let len = pinned.len();
+        pinned.retain(|_, pin| pin.expire_at > now);
+        if pinned.len() != len {
+            self.unpinned(&pinned);
+        }
+    }
+
+    /// 有版本被释放之后调用，旧的 value 在下一次回收时删除
+    fn unpinned(&self, pinned: &BTreeMap<u64, Pin>) {
+        self.pins.store(pinned.len(), Ordering::SeqCst);
+        self.garbage.store(true, Ordering::SeqCst);
+    }
+
+    /// 删除不再被任何固定的版本需要的旧 value，调用者需要持有 gc 的写锁
+    fn collect(&self) {
+        if !self.garbage.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let pinned: BTreeSet<u64> = self.pinned.lock().unwrap().keys().copied().collect();
+        for stripe in &self.stripes {
+            let mut history = stripe.lock().unwrap();
+            history.retain(|_, keys| {
+                keys.retain(|_, versions| {
+                    // 序号为 seq 的记录是 [上一条记录的 seq, seq) 之间的版本看到的 value
+                    let mut from = 0;
+                    versions.retain(|v| {
+                        let needed = pinned.range(from..v.seq).next().is_some();
+                        from = v.seq;
+                        needed
+                    });
+                    !versions.is_empty()
+                });
+                !keys.is_empty()
+            });
+        }
+    }
+
+    /// 记录的旧 value 的数量
+    fn versions(&self) -> usize {
+        self.stripes
+            .iter()
+            .map(|s| {
+                let history = s.lock().unwrap();
+                history
+                    .values()
+                    .flat_map(|t| t.values())
+                    .map(Vec::len)
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+/// key 在 version 版本时的 value：version 之后第一次写入之前的 value，没有这样的写入时返回 None，
+/// 此时存储中的 value 就是 version 版本的 value
+fn lookup(versions: &[Version], version: u64) -> Option<&Option<Value>> {
+    versions.iter().find(|v| v.seq > version).map(|v| &v.value)
+}
+
+impl<S: Storage> Storage for Mvcc<S> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
+        let name = key.clone();
+        self.write(table, &name, || self.inner.set(table, key, value))
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.write(table, key, || self.inner.del(table, key))
+    }
+
+    fn expire(&self, table: &str, key: &[u8], ttl: Duration) -> Result<bool, KvError> {
+        self.inner.expire(table, key, ttl)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.get_tables()
+    }
+
+    fn stats(&self) -> Result<Vec<Kvpair>, KvError> {
+        let mut stats = self.inner.stats()?;
+        stats.extend([
+            Kvpair::new("mvcc_version", (self.version() as i64).into()),
+            Kvpair::new(
+                "mvcc_snapshots",
+                (self.pins.load(Ordering::SeqCst) as i64).into(),
+            ),
+            Kvpair::new("mvcc_versions", (self.versions() as i64).into()),
+        ]);
+        Ok(stats)
+    }
+
+    fn removed_keys(&self) -> u64 {
+        self.inner.removed_keys()
+    }
+
+    fn snapshot(&self) -> Result<u64, KvError> {
+        let _gate = self.gate.write().unwrap();
+        let _gc = self.gc.read().unwrap();
+        let version = self.version();
+        let mut pinned = self.pinned.lock().unwrap();
+        let pin = pinned.entry(version).or_insert(Pin {
+            refs: 0,
+            expire_at: Instant::now(),
+        });
+        pin.refs += 1;
+        pin.expire_at = Instant::now() + self.ttl();
+        self.pins.store(pinned.len(), Ordering::SeqCst);
+        Ok(version)
+    }
+
+    fn release(&self, version: u64) -> Result<bool, KvError> {
+        self.expire_pins();
+        {
+            let mut pinned = self.pinned.lock().unwrap();
+            let pin = match pinned.get_mut(&version) {
+                Some(pin) => pin,
+                None => return Ok(false),
+            };
+            pin.refs -= 1;
+            if pin.refs == 0 {
+                pinned.remove(&version);
+                self.unpinned(&pinned);
+            }
+        }
+
+        let _gc = self.gc.write().unwrap();
+        self.collect();
+        Ok(true)
+    }
+
+    fn get_at(&self, table: &str, key: &[u8], version: u64) -> Result<Option<Value>, KvError> {
+        let _gc = self.read_at(version)?;
+        let history = self.stripe(table, key);
+        let versions = history.get(table).and_then(|t| t.get(key));
+        match versions.and_then(|v| lookup(v, version)) {
+            Some(value) => Ok(value.clone()),
+            None => self.inner.get(table, key),
+        }
+    }
+
+    fn get_all_at(&self, table: &str, version: u64) -> Result<Vec<Kvpair>, KvError> {
+        let _gc = self.read_at(version)?;
+
+        // 遍历存储时不持有任何锁，遍历期间的写入一定在之后检查记录时能看到
+        let mut seen = HashSet::new();
+        let mut pairs = Vec::new();
+        for pair in self.inner.get_all(table)? {
+            let history = self.stripe(table, &pair.key);
+            let versions = history.get(table).and_then(|t| t.get(&pair.key[..]));
+            let value = match versions.and_then(|v| lookup(v, version)) {
+                Some(value) => value.clone(),
+                None => pair.value.clone(),
+            };
+            drop(history);
+
+            seen.insert(pair.key.clone());
+            if let Some(value) = value {
+                pairs.push(Kvpair::new(pair.key, value));
+            }
+        }
+
+        // version 之后被删除的 key 不在存储中了，只能从记录中找到
+        for stripe in &self.stripes {
+            let history = stripe.lock().unwrap();
+            let keys = match history.get(table) {
+                Some(keys) => keys,
+                None => continue,
+            };
+            for (key, versions) in keys {
+                if seen.contains(key) {
+                    continue;
+                }
+                if let Some(Some(value)) = lookup(versions, version) {
+                    pairs.push(Kvpair::new(key.clone(), value.clone()));
+                }
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn get_at_should_return_value_of_pinned_version() {
+        let store = mvcc();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        let version = store.snapshot().unwrap();
+        assert_eq!(version, 2);
+
+        store.set("t1", "k1".into(), "v1.1".into()).unwrap();
+        store.set("t1", "k1".into(), "v1.2".into()).unwrap();
+        store.del("t1", b"k2").unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+
+        assert_eq!(
+            store.get_at("t1", b"k1", version).unwrap(),
+            Some("v1".into())
+        );
+        assert_eq!(
+            store.get_at("t1", b"k2", version).unwrap(),
+            Some("v2".into())
+        );
+        assert_eq!(store.get_at("t1", b"k3", version).unwrap(), None);
+        // 不带版本的读取看到的是最新的数据
+        assert_eq!(store.get("t1", b"k1").unwrap(), Some("v1.2".into()));
+
+        let mut pairs = store.get_all_at("t1", version).unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            pairs,
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn multiple_snapshots_should_see_their_own_versions() {
+        let store = mvcc();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let v1 = store.snapshot().unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        let v2 = store.snapshot().unwrap();
+        store.set("t1", "k1".into(), "v3".into()).unwrap();
+
+        assert_eq!(store.get_at("t1", b"k1", v1).unwrap(), Some("v1".into()));
+        assert_eq!(store.get_at("t1", b"k1", v2).unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn released_versions_should_be_collected() {
+        let store = mvcc();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let v1 = store.snapshot().unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        let v2 = store.snapshot().unwrap();
+        store.set("t1", "k1".into(), "v3".into()).unwrap();
+        assert_eq!(store.versions(), 2);
+
+        // v1 不再需要，v2 需要的记录还在
+        assert!(store.release(v1).unwrap());
+        assert!(!store.release(v1).unwrap());
+        assert_eq!(store.versions(), 1);
+        assert!(matches!(
+            store.get_at("t1", b"k1", v1),
+            Err(KvError::SnapshotExpired(_))
+        ));
+        assert_eq!(store.get_at("t1", b"k1", v2).unwrap(), Some("v2".into()));
+
+        assert!(store.release(v2).unwrap());
+        assert_eq!(store.versions(), 0);
+        // 没有固定的版本时写入不会记录旧的 value
+        store.set("t1", "k1".into(), "v4".into()).unwrap();
+        assert_eq!(store.versions(), 0);
+    }
+
+    #[test]
+    fn same_version_should_be_pinned_until_all_released() {
+        let store = mvcc();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let v1 = store.snapshot().unwrap();
+        assert_eq!(store.snapshot().unwrap(), v1);
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+
+        store.release(v1).unwrap();
+        assert_eq!(store.get_at("t1", b"k1", v1).unwrap(), Some("v1".into()));
+        store.release(v1).unwrap();
+        assert!(store.get_at("t1", b"k1", v1).is_err());
+    }
+
+    #[test]
+    fn expired_snapshot_should_be_released() {
+        let store = Mvcc::new(MemTable::new(), SnapshotConfig { ttl: 0 });
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let version = store.snapshot().unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+
+        assert!(matches!(
+            store.get_at("t1", b"k1", version),
+            Err(KvError::SnapshotExpired(v)) if v == version
+        ));
+        store.set("t1", "k1".into(), "v3".into()).unwrap();
+        assert_eq!(store.versions(), 0);
+    }
+
+    #[test]
+    fn snapshot_should_be_consistent_under_concurrent_writes() {
+        let store = Arc::new(mvcc());
+        for i in 0..100 {
+            store.set("t1", format!("k{}", i).into(), 0.into()).unwrap();
+        }
+        let version = store.snapshot().unwrap();
+
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        let key = format!("k{}", (i * 7 + t) % 150);
+                        match i % 3 {
+                            0 => store.del("t1", key.as_bytes()).unwrap(),
+                            _ => store.set("t1", key.into(), (i + 1).into()).unwrap(),
+                        };
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..20 {
+            let pairs = store.get_all_at("t1", version).unwrap();
+            assert_eq!(pairs.len(), 100);
+            assert!(pairs.iter().all(|p| p.value == Some(0.into())));
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(store.get_all_at("t1", version).unwrap().len(), 100);
+    }
+
+    #[test]
+    fn stats_should_include_mvcc_state() {
+        let store = mvcc();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.snapshot().unwrap();
+        let stats = store.stats().unwrap();
+        assert!(stats.contains(&Kvpair::new("engine", "memtable".into())));
+        assert!(stats.contains(&Kvpair::new("mvcc_version", 1.into())));
+        assert!(stats.contains(&Kvpair::new("mvcc_snapshots", 1.into())));
+    }
+
+    fn mvcc() -> Mvcc<MemTable> {
+        Mvcc::new(MemTable::new(), SnapshotConfig::default())
+    }
+}
@@ -1,7 +1,8 @@
+use bytes::Bytes;
 use sled::{Db, IVec};
-use std::{convert::TryInto, path::Path, str};
+use std::{convert::TryInto, path::Path};
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use crate::{format_key, KvError, Kvpair, Storage, StorageIter, Value};
 
 #[derive(Debug)]
 pub struct SledDb(Db);
@@ -13,8 +14,14 @@ impl SledDb {
 
     // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
     // 来模拟一个 table。当然，还可以用其它方案。
-    fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    // key 可以是任意字节（包括 ':'），解析时以第一个 ':' 分隔 table 和 key，
+    // 所以 string key 的布局和之前完全一样，已有的数据不需要迁移
+    fn get_full_key(table: &str, key: &[u8]) -> Vec<u8> {
+        let mut name = Vec::with_capacity(table.len() + 1 + key.len());
+        name.extend_from_slice(table.as_bytes());
+        name.push(b':');
+        name.extend_from_slice(key);
+        name
     }
 
     // 遍历 table 的 key 时，我们直接把 prefix: 当成 table
@@ -30,13 +37,13 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 }
 
 impl Storage for SledDb {
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        let result = self.0.get(name)?.map(|v| v.as_ref().try_into());
         flip(result)
     }
 
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+    fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, &key);
         let data: Vec<u8> = value.try_into()?;
 
@@ -44,13 +51,13 @@ impl Storage for SledDb {
         flip(result)
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
         let name = SledDb::get_full_key(table, key);
 
         Ok(self.0.contains_key(name)?)
     }
 
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
 
         let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
@@ -69,13 +76,41 @@ impl Storage for SledDb {
         let iter = StorageIter::new(self.0.scan_prefix(prefix));
         Ok(Box::new(iter))
     }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = Vec::new();
+        let mut start = Vec::new();
+
+        // 找到一个 table 后，直接跳到 "table;" 继续找下一个（';' 紧挨着 ':'），
+        // 这样不必遍历每个 table 下所有的 key
+        while let Some(key) = self.0.range(start.as_slice()..).keys().next() {
+            let key = key?;
+            let table = ivec_to_table(key.as_ref());
+            start = [table, b";"].concat();
+            // table 的名字都是 string，不是 UTF-8 说明数据被损坏了，不能悄悄地改掉名字
+            let table = String::from_utf8(table.to_vec()).map_err(|_| {
+                KvError::Internal(format!("table name is not UTF-8: {}", format_key(table)))
+            })?;
+            tables.push(table);
+        }
+
+        Ok(tables)
+    }
+
+    fn stats(&self) -> Result<Vec<Kvpair>, KvError> {
+        Ok(vec![
+            Kvpair::new("engine", "sled".into()),
+            Kvpair::new("keys", (self.0.len() as i64).into()),
+            Kvpair::new("size_on_disk", (self.0.size_on_disk()? as i64).into()),
+        ])
+    }
 }
 
 impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
     fn from(v: Result<(IVec, IVec), sled::Error>) -> Self {
         match v {
             Ok((k, v)) => match v.as_ref().try_into() {
-                Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
+                Ok(v) => Kvpair::new(Bytes::copy_from_slice(ivec_to_key(k.as_ref())), v),
                 Err(_) => Kvpair::default(),
             },
             _ => Kvpair::default(),
@@ -83,9 +118,29 @@ impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
     }
 }
 
-fn ivec_to_key(ivec: &[u8]) -> &str {
-    let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(':');
-    iter.next();
-    iter.next().unwrap()
+fn ivec_to_key(ivec: &[u8]) -> &[u8] {
+    let table = ivec_to_table(ivec);
+    &ivec[(table.len() + 1).min(ivec.len())..]
+}
+
+fn ivec_to_table(ivec: &[u8]) -> &[u8] {
+    let pos = ivec.iter().position(|b| *b == b':').unwrap_or(ivec.len());
+    &ivec[..pos]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn get_tables_should_reject_non_utf8_names() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.0.insert(b"t\xff:k1", b"".as_ref()).unwrap();
+
+        let e = store.get_tables().unwrap_err();
+        assert!(e.to_string().contains(r#"b"t\xff""#));
+    }
 }
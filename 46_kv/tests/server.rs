@@ -1,7 +1,8 @@
 use anyhow::Result;
+use futures::StreamExt;
 use kv6::{
-    start_client_with_config, start_server_with_config, ClientConfig, CommandRequest, ServerConfig,
-    StorageConfig,
+    start_client_with_config, start_server_with_config, ClientConfig, CommandRequest, Compression,
+    KvClient, ListenerConfig, QuicCtrl, ServerConfig, StorageConfig, Transport,
 };
 use std::time::Duration;
 use tokio::time;
@@ -39,3 +40,135 @@ async fn yamux_server_client_full_tests() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn kv_client_full_tests() -> Result<()> {
+    let addr = "127.0.0.1:10087";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+
+    // 启动服务器
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+
+    let client = KvClient::connect(&config).await?;
+    client.set("table1", "hello", "world").await?;
+
+    let data: Option<String> = client.get("table1", "hello").await?;
+    assert_eq!(data.as_deref(), Some("world"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiple_listeners_full_tests() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let sock = dir.path().join("kv.sock").to_string_lossy().to_string();
+    let listeners = vec![
+        (Transport::Tls, "127.0.0.1:10088".to_string()),
+        (Transport::Tcp, "127.0.0.1:10089".to_string()),
+        (Transport::Unix, sock),
+        (Transport::WebSocket, "127.0.0.1:10090".to_string()),
+    ];
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.storage = StorageConfig::MemTable;
+    config.listeners = listeners
+        .iter()
+        .map(|(transport, addr)| ListenerConfig {
+            transport: *transport,
+            addr: addr.clone(),
+            allow_plaintext: false,
+        })
+        .collect();
+
+    // 启动服务器
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+
+    // 不同的 listener 共享同一个存储
+    for (i, (transport, addr)) in listeners.into_iter().enumerate() {
+        let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+        config.general.addr = addr;
+        config.transport = transport;
+
+        let client = KvClient::connect(&config).await?;
+        client
+            .set("table1", &format!("{:?}", transport), i as i64)
+            .await?;
+
+        let data: Option<i64> = client.get("table1", "Tls").await?;
+        assert_eq!(data, Some(0));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn quic_server_client_full_tests() -> Result<()> {
+    let addr = "127.0.0.1:10091";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.storage = StorageConfig::MemTable;
+    config.listeners = vec![ListenerConfig {
+        transport: Transport::Quic,
+        addr: addr.into(),
+        allow_plaintext: false,
+    }];
+
+    // 启动服务器
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.transport = Transport::Quic;
+    config.compression.algorithm = Compression::Zstd;
+    config.frame.checksum = true;
+
+    let mut ctrl = QuicCtrl::connect(&config).await?;
+
+    // 每个 stream 都是独立的 QUIC stream
+    let mut stream = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+    stream.execute_unary(&cmd).await?;
+
+    let mut stream = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hget("table1", "hello");
+    let data = stream.execute_unary(&cmd).await?;
+
+    assert_eq!(data.status, 200);
+    assert_eq!(data.values, &["world".into()]);
+
+    // streaming 的命令也可以工作
+    let stream = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_subscribe("quic");
+    let mut result = stream.execute_streaming(&cmd).await?;
+    let id = result.id;
+
+    let mut stream = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_publish("quic", vec!["hello".into()]);
+    stream.execute_unary(&cmd).await?;
+
+    let data = result.next().await.unwrap()?;
+    assert_eq!(data.values, &["hello".into()]);
+
+    let mut stream = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_unsubscribe("quic", id as _);
+    stream.execute_unary(&cmd).await?;
+
+    ctrl.close();
+    Ok(())
+}